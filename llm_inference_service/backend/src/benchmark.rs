@@ -0,0 +1,117 @@
+// src/benchmark.rs
+// Synthetic generations for measuring raw throughput of a loaded model.
+// The prompt is a fixed token sequence and decoding is greedy with no stop tokens, so every
+// run does exactly the same work and results are comparable across models and devices.
+use crate::gpu;
+use crate::infer::forward;
+use crate::model::LoadedModel;
+use anyhow::{Context, Result};
+use candle_core::{D, DType, Tensor};
+use serde::Serialize;
+use utoipa::ToSchema;
+use std::time::Instant;
+
+// Upper bound on runs per benchmark request
+pub const MAX_BENCHMARK_RUNS: usize = 20;
+// Standard workload for requests that do not size it
+pub const DEFAULT_PROMPT_TOKENS: usize = 512;
+pub const DEFAULT_GEN_TOKENS: usize = 128;
+
+// Timings of one synthetic generation
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct BenchmarkRun {
+    pub ttft_ms: f64, // Prefill plus the first sampled token
+    pub total_ms: f64,
+    pub prefill_tokens_per_sec: f64,
+    pub decode_tokens_per_sec: f64, // Tokens after the first one, over the time they took
+    pub gpu_used_mb: Option<usize>, // Memory in use on the model's GPU (all processes) at the end of the run
+}
+
+// Aggregate of one metric across runs
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct Summary {
+    pub mean: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub stddev: f64, // Sample standard deviation; 0 for a single run
+}
+
+// Fixed prompt of `len` tokens spread over the vocabulary, away from the special tokens at its start
+fn synthetic_prompt(len: usize, vocab_size: usize) -> Vec<u32> {
+    let start = 1000.min(vocab_size / 2);
+    let span = (vocab_size - start).clamp(1, 1000);
+    (0..len).map(|i| (start + (i * 7) % span) as u32).collect()
+}
+
+// Greedy next token from the logits of the last position
+fn argmax(logits: &Tensor) -> Result<u32> {
+    let token = logits
+        .squeeze(0)?
+        .squeeze(0)?
+        .to_dtype(DType::F32)?
+        .argmax(D::Minus1)?
+        .to_scalar::<u32>()
+        .context("benchmark: argmax failed")?;
+    Ok(token)
+}
+
+// Prefill `prompt_tokens` synthetic tokens, then decode until `gen_tokens` tokens exist.
+// Overwrites the KV cache, so the next real request starts from a reset cache.
+// `vram_index` is the GPU to read memory use from, for models on CUDA.
+pub fn run(
+    loaded_model: &mut LoadedModel,
+    prompt_tokens: usize,
+    gen_tokens: usize,
+    vram_index: Option<usize>,
+) -> Result<BenchmarkRun> {
+    loaded_model.reset_cache();
+    let device = loaded_model.device.clone();
+    let prompt = synthetic_prompt(prompt_tokens, loaded_model.tokenizer.get_vocab_size(true));
+
+    let started = Instant::now();
+    let input = Tensor::new(prompt.as_slice(), &device)?.unsqueeze(0)?;
+    let mut next = argmax(&forward(&mut loaded_model.model, &input, 0)?)?;
+    let ttft = started.elapsed();
+
+    for pos in prompt_tokens..prompt_tokens + gen_tokens - 1 {
+        let input = Tensor::new(&[next], &device)?.unsqueeze(0)?;
+        next = argmax(&forward(&mut loaded_model.model, &input, pos)?)?;
+    }
+    let total = started.elapsed();
+    // Read while the filled KV cache is still allocated
+    let gpu_used_mb = vram_index.filter(|_| device.is_cuda()).and_then(gpu::used_vram_mb);
+
+    let decode_secs = (total - ttft).as_secs_f64();
+    Ok(BenchmarkRun {
+        ttft_ms: ttft.as_secs_f64() * 1000.0,
+        total_ms: total.as_secs_f64() * 1000.0,
+        prefill_tokens_per_sec: prompt_tokens as f64 / ttft.as_secs_f64(),
+        decode_tokens_per_sec: if decode_secs > 0.0 { (gen_tokens - 1) as f64 / decode_secs } else { 0.0 },
+        gpu_used_mb,
+    })
+}
+
+// Mean, median, 95th percentile (nearest rank) and standard deviation of non-empty `values`
+pub fn summarize(values: &[f64]) -> Summary {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let len = sorted.len();
+    let median = if len.is_multiple_of(2) {
+        (sorted[len / 2 - 1] + sorted[len / 2]) / 2.0
+    } else {
+        sorted[len / 2]
+    };
+    let p95_rank = ((len as f64 * 0.95).ceil() as usize).clamp(1, len);
+    let mean = sorted.iter().sum::<f64>() / len as f64;
+    let stddev = if len > 1 {
+        (sorted.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (len - 1) as f64).sqrt()
+    } else {
+        0.0
+    };
+    Summary {
+        mean,
+        median,
+        p95: sorted[p95_rank - 1],
+        stddev,
+    }
+}
@@ -0,0 +1,317 @@
+// src/bin/llm_chat.rs
+// Command-line chat client for the inference server: one-shot with a prompt argument, or an
+// interactive session without one. Replies are streamed from /infer_stream as they arrive.
+use std::io::Write;
+use std::process::ExitCode;
+use std::str::FromStr;
+
+use serde::{Serialize, de::DeserializeOwned};
+use shared_types::{ApiResponse, InferRequest, LoadModelRequest, ModelList, sse::SseParser};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+const DEFAULT_URL: &str = "http://127.0.0.1:8081";
+
+// Exit codes, so scripts can tell a server that is down from a request that failed
+const EXIT_GENERATION: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_CONNECTION: u8 = 3;
+
+const USAGE: &str = "\
+Usage: llm_chat [OPTIONS] [PROMPT]
+
+Sends PROMPT and prints the reply as it is generated. Without PROMPT, starts an
+interactive session.
+
+Options:
+  --url <URL>            Server address (default http://127.0.0.1:8081)
+  --model <NAME>         Load (if needed) and switch to this model first
+  --system <TEXT>        System prompt
+  --temperature <T>      Sampling temperature (0 decodes greedily)
+  --top-p <P>            Nucleus sampling threshold
+  --max-tokens <N>       Most tokens to generate
+  --seed <S>             Random seed
+  -h, --help             Show this help
+
+Exit codes: 0 success, 1 the server reported an error, 2 bad arguments,
+3 the server could not be reached or the connection dropped.";
+
+const SESSION_HELP: &str = "\
+Commands:
+  /model [NAME]   Show the active model, or load and switch to NAME
+  /system [TEXT]  Set the system prompt (empty clears it)
+  /temp [T]       Set the temperature (empty returns to the model default)
+  /help           Show this help
+  /quit           Leave (Ctrl-D works too)
+Each line is sent as its own prompt: the server keeps no conversation history.";
+
+enum Failure {
+    Connection(String), // Server unreachable, or the connection dropped mid-reply
+    Generation(String), // The server answered with an error
+}
+
+impl Failure {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Failure::Connection(_) => EXIT_CONNECTION,
+            Failure::Generation(_) => EXIT_GENERATION,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            Failure::Connection(msg) | Failure::Generation(msg) => msg,
+        }
+    }
+}
+
+fn connection_failure(url: &str, e: reqwest::Error) -> Failure {
+    Failure::Connection(format!("cannot reach {}: {}", url, e))
+}
+
+struct Options {
+    url: String,
+    model: Option<String>,
+    prompt: Option<String>,
+    request: InferRequest, // Sampling options; the prompt is filled in per message
+}
+
+// Value of a flag, parsed; the flag name is kept for the error message
+fn flag_value<T: FromStr>(flag: &str, args: &mut impl Iterator<Item = String>) -> Result<T, String> {
+    let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+    value.parse().map_err(|_| format!("invalid value for {}: `{}`", flag, value))
+}
+
+// None when help was asked for
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        url: DEFAULT_URL.to_string(),
+        model: None,
+        prompt: None,
+        request: InferRequest::default(),
+    };
+    let mut words = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--url" => options.url = flag_value(&arg, &mut args)?,
+            "--model" => options.model = Some(flag_value(&arg, &mut args)?),
+            "--system" => options.request.system_prompt = Some(flag_value(&arg, &mut args)?),
+            "--temperature" => options.request.temperature = Some(flag_value(&arg, &mut args)?),
+            "--top-p" => options.request.top_p = Some(flag_value(&arg, &mut args)?),
+            "--max-tokens" => options.request.max_tokens = Some(flag_value(&arg, &mut args)?),
+            "--seed" => options.request.seed = Some(flag_value(&arg, &mut args)?),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ => words.push(arg),
+        }
+    }
+    // Unquoted prompts arrive as several words
+    if !words.is_empty() {
+        options.prompt = Some(words.join(" "));
+    }
+    options.url = options.url.trim_end_matches('/').to_string();
+    Ok(Some(options))
+}
+
+struct Client {
+    http: reqwest::Client,
+    url: String,
+}
+
+impl Client {
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, Failure> {
+        let url = format!("{}{}", self.url, path);
+        let response = self.http.get(&url).send().await.map_err(|e| connection_failure(&self.url, e))?;
+        response
+            .json()
+            .await
+            .map_err(|e| Failure::Generation(format!("unexpected reply from {}: {}", path, e)))
+    }
+
+    // POST a JSON body and unwrap the ApiResponse envelope
+    async fn post<T: DeserializeOwned>(&self, path: &str, body: &impl Serialize) -> Result<T, Failure> {
+        let url = format!("{}{}", self.url, path);
+        let response = self.http.post(&url).json(body).send().await.map_err(|e| connection_failure(&self.url, e))?;
+        let reply: ApiResponse<T> = response
+            .json()
+            .await
+            .map_err(|e| Failure::Generation(format!("unexpected reply from {}: {}", path, e)))?;
+        match reply.data {
+            Some(data) if reply.status == "ok" => Ok(data),
+            _ => Err(Failure::Generation(reply.message.unwrap_or_else(|| format!("{} failed", path)))),
+        }
+    }
+
+    // Load the model unless it already is, and make it the active one
+    async fn use_model(&self, name: &str) -> Result<(), Failure> {
+        let request = LoadModelRequest { name: name.to_string(), run_async: false };
+        let message: String = self.post("/load_model", &request).await?;
+        eprintln!("{}", message);
+        Ok(())
+    }
+
+    async fn active_model(&self) -> Result<String, Failure> {
+        let list: ModelList = self.get("/models").await?;
+        Ok(list.active)
+    }
+
+    // Send a prompt and print the reply as it is generated
+    async fn stream(&self, request: &InferRequest) -> Result<(), Failure> {
+        let url = format!("{}/infer_stream", self.url);
+        let mut response = self
+            .http
+            .post(&url)
+            .json(request)
+            .send()
+            .await
+            .map_err(|e| connection_failure(&self.url, e))?;
+        // Rejected before the stream started: the body is an ApiResponse
+        if !response.status().is_success() {
+            let status = response.status();
+            let reply: Option<ApiResponse<()>> = response.json().await.ok();
+            let message = reply.and_then(|reply| reply.message);
+            return Err(Failure::Generation(message.unwrap_or_else(|| format!("server returned {}", status))));
+        }
+
+        let mut parser = SseParser::default();
+        let mut stdout = std::io::stdout();
+        while let Some(chunk) = response.chunk().await.map_err(|e| connection_failure(&self.url, e))? {
+            // The server only sends unnamed events; any other kind is not meant for this client
+            for event in parser.push(&chunk).into_iter().filter(|e| e.event == "message").map(|e| e.data) {
+                if event == "[DONE]" {
+                    println!();
+                    return Ok(());
+                }
+                if event.starts_with("[MODEL:") {
+                    continue;
+                }
+                match serde_json::from_str::<serde_json::Value>(&event) {
+                    // Generation failed, or could not start (e.g. no model is active)
+                    Ok(json) if json.get("error").is_some() => {
+                        println!();
+                        let error = json["error"].as_str().unwrap_or("generation failed");
+                        return Err(Failure::Generation(error.to_string()));
+                    }
+                    Ok(json) => {
+                        if let Some(text) = json["text"].as_str() {
+                            print!("{}", text);
+                            let _ = stdout.flush();
+                        }
+                        // Say why a reply stopped early; progress and usage events are skipped
+                        if let Some(reason) = json["finish_reason"].as_str()
+                            && reason != "stop"
+                        {
+                            eprint!("\n[stopped: {}]", reason);
+                        }
+                    }
+                    // Not an event this client knows
+                    Err(_) => return Err(Failure::Generation(event)),
+                }
+            }
+        }
+        Err(Failure::Connection("connection closed before the reply finished".to_string()))
+    }
+}
+
+// Handle a /command of the interactive session
+async fn run_command(client: &Client, request: &mut InferRequest, command: &str) {
+    let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+    let argument = argument.trim();
+    match name {
+        "model" if argument.is_empty() => match client.active_model().await {
+            Ok(active) if active.is_empty() => eprintln!("No active model."),
+            Ok(active) => eprintln!("Active model: {}", active),
+            Err(failure) => eprintln!("error: {}", failure.message()),
+        },
+        "model" => {
+            if let Err(failure) = client.use_model(argument).await {
+                eprintln!("error: {}", failure.message());
+            }
+        }
+        "system" if argument.is_empty() => {
+            request.system_prompt = None;
+            eprintln!("System prompt cleared.");
+        }
+        "system" => {
+            request.system_prompt = Some(argument.to_string());
+            eprintln!("System prompt set.");
+        }
+        "temp" if argument.is_empty() => {
+            request.temperature = None;
+            eprintln!("Temperature: model default.");
+        }
+        "temp" => match argument.parse::<f64>() {
+            Ok(temperature) => {
+                request.temperature = Some(temperature);
+                eprintln!("Temperature: {}", temperature);
+            }
+            Err(_) => eprintln!("invalid temperature `{}`", argument),
+        },
+        "help" => eprintln!("{}", SESSION_HELP),
+        _ => eprintln!("Unknown command /{} (try /help)", name),
+    }
+}
+
+// Read prompts from stdin until /quit or end of input; failures are reported and the session goes on
+async fn run_session(client: &Client, mut request: InferRequest) {
+    eprintln!("Connected to {}. Type /help for commands.", client.url);
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        print!("> ");
+        let _ = std::io::stdout().flush();
+        let Ok(Some(line)) = lines.next_line().await else {
+            println!();
+            break;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(command) = line.strip_prefix('/') {
+            if command == "quit" || command == "exit" {
+                break;
+            }
+            run_command(client, &mut request, command).await;
+            continue;
+        }
+        request.prompt = line.to_string();
+        if let Err(failure) = client.stream(&request).await {
+            eprintln!("error: {}", failure.message());
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    let client = Client { http: reqwest::Client::new(), url: options.url };
+
+    if let Some(model) = &options.model
+        && let Err(failure) = client.use_model(model).await
+    {
+        eprintln!("error: {}", failure.message());
+        return ExitCode::from(failure.exit_code());
+    }
+
+    let Some(prompt) = options.prompt else {
+        run_session(&client, options.request).await;
+        return ExitCode::SUCCESS;
+    };
+    let request = InferRequest { prompt, ..options.request };
+    match client.stream(&request).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            eprintln!("error: {}", failure.message());
+            ExitCode::from(failure.exit_code())
+        }
+    }
+}
@@ -1,51 +1,254 @@
 // src/config.rs
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use config::Config;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use std::collections::HashMap;
+use std::fmt;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default, ToSchema)]
 #[allow(dead_code)]
 pub struct ModelConfig {
     pub arch: String,
+    #[serde(default)]
     pub repo: String,           // HuggingFace Repo for Weights
+    #[serde(default)]
     pub file: String,           // GGUF Filename
+    #[serde(default)]
     pub tokenizer_repo: String, // HuggingFace Repo for Tokenizer
+    #[serde(default)]
     pub tokenizer_file: String, // Tokenizer Filename
+    pub local_path: Option<String>,           // GGUF on disk; skips the HF download when set
+    pub sha256: Option<String>, // Expected SHA-256 (hex) of the weights, checked before loading
+    pub tokenizer_local_path: Option<String>, // tokenizer.json on disk; skips the HF download when set
+    pub config_file: Option<String>,       // Embedding models: config.json name in `repo` (default "config.json")
+    pub config_local_path: Option<String>, // Embedding models: config.json on disk
+    // Recommended sampling settings, used when a request omits them
+    pub default_temperature: Option<f64>,
+    pub default_top_p: Option<f64>,
+    pub default_max_tokens: Option<usize>,
+    pub default_system_prompt: Option<String>, // Used when a request has no system_prompt; "" in the request turns it off
+    pub idle_unload_secs: Option<u64>, // Overrides the global idle unload threshold
+    #[serde(default)]
+    pub preload: bool, // Load this model in the background at startup
+    pub device: Option<String>, // "auto" (default), "cpu", "cuda:N" or "metal"
+    pub warmup: Option<bool>, // Overrides the global warmup setting
+    pub prefix_cache: Option<bool>, // Overrides the global prefix_cache setting
+    pub max_context: Option<usize>, // Context window in tokens (default: from the GGUF, capped per arch)
+    pub mock_size_mb: Option<usize>, // arch = "mock": size counted against VRAM (default 0)
+    pub stop_token_ids: Option<Vec<u32>>, // Token ids that end generation (default: the arch's EOS tokens)
+    #[serde(default)]
+    pub aliases: Vec<String>, // Other names requests may use for this model, e.g. "gpt-3.5-turbo"
+}
+
+// Handling of prompts that leave no room for max_tokens in the context window
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextStrategy {
+    #[default]
+    Error, // Reject the request
+    TruncateOldest, // Drop the start of the prompt
+    TruncateMiddle, // Keep the start and the end, drop the middle
+}
+
+// Where a model's weights are placed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceSpec {
+    Auto, // CUDA 0 / Metal if available, else CPU (spread over the GPUs when several are detected)
+    Cpu,
+    Cuda(usize),
+    Metal,
+}
+
+impl DeviceSpec {
+    pub fn parse(s: &str) -> Result<Self> {
+        let spec = match s.trim() {
+            "auto" => DeviceSpec::Auto,
+            "cpu" => DeviceSpec::Cpu,
+            "metal" => DeviceSpec::Metal,
+            "cuda" => DeviceSpec::Cuda(0),
+            other => match other.strip_prefix("cuda:") {
+                Some(index) => DeviceSpec::Cuda(
+                    index.parse().with_context(|| format!("invalid CUDA device index in `{}`", other))?,
+                ),
+                None => bail!("unknown device `{}` (expected auto, cpu, cuda:N or metal)", other),
+            },
+        };
+        Ok(spec)
+    }
+
+    // GPU whose VRAM budget a model on this device counts against; None for CPU
+    pub fn vram_index(self) -> Option<usize> {
+        match self {
+            DeviceSpec::Cpu => None,
+            DeviceSpec::Cuda(index) => Some(index),
+            DeviceSpec::Auto | DeviceSpec::Metal => Some(0),
+        }
+    }
+}
+
+impl fmt::Display for DeviceSpec {
+    // Same spelling config.toml accepts
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceSpec::Auto => write!(f, "auto"),
+            DeviceSpec::Cpu => write!(f, "cpu"),
+            DeviceSpec::Cuda(index) => write!(f, "cuda:{}", index),
+            DeviceSpec::Metal => write!(f, "metal"),
+        }
+    }
+}
+
+impl ModelConfig {
+    pub fn device_spec(&self) -> Result<DeviceSpec> {
+        match &self.device {
+            Some(device) => DeviceSpec::parse(device),
+            None => Ok(DeviceSpec::Auto),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 #[allow(dead_code)]
 pub struct Settings {
+    #[serde(default)]
     pub models: HashMap<String, ModelConfig>,
+    pub models_dir: Option<String>, // Register every *.gguf in this directory (explicit [models] win on name clashes)
+    pub generation_timeout_secs: Option<u64>, // Wall-clock limit per generation; requests may only lower it
+    pub max_tokens_limit: Option<usize>, // Upper bound for max_tokens; larger requests are clamped
+    pub max_prompt_chars: Option<usize>, // Longest prompt (plus system prompt) accepted, in characters; longer gets 413
+    pub max_choices: Option<usize>, // Upper bound for a request's `n` completions (default 4)
+    pub idle_unload_secs: Option<u64>, // Unload models unused for this long
+    pub session_idle_secs: Option<u64>, // Drop /sessions conversations unused for this long (default 1800)
+    #[serde(default)]
+    pub unload_active_on_idle: bool, // Let the idle unload take the active model too (off: it stays)
+    #[serde(default)]
+    pub reload_after_failure: bool, // Reload a model unloaded after a fatal inference error (off: wait for /load_model)
+    pub default_model: Option<String>, // Loaded at startup and made active
+    pub shutdown_grace_secs: Option<u64>, // How long in-flight generations may finish on shutdown
+    pub stream_send_timeout_secs: Option<u64>, // A streaming client that stalls this long is dropped (default 30)
+    pub unified_memory_fraction: Option<f64>, // Share of system RAM Metal may use (macOS)
+    pub warmup: Option<bool>, // Run a short generation after load (default true)
+    pub prefix_cache: Option<bool>, // Reuse the KV cache when a prompt extends the previous one (default false)
+    pub response_cache_size: Option<usize>, // Replay this many seeded responses for identical repeats (default off)
+    pub context_strategy: Option<ContextStrategy>, // What to do with prompts longer than the context
+    // Batch at most this many prompt tokens in one forward pass. Only models that can batch past
+    // position 0 honour it: candle 0.8's quantized phi and llama (phi, mistral, llama3) mask
+    // multi-token input at position 0 only, so they still prefill a fresh prompt in one pass.
+    pub prefill_chunk_size: Option<usize>,
+    pub log_dir: Option<String>, // Write a daily rolling JSON request log here (omit for stdout only)
+    pub log_level: Option<String>, // "error", "warn", "info" (default), "debug" or "trace"
+    #[serde(default)]
+    pub log_prompts: bool, // Include prompt text in request logs (redacted by default)
+    pub max_loaded_models: Option<usize>, // Cap on simultaneously loaded models (default: VRAM is the only limit)
+    #[serde(default)]
+    pub enable_benchmark: bool, // Serve POST /benchmark (off by default)
+    pub admin_api_key: Option<String>, // Bearer token for /admin endpoints, which only exist when set
+    pub embedding_batch_size: Option<usize>, // Inputs per forward pass of an embedding model (default 32)
+    pub cpu_threads: Option<usize>, // Threads for CPU inference (default: RAYON_NUM_THREADS, else all cores)
+    pub download_attempts: Option<u32>, // Tries per HF download before a load fails (default 3)
+    pub hf_token: Option<String>, // Hugging Face token for gated/private repos (default: HF_TOKEN, else the saved login)
+    #[serde(default)]
+    pub allowed_origins: Vec<String>, // CORS allowlist with credentials; empty allows any origin (local dev)
 }
 
 #[allow(dead_code)]
 impl Settings {
     // Load settings from config
     pub fn new() -> Result<Self> {
+        Self::from_source(config::File::with_name("config"))
+    }
+
+    // Settings written inline, for tests
+    #[cfg(test)]
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        Self::from_source(config::File::from_str(toml, config::FileFormat::Toml))
+    }
+
+    fn from_source(source: impl config::Source + Send + Sync + 'static) -> Result<Self> {
         let built = Config::builder()
-            .add_source(config::File::with_name("config"))
+            .add_source(source)
             .build()
             .context("failed to build config (expected config.{toml|yaml|json} in CWD)")?;
 
-        let settings: Self = built
+        let mut settings: Self = built
             .try_deserialize()
             .map_err(|e| anyhow::Error::msg(e.to_string()))
             .context("failed to deserialize config into Settings")?;
 
+        if let Some(dir) = &settings.models_dir {
+            let found = scan_models_dir(dir).with_context(|| format!("failed to scan models_dir `{}`", dir))?;
+            for (name, model) in found {
+                settings.models.entry(name).or_insert(model);
+            }
+        }
+
         debug_assert!(
             !settings.models.is_empty(),
-            "settings.models is empty; did you forget to define [models]?"
+            "settings.models is empty; did you forget to define [models] or models_dir?"
         );
+        for (name, model) in &settings.models {
+            model.device_spec().with_context(|| format!("invalid device for model `{}`", name))?;
+            if let Some(sha256) = &model.sha256
+                && (sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()))
+            {
+                bail!("sha256 of model `{}` must be 64 hex characters", name);
+            }
+            if model.stop_token_ids.as_ref().is_some_and(Vec::is_empty) {
+                bail!("stop_token_ids of model `{}` must not be empty", name);
+            }
+        }
+        if settings.admin_api_key.as_deref().is_some_and(|key| key.trim().is_empty()) {
+            bail!("admin_api_key must not be empty");
+        }
+        if settings.hf_token.as_deref().is_some_and(|token| token.trim().is_empty()) {
+            bail!("hf_token must not be empty");
+        }
+        if settings.max_prompt_chars == Some(0) {
+            bail!("max_prompt_chars must be at least 1");
+        }
+        if settings.download_attempts == Some(0) {
+            bail!("download_attempts must be at least 1");
+        }
+        if settings.cpu_threads == Some(0) {
+            bail!("cpu_threads must be at least 1");
+        }
+        if settings.max_loaded_models == Some(0) {
+            bail!("max_loaded_models must be at least 1");
+        }
+        if settings.prefill_chunk_size == Some(0) {
+            bail!("prefill_chunk_size must be at least 1");
+        }
+        if settings.session_idle_secs == Some(0) {
+            bail!("session_idle_secs must be at least 1");
+        }
+        for origin in &settings.allowed_origins {
+            validate_origin(origin).with_context(|| format!("invalid entry in allowed_origins: `{}`", origin))?;
+        }
+        check_aliases(&settings.models)?;
+        if let Some(name) = &settings.default_model {
+            settings.get_model(name).context("default_model must name a configured model")?;
+            settings.default_model = Some(settings.canonical_name(name).to_string());
+        }
 
         Ok(settings)
     }
     pub fn get_model(&self, name: &str) -> Result<&ModelConfig> {
         self.models
-            .get(name)
+            .get(self.canonical_name(name))
             .with_context(|| format!("model `{}` not found in settings.models", name))
     }
+    // Configured name of the model `name` refers to, directly or as one of its aliases;
+    // a name that is neither comes back unchanged
+    pub fn canonical_name<'a>(&'a self, name: &'a str) -> &'a str {
+        if self.models.contains_key(name) {
+            return name;
+        }
+        self.models
+            .iter()
+            .find(|(_, model)| model.aliases.iter().any(|alias| alias == name))
+            .map_or(name, |(canonical, _)| canonical.as_str())
+    }
     // list model keys
     pub fn model_names(&self) -> Vec<String> {
         // deterministic ordering helps tests and logs
@@ -53,4 +256,152 @@ impl Settings {
         keys.sort();
         keys
     }
-}
\ No newline at end of file
+}
+// Each alias must be unique and must not shadow a model name, so every name refers to one model
+fn check_aliases(models: &HashMap<String, ModelConfig>) -> Result<()> {
+    let mut owners: HashMap<&str, &str> = HashMap::new();
+    let mut names: Vec<&String> = models.keys().collect();
+    names.sort();
+    for name in names {
+        for alias in &models[name].aliases {
+            if alias.trim().is_empty() {
+                bail!("aliases of model `{}` must not be empty", name);
+            }
+            if models.contains_key(alias) {
+                bail!("alias `{}` of model `{}` is already the name of a model", alias, name);
+            }
+            if let Some(owner) = owners.insert(alias, name) {
+                bail!("alias `{}` is given to both `{}` and `{}`", alias, owner, name);
+            }
+        }
+    }
+    Ok(())
+}
+
+// GGUF suffix and the tokenizer expected next to each discovered model
+const GGUF_EXTENSION: &str = "gguf";
+const TOKENIZER_SUFFIX: &str = "-tokenizer.json";
+// Architecture given to discovered models; candle runs Llama-family GGUFs through quantized_llama
+const DISCOVERED_ARCH: &str = "llama3";
+
+// Register each `<name>.gguf` in `dir` as model `<name>`, with `<name>-tokenizer.json` beside it.
+// GGUFs without that tokenizer are skipped, since the model could not be loaded.
+fn scan_models_dir(dir: &str) -> Result<Vec<(String, ModelConfig)>> {
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().and_then(|e| e.to_str()) != Some(GGUF_EXTENSION) {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|s| s.to_str()).map(str::to_string) else {
+            continue;
+        };
+        let tokenizer = path.with_file_name(format!("{}{}", name, TOKENIZER_SUFFIX));
+        if !tokenizer.is_file() {
+            println!("models_dir: skipping {} (no {} next to it)", path.display(), tokenizer.display());
+            continue;
+        }
+        let model = ModelConfig {
+            arch: DISCOVERED_ARCH.to_string(),
+            local_path: Some(path.to_string_lossy().into_owned()),
+            tokenizer_local_path: Some(tokenizer.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        found.push((name, model));
+    }
+    Ok(found)
+}
+
+// An origin is `scheme://host[:port]`: http(s), no path, no trailing slash, no wildcard
+fn validate_origin(origin: &str) -> Result<()> {
+    let Some(host) = origin.strip_prefix("http://").or_else(|| origin.strip_prefix("https://")) else {
+        bail!("origin must start with http:// or https://");
+    };
+    if host.is_empty() || host.contains(['/', '*']) || !host.chars().all(|c| c.is_ascii_graphic()) {
+        bail!("origin must be scheme://host[:port] with no path or wildcard");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mock models with the given aliases, e.g. [("a", r#"["x"]"#)]
+    fn with_aliases(models: &[(&str, &str)], global: &str) -> Result<Settings> {
+        let mut toml = format!("{}\n", global);
+        for (name, aliases) in models {
+            toml += &format!("[models.{}]\narch = \"mock\"\naliases = {}\n", name, aliases);
+        }
+        Settings::from_toml(&toml)
+    }
+
+    fn error(result: Result<Settings>) -> String {
+        format!("{:#}", result.expect_err("config should be rejected"))
+    }
+
+    #[test]
+    fn aliases_resolve_to_their_model() {
+        let settings = with_aliases(&[("llama3", r#"["llama", "l3"]"#), ("phi", "[]")], "").unwrap();
+        assert_eq!(settings.canonical_name("llama"), "llama3");
+        assert_eq!(settings.canonical_name("l3"), "llama3");
+        assert_eq!(settings.canonical_name("llama3"), "llama3");
+        assert_eq!(settings.canonical_name("phi"), "phi");
+        // Unknown names come back as they are, for the caller's "not found"
+        assert_eq!(settings.canonical_name("gpt"), "gpt");
+        assert!(settings.get_model("l3").is_ok());
+        assert!(settings.get_model("gpt").is_err());
+    }
+
+    #[test]
+    fn default_model_may_be_an_alias() {
+        let settings = with_aliases(&[("llama3", r#"["llama"]"#)], "default_model = \"llama\"").unwrap();
+        assert_eq!(settings.default_model.as_deref(), Some("llama3"));
+    }
+
+    #[test]
+    fn an_alias_belongs_to_one_model() {
+        let err = error(with_aliases(&[("a", r#"["x"]"#), ("b", r#"["x"]"#)], ""));
+        assert!(err.contains("alias `x` is given to both `a` and `b`"), "{}", err);
+    }
+
+    #[test]
+    fn an_alias_cannot_shadow_a_model_name() {
+        let err = error(with_aliases(&[("a", r#"["b"]"#), ("b", "[]")], ""));
+        assert!(err.contains("alias `b` of model `a` is already the name of a model"), "{}", err);
+    }
+
+    #[test]
+    fn an_alias_cannot_be_empty() {
+        let err = error(with_aliases(&[("a", r#"[" "]"#)], ""));
+        assert!(err.contains("aliases of model `a` must not be empty"), "{}", err);
+    }
+
+    #[test]
+    fn origins_are_scheme_host_and_port() {
+        for origin in ["http://localhost:8080", "https://chat.example.com", "http://127.0.0.1:3000", "http://[::1]:8080"] {
+            assert!(validate_origin(origin).is_ok(), "{}", origin);
+        }
+        let rejected = [
+            ("https://example.com/app", "no path or wildcard"),
+            ("https://example.com/", "no path or wildcard"),
+            ("https://*.example.com", "no path or wildcard"),
+            ("*", "must start with http:// or https://"),
+            ("example.com", "must start with http:// or https://"),
+            ("ftp://example.com", "must start with http:// or https://"),
+            ("https://", "no path or wildcard"),
+            ("https://exa mple.com", "no path or wildcard"),
+        ];
+        for (origin, want) in rejected {
+            let err = validate_origin(origin).expect_err(origin).to_string();
+            assert!(err.contains(want), "{}: {}", origin, err);
+        }
+    }
+
+    #[test]
+    fn a_bad_origin_is_named_in_the_error() {
+        let global = r#"allowed_origins = ["http://localhost:8080", "http://localhost:8080/"]"#;
+        let err = error(with_aliases(&[("a", "[]")], global));
+        assert!(err.contains("invalid entry in allowed_origins: `http://localhost:8080/`"), "{}", err);
+    }
+}
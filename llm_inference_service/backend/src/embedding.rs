@@ -0,0 +1,137 @@
+// src/embedding.rs
+// Sentence embeddings from BERT-style models: mean pooling over the attention mask, then L2 normalization
+use crate::model::{LoadedModel, ModelEnum};
+use anyhow::{Result, bail};
+use candle_core::Tensor;
+use candle_transformers::models::bert;
+use std::time::{Duration, Instant};
+
+// Inputs per forward pass when config.toml does not set embedding_batch_size
+pub const DEFAULT_EMBEDDING_BATCH_SIZE: usize = 32;
+
+// Embed `inputs` in batches of `batch_size`; returns one unit vector per input
+// and the number of (non-padding) tokens processed
+pub fn embed(loaded_model: &LoadedModel, inputs: &[String], batch_size: usize) -> Result<(Vec<Vec<f32>>, usize)> {
+    let ModelEnum::Embedding(model) = &loaded_model.model else {
+        bail!("model '{}' is not an embedding model", loaded_model.arch);
+    };
+    let device = &loaded_model.device;
+    let mut embeddings = Vec::with_capacity(inputs.len());
+    let mut total_tokens = 0;
+    for batch in inputs.chunks(batch_size.max(1)) {
+        // The tokenizer pads the batch to its longest input
+        let encodings = loaded_model
+            .tokenizer
+            .encode_batch(batch.to_vec(), true)
+            .map_err(anyhow::Error::msg)?;
+        let seq_len = encodings[0].len();
+        let flatten = |field: fn(&tokenizers::Encoding) -> &[u32]| -> Result<Tensor> {
+            let values: Vec<u32> = encodings.iter().flat_map(|e| field(e).iter().copied()).collect();
+            Ok(Tensor::from_vec(values, (batch.len(), seq_len), device)?)
+        };
+        let input_ids = flatten(|e| e.get_ids())?;
+        let token_type_ids = flatten(|e| e.get_type_ids())?;
+        let attention_mask = flatten(|e| e.get_attention_mask())?;
+        total_tokens += encodings.iter().map(|e| e.get_attention_mask().iter().sum::<u32>() as usize).sum::<usize>();
+
+        // [batch, seq, hidden] -> mean over real tokens -> [batch, hidden]
+        let hidden = model.forward(&input_ids, &token_type_ids, Some(&attention_mask))?;
+        let mask = attention_mask.to_dtype(bert::DTYPE)?.unsqueeze(2)?;
+        let pooled = hidden.broadcast_mul(&mask)?.sum(1)?.broadcast_div(&mask.sum(1)?)?;
+        let norm = pooled.sqr()?.sum_keepdim(1)?.sqrt()?;
+        embeddings.extend(pooled.broadcast_div(&norm)?.to_vec2::<f32>()?);
+    }
+    Ok((embeddings, total_tokens))
+}
+
+// Embed one short input so kernel compilation and allocations happen at load time
+pub fn warmup(loaded_model: &LoadedModel) -> Result<Duration> {
+    let started = Instant::now();
+    embed(loaded_model, &["Hello".to_string()], 1)?;
+    Ok(started.elapsed())
+}
+
+// Files of a one-layer BERT with random weights, written to `dir`, and the model config that
+// loads them with the mock tokenizer. For tests, which have no real embedding model to load.
+#[cfg(test)]
+pub fn write_tiny_bert(dir: &std::path::Path) -> crate::config::ModelConfig {
+    use candle_core::{DType, Device};
+    use candle_nn::{VarBuilder, VarMap};
+    let config = serde_json::json!({
+        "vocab_size": 32,
+        "hidden_size": 8,
+        "num_hidden_layers": 1,
+        "num_attention_heads": 2,
+        "intermediate_size": 16,
+        "hidden_act": "gelu",
+        "hidden_dropout_prob": 0.0,
+        "max_position_embeddings": 16,
+        "type_vocab_size": 2,
+        "initializer_range": 0.02,
+        "layer_norm_eps": 1e-12,
+        "pad_token_id": 0,
+    });
+    let config_path = dir.join("config.json");
+    std::fs::write(&config_path, config.to_string()).unwrap();
+    // Loading through a VarMap creates every weight the model asks for, randomly initialized
+    let weights = VarMap::new();
+    let vb = VarBuilder::from_varmap(&weights, DType::F32, &Device::Cpu);
+    bert::BertModel::load(vb, &serde_json::from_value(config).unwrap()).unwrap();
+    let weights_path = dir.join("model.safetensors");
+    weights.save(&weights_path).unwrap();
+    crate::config::ModelConfig {
+        arch: crate::model::EMBEDDING_ARCH.to_string(),
+        local_path: Some(weights_path.display().to_string()),
+        config_local_path: Some(config_path.display().to_string()),
+        tokenizer_local_path: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/mock/tokenizer.json").to_string()),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DeviceSpec;
+
+    const MOCK_TOKENIZER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/mock/tokenizer.json");
+
+    #[test]
+    fn batching_does_not_change_the_embeddings() {
+        let dir = tempfile::tempdir().unwrap();
+        let model = LoadedModel::load("bert", &write_tiny_bert(dir.path()), DeviceSpec::Cpu).unwrap();
+        let inputs: Vec<String> = ["hello", "hello world", "this is a test of the mock model", "yes", "no ."]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let (one_by_one, tokens) = embed(&model, &inputs, 1).unwrap();
+        assert_eq!(one_by_one.len(), inputs.len());
+        assert_eq!(tokens, 1 + 2 + 8 + 1 + 2);
+        for vector in &one_by_one {
+            let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+            assert!((norm - 1.0).abs() < 1e-5, "norm {}", norm);
+        }
+        // Padding shorter inputs up to the longest in their batch must not leak into the pooling;
+        // a batch size of 0 is treated as 1
+        for batch_size in [0, 2, 3, 32] {
+            let (batched, batched_tokens) = embed(&model, &inputs, batch_size).unwrap();
+            assert_eq!(batched_tokens, tokens, "batch size {}", batch_size);
+            assert_eq!(batched.len(), inputs.len());
+            for (a, b) in batched.iter().zip(&one_by_one) {
+                let diff = a.iter().zip(b).map(|(x, y)| (x - y).abs()).fold(0.0, f32::max);
+                assert!(diff < 1e-4, "batch size {}: off by {}", batch_size, diff);
+            }
+        }
+    }
+
+    #[test]
+    fn generative_models_cannot_embed() {
+        let conf = crate::config::ModelConfig {
+            arch: crate::model::MOCK_ARCH.to_string(),
+            tokenizer_local_path: Some(MOCK_TOKENIZER.to_string()),
+            ..Default::default()
+        };
+        let model = LoadedModel::load("mock", &conf, DeviceSpec::Cpu).unwrap();
+        let err = embed(&model, &["hello".to_string()], 1).unwrap_err();
+        assert!(err.to_string().contains("is not an embedding model"), "{}", err);
+    }
+}
@@ -0,0 +1,241 @@
+// src/gpu.rs
+// Detect GPU devices and their memory so the VRAM budget matches the real hardware
+use serde::Serialize;
+use utoipa::ToSchema;
+use std::collections::BTreeMap;
+use std::process::Command;
+
+// Keep this much VRAM free for the driver, CUDA context and activations
+const SAFETY_MARGIN_MB: usize = 1024;
+// Budget used when a device could not be detected: 8000 - 1024(1G)
+pub const DEFAULT_VRAM_LIMIT_MB: usize = 6976;
+// Smallest total memory a usable GPU reports; less (e.g. 0 under MIG, or unreadable
+// nvidia-smi output) is not trusted for its budget
+const MIN_PLAUSIBLE_VRAM_MB: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct GpuInfo {
+    pub index: usize,
+    pub name: String,
+    pub total_mb: usize,
+    pub free_mb: usize,
+}
+
+// Query NVIDIA devices through NVML (no nvidia-smi binary needed)
+#[cfg(not(target_os = "macos"))]
+fn probe_nvml() -> Option<Vec<GpuInfo>> {
+    use nvml_wrapper::Nvml;
+    let nvml = Nvml::init().ok()?;
+    let count = nvml.device_count().ok()?;
+    let mut gpus = Vec::new();
+    for index in 0..count {
+        let device = nvml.device_by_index(index).ok()?;
+        let memory = device.memory_info().ok()?;
+        gpus.push(GpuInfo {
+            index: index as usize,
+            name: device.name().unwrap_or_else(|_| "NVIDIA GPU".to_string()),
+            total_mb: (memory.total / 1024 / 1024) as usize,
+            free_mb: (memory.free / 1024 / 1024) as usize,
+        });
+    }
+    Some(gpus)
+}
+
+// Current free memory of one CUDA device, used to measure what a model load consumed.
+// Only NVML is precise enough for this; returns None without it (and on Metal).
+pub fn free_vram_mb(index: usize) -> Option<usize> {
+    #[cfg(not(target_os = "macos"))]
+    {
+        let nvml = nvml_wrapper::Nvml::init().ok()?;
+        let device = nvml.device_by_index(index as u32).ok()?;
+        let memory = device.memory_info().ok()?;
+        Some((memory.free / 1024 / 1024) as usize)
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let _ = index;
+        None
+    }
+}
+
+// Memory in use on a GPU by all processes, read through NVML
+pub fn used_vram_mb(index: usize) -> Option<usize> {
+    #[cfg(not(target_os = "macos"))]
+    {
+        let nvml = nvml_wrapper::Nvml::init().ok()?;
+        let device = nvml.device_by_index(index as u32).ok()?;
+        let memory = device.memory_info().ok()?;
+        Some((memory.used / 1024 / 1024) as usize)
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let _ = index;
+        None
+    }
+}
+
+// Last resort for NVIDIA: shell out to nvidia-smi
+#[cfg(not(target_os = "macos"))]
+fn probe_nvidia_smi() -> Option<Vec<GpuInfo>> {
+    let output = Command::new("nvidia-smi")
+        .args([
+            "--query-gpu=index,name,memory.total,memory.free",
+            "--format=csv,noheader,nounits",
+        ])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(parse_nvidia_smi(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// Parse `index, name, memory.total, memory.free` CSV lines; malformed lines are skipped.
+// A device whose memory is not a number (e.g. "[N/A]" under MIG) is kept with 0 MB.
+#[cfg(not(target_os = "macos"))]
+pub fn parse_nvidia_smi(stdout: &str) -> Vec<GpuInfo> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() != 4 {
+                return None;
+            }
+            Some(GpuInfo {
+                index: fields[0].parse().ok()?,
+                name: fields[1].to_string(),
+                total_mb: fields[2].parse().unwrap_or(0),
+                free_mb: fields[3].parse().unwrap_or(0),
+            })
+        })
+        .collect()
+}
+
+// Apple Silicon shares system memory with the GPU; Metal may use a fraction of it
+#[cfg(target_os = "macos")]
+fn probe_unified_memory(fraction: f64) -> Option<Vec<GpuInfo>> {
+    let output = Command::new("sysctl").args(["-n", "hw.memsize"]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let bytes = parse_sysctl_memsize(&String::from_utf8_lossy(&output.stdout))?;
+    Some(vec![unified_memory_gpu(bytes, fraction)])
+}
+
+// Parse the byte count printed by `sysctl -n hw.memsize`
+#[cfg(target_os = "macos")]
+pub fn parse_sysctl_memsize(stdout: &str) -> Option<u64> {
+    stdout.trim().parse().ok()
+}
+
+// Describe the share of unified memory Metal is allowed to use
+#[cfg(target_os = "macos")]
+pub fn unified_memory_gpu(memsize_bytes: u64, fraction: f64) -> GpuInfo {
+    let usable_mb = (memsize_bytes as f64 / 1024.0 / 1024.0 * fraction.clamp(0.0, 1.0)) as usize;
+    GpuInfo {
+        index: 0,
+        name: "Apple Metal (unified memory)".to_string(),
+        total_mb: usable_mb,
+        free_mb: usable_mb,
+    }
+}
+
+// Detect available GPUs: NVML first, nvidia-smi as fallback; sysctl on macOS
+#[allow(unused_variables)]
+pub fn detect_gpus(unified_memory_fraction: f64) -> Vec<GpuInfo> {
+    #[cfg(target_os = "macos")]
+    {
+        probe_unified_memory(unified_memory_fraction).unwrap_or_default()
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        probe_nvml()
+            .filter(|gpus| !gpus.is_empty())
+            .or_else(probe_nvidia_smi)
+            .unwrap_or_default()
+    }
+}
+
+// Whether the memory a device reports can be trusted
+pub fn plausible_vram(gpu: &GpuInfo) -> bool {
+    gpu.total_mb >= MIN_PLAUSIBLE_VRAM_MB
+}
+
+// Pick the VRAM budget of each device: its total minus a margin, or the default when the
+// reported total is implausible. Device 0 always gets a budget since models default to it.
+pub fn select_vram_limits(gpus: &[GpuInfo]) -> BTreeMap<usize, usize> {
+    let mut limits: BTreeMap<usize, usize> = gpus
+        .iter()
+        .map(|gpu| {
+            let limit = if plausible_vram(gpu) {
+                gpu.total_mb.saturating_sub(SAFETY_MARGIN_MB)
+            } else {
+                DEFAULT_VRAM_LIMIT_MB
+            };
+            (gpu.index, limit)
+        })
+        .collect();
+    limits.entry(0).or_insert(DEFAULT_VRAM_LIMIT_MB);
+    limits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gpu(index: usize, total_mb: usize) -> GpuInfo {
+        GpuInfo { index, name: format!("GPU {}", index), total_mb, free_mb: total_mb }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn parse_nvidia_smi_reads_each_device() {
+        let stdout = "0, NVIDIA GeForce RTX 3090, 24576, 23800\n1, Tesla T4, 15360, 15000\n";
+        let gpus = parse_nvidia_smi(stdout);
+        assert_eq!(gpus.len(), 2);
+        assert_eq!(gpus[0], GpuInfo { index: 0, name: "NVIDIA GeForce RTX 3090".to_string(), total_mb: 24576, free_mb: 23800 });
+        assert_eq!(gpus[1].name, "Tesla T4");
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn parse_nvidia_smi_keeps_mig_devices_with_zero_memory() {
+        let gpus = parse_nvidia_smi("0, NVIDIA A100-SXM4-40GB, [N/A], [N/A]\n");
+        assert_eq!(gpus, [GpuInfo { index: 0, name: "NVIDIA A100-SXM4-40GB".to_string(), total_mb: 0, free_mb: 0 }]);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn parse_nvidia_smi_skips_malformed_lines() {
+        let stdout = "\nNVIDIA-SMI has failed\n0, too, few\nx, Named, 8192, 8000\n1, A, B, 8192, 8000\n2, RTX 4060, 8188, 7900\n";
+        let indices: Vec<usize> = parse_nvidia_smi(stdout).iter().map(|gpu| gpu.index).collect();
+        assert_eq!(indices, [2]);
+    }
+
+    #[test]
+    fn vram_limits_keep_a_safety_margin() {
+        assert_eq!(select_vram_limits(&[gpu(0, 24576), gpu(1, 8192)]), BTreeMap::from([(0, 23552), (1, 7168)]));
+    }
+
+    #[test]
+    fn implausible_totals_get_the_default_budget() {
+        let limits = select_vram_limits(&[gpu(0, 0), gpu(1, MIN_PLAUSIBLE_VRAM_MB - 1), gpu(2, MIN_PLAUSIBLE_VRAM_MB)]);
+        assert_eq!(limits, BTreeMap::from([(0, DEFAULT_VRAM_LIMIT_MB), (1, DEFAULT_VRAM_LIMIT_MB), (2, 0)]));
+    }
+
+    #[test]
+    fn device_0_always_gets_a_budget() {
+        assert_eq!(select_vram_limits(&[]), BTreeMap::from([(0, DEFAULT_VRAM_LIMIT_MB)]));
+        // Models default to device 0, so it gets the default budget even when not listed
+        assert_eq!(select_vram_limits(&[gpu(1, 16384)]), BTreeMap::from([(0, DEFAULT_VRAM_LIMIT_MB), (1, 15360)]));
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn unified_memory_budget_is_a_share_of_memsize() {
+        let bytes = parse_sysctl_memsize("17179869184\n").unwrap();
+        assert_eq!(unified_memory_gpu(bytes, 0.75).total_mb, 12288);
+        assert_eq!(unified_memory_gpu(bytes, 2.0).total_mb, 16384);
+        assert_eq!(parse_sysctl_memsize("unknown"), None);
+    }
+}
@@ -1,30 +1,89 @@
 // src/infer.rs
+use crate::config::ContextStrategy;
 use crate::model::{LoadedModel, ModelEnum};
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use candle_core::{DType, Tensor};
-use candle_transformers::generation::LogitsProcessor;
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::sampling::{SamplerPipeline, log_softmax, top_k};
+use serde::Serialize;
+use utoipa::ToSchema;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Upper bound on the alternatives returned per token with logprobs
+pub const MAX_TOP_LOGPROBS: usize = 20;
+// Replaces the dropped part of a prompt that did not fit the context
+const TRUNCATION_NOTE: &str = "[earlier conversation truncated]";
 
 // Parameters that control model generation behavior
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct InferenceParams {
     // Softmax temperature. Higher => more random
     pub temperature: Option<f64>,
     // Nucleus sampling (top-p). Lower => more conservative
     pub top_p: Option<f64>,
-    // Maximum number of new tokens to generate
+    // Maximum number of new tokens to generate. If None, until a stop token or a full context
     pub max_tokens: Option<usize>,
+    // Context window from the model's config, which bounds generation without max_tokens.
+    // If None, the loaded model's own
+    pub max_context: Option<usize>,
     // RNG seed for sampling. If None, seed is derived from current time
     pub seed: Option<u64>,
+    // Wall-clock budget for the whole generation. If None, only max_tokens bounds it
+    pub timeout_secs: Option<u64>,
+    // Set by the server on shutdown; generation stops at the next token
+    pub shutdown: Option<Arc<AtomicBool>>,
+    // Set when the client disconnects; generation stops at the next token
+    pub cancel: Option<Arc<AtomicBool>>,
+    // Mirostat v2 target surprise (bits). When set, replaces temperature/top-p sampling
+    pub mirostat_tau: Option<f64>,
+    // Mirostat v2 learning rate for the running `mu` estimate
+    pub mirostat_eta: Option<f64>,
+    // Skip prefilling the part of the prompt already in the KV cache from the previous request
+    pub reuse_prefix: bool,
+    // Prompts longer than this are not prefilled in one batch, to bound peak memory
+    pub prefill_chunk_size: Option<usize>,
+    // Added to the logits of the given token ids at every step
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    // Strings the output must never contain (best effort, token level)
+    pub banned_strings: Option<Vec<String>>,
+    // Never generate an n-gram of this many tokens twice
+    pub no_repeat_ngram_size: Option<usize>,
+    // Only look this many generated tokens back for repeats. If None, the whole output
+    pub repeat_last_n: Option<usize>,
+    // Report each token's log-probability with this many top alternatives
+    pub logprobs: Option<usize>,
 }
 
-#[inline]
-fn derive_seed_from_time() -> u64 {
-    // Fetch system current time
-    SystemTime::now()
+// Why the generation loop stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    // Model emitted a stop token
+    Stop,
+    // Reached max_tokens
+    Length,
+    // Ran out of wall-clock time
+    Timeout,
+    // Server is shutting down
+    ServerShutdown,
+    // The client went away (e.g. stream disconnected)
+    Cancelled,
+}
+
+// Seed for a request that set none. The time alone repeats for requests started at the same
+// moment, so a counter is mixed in; the seed is reported back, so the output can be replayed.
+pub fn derive_seed_from_time() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or_default()
-        .as_millis() as u64
+        .as_nanos() as u64;
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    // Kept below 2^53 so JavaScript clients read it back exactly
+    (nanos ^ count.wrapping_mul(0x9E37_79B9_7F4A_7C15)) & ((1 << 53) - 1)
 }
 
 #[inline]
@@ -36,7 +95,7 @@ fn decode_ids(tokenizer: &tokenizers::Tokenizer, ids: &[u32]) -> Result<String>
 }
 
 #[inline]
-fn encode_prompt(tokenizer: &tokenizers::Tokenizer, prompt: &str) -> Result<Vec<u32>> {
+pub fn encode_prompt(tokenizer: &tokenizers::Tokenizer, prompt: &str) -> Result<Vec<u32>> {
     let enc = tokenizer
         .encode(prompt, true)
         .map_err(anyhow::Error::msg)
@@ -45,14 +104,278 @@ fn encode_prompt(tokenizer: &tokenizers::Tokenizer, prompt: &str) -> Result<Vec<
     Ok(enc.get_ids().to_vec())
 }
 
-// stop token ids for models
-#[inline]
-fn stop_token_ids(tokenizer: &tokenizers::Tokenizer) -> (u32, u32, u32, u32) {
-    let eos = tokenizer.token_to_id("</s>").unwrap_or(2);
-    let gpt2_eos = 50256;
-    let llama3_eot = 128001;
-    let llama3_eom = 128009;
-    (eos, gpt2_eos, llama3_eot, llama3_eom)
+// Default stop token ids for a model architecture, looked up in the tokenizer with known fallbacks
+pub fn default_stop_token_ids(arch: &str, tokenizer: &tokenizers::Tokenizer) -> Vec<u32> {
+    let id = |token: &str, fallback: u32| tokenizer.token_to_id(token).unwrap_or(fallback);
+    match arch {
+        "phi" => vec![id("<|endoftext|>", 50256)],
+        "mistral" => vec![id("</s>", 2)],
+        // End of text, and end of turn which chat models emit after each reply
+        "llama3" => vec![id("<|end_of_text|>", 128001), id("<|eot_id|>", 128009)],
+        _ => vec![id("</s>", 2)],
+    }
+}
+
+// Make the templated prompt fit in `token_budget` tokens (context window minus max_tokens).
+// `render` applies the chat template to the user prompt. When it is too long, the user prompt
+// is shortened per `strategy` and the dropped part replaced by a note.
+// Returns the templated prompt and the number of prompt tokens dropped.
+pub fn fit_prompt(
+    tokenizer: &tokenizers::Tokenizer,
+    raw_prompt: &str,
+    render: impl Fn(&str) -> String,
+    token_budget: usize,
+    strategy: ContextStrategy,
+) -> Result<(String, usize)> {
+    let templated = render(raw_prompt);
+    let total = encode_prompt(tokenizer, &templated)?.len();
+    if total <= token_budget {
+        return Ok((templated, 0));
+    }
+    if strategy == ContextStrategy::Error {
+        bail!(
+            "prompt is {} tokens but only {} fit in the context after reserving max_tokens; \
+             shorten it, lower max_tokens or set context_strategy",
+            total,
+            token_budget
+        );
+    }
+    let user_ids = tokenizer
+        .encode(raw_prompt, false)
+        .map_err(anyhow::Error::msg)
+        .context("tokenizer.encode failed for user prompt")?
+        .get_ids()
+        .to_vec();
+    let note_tokens = tokenizer
+        .encode(TRUNCATION_NOTE, false)
+        .map_err(anyhow::Error::msg)?
+        .len();
+    // Token boundaries can shift when the cut text is re-encoded, so retry with the new excess
+    let mut dropped = (total - token_budget + note_tokens).min(user_ids.len());
+    loop {
+        let keep = user_ids.len() - dropped;
+        let shortened = match strategy {
+            ContextStrategy::TruncateMiddle => {
+                let head = keep / 2;
+                let tail = keep - head;
+                format!(
+                    "{}\n{}\n{}",
+                    decode_ids(tokenizer, &user_ids[..head])?,
+                    TRUNCATION_NOTE,
+                    decode_ids(tokenizer, &user_ids[user_ids.len() - tail..])?
+                )
+            }
+            _ => format!("{}\n{}", TRUNCATION_NOTE, decode_ids(tokenizer, &user_ids[dropped..])?),
+        };
+        let templated = render(&shortened);
+        let total = encode_prompt(tokenizer, &templated)?.len();
+        if total <= token_budget {
+            return Ok((templated, dropped));
+        }
+        if keep == 0 {
+            break;
+        }
+        dropped = (dropped + total - token_budget).min(user_ids.len());
+    }
+    bail!(
+        "prompt does not fit in the context even when fully truncated (budget {} tokens)",
+        token_budget
+    )
+}
+
+// Token counts and sampling seed of one generation, reported back to the client
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct GenerationStats {
+    // Seed the sampler used, the request's or a generated one; sending it back as `seed`
+    // with the same prompt and options reproduces the output
+    pub seed: u64,
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    // Prompt tokens whose KV cache was reused from the previous request
+    pub prefill_skipped: usize,
+    // Prompt tokens dropped to fit the context window
+    pub context_dropped_tokens: usize,
+    // Replayed from the response cache instead of generated
+    pub cached: bool,
+    // Streamed from an identical request's generation that was already running
+    pub coalesced: bool,
+}
+
+// One candidate token and its log-probability
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TokenAlternative {
+    pub token: String,
+    pub logprob: f32,
+}
+
+// Log-probability of a generated token under the model's distribution
+// (before temperature, bias or bans), with the most likely alternatives at that step
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub top_alternatives: Vec<TokenAlternative>,
+}
+
+// What the generation loop hands to its callback after each token
+#[derive(Debug, Clone)]
+pub struct TokenEvent {
+    // Newly decoded text; empty while a partial character is held back
+    pub text: String,
+    // Set when the request asked for logprobs
+    pub logprob: Option<TokenLogprob>,
+    // Tokens generated so far, this one included
+    pub generated_tokens: usize,
+}
+
+// Result of a finished generation
+#[derive(Debug, Clone, Copy)]
+pub struct GenerationOutcome {
+    pub finish_reason: FinishReason,
+    pub stats: GenerationStats,
+}
+
+// Run the model on `input` placed at `start_pos`; returns logits of the last position
+pub fn forward(model: &mut ModelEnum, input: &Tensor, start_pos: usize) -> Result<Tensor> {
+    let logits = match model {
+        ModelEnum::Phi(m) => m
+            .forward(input, start_pos)
+            .with_context(|| format!("Phi.forward failed (start_at={})", start_pos))?,
+        ModelEnum::Llama(m) => m
+            .forward(input, start_pos)
+            .with_context(|| format!("Llama.forward failed (start_at={})", start_pos))?,
+        ModelEnum::Mock(m) => m.forward(input, start_pos)?,
+        ModelEnum::Embedding(_) => bail!("embedding models cannot generate text"),
+    };
+    Ok(logits)
+}
+
+// Number of prompt tokens whose KV cache can be reused.
+// candle can only append to its cache, so the cached tokens must be a prefix of the prompt
+// and at least one prompt token must remain to produce logits.
+pub fn reusable_prefix(cached: &[u32], prompt: &[u32]) -> usize {
+    if !cached.is_empty() && cached.len() < prompt.len() && prompt.starts_with(cached) {
+        cached.len()
+    } else {
+        0
+    }
+}
+
+// Log-probability of `token` and the `top` most likely tokens from the F32 logits of one step
+fn token_logprob(tokenizer: &tokenizers::Tokenizer, logits: &Tensor, token: u32, top: usize) -> Result<TokenLogprob> {
+    let logprobs = log_softmax(&logits.to_vec1::<f32>().context("logprobs: logits to_vec1 failed")?);
+    let top_alternatives = top_k(&logprobs, top.min(MAX_TOP_LOGPROBS))
+        .into_iter()
+        .map(|(id, logprob)| Ok(TokenAlternative { token: decode_ids(tokenizer, &[id])?, logprob }))
+        .collect::<Result<_>>()?;
+    Ok(TokenLogprob {
+        token: decode_ids(tokenizer, &[token])?,
+        logprob: logprobs[token as usize],
+        top_alternatives,
+    })
+}
+
+// Newly decoded text after the first `emitted_len` bytes, if it is ready to send.
+// Byte-fallback tokenizers split some characters over several tokens; until the last byte
+// arrives the text ends in U+FFFD, so hold it back rather than emit a broken character.
+fn ready_suffix(text: &str, emitted_len: usize) -> Option<&str> {
+    if text.len() <= emitted_len || !text.is_char_boundary(emitted_len) || text.ends_with('\u{FFFD}') {
+        return None;
+    }
+    Some(&text[emitted_len..])
+}
+
+// Text of a generation that is decoded in full after every token; hands out only the part
+// not sent yet, once it is ready (see ready_suffix)
+struct IncrementalText {
+    text: String,
+    emitted_len: usize,
+}
+
+impl IncrementalText {
+    // Starts after the prompt, which is never sent
+    fn new(prompt_text: String) -> Self {
+        Self { emitted_len: prompt_text.len(), text: prompt_text }
+    }
+
+    // Take the full decoded text after a step; returns the new text to send, empty while held back
+    fn update(&mut self, text: String) -> String {
+        self.text = text;
+        match ready_suffix(&self.text, self.emitted_len) {
+            Some(new_text) => {
+                let new_text = new_text.to_string();
+                self.emitted_len = self.text.len();
+                new_text
+            }
+            None => String::new(),
+        }
+    }
+
+    // Text still held back when generation ends; a character that never completed stays as U+FFFD
+    fn flush(&mut self) -> Option<String> {
+        if self.text.len() <= self.emitted_len || !self.text.is_char_boundary(self.emitted_len) {
+            return None;
+        }
+        let rest = self.text[self.emitted_len..].to_string();
+        self.emitted_len = self.text.len();
+        Some(rest)
+    }
+}
+
+// Why generation must stop before the next token, if it must: out of time, server shutting
+// down or client gone
+fn interruption(params: &InferenceParams, elapsed: Duration) -> Option<FinishReason> {
+    let is_set = |flag: &Option<Arc<AtomicBool>>| flag.as_ref().is_some_and(|f| f.load(Ordering::SeqCst));
+    if params.timeout_secs.is_some_and(|secs| elapsed >= Duration::from_secs(secs)) {
+        Some(FinishReason::Timeout)
+    } else if is_set(&params.shutdown) {
+        Some(FinishReason::ServerShutdown)
+    } else if is_set(&params.cancel) {
+        Some(FinishReason::Cancelled)
+    } else {
+        None
+    }
+}
+
+// Whether `model` can take several tokens in one forward at a position past 0. candle 0.8's
+// quantized phi and llama build the causal mask for the new tokens alone ([seq, seq]), which
+// cannot cover a KV cache that already holds earlier positions, so past position 0 they must
+// be fed one token at a time.
+pub fn batches_at_offset(model: &ModelEnum) -> bool {
+    matches!(model, ModelEnum::Mock(_))
+}
+
+// Ranges of the prompt to forward in turn, after the `cached` tokens whose KV is reused; only
+// the logits of the last one are kept. Chunks hold at most `chunk_size` tokens. A model that
+// cannot batch past position 0 takes the whole prompt in one forward (the chunk size does not
+// apply to it), and the rest of a prompt after a cached prefix one token at a time.
+fn prefill_chunks(prompt_len: usize, cached: usize, chunk_size: Option<usize>, batches_at_offset: bool) -> Vec<Range<usize>> {
+    let chunk = match chunk_size {
+        Some(size) if batches_at_offset => size,
+        _ if batches_at_offset || cached == 0 => prompt_len,
+        _ => 1,
+    };
+    (cached..prompt_len)
+        .step_by(chunk.max(1))
+        .map(|start| start..(start + chunk.max(1)).min(prompt_len))
+        .collect()
+}
+
+// Tokens a generation may produce: max_tokens when set, otherwise what the context has left
+// after the prompt. Fails when the prompt leaves no room at all.
+pub fn generation_limit(max_tokens: Option<usize>, max_context: usize, prompt_tokens: usize) -> Result<usize> {
+    if let Some(max_tokens) = max_tokens {
+        return Ok(max_tokens);
+    }
+    let remaining = max_context.saturating_sub(prompt_tokens);
+    if remaining == 0 {
+        bail!(
+            "prompt is {} tokens and fills the {}-token context; nothing is left to generate",
+            prompt_tokens,
+            max_context
+        );
+    }
+    Ok(remaining)
 }
 
 // Inference loop for a given prompt.
@@ -60,13 +383,16 @@ pub fn run_inference(
     loaded_model: &mut LoadedModel,
     prompt: &str,
     params: InferenceParams,
-    mut callback: impl FnMut(String),
-) -> Result<()> {
+    mut callback: impl FnMut(TokenEvent),
+) -> Result<GenerationOutcome> {
     // Parameter defaults
-    let temp = params.temperature.unwrap_or(0.7);
-    let top_p = params.top_p.unwrap_or(0.9);
-    let max_new_tokens = params.max_tokens.unwrap_or(1024);
     let seed = params.seed.unwrap_or_else(derive_seed_from_time);
+    let started = Instant::now();
+
+    // Start from a reset cache unless the prefix is reused, so an unrelated earlier request
+    // can never leak into this one. It also stays reset if this request fails midway.
+    let cached_tokens = loaded_model.cached_tokens.clone();
+    loaded_model.reset_cache();
 
     let tokenizer = &loaded_model.tokenizer;
     let device = &loaded_model.device;
@@ -74,84 +400,525 @@ pub fn run_inference(
     // Encode prompt into Token Ids
     let mut input_ids = encode_prompt(tokenizer, prompt)
         .with_context(|| "failed to encode prompt into token ids")?;
+    let prompt_tokens = input_ids.len();
+    let max_new_tokens = generation_limit(
+        params.max_tokens,
+        loaded_model.max_context(params.max_context),
+        prompt_tokens,
+    )?;
 
+    // Reuse the KV cache of the previous request when this prompt extends it
+    let prefill_skipped = if params.reuse_prefix {
+        reusable_prefix(&cached_tokens, &input_ids)
+    } else {
+        0
+    };
     // Initialize sampler
-    // temperature for randomness
-    // top-p for diversity
-    let mut logits_processor = LogitsProcessor::new(seed, Some(temp), Some(top_p));
-
-    // Track length of generated text so far
-    //let mut prev_text_len = 0usize;
+    let mut sampler = SamplerPipeline::from_params(&params, seed, tokenizer)?;
 
     let initial_text = decode_ids(tokenizer, &input_ids)
         .with_context(|| "failed to decode initial prompt tokens")?;
-    let mut prev_text_len = initial_text.len();
+    let mut output = IncrementalText::new(initial_text);
+
+    let stop_tokens = &loaded_model.stop_token_ids;
 
-    // Precompute stop token ids (same checks as before).
-    let (stop_0, stop_1, stop_2, stop_3) = stop_token_ids(tokenizer);
+    let prefill = prefill_chunks(
+        input_ids.len(),
+        prefill_skipped,
+        params.prefill_chunk_size,
+        batches_at_offset(&loaded_model.model),
+    );
+    debug_assert!(
+        prefill_skipped > 0 || prefill.first().is_some_and(|chunk| chunk.start == 0),
+        "a fresh prompt must be prefilled from position 0 to reset the KV cache"
+    );
+
+    // Number of tokens in input_ids whose KV is in the cache
+    let mut cached_len = 0;
+    let mut generated_tokens = 0;
 
     // Generation loop
+    let mut finish_reason = FinishReason::Length;
     for index in 0..max_new_tokens {
-        // Context sizing:
-        // - First step uses full prompt context
-        // - Later steps feed only the last token
-        let context_size = if index > 0 { 1 } else { input_ids.len() };
-
-        debug_assert!(context_size >= 1, "context_size must be >= 1");
-        debug_assert!(
-            input_ids.len() >= context_size,
-            "input_ids.len() must be >= context_size"
-        );
+        // Text emitted so far stays with the client
+        if let Some(reason) = interruption(&params, started.elapsed()) {
+            finish_reason = reason;
+            break;
+        }
+
+        let logits = if index == 0 {
+            // First step prefills the prompt (position 0 also resets the KV cache)
+            let mut last = None;
+            for chunk in &prefill {
+                let input_tensor = Tensor::new(&input_ids[chunk.clone()], device)
+                    .with_context(|| format!("Tensor::new failed (chunk={:?})", chunk))?
+                    .unsqueeze(0)
+                    .context("unsqueeze(0) failed for input_tensor")?;
+                last = Some(forward(&mut loaded_model.model, &input_tensor, chunk.start)?);
+            }
+            last.context("no prompt tokens left to prefill")?
+        } else {
+            // Later steps feed only the last token
+            let start_at = input_ids.len() - 1;
+
+            // Build input tensor: shape [1, 1]
+            let input_slice = &input_ids[start_at..];
+            let input_tensor = Tensor::new(input_slice, device)
+                .with_context(|| format!("Tensor::new failed (slice_len={})", input_slice.len()))?
+                .unsqueeze(0)
+                .context("unsqueeze(0) failed for input_tensor")?;
 
-        let start_at = input_ids.len() - context_size;
-
-        // Build input tensor: shape [1, context_size]
-        let input_slice = &input_ids[start_at..];
-        let input_tensor = Tensor::new(input_slice, device)
-            .with_context(|| format!("Tensor::new failed (slice_len={})", input_slice.len()))?
-            .unsqueeze(0)
-            .context("unsqueeze(0) failed for input_tensor")?;
-
-        // Forward pass: call correct model variant (unchanged)
-        let logits = match &mut loaded_model.model {
-            ModelEnum::Phi(m) => m
-                .forward(&input_tensor, start_at)
-                .with_context(|| format!("Phi.forward failed (start_at={})", start_at))?,
-            ModelEnum::Mistral(m) => m
-                .forward(&input_tensor, start_at)
-                .with_context(|| format!("Mistral.forward failed (start_at={})", start_at))?,
-            ModelEnum::Llama3(m) => m
-                .forward(&input_tensor, start_at)
-                .with_context(|| format!("Llama3.forward failed (start_at={})", start_at))?,
+            forward(&mut loaded_model.model, &input_tensor, start_at)?
         };
+        cached_len = input_ids.len();
 
         // Extract logits for the last token:
         let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
         // Sample next token
-        let next_token = logits_processor
-            .sample(&logits)
-            .context("logits_processor.sample failed")?;
+        let next_token = sampler.sample(&logits, &input_ids[prompt_tokens..])?;
+        let logprob = match params.logprobs {
+            Some(top) => Some(token_logprob(tokenizer, &logits, next_token, top)?),
+            None => None,
+        };
 
         // Append token to running sequence
         input_ids.push(next_token);
+        generated_tokens += 1;
 
         // Incremental decoding
         // Decode full text each step, then only emit the newly added suffix.
         let current_text = decode_ids(tokenizer, &input_ids)
             .with_context(|| format!("failed to decode at step index={}", index))?;
-
-        if current_text.len() > prev_text_len {
-            let new_text = &current_text[prev_text_len..];
-            callback(new_text.to_string());
-            prev_text_len = current_text.len();
+        let text = output.update(current_text);
+        // Every token carries its logprob, even when its text is still held back
+        if !text.is_empty() || logprob.is_some() {
+            callback(TokenEvent { text, logprob, generated_tokens });
         }
         // Stop tokens
-        if next_token == stop_0 || next_token == stop_1 || next_token == stop_2 || next_token == stop_3
-        {
+        if stop_tokens.contains(&next_token) {
+            finish_reason = FinishReason::Stop;
             break;
         }
     }
-    Ok(())
+
+    if let Some(text) = output.flush() {
+        callback(TokenEvent { text, logprob: None, generated_tokens });
+    }
+
+    // Remember what the cache holds so the next request can extend it
+    input_ids.truncate(cached_len);
+    loaded_model.cached_tokens = input_ids;
+    Ok(GenerationOutcome {
+        finish_reason,
+        stats: GenerationStats {
+            seed,
+            prompt_tokens,
+            generated_tokens,
+            prefill_skipped,
+            context_dropped_tokens: 0,
+            cached: false,
+            coalesced: false,
+        },
+    })
 }
 
+// Generate one token so kernel compilation and allocations happen at load time, not on the
+// first real request. Returns how long it took.
+pub fn warmup(loaded_model: &mut LoadedModel) -> Result<Duration> {
+    let started = Instant::now();
+    let params = InferenceParams { max_tokens: Some(1), seed: Some(0), ..Default::default() };
+    run_inference(loaded_model, "Hello", params, |_| {}).context("warmup generation failed")?;
+    Ok(started.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DeviceSpec, ModelConfig};
+    use crate::model::MOCK_ARCH;
+
+    const MOCK_TOKENIZER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/mock/tokenizer.json");
+    // Ten tokens of the mock tokenizer
+    const PROMPT: &str = "the mock model is a test of the reply token";
+
+    fn mock_model() -> LoadedModel {
+        let conf = ModelConfig {
+            arch: MOCK_ARCH.to_string(),
+            tokenizer_local_path: Some(MOCK_TOKENIZER.to_string()),
+            ..Default::default()
+        };
+        LoadedModel::load("mock", &conf, DeviceSpec::Cpu).unwrap()
+    }
+
+    fn mock(model: &LoadedModel) -> &crate::mock::MockModel {
+        match &model.model {
+            ModelEnum::Mock(mock) => mock,
+            _ => unreachable!(),
+        }
+    }
+
+    fn forward_calls(model: &LoadedModel) -> usize {
+        mock(model).forward_calls()
+    }
+
+    // Text and finish reason of a generation
+    fn generate(model: &mut LoadedModel, prompt: &str, params: InferenceParams) -> (String, GenerationOutcome) {
+        let mut text = String::new();
+        let outcome = run_inference(model, prompt, params, |event| text.push_str(&event.text)).unwrap();
+        (text, outcome)
+    }
+
+    #[test]
+    fn derived_seeds_differ_and_fit_in_a_javascript_number() {
+        // Calls in the same clock tick still differ through the counter
+        let seeds: Vec<u64> = (0..1000).map(|_| derive_seed_from_time()).collect();
+        let distinct: std::collections::HashSet<&u64> = seeds.iter().collect();
+        assert_eq!(distinct.len(), seeds.len());
+        assert!(seeds.iter().all(|&seed| seed < 1 << 53));
+    }
+
+    #[test]
+    fn prefill_chunks_cover_the_prompt() {
+        // (prompt length, cached, chunk size, batches at offset, chunks as (start, end))
+        let cases = [
+            (10, 0, None, true, vec![(0, 10)]),
+            (10, 0, Some(4), true, vec![(0, 4), (4, 8), (8, 10)]),
+            (10, 0, Some(10), true, vec![(0, 10)]),
+            (10, 0, Some(64), true, vec![(0, 10)]),
+            (10, 6, Some(3), true, vec![(6, 9), (9, 10)]),
+            // The chunk size does not apply where candle cannot batch past position 0
+            (10, 0, Some(4), false, vec![(0, 10)]),
+            (10, 7, None, false, vec![(7, 8), (8, 9), (9, 10)]),
+        ];
+        for (prompt_len, cached, chunk_size, batches, expected) in cases {
+            let chunks: Vec<(usize, usize)> = prefill_chunks(prompt_len, cached, chunk_size, batches)
+                .into_iter()
+                .map(|chunk| (chunk.start, chunk.end))
+                .collect();
+            assert_eq!(chunks, expected, "prompt {} cached {} chunk {:?} batches {}", prompt_len, cached, chunk_size, batches);
+        }
+    }
+
+    #[test]
+    fn chunked_prefill_forwards_each_chunk_once() {
+        let params = |chunk| InferenceParams { max_tokens: Some(3), seed: Some(0), prefill_chunk_size: chunk, ..Default::default() };
+
+        let mut model = mock_model();
+        let (whole, _) = generate(&mut model, PROMPT, params(None));
+        // One prefill forward, then one per generated token after the first
+        assert_eq!(forward_calls(&model), 3);
+
+        let mut model = mock_model();
+        let (chunked, outcome) = generate(&mut model, PROMPT, params(Some(4)));
+        // Chunks 0..4, 4..8 and 8..10, then two decode steps
+        assert_eq!(forward_calls(&model), 5);
+        assert_eq!(chunked, whole);
+        assert_eq!(outcome.stats.prompt_tokens, 10);
+        assert_eq!(outcome.stats.generated_tokens, 3);
+    }
+
+    #[test]
+    fn fit_prompt_applies_the_context_strategy() {
+        use ContextStrategy::*;
+        let tokenizer = tokenizers::Tokenizer::from_file(MOCK_TOKENIZER).unwrap();
+        // Two template tokens around the ten of PROMPT: 12 in all
+        let render = |p: &str| format!("question {} answer", p);
+        // (strategy, token budget, expected prompt and dropped tokens, or part of the error)
+        let cases = [
+            (Error, 12, Ok(("question the mock model is a test of the reply token answer", 0))),
+            (TruncateOldest, 12, Ok(("question the mock model is a test of the reply token answer", 0))),
+            (TruncateMiddle, 12, Ok(("question the mock model is a test of the reply token answer", 0))),
+            (Error, 11, Err("prompt is 12 tokens but only 11 fit")),
+            // One over: dropping one token is not enough, the five-token note has to fit too
+            (TruncateOldest, 11, Ok(("question [earlier conversation truncated]\nof the reply token answer", 6))),
+            (TruncateMiddle, 11, Ok(("question the mock\n[earlier conversation truncated]\nreply token answer", 6))),
+            // max_tokens at or past the context leaves a budget of 0
+            (Error, 0, Err("prompt is 12 tokens but only 0 fit")),
+            (TruncateOldest, 0, Err("does not fit in the context even when fully truncated")),
+            (TruncateMiddle, 0, Err("does not fit in the context even when fully truncated")),
+        ];
+        for (strategy, budget, expected) in cases {
+            let result = fit_prompt(&tokenizer, PROMPT, render, budget, strategy);
+            match (result, expected) {
+                (Ok((prompt, dropped)), Ok((want, want_dropped))) => {
+                    assert_eq!((prompt.as_str(), dropped), (want, want_dropped), "{:?} budget {}", strategy, budget);
+                    assert!(encode_prompt(&tokenizer, &prompt).unwrap().len() <= budget);
+                }
+                (Err(e), Err(want)) => assert!(e.to_string().contains(want), "{:?} budget {}: {}", strategy, budget, e),
+                (result, _) => panic!("{:?} budget {}: unexpected {:?}", strategy, budget, result),
+            }
+        }
+    }
+
+    #[test]
+    fn ready_suffix_holds_back_broken_characters() {
+        assert_eq!(ready_suffix("Hi there", 2), Some(" there"));
+        assert_eq!(ready_suffix("Hi", 2), None);
+        // The first byte of a split character decodes as U+FFFD
+        assert_eq!(ready_suffix("Hi \u{FFFD}", 2), None);
+        // Decoding shifted under what was sent, leaving the offset inside a character
+        assert_eq!(ready_suffix("aé", 2), None);
+    }
+
+    #[test]
+    fn incremental_text_sends_a_split_character_once_complete() {
+        let mut text = IncrementalText::new("Hi".to_string());
+        // Decoded text after each token: "é" and "😀" arrive one byte-fallback token at a time
+        let steps = ["Hi", "Hi \u{FFFD}", "Hi é", "Hi é\u{FFFD}", "Hi é\u{FFFD}\u{FFFD}", "Hi é😀", "Hi é😀!"];
+        let sent: Vec<String> = steps.into_iter().map(|step| text.update(step.to_string())).collect();
+        assert_eq!(sent, ["", "", " é", "", "", "😀", "!"]);
+        assert_eq!(text.flush(), None);
+    }
+
+    #[test]
+    fn incremental_text_flushes_a_character_that_never_completed() {
+        let mut text = IncrementalText::new(String::new());
+        assert_eq!(text.update("ok".to_string()), "ok");
+        assert_eq!(text.update("ok\u{FFFD}".to_string()), "");
+        assert_eq!(text.flush(), Some("\u{FFFD}".to_string()));
+        assert_eq!(text.flush(), None);
+    }
 
+    // Byte-level tokenizer (as in GPT-2, Llama 3 or Qwen) with a handful of letters; é and 😀
+    // are split into single-byte tokens. Decoding replaces only the invalid bytes with U+FFFD,
+    // so a stray byte never changes the characters before it.
+    const BYTE_LEVEL_TOKENIZER: &str = r#"{
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [
+            { "id": 0, "content": "<unk>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true },
+            { "id": 1, "content": "</s>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true }
+        ],
+        "normalizer": null,
+        "pre_tokenizer": { "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true },
+        "post_processor": null,
+        "decoder": { "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true },
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": "<unk>",
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "vocab": {
+                "<unk>": 0, "</s>": 1, "Ġ": 2, "t": 3, "h": 4, "e": 5, "c": 6, "a": 7, "f": 8,
+                "Ã": 9, "©": 10, "ð": 11, "Ł": 12, "ĺ": 13, "Ģ": 14
+            },
+            "merges": []
+        }
+    }"#;
+
+    #[test]
+    fn streamed_text_of_multi_byte_characters_matches_a_full_decode() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokenizer_path = dir.path().join("tokenizer.json");
+        std::fs::write(&tokenizer_path, BYTE_LEVEL_TOKENIZER).unwrap();
+        let conf = ModelConfig {
+            arch: MOCK_ARCH.to_string(),
+            tokenizer_local_path: Some(tokenizer_path.display().to_string()),
+            ..Default::default()
+        };
+        let mut model = LoadedModel::load("mock", &conf, DeviceSpec::Cpu).unwrap();
+        let tokenizer = model.tokenizer.clone();
+        let prompt = "the 😀 café";
+        let max_tokens = 60;
+        let params = InferenceParams { max_tokens: Some(max_tokens), temperature: Some(0.0), seed: Some(0), ..Default::default() };
+        let mut deltas = Vec::new();
+        run_inference(&mut model, prompt, params, |event| deltas.push(event.text)).unwrap();
+
+        // The mock is deterministic: replay it greedily for the ids it generated
+        let mut ids = encode_prompt(&tokenizer, prompt).unwrap();
+        let prompt_len = ids.len();
+        let mut replay = crate::mock::MockModel::new(tokenizer.get_vocab_size(true));
+        let mut start_pos = 0;
+        while ids.len() < prompt_len + max_tokens {
+            let input = Tensor::new(&ids[start_pos..], &candle_core::Device::Cpu).unwrap().unsqueeze(0).unwrap();
+            let logits = replay.forward(&input, start_pos).unwrap();
+            start_pos = ids.len();
+            ids.push(logits.squeeze(0).unwrap().argmax(0).unwrap().to_scalar::<u32>().unwrap());
+        }
+        let prompt_text = decode_ids(&tokenizer, &ids[..prompt_len]).unwrap();
+        assert_eq!(prompt_text, prompt);
+        assert_eq!(prompt_text + &deltas.concat(), decode_ids(&tokenizer, &ids).unwrap());
+        // Characters were split across tokens: bytes were held back, and some characters completed
+        assert!(deltas.len() < max_tokens);
+        assert!(deltas.concat().chars().any(|c| c.len_utf8() > 1 && c != '\u{FFFD}'), "{:?}", deltas);
+    }
+
+    #[test]
+    fn no_repeat_ngram_size_stops_repeated_pairs() {
+        // Greedy: the mock's choice wins unless it is banned
+        let params = |size| InferenceParams {
+            temperature: Some(0.0),
+            max_tokens: Some(60),
+            no_repeat_ngram_size: size,
+            ..Default::default()
+        };
+        let mut model = mock_model();
+        let (plain, _) = generate(&mut model, PROMPT, params(None));
+        let (banned, outcome) = generate(&mut model, PROMPT, params(Some(2)));
+        // Pairs of generated tokens seen earlier in the same output
+        let repeated_pairs = |text: &str| {
+            let ids = encode_prompt(&model.tokenizer, text).unwrap();
+            let pairs: Vec<&[u32]> = ids.windows(2).collect();
+            pairs.iter().enumerate().filter(|(i, pair)| pairs[..*i].contains(pair)).count()
+        };
+        assert!(repeated_pairs(&plain) > 0, "{}", plain);
+        assert_eq!(repeated_pairs(&banned), 0, "{}", banned);
+        assert_eq!(outcome.stats.generated_tokens, 60);
+    }
+
+    #[test]
+    fn only_the_configured_stop_ids_end_generation() {
+        // The mock tokenizer without </s>: the default stop set falls back to id 2, which is
+        // "the" here, an ordinary word of the mock model
+        let dir = tempfile::tempdir().unwrap();
+        let tokenizer_path = dir.path().join("tokenizer.json");
+        let json = std::fs::read_to_string(MOCK_TOKENIZER).unwrap().replace("\"</s>\"", "\"<pad>\"");
+        std::fs::write(&tokenizer_path, json).unwrap();
+        let load = |stop_token_ids| {
+            let conf = ModelConfig {
+                arch: MOCK_ARCH.to_string(),
+                tokenizer_local_path: Some(tokenizer_path.display().to_string()),
+                stop_token_ids,
+                ..Default::default()
+            };
+            LoadedModel::load("mock", &conf, DeviceSpec::Cpu).unwrap()
+        };
+        let params = || InferenceParams { max_tokens: Some(40), temperature: Some(0.0), ..Default::default() };
+
+        let mut model = load(None);
+        assert_eq!(model.stop_token_ids, [2]);
+        let (text, outcome) = generate(&mut model, PROMPT, params());
+        assert_eq!(outcome.finish_reason, FinishReason::Stop);
+        assert_eq!(text.split_whitespace().last(), Some("the"));
+
+        // With <pad>, which the mock never emits, as the only stop id, the whole generation
+        let mut model = load(Some(vec![1]));
+        let (whole, outcome) = generate(&mut model, PROMPT, params());
+        assert_eq!(outcome.finish_reason, FinishReason::Length);
+        let words: Vec<&str> = whole.split_whitespace().collect();
+        let first_the = words.iter().position(|word| *word == "the").unwrap();
+        // A custom stop word first generated after "the"
+        let stop_at = (first_the + 1..words.len()).find(|&i| !words[..i].contains(&words[i])).unwrap();
+        let stop_id = model.tokenizer.token_to_id(words[stop_at]).unwrap();
+        let mut model = load(Some(vec![stop_id]));
+        let (text, outcome) = generate(&mut model, PROMPT, params());
+        assert_eq!(outcome.finish_reason, FinishReason::Stop);
+        assert_eq!(text.split_whitespace().collect::<Vec<_>>(), words[..=stop_at]);
+        assert_eq!(outcome.stats.generated_tokens, stop_at + 1);
+    }
+
+    #[test]
+    fn generation_stops_when_interrupted() {
+        let flag = |set| Some(Arc::new(AtomicBool::new(set)));
+        // (timeout, shutdown, cancel, expected reason); out of time is checked first
+        let cases = [
+            (Some(0), None, None, FinishReason::Timeout),
+            (Some(0), flag(true), flag(true), FinishReason::Timeout),
+            (None, flag(true), flag(true), FinishReason::ServerShutdown),
+            (None, flag(false), flag(true), FinishReason::Cancelled),
+        ];
+        for (timeout_secs, shutdown, cancel, expected) in cases {
+            let mut model = mock_model();
+            let params = InferenceParams { max_tokens: Some(8), timeout_secs, shutdown, cancel, ..Default::default() };
+            let (text, outcome) = generate(&mut model, PROMPT, params);
+            assert_eq!(outcome.finish_reason, expected);
+            assert_eq!((text.as_str(), outcome.stats.generated_tokens), ("", 0), "{:?}", expected);
+            assert_eq!(forward_calls(&model), 0);
+        }
+    }
+
+    #[test]
+    fn cancelling_mid_generation_keeps_what_was_sent() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let params = InferenceParams { max_tokens: Some(8), cancel: Some(cancel.clone()), ..Default::default() };
+        let mut model = mock_model();
+        let mut text = String::new();
+        let outcome = run_inference(&mut model, PROMPT, params, |event| {
+            text.push_str(&event.text);
+            // The client goes away after the third token
+            if event.generated_tokens == 3 {
+                cancel.store(true, Ordering::SeqCst);
+            }
+        })
+        .unwrap();
+        assert_eq!(outcome.finish_reason, FinishReason::Cancelled);
+        assert_eq!(outcome.stats.generated_tokens, 3);
+
+        let (whole, _) = generate(&mut mock_model(), PROMPT, InferenceParams { max_tokens: Some(8), ..Default::default() });
+        assert!(whole.starts_with(&text) && whole.len() > text.len(), "{:?} is not a prefix of {:?}", text, whole);
+    }
+
+    #[test]
+    fn without_max_tokens_generation_fills_the_context() {
+        let mut model = mock_model();
+        let params = InferenceParams { max_tokens: None, max_context: Some(16), ..Default::default() };
+        let (_, outcome) = generate(&mut model, PROMPT, params);
+        assert_eq!(outcome.finish_reason, FinishReason::Length);
+        assert_eq!((outcome.stats.prompt_tokens, outcome.stats.generated_tokens), (10, 6));
+        // The last token is sampled but never fed back, so the cache stays within the context
+        assert_eq!(mock(&model).cached_len(), 15);
+
+        // A prompt that fills the context leaves nothing to generate
+        let params = InferenceParams { max_tokens: None, max_context: Some(10), ..Default::default() };
+        let err = run_inference(&mut model, PROMPT, params, |_| {}).unwrap_err();
+        assert!(err.to_string().contains("fills the 10-token context"), "{}", err);
+    }
+
+    #[test]
+    fn generation_limit_prefers_max_tokens() {
+        assert_eq!(generation_limit(Some(4), 16, 10).unwrap(), 4);
+        assert_eq!(generation_limit(None, 16, 10).unwrap(), 6);
+        assert!(generation_limit(None, 16, 16).is_err());
+        assert!(generation_limit(None, 16, 20).is_err());
+    }
+
+    #[test]
+    fn banned_strings_never_appear_in_the_output() {
+        let params = |banned: &[&str]| InferenceParams {
+            temperature: Some(0.0),
+            max_tokens: Some(60),
+            banned_strings: Some(banned.iter().map(|b| b.to_string()).collect()),
+            ..Default::default()
+        };
+        let tokenizer = tokenizers::Tokenizer::from_file(MOCK_TOKENIZER).unwrap();
+        let ids = |text: &str| encode_prompt(&tokenizer, text).unwrap();
+        let mut model = mock_model();
+        let (plain, _) = generate(&mut model, PROMPT, params(&[]));
+        // One word, and a phrase the mock tokenizes as two tokens
+        for banned in ["is", "of is"] {
+            let sequence = ids(banned);
+            let contains = |text: &str| ids(text).windows(sequence.len()).any(|w| w == sequence);
+            assert!(contains(&plain), "{:?} never shows up unbanned: {}", banned, plain);
+            let (text, outcome) = generate(&mut model, PROMPT, params(&[banned]));
+            assert!(!contains(&text), "{:?} in {}", banned, text);
+            assert_eq!(outcome.stats.generated_tokens, 60);
+        }
+        // Banning the phrase leaves its first word free
+        let (text, _) = generate(&mut model, PROMPT, params(&["of is"]));
+        assert!(ids(&text).contains(&ids("of")[0]), "{}", text);
+    }
+
+    #[test]
+    fn consecutive_generations_do_not_see_each_other() {
+        let first = "the mock model is a test of the reply token";
+        let second = "hello world";
+        for reuse_prefix in [false, true] {
+            let params = || InferenceParams { max_tokens: Some(1), seed: Some(0), reuse_prefix, ..Default::default() };
+            let mut fresh = mock_model();
+            let (alone, _) = generate(&mut fresh, second, params());
+
+            let mut model = mock_model();
+            generate(&mut model, first, params());
+            let (after, outcome) = generate(&mut model, second, params());
+            assert_eq!(after, alone, "reuse_prefix {}", reuse_prefix);
+            assert_eq!(outcome.stats.prefill_skipped, 0);
+            // The cache holds the second prompt only: its prefill started over at position 0
+            assert_eq!(mock(&model).cached_len(), outcome.stats.prompt_tokens);
+            assert_eq!(model.cached_tokens, encode_prompt(&model.tokenizer, second).unwrap());
+        }
+    }
+}
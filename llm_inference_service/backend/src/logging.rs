@@ -0,0 +1,91 @@
+// src/logging.rs
+// Structured logging: one record per inference request, to stdout and optionally a daily rolling file
+use crate::config::Settings;
+use crate::infer::{FinishReason, GenerationStats};
+use tracing::level_filters::LevelFilter;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{Layer, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+
+// Target used for request records, so they can be filtered apart from other events
+const REQUEST_TARGET: &str = "inference_request";
+
+// How a request ended
+pub enum RequestOutcome {
+    Completed(FinishReason),
+    Failed(String),
+    Cancelled, // Client disconnected mid-stream
+    SlowClient, // Client stopped reading the stream and was dropped
+}
+
+// Everything recorded about one inference request
+pub struct RequestLog<'a> {
+    pub model: &'a str,
+    pub prompt: &'a str,
+    pub stats: Option<GenerationStats>, // Unknown when generation failed
+    pub duration_ms: u64,
+    pub outcome: RequestOutcome,
+}
+
+// Install the global subscriber. The returned guard flushes the log file and must live until exit.
+pub fn init(settings: &Settings) -> Option<WorkerGuard> {
+    let level = settings
+        .log_level
+        .as_deref()
+        .and_then(|level| level.parse::<LevelFilter>().ok())
+        .unwrap_or(LevelFilter::INFO);
+    let stdout_layer = fmt::layer().with_filter(level);
+
+    let (file_layer, guard) = match &settings.log_dir {
+        Some(dir) => {
+            let appender = tracing_appender::rolling::daily(dir, "inference.log");
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let layer = fmt::layer()
+                .json()
+                .with_writer(writer)
+                .with_ansi(false)
+                .with_filter(level);
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(stdout_layer)
+        .with(file_layer)
+        .init();
+    guard
+}
+
+// Emit the record of a finished request. Prompts are only written when `log_prompts` is on.
+pub fn log_request(record: &RequestLog, log_prompts: bool) {
+    let prompt = if log_prompts { record.prompt } else { "[redacted]" };
+    let prompt_tokens = record.stats.map(|s| s.prompt_tokens);
+    let completion_tokens = record.stats.map(|s| s.generated_tokens);
+    let seed = record.stats.map(|s| s.seed); // Enough to replay the request, with its prompt
+    let tokens_per_sec = completion_tokens
+        .filter(|_| record.duration_ms > 0)
+        .map(|tokens| tokens as f64 * 1000.0 / record.duration_ms as f64);
+    let (outcome, finish_reason, error) = match &record.outcome {
+        RequestOutcome::Completed(reason) => {
+            let reason = serde_json::json!(reason).as_str().unwrap_or_default().to_string();
+            ("completed", Some(reason), None)
+        }
+        RequestOutcome::Failed(e) => ("error", None, Some(e.as_str())),
+        RequestOutcome::Cancelled => ("cancelled", None, None),
+        RequestOutcome::SlowClient => ("slow_client", None, None),
+    };
+    tracing::info!(
+        target: REQUEST_TARGET,
+        model = record.model,
+        prompt,
+        prompt_tokens,
+        completion_tokens,
+        seed,
+        duration_ms = record.duration_ms,
+        tokens_per_sec,
+        outcome,
+        finish_reason,
+        error,
+        "inference request finished"
+    );
+}
@@ -1,23 +1,39 @@
+mod benchmark;
 mod config;
+mod embedding;
+mod gpu;
 mod infer;
+mod logging;
+mod mock;
 mod model;
+mod openapi;
+mod response_cache;
+mod sampling;
+mod sessions;
 mod template;
 
 // import standard library
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     net::SocketAddr,
     path::PathBuf,
-    process::Command,
-    sync::{Arc, Mutex as StdMutex},
+    sync::{
+        Arc, Mutex as StdMutex, MutexGuard, PoisonError,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 // import Axum
 use axum::{
     Json, 
     Router,
-    extract::State,
-    response::sse::{Event, KeepAlive, Sse},
-    routing::{get, post},
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE}},
+    response::{
+        Html, IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{MethodRouter, delete, get, post},
 };
 // import serde for serializing and deserializing
 use serde::{
@@ -25,64 +41,137 @@ use serde::{
     Serialize
 };
 use serde_json::json;
+use utoipa::{OpenApi, ToSchema};
 // import tokio for asynchronous runtime handling
 use tokio::{
-    sync::{Mutex as TokioMutex, Semaphore, mpsc},
+    signal,
+    sync::{Mutex as TokioMutex, Notify, OwnedSemaphorePermit, Semaphore, mpsc, mpsc::error::SendTimeoutError},
     task,
+    time,
 };
 // import tokio_stream for SSE
-use hf_hub::{
-    Repo, 
-    RepoType, 
-    api::sync::Api
-};
 use tokio_stream::{StreamExt, wrappers::ReceiverStream};
-use tower_http::cors::{Any, CorsLayer}; // CORS // Hugging face
+use tower_http::cors::{AllowHeaders, AllowMethods, Any, CorsLayer}; // CORS // Hugging face
+use tokenizers::Tokenizer;
 
 // Internal modules
-use config::Settings;
-use infer::{InferenceParams, run_inference};
+use benchmark::{BenchmarkRun, MAX_BENCHMARK_RUNS, Summary};
+use config::{ContextStrategy, DeviceSpec, Settings};
+use infer::{
+    FinishReason, GenerationOutcome, GenerationStats, InferenceParams,
+    TokenEvent, TokenLogprob, derive_seed_from_time, encode_prompt, fit_prompt, run_inference, warmup,
+};
+use logging::{RequestLog, RequestOutcome, log_request};
+use shared_types::{
+    ApiResponse, ChatRole, ChatTurn, InferRequest, JobStatus, LoadJob, LoadModelRequest, ModelList, ModelStatus, TokenizeRequest,
+    TokenizeResponse, UnloadModelRequest,
+};
 use model::LoadedModel;
-use template::apply_chat_template;
-
-// Calculate how much VRAM the GPU has (in order to determine if unload model)
-fn detect_vram_mb() -> usize {
-    // Run 'nvidia-smi' command
-    let output_result = Command::new("nvidia-smi")
-        .args(["--query-gpu=memory.total", "--format=csv,noheader,nounits"])
-        .output();
-
-    if let Ok(o) = output_result {
-        if o.status.success() {
-            let stdout = String::from_utf8_lossy(&o.stdout);
-            let first_line = stdout.lines().next();
-            if let Some(line) = first_line {
-                if let Ok(total_mb) = line.trim().parse::<usize>() {
-                    let safe_limit = total_mb.saturating_sub(1024);
-                    println!("GPU VRAM: {} MB. Using safe limit: {} MB", total_mb, safe_limit);
-                    return safe_limit;
-                }
-            }
+use response_cache::{CachedChoice, ResponseCache, ResponseCacheStats};
+use sessions::{DEFAULT_SESSION_IDLE_SECS, Session, Sessions, TurnError};
+use template::{apply_chat_template, strip_control_tokens};
+
+// Concurrent generations; only one allowed for enough VRAM space
+const MAX_CONCURRENCY: usize = 1;
+// Completions per /infer request when config.toml does not set max_choices
+const DEFAULT_MAX_CHOICES: usize = 4;
+// How long a stream event may wait for buffer space when config.toml does not say
+const DEFAULT_STREAM_SEND_TIMEOUT_SECS: u64 = 30;
+// A stream reports its token count every this many generated tokens
+const STREAM_PROGRESS_INTERVAL: usize = 16;
+
+// CORS policy: any origin without credentials for local dev, or only the listed origins
+// with credentials allowed. Credentials rule out `Any`, so methods and headers mirror the request.
+fn cors_layer(allowed_origins: &[String]) -> CorsLayer {
+    if allowed_origins.is_empty() {
+        println!("CORS: allowing any origin (set allowed_origins in config.toml to restrict)");
+        return CorsLayer::new()
+            .allow_origin(Any)
+            .allow_methods(Any)
+            .allow_headers(Any);
+    }
+    println!("CORS: allowing origins {:?} with credentials", allowed_origins);
+    // Origins were validated when the settings were loaded
+    let origins: Vec<HeaderValue> = allowed_origins
+        .iter()
+        .map(|origin| HeaderValue::from_str(origin).expect("validated origin"))
+        .collect();
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(AllowMethods::mirror_request())
+        .allow_headers(AllowHeaders::mirror_request())
+        .allow_credentials(true)
+}
+
+// Detect GPUs and derive the VRAM budget of each (in order to determine if unload model)
+fn detect_devices(settings: &Settings) -> (Vec<gpu::GpuInfo>, BTreeMap<usize, usize>) {
+    let gpus = gpu::detect_gpus(unified_memory_fraction(settings));
+    for g in &gpus {
+        println!("GPU {}: {} ({} MB total, {} MB free)", g.index, g.name, g.total_mb, g.free_mb);
+        if !gpu::plausible_vram(g) {
+            println!(
+                "Warning: GPU {} reports {} MB of VRAM, which cannot be right (MIG mode or unreadable output?). Using the default.",
+                g.index, g.total_mb
+            );
         }
     }
-    println!("VRAM detection failed. Using default.");
-    #[cfg(target_os = "macos")]{
-        return 6976;
-    } // Default for Mac
-    // Default: 8000 - 1024(1G)
-    return 6976; 
+    if gpus.is_empty() {
+        println!("VRAM detection failed. Using default.");
+    }
+    let limits = gpu::select_vram_limits(&gpus);
+    for (index, limit) in &limits {
+        println!("Using VRAM limit for GPU {}: {} MB", index, limit);
+    }
+    (gpus, limits)
+}
+
+// Device a model was last placed on, else the one config.toml gives it
+fn model_device(state: &AppState, name: &str) -> DeviceSpec {
+    if let Some(&device) = lock(&state.placements).get(name) {
+        return device;
+    }
+    state.settings.models.get(name)
+        .and_then(|conf| conf.device_spec().ok())
+        .unwrap_or(DeviceSpec::Auto)
+}
+
+// GPU whose VRAM budget a model counts against; None for CPU models
+fn model_vram_index(state: &AppState, name: &str) -> Option<usize> {
+    model_device(state, name).vram_index()
+}
+
+// Choose the device for a new load of `name`. Models left on "auto" take the detected GPUs in
+// turn when there are several; an explicit device is always honoured.
+fn place_model(state: &AppState, name: &str, spec: DeviceSpec) -> DeviceSpec {
+    let device = match spec {
+        DeviceSpec::Auto if state.vram_limits.len() > 1 => {
+            let gpus: Vec<usize> = state.vram_limits.keys().copied().collect();
+            let turn = state.next_gpu.fetch_add(1, Ordering::SeqCst);
+            DeviceSpec::Cuda(gpus[turn % gpus.len()])
+        }
+        spec => spec,
+    };
+    println!("Placing model '{}' on {}", name, device);
+    lock(&state.placements).insert(name.to_string(), device);
+    device
+}
+
+// Share of system memory Metal may use on unified-memory Macs
+fn unified_memory_fraction(settings: &Settings) -> f64 {
+    settings.unified_memory_fraction.unwrap_or(0.75)
 }
 
 // Check the model file's actual size on disk so that we know
 // if we can actually load it
 // Return (file path, size in mb)
 fn get_model_file_info(name: &str, conf: &config::ModelConfig) -> anyhow::Result<(PathBuf, usize)> {
-    let api = Api::new()?;
-    let repo_id = conf.repo.clone();
-    let repo = api.repo(Repo::new(repo_id, RepoType::Model));
-    // This .get() call will download the file if not present, or return path if cached.
+    // Local file, or download from HF if not present / return path if cached.
     println!("Checking file for '{}'", name);
-    let path = repo.get(&conf.file)?;
+    // Mock models have no weights; they take the size config.toml gives them
+    if model::is_mock_arch(&conf.arch) {
+        return Ok((model::resolve_tokenizer_path(conf)?, conf.mock_size_mb.unwrap_or(0)));
+    }
+    let path = model::fetch_verified_weights(conf)?;
 
     // Read file size
     let metadata = std::fs::metadata(&path)?;
@@ -94,57 +183,494 @@ fn get_model_file_info(name: &str, conf: &config::ModelConfig) -> anyhow::Result
     Ok((path, effective_mb))
 }
 
+// A loaded model, as seen when making room for another
+struct Resident {
+    name: String,
+    vram_index: Option<usize>, // GPU it occupies; None on the CPU
+    mb: usize, // Effective size, 0 when unknown
+    last_used: u64,
+}
+
+// Why a model cannot be loaded next to the resident ones
+#[derive(Debug, PartialEq)]
+enum NoRoom {
+    TooManyModels(usize), // max_loaded_models are already loaded
+    TooLarge, // Does not fit on its GPU even with every other model there unloaded
+}
+
+// Models to unload, in order, so that a model of `required_mb` fits under `limit_mb` on GPU
+// `vram_index` (None: the CPU, which has no budget). Only models on that GPU are candidates,
+// least recently used first, and `keep` is never one of them.
+fn plan_evictions(
+    resident: &[Resident],
+    vram_index: Option<usize>,
+    required_mb: usize,
+    limit_mb: usize,
+    max_loaded: Option<usize>,
+    keep: Option<&str>,
+) -> Result<Vec<String>, NoRoom> {
+    if max_loaded.is_some_and(|max| resident.len() >= max) {
+        return Err(NoRoom::TooManyModels(resident.len()));
+    }
+    let Some(index) = vram_index else {
+        return Ok(Vec::new());
+    };
+    let mut on_gpu: Vec<&Resident> = resident.iter().filter(|r| r.vram_index == Some(index)).collect();
+    on_gpu.sort_by_key(|r| (r.last_used, &r.name));
+    let mut usage_mb: usize = on_gpu.iter().map(|r| r.mb).sum();
+    let mut victims = Vec::new();
+    for r in on_gpu {
+        if usage_mb.saturating_add(required_mb) <= limit_mb {
+            break;
+        }
+        // Unloading a model of unknown size frees nothing we can count on
+        if Some(r.name.as_str()) == keep || r.mb == 0 {
+            continue;
+        }
+        usage_mb -= r.mb;
+        victims.push(r.name.clone());
+    }
+    if usage_mb.saturating_add(required_mb) <= limit_mb {
+        Ok(victims)
+    } else {
+        Err(NoRoom::TooLarge)
+    }
+}
+
+fn too_many_models_message(name: &str, loaded: usize, max: usize) -> String {
+    format!(
+        "Cannot load '{}': {} models already loaded (max_loaded_models = {}). Unload one first.",
+        name, loaded, max
+    )
+}
+
+// Current time in unix seconds
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Lock a std mutex even if a thread panicked while holding it. Their critical sections only
+// insert, remove or read, so the data is still consistent, and one panic must not make every
+// later request that touches the mutex panic too.
+fn lock<T>(mutex: &StdMutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Lock a model for use. A panic mid-generation can leave its KV cache out of step with
+// `cached_tokens`, so after one the next request prefills from scratch.
+fn lock_model(model: &StdMutex<LoadedModel>) -> MutexGuard<'_, LoadedModel> {
+    model.lock().unwrap_or_else(|poisoned| {
+        model.clear_poison();
+        let mut model = poisoned.into_inner();
+        model.reset_cache();
+        model
+    })
+}
+
+// Record that a model was just used so the idle unloader leaves it alone
+async fn touch_model(state: &AppState, name: &str) {
+    let mut last_used = state.last_used.lock().await;
+    last_used.insert(name.to_string(), now_secs());
+}
+
+// Idle time after which a model is unloaded; None if it is kept however long it sits unused.
+// The active model is kept unless unload_active_on_idle is set.
+fn idle_threshold(state: &AppState, name: &str, active: &str) -> Option<u64> {
+    if name == active && !state.settings.unload_active_on_idle {
+        return None;
+    }
+    state.settings.models.get(name)
+        .and_then(|c| c.idle_unload_secs)
+        .or(state.settings.idle_unload_secs)
+}
+
+// Unload every model that has been idle longer than its threshold
+async fn unload_idle_models(state: &AppState) {
+    let now = now_secs();
+    let mut models = state.models.lock().await;
+    let mut active = state.active_model.lock().await;
+    let last_used = state.last_used.lock().await;
+    for (name, slot) in models.iter_mut() {
+        let Some(model) = slot else { continue };
+        let Some(threshold) = idle_threshold(state, name, &active) else { continue };
+        let idle_secs = now.saturating_sub(*last_used.get(name).unwrap_or(&0));
+        if idle_secs < threshold {
+            continue;
+        }
+        // A locked model is generating right now, so it is not idle
+        if model.try_lock().is_err() {
+            continue;
+        }
+        println!("Idle unload: {} unused for {}s (threshold {}s)", name, idle_secs, threshold);
+        *slot = None;
+        if *active == *name {
+            *active = "".into();
+        }
+    }
+}
+
+// Background task that periodically frees VRAM held by idle models
+async fn idle_unload_loop(state: AppState) {
+    // Check often enough to honour the smallest configured threshold
+    let smallest = state.settings.models.values()
+        .filter_map(|c| c.idle_unload_secs)
+        .chain(state.settings.idle_unload_secs)
+        .min();
+    let Some(smallest) = smallest else { return };
+    let period = Duration::from_secs((smallest / 2).clamp(1, 60));
+    let mut ticker = time::interval(period);
+    loop {
+        ticker.tick().await;
+        unload_idle_models(&state).await;
+    }
+}
+
+// Background task that forgets idle sessions; they hold no VRAM, only their transcripts
+async fn session_expiry_loop(state: AppState) {
+    let idle_secs = state.settings.session_idle_secs.unwrap_or(DEFAULT_SESSION_IDLE_SECS);
+    let mut ticker = time::interval(Duration::from_secs((idle_secs / 2).clamp(1, 60)));
+    loop {
+        ticker.tick().await;
+        let expired = state.sessions.expire(idle_secs);
+        if expired > 0 {
+            println!("Expired {} idle session(s).", expired);
+        }
+    }
+}
+
 // --- App State ---
 #[derive(Clone)]
 struct AppState {
     models: Arc<TokioMutex<HashMap<String, Option<Arc<StdMutex<LoadedModel>>>>>>,
     active_model: Arc<TokioMutex<String>>,
     semaphore: Arc<Semaphore>,
-    model_sizes: Arc<TokioMutex<HashMap<String, usize>>>, // Track VRAM size of each model
-    vram_limit: usize,
+    model_sizes: Arc<TokioMutex<HashMap<String, ModelSize>>>, // Track VRAM size of each model
+    last_used: Arc<TokioMutex<HashMap<String, u64>>>, // Unix seconds each model was last loaded or used
+    loading: Arc<TokioMutex<HashSet<String>>>, // Models currently being downloaded/loaded
+    jobs: Arc<StdMutex<BTreeMap<u64, LoadJob>>>, // Queued loads by id; the oldest finished ones are dropped
+    next_job_id: Arc<AtomicU64>,
+    load_queue: mpsc::UnboundedSender<u64>, // Job ids for the background loader
+    shutting_down: Arc<AtomicBool>, // Set once a shutdown signal arrives; new inference gets 503
+    shutdown_cancel: Arc<AtomicBool>, // Tells in-flight generations to stop after the grace period
+    shutdown_requested: Arc<Notify>, // Signalled by POST /admin/shutdown
+    in_flight: Arc<AtomicUsize>, // Inference requests accepted but not finished
+    started_at: u64, // Unix seconds the server started
+    queue_depth: Arc<AtomicUsize>, // Requests waiting for a semaphore permit
+    max_concurrency: usize, // Number of semaphore permits
+    stats: Arc<StdMutex<RequestStats>>, // Queue/generation timing of finished requests
+    tokenizers: Arc<StdMutex<HashMap<String, Arc<Tokenizer>>>>, // Read on first /tokenize use, model loaded or not
+    vram_limits: BTreeMap<usize, usize>, // VRAM budget in MB per GPU index
+    devices: Arc<Vec<gpu::GpuInfo>>, // GPUs as detected at startup, reported by /health
+    cache_size_mb: Arc<AtomicU64>, // Disk space of the cached model files, kept by refresh_cache_size
+    placements: Arc<StdMutex<HashMap<String, DeviceSpec>>>, // Device each model was last loaded on
+    unhealthy: Arc<StdMutex<HashMap<String, String>>>, // Models unloaded after a fatal inference error, with the error; cleared by the next load
+    next_gpu: Arc<AtomicUsize>, // Round-robin position for "auto" models on multi-GPU hosts
+    sessions: Sessions, // Server-side conversations of /sessions
+    response_cache: Option<Arc<StdMutex<ResponseCache>>>, // Seeded responses for replay; None when response_cache_size is unset
+    shared_streams: Arc<StdMutex<HashMap<u64, SharedStream>>>, // Seeded streams in progress, by response cache key, that identical requests join
     settings: Arc<Settings>, // Global settings
 }
-// Response structures in JSON
-#[derive(Serialize)]
-struct ModelStatus {
-    loaded: bool,
-    size_mb: usize,
+// VRAM cost of a model: estimated from the file size, and measured once it has been loaded on a GPU
+#[derive(Clone, Copy, Default)]
+struct ModelSize {
+    estimated_mb: usize,
+    measured_mb: Option<usize>,
+}
+impl AppState {
+    // Budget of a GPU that was not detected falls back to the default
+    fn vram_limit(&self, index: usize) -> usize {
+        self.vram_limits.get(&index).copied().unwrap_or(gpu::DEFAULT_VRAM_LIMIT_MB)
+    }
+}
+impl ModelSize {
+    // Size used for admission and eviction decisions
+    fn effective_mb(&self) -> usize {
+        self.measured_mb.unwrap_or(self.estimated_mb)
+    }
+}
+// Where finished requests spent their time: waiting for a permit vs generating
+#[derive(Default)]
+struct RequestStats {
+    completed: u64,
+    total_queue_ms: u64,
+    total_generate_ms: u64,
+}
+impl RequestStats {
+    fn record(&mut self, timing: &Timing) {
+        self.completed += 1;
+        self.total_queue_ms += timing.queue_ms;
+        self.total_generate_ms += timing.generate_ms;
+    }
+}
+// Time one request spent queued and generating, in milliseconds
+#[derive(Serialize, Clone, Copy, ToSchema)]
+struct Timing {
+    queue_ms: u64,
+    generate_ms: u64,
+}
+// Counts a request as waiting in the queue until dropped (also if the client gives up)
+struct QueueGuard(Arc<AtomicUsize>);
+impl Drop for QueueGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+// Wait for an inference permit; returns it with the time spent waiting in ms
+async fn acquire_permit(state: &AppState) -> (OwnedSemaphorePermit, u64) {
+    state.queue_depth.fetch_add(1, Ordering::SeqCst);
+    let _queued = QueueGuard(state.queue_depth.clone());
+    let start = Instant::now();
+    let permit = state.semaphore.clone().acquire_owned().await.unwrap();
+    (permit, start.elapsed().as_millis() as u64)
+}
+// Events of a generation that identical seeded streams follow instead of generating again
+struct SharedStream {
+    sent: Vec<String>, // Everything so far, replayed to followers that join late
+    followers: Vec<mpsc::UnboundedSender<String>>,
+}
+// Counts an inference request as in flight until dropped
+struct InFlightGuard(Arc<AtomicUsize>);
+impl InFlightGuard {
+    // Returns None when the server is shutting down and must not take new work
+    fn enter(state: &AppState) -> Option<Self> {
+        if state.shutting_down.load(Ordering::SeqCst) {
+            return None;
+        }
+        state.in_flight.fetch_add(1, Ordering::SeqCst);
+        Some(Self(state.in_flight.clone()))
+    }
+}
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+// POST /embeddings body, in OpenAI's format
+#[derive(Deserialize, ToSchema)]
+struct EmbeddingRequest {
+    model: String,
+    input: EmbeddingInput,
+}
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+// OpenAI embeddings response
+#[derive(Serialize, ToSchema)]
+struct EmbeddingList {
+    object: &'static str, // always "list"
+    data: Vec<EmbeddingData>,
+    model: String,
+    usage: EmbeddingUsage,
+}
+#[derive(Serialize, ToSchema)]
+struct EmbeddingData {
+    object: &'static str, // always "embedding"
+    index: usize,
+    embedding: Vec<f32>,
+}
+#[derive(Serialize, ToSchema)]
+struct EmbeddingUsage {
+    prompt_tokens: usize,
+    total_tokens: usize,
 }
-#[derive(Serialize)]
-struct ModelList {
-    models: HashMap<String, ModelStatus>,
+// OpenAI list format for GET /v1/models
+#[derive(Serialize, ToSchema)]
+struct OpenAiModelList {
+    object: &'static str, // always "list"
+    data: Vec<OpenAiModel>,
+}
+#[derive(Serialize, ToSchema)]
+struct OpenAiModel {
+    id: String,
+    object: &'static str, // always "model"
+    created: u64,
+    owned_by: &'static str,
+}
+#[derive(Serialize, ToSchema)]
+struct HealthStatus {
+    status: String,
     active: String,
-    vram_usage: String,
+    loading: Vec<String>,
+    max_prompt_chars: Option<usize>, // Longest prompt accepted, so clients can check before sending
+    devices: Vec<gpu::GpuInfo>, // As detected at startup; /stats has current free memory
+    cache_size_mb: u64, // hf-hub cache files of the configured models
 }
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct SetModelRequest {
     name: String,
 }
-#[derive(Deserialize)]
-struct LoadModelRequest {
+// What POST /switch_model had to do
+#[derive(Serialize, ToSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum SwitchOutcome {
+    AlreadyActive,
+    ActivatedExisting, // Was loaded but inactive
+    LoadedAndActivated,
+}
+#[derive(Serialize, ToSchema)]
+struct SwitchModelResponse {
+    model: String, // Configured name, also when the request used an alias
+    outcome: SwitchOutcome,
+    loaded: bool, // Whether a load happened
+    message: String,
+}
+#[derive(Deserialize, ToSchema)]
+struct ResetRequest {
+    name: String,
+}
+#[derive(Serialize, ToSchema)]
+struct InferResponse {
+    model: String, // Model that generated the text
+    text: String,
+    finish_reason: FinishReason,
+    stats: GenerationStats,
+    timing: Timing,
+    // Per generated token, when the request set logprobs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<Vec<TokenLogprob>>,
+    // Every completion when the request set n > 1; the fields above repeat the first one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    choices: Option<Vec<InferChoice>>,
+}
+#[derive(Serialize, Clone, ToSchema)]
+struct InferChoice {
+    index: usize,
+    text: String,
+    finish_reason: FinishReason,
+    stats: GenerationStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<Vec<TokenLogprob>>,
+    #[serde(skip)]
+    events: Vec<TokenEvent>, // As generated, for the response cache; empty when it is off
+}
+// POST /sessions body
+#[derive(Deserialize, ToSchema)]
+struct CreateSessionRequest {
+    model: Option<String>, // Configured model or alias; defaults to the active model
+    system_prompt: Option<String>, // Omitted: the model's default_system_prompt
+}
+// POST /compare: the same prompt and sampling options on several models
+#[derive(Deserialize, ToSchema)]
+struct CompareRequest {
+    models: Vec<String>, // Loaded models (or aliases), run in this order
+    #[serde(default)]
+    stream: bool, // Send SSE events tagged with the model instead of one JSON response
+    #[serde(flatten)]
+    request: InferRequest, // `n` must be 1
+}
+#[derive(Serialize, ToSchema)]
+struct CompareResponse {
+    seed: u64, // Used by every run; random unless the request fixed it
+    results: Vec<CompareResult>, // In the order of `models`
+}
+// One model's completion, or why it has none; a failed model does not fail the others
+#[derive(Serialize, Default, ToSchema)]
+struct CompareResult {
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<FinishReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<GenerationStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timing: Option<Timing>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>, // Not configured, not loaded, an embedding model, or generation failed
+}
+#[derive(Deserialize, ToSchema)]
+struct DetokenizeRequest {
+    model: Option<String>, // Defaults to the active model
+    tokens: Vec<u32>,
+    #[serde(default)]
+    skip_special_tokens: bool,
+}
+#[derive(Serialize, ToSchema)]
+struct DetokenizeResponse {
+    text: String,
+}
+#[derive(Deserialize, ToSchema)]
+struct BenchmarkRequest {
+    model: Option<String>, // Defaults to the active model
+    prompt_tokens: Option<usize>, // Default 512
+    gen_tokens: Option<usize>, // Default 128
+    runs: Option<usize>, // Default 3
+}
+#[derive(Serialize, ToSchema)]
+struct BenchmarkReport {
+    model: String,
+    prompt_tokens: usize,
+    gen_tokens: usize,
+    ttft_ms: Summary,
+    prefill_tokens_per_sec: Summary,
+    decode_tokens_per_sec: Summary,
+    peak_gpu_used_mb: Option<usize>, // Highest gpu_used_mb of the runs; null off CUDA
+    runs: Vec<BenchmarkRun>,
+}
+#[derive(Serialize, ToSchema)]
+struct StatsResponse {
+    queue_depth: usize,
+    in_flight: usize,
+    max_concurrency: usize,
+    completed: u64,
+    avg_queue_ms: u64,
+    avg_generate_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_cache: Option<ResponseCacheStats>, // Only when response_cache_size is set
+    devices: Vec<gpu::GpuInfo>,
+    cache_size_mb: u64, // hf-hub cache files of the configured models
+}
+#[derive(Serialize, ToSchema)]
+struct ModelDetails {
     name: String,
+    config: config::ModelConfig,
+    cached: bool, // Weights are on disk (no download needed to load)
+    file_size_mb: Option<usize>,
+    gguf: Option<model::GgufInfo>, // Header metadata, when cached and in GGUF format
 }
-#[derive(Deserialize)]
-struct UnloadModelRequest {
+#[derive(Serialize, ToSchema)]
+struct CachedModel {
     name: String,
+    size_mb: u64,
+    files: Vec<model::CachedFile>,
 }
-#[derive(Deserialize)]
-struct InferRequest {
-    prompt: String,
-    temperature: Option<f64>,
-    top_p: Option<f64>,
-    max_tokens: Option<usize>,
-    seed: Option<u64>,
-    system_prompt: Option<String>,
+#[derive(Serialize, ToSchema)]
+struct CacheListing {
+    models: Vec<CachedModel>,
+    total_size_mb: u64, // Files shared by several models are counted once
 }
-// Standardized API response
-#[derive(Serialize)]
-struct ApiResponse<T> {
-    status: String,
-    data: Option<T>,
-    message: Option<String>,
+#[derive(Serialize, ToSchema)]
+struct CacheDeletion {
+    deleted: Vec<String>,
+    kept: Vec<String>, // Also used by another configured model
+    freed_mb: u64,
 }
-impl<T> ApiResponse<T> {
+#[derive(Serialize, ToSchema)]
+struct DownloadInfo {
+    path: String,
+    tokenizer_path: String,
+    estimated_mb: usize,
+}
+#[derive(Serialize, ToSchema)]
+struct PromptPreview {
+    templated_prompt: String,
+    prompt_tokens: usize,
+    dropped_tokens: usize, // Tokens cut to fit the context (see context_strategy)
+}
+// Build the shared response envelope as axum responses
+trait ApiReply<T>: Sized {
+    fn ok(data: T) -> Json<Self>;
+    fn error(msg: impl Into<String>) -> Json<Self>;
+    // Error body with a non-200 status code
+    fn error_with_status(status: StatusCode, msg: impl Into<String>) -> Response;
+}
+impl<T: Serialize> ApiReply<T> for ApiResponse<T> {
     fn ok(data: T) -> Json<Self> {
         Json(Self {
             status: "ok".to_string(),
@@ -159,291 +685,2111 @@ impl<T> ApiResponse<T> {
             message: Some(msg.into()),
         })
     }
+    fn error_with_status(status: StatusCode, msg: impl Into<String>) -> Response {
+        (status, Self::error(msg)).into_response()
+    }
 }
 
-// POST /load_model
-async fn load_model_handler(
-    State(state): State<AppState>,
-    Json(req): Json<LoadModelRequest>,
-) -> Json<ApiResponse<String>> {
+// Stream event reporting a failure; the stream ends with [DONE] after it
+fn stream_error(message: impl std::fmt::Display) -> String {
+    json!({ "error": message.to_string() }).to_string()
+}
+
+// A generation task that panicked; the model it held may be in any state
+#[derive(Debug)]
+struct GenerationPanicked(String);
+impl std::fmt::Display for GenerationPanicked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "generation panicked: {}", self.0)
+    }
+}
+impl std::error::Error for GenerationPanicked {}
+
+// Errors after which a loaded model cannot be trusted: the device failed, or the generation
+// panicked while holding the model (its lock is poisoned for every later request)
+fn is_fatal_inference_error(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause.is::<GenerationPanicked>() || cause.downcast_ref::<candle_core::Error>().is_some_and(is_device_error)
+    })
+}
+
+// Candle's errors from the GPU backends (out of memory, driver or kernel errors), looking
+// through the context, path and backtrace candle wraps them in
+fn is_device_error(e: &candle_core::Error) -> bool {
+    use candle_core::Error;
+    match e {
+        Error::Cuda(_) | Error::Metal(_) => true,
+        Error::Context { inner, .. } | Error::WithPath { inner, .. } | Error::WithBacktrace { inner, .. } => {
+            is_device_error(inner)
+        }
+        _ => false,
+    }
+}
+
+// Unload a model left unusable by a fatal inference error, so later requests are told to load
+// it again instead of failing on it, and mark it unhealthy until then. With
+// reload_after_failure set, a reload is queued right away. Returns the note for the client.
+async fn unload_failed_model(state: &AppState, name: &str, error: &anyhow::Error) -> String {
+    if let Some(slot) = state.models.lock().await.get_mut(name) {
+        *slot = None;
+    }
+    {
+        let mut active = state.active_model.lock().await;
+        if *active == name {
+            *active = "".into();
+        }
+    }
+    lock(&state.unhealthy).insert(name.to_string(), error.to_string());
+    println!("Model {} unloaded after a fatal inference error: {:#}", name, error);
+    if state.settings.reload_after_failure {
+        let job = queue_load(state, name.to_string());
+        println!("Reloading {} (job {})", name, job.id);
+        format!("Model {} was unloaded because it may be unusable and is being reloaded (job {}).", name, job.id)
+    } else {
+        format!("Model {} was unloaded because it may be unusable; load it again.", name)
+    }
+}
+
+fn shutting_down_response() -> Response {
+    ApiResponse::<()>::error_with_status(StatusCode::SERVICE_UNAVAILABLE, "Server is shutting down.")
+}
+
+// 413 for a prompt (with its system prompt and earlier turns) longer than max_prompt_chars, checked before any
+// tokenization so a huge paste cannot exhaust memory
+fn prompt_length_error(state: &AppState, req: &InferRequest) -> Option<Response> {
+    let limit = state.settings.max_prompt_chars?;
+    let chars = req.prompt.chars().count()
+        + req.system_prompt.as_deref().map_or(0, |s| s.chars().count())
+        + req.messages.iter().map(|turn| turn.content.chars().count()).sum::<usize>();
+    (chars > limit).then(|| {
+        ApiResponse::<()>::error_with_status(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Prompt is {} characters long; the limit is {}.", chars, limit),
+        )
+    })
+}
+
+// Download (if needed), make room in VRAM and load a model, then make it active.
+// Shared by /load_model, the background loader and the startup preload task; `job` is the
+// queued load to report progress on, if any.
+async fn load_model(state: &AppState, name: &str, job: Option<u64>) -> Result<String, String> {
+    // Mark as loading so inference requests can report it instead of a generic error
+    state.loading.lock().await.insert(name.to_string());
+    let result = load_model_inner(state, name, job).await;
+    state.loading.lock().await.remove(name);
+    result
+}
+
+async fn load_model_inner(state: &AppState, name: &str, job: Option<u64>) -> Result<String, String> {
     // Check if model exists in config
     let model_conf = {
         let models_map = &state.settings.models;
-        match models_map.get(&req.name) {
+        match models_map.get(name) {
             Some(c) => c.clone(),
             None => {
-                let error_msg = format!("Model '{}' not found in config.", req.name);
-                return ApiResponse::error(error_msg);
+                let error_msg = format!("Model '{}' not found in config.", name);
+                return Err(error_msg);
             }
         }
     };
+    let spec = model_conf.device_spec().map_err(|e| e.to_string())?;
     // Check if model already loaded
     let models_guard = state.models.lock().await;
-    let model_entry = models_guard.get(&req.name).unwrap();
+    let model_entry = models_guard.get(name).unwrap();
     if model_entry.is_some() {
-        let mut active = state.active_model.lock().await;
-        *active = req.name.clone();
-        let msg = format!("Model '{}' is already loaded.", req.name);
-        return ApiResponse::ok(msg);
+        // As after a fresh load, embedding models are never made active
+        if !model::is_embedding_arch(&model_conf.arch) {
+            let mut active = state.active_model.lock().await;
+            *active = name.to_string();
+        }
+        let msg = format!("Model '{}' is already loaded.", name);
+        return Ok(msg);
+    }
+    // Respect the configured cap on simultaneously loaded models before downloading anything;
+    // plan_evictions checks it again once the files are ready
+    if let Some(max) = state.settings.max_loaded_models {
+        let loaded = models_guard.values().filter(|m| m.is_some()).count();
+        if loaded >= max {
+            return Err(too_many_models_message(name, loaded, max));
+        }
     }
     drop(models_guard); // Release lock so other requests are not blocked
 
     // Download and measure, run in a blocking task to avoid block other requests
-    let name_clone = req.name.clone();
+    let name_clone = name.to_string();
+    let conf_clone = model_conf.clone();
     let file_info_result =
-        task::spawn_blocking(move || get_model_file_info(&name_clone, &model_conf))
+        task::spawn_blocking(move || get_model_file_info(&name_clone, &conf_clone))
             .await
             .unwrap();
 
-    let (_path, required_mb) = match file_info_result {
+    let (_path, estimated_mb) = match file_info_result {
         Ok(info) => info,
         Err(e) => {
             let error_msg = format!("Failed to fetch model info: {}", e);
-            return ApiResponse::error(error_msg);
+            return Err(error_msg);
         }
     };
+    set_job_status(state, job, JobStatus::Loading, None);
 
     // VRAM Check
     let mut models = state.models.lock().await;
     let mut sizes = state.model_sizes.lock().await;
 
-    // Update the size record with actual data
-    sizes.insert(req.name.clone(), required_mb);
-    // Calculate current total VRAM usage
-    let mut current_usage_mb: usize = 0;
-    for (name, instance) in models.iter() {
-        if instance.is_some() {
-            current_usage_mb += sizes.get(name).unwrap_or(&0);
-        }
+    // Update the size record with actual data; a measurement from an earlier load wins
+    let size = sizes.entry(name.to_string()).or_default();
+    size.estimated_mb = estimated_mb;
+    let required_mb = size.effective_mb();
+    // Only models on the same GPU compete for its VRAM; CPU models skip the check
+    let device = place_model(state, name, spec);
+    let vram_index = device.vram_index();
+    let vram_limit = vram_index.map_or(usize::MAX, |index| state.vram_limit(index));
+    // From the authoritative loaded set, so loads that finished during the download count
+    let last_used = state.last_used.lock().await;
+    let resident: Vec<Resident> = models
+        .iter()
+        .filter(|(_, instance)| instance.is_some())
+        .map(|(name, _)| Resident {
+            name: name.clone(),
+            vram_index: model_vram_index(state, name),
+            mb: sizes.get(name).map_or(0, ModelSize::effective_mb),
+            last_used: last_used.get(name).copied().unwrap_or(0),
+        })
+        .collect();
+    drop(last_used);
+    if let Some(index) = vram_index {
+        let current_usage_mb: usize = resident.iter().filter(|r| r.vram_index == Some(index)).map(|r| r.mb).sum();
+        println!(
+            "VRAM Check (GPU {}): Current={}MB, Needed={}MB, Limit={}MB",
+            index,
+            current_usage_mb, 
+            required_mb, 
+            vram_limit
+        );
     }
-    println!(
-        "VRAM Check: Current={}MB, Needed={}MB, Limit={}MB",
-        current_usage_mb, 
-        required_mb, 
-        state.vram_limit
-    );
+    // A chat model becomes the active one; an embedding model leaves it in place, so it must stay
+    let keep = if model::is_embedding_arch(&model_conf.arch) {
+        Some(state.active_model.lock().await.clone())
+    } else {
+        None
+    };
 
-    // Auto unload old models if no enough VRAM
-    while current_usage_mb + required_mb > state.vram_limit {
-        let mut victim = String::new();
-        for (name, instance) in models.iter() {
-            if instance.is_some() {
-                victim = name.clone();
-                break;
-            }
+    // Auto unload old models if no enough VRAM; nothing is unloaded when the model cannot fit anyway
+    let max_loaded = state.settings.max_loaded_models;
+    let victims = match plan_evictions(&resident, vram_index, required_mb, vram_limit, max_loaded, keep.as_deref()) {
+        Ok(victims) => victims,
+        Err(NoRoom::TooManyModels(loaded)) => {
+            return Err(too_many_models_message(name, loaded, max_loaded.unwrap_or(loaded)));
         }
-        // No enough VRAM space for model to be load
-        if victim.is_empty() {
+        Err(NoRoom::TooLarge) => {
             let error_msg = format!(
                 "Model {} ({}MB) is too large for VRAM limit",
-                req.name, 
+                name, 
                 required_mb
             );
-            return ApiResponse::error(error_msg);
+            return Err(error_msg);
         }
-
+    };
+    for victim in victims {
         println!("Auto-unloading: {} to free space", victim);
         if let Some(slot) = models.get_mut(&victim) {
             *slot = None; // Free VRAM
         }
-        current_usage_mb -= sizes.get(&victim).unwrap_or(&0);
     }
 
     // Release locks before the heavy loading to keep the server responsive
     drop(models);
     drop(sizes);
 
-    let name_final = req.name.clone();
+    let name_final = name.to_string();
+    let run_warmup = state.settings.models.get(name)
+        .and_then(|conf| conf.warmup)
+        .or(state.settings.warmup)
+        .unwrap_or(true);
     //println!("Loading weights for {}", name_final);
     // Actual loading
     let load_task = task::spawn_blocking(move || {
-        LoadedModel::load(&name_final)
+        // Measure the real footprint as the drop in free VRAM across the load
+        let free_before = vram_index.and_then(gpu::free_vram_mb);
+        let mut model = LoadedModel::load(&name_final, &model_conf, device)?;
+        // Warm up before the model becomes visible, so the first request is not slow
+        let warmup_ms = if !run_warmup {
+            None
+        } else if model::is_embedding_arch(&model.arch) {
+            Some(embedding::warmup(&model)?.as_millis() as u64)
+        } else {
+            Some(warmup(&mut model)?.as_millis() as u64)
+        };
+        // Measured after warmup so the activation buffers are included too
+        let free_after = vram_index.and_then(gpu::free_vram_mb);
+        let measured_mb = match (free_before, free_after) {
+            (Some(before), Some(after)) if model.device.is_cuda() => {
+                Some(before.saturating_sub(after))
+            }
+            _ => None, // Metal/CPU: keep the estimate
+        };
+        anyhow::Ok((model, measured_mb, warmup_ms))
     });
     let load_result = load_task.await.unwrap();
+    // Files may have been downloaded for it, whether or not the load worked
+    refresh_cache_size(state, hf_hub::Cache::default()).await;
     match load_result {
-        Ok(model) => {
+        Ok((model, measured_mb, warmup_ms)) => {
+            if let Some(measured) = measured_mb {
+                println!(
+                    "Measured VRAM for {}: {}MB (estimated {}MB)",
+                    name, measured, estimated_mb
+                );
+                let mut sizes = state.model_sizes.lock().await;
+                sizes.entry(name.to_string()).or_default().measured_mb = Some(measured);
+            }
+            if state.settings.prefill_chunk_size.is_some() && !infer::batches_at_offset(&model.model) {
+                println!(
+                    "prefill_chunk_size does not apply to '{}' (arch {}): it cannot batch prompt tokens past position 0, so prompts are prefilled in one pass",
+                    name, model.arch
+                );
+            }
             // Re-acquire lock for newly loaded model.
             let mut models = state.models.lock().await;
-            models.insert(req.name.clone(), Some(Arc::new(StdMutex::new(model))));
-            // Set as active model
-            let mut active = state.active_model.lock().await;
-            *active = req.name.clone();
-            println!("Model {} loaded successfully.", req.name);
-            ApiResponse::ok(format!("Model '{}' loaded.", req.name))
+            models.insert(name.to_string(), Some(Arc::new(StdMutex::new(model))));
+            lock(&state.unhealthy).remove(name);
+            touch_model(state, name).await;
+            // Set as active model; embedding models cannot chat, so they are never made active
+            if !model::is_embedding_arch(model_arch(state, name)) {
+                let mut active = state.active_model.lock().await;
+                *active = name.to_string();
+            }
+            println!("Model {} loaded successfully.", name);
+            match warmup_ms {
+                Some(ms) => Ok(format!("Model '{}' loaded (warmup took {} ms).", name, ms)),
+                None => Ok(format!("Model '{}' loaded.", name)),
+            }
         }
-        Err(e) => ApiResponse::error(format!("Failed to load: {}", e)),
+        Err(e) => Err(format!("Failed to load: {}", e)),
     }
 }
 
-// GET /models
-// Return a list with all models, including status and VRAM usage
-async fn list_models(State(state): State<AppState>) -> Json<ModelList> {
-    let models = state.models.lock().await;
-    let sizes = state.model_sizes.lock().await;
-    let active = state.active_model.lock().await;
-    let mut result = HashMap::new();
-    let mut used = 0;
-    for (name, instance) in models.iter() {
-        let is_loaded = instance.is_some();
-        let size = *sizes.get(name).unwrap_or(&0);
-        if is_loaded {
-            used += size;
+// POST /load_model
+// Waits for the load, or with `async` queues it and returns the job to poll at /jobs/:id
+#[utoipa::path(
+    post, path = "/load_model", tag = "models", request_body = LoadModelRequest,
+    responses(
+        (status = 200, description = "Outcome of the load (data is a message), or with `async` the queued job (data is a LoadJob); `status` is \"error\" on failure", body = ApiResponse<LoadJob>),
+    )
+)]
+async fn load_model_handler(
+    State(state): State<AppState>,
+    Json(mut req): Json<LoadModelRequest>,
+) -> Response {
+    req.name = resolve_model_name(&state, &req.name);
+    if req.run_async {
+        if !state.settings.models.contains_key(&req.name) {
+            return ApiResponse::<()>::error(format!("Model '{}' not found in config.", req.name)).into_response();
         }
-        result.insert(
-            name.clone(),
-            ModelStatus {
-                loaded: is_loaded,
-                size_mb: size,
-            },
-        );
+        return ApiResponse::ok(queue_load(&state, req.name)).into_response();
+    }
+    match load_model(&state, &req.name, None).await {
+        Ok(msg) => ApiResponse::ok(msg).into_response(),
+        Err(msg) => ApiResponse::<String>::error(msg).into_response(),
     }
-    Json(ModelList {
-        models: result,
-        active: active.clone(),
-        vram_usage: format!("{}/{} MB", used, state.vram_limit),
-    })
 }
 
-// POST /infer
-// Return full response at once
-async fn infer_handler(
-    State(state): State<AppState>,
-    Json(req): Json<InferRequest>,
-) -> Json<ApiResponse<String>> {
-    // Concurrency Control
-    let _permit = state.semaphore.acquire().await.unwrap();
-    // Check if there is active model
-    let active = state.active_model.lock().await.clone();
-    if active.is_empty() {
-        return ApiResponse::error("Active model not selected.");
+// Finished jobs kept for /jobs/:id; older ones are forgotten first
+const MAX_FINISHED_JOBS: usize = 100;
+
+// Record a pending load of `model` and hand it to the background loader
+fn queue_load(state: &AppState, model: String) -> LoadJob {
+    let job = LoadJob {
+        id: state.next_job_id.fetch_add(1, Ordering::SeqCst),
+        model,
+        status: JobStatus::Pending,
+        message: None,
+    };
+    let mut jobs = lock(&state.jobs);
+    jobs.insert(job.id, job.clone());
+    let finished: Vec<u64> = jobs.values().filter(|job| job.status.is_finished()).map(|job| job.id).collect();
+    for id in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_JOBS)) {
+        jobs.remove(id);
     }
-    let models = state.models.lock().await;
-    // Clone the Arc to the model
-    let model_arc = match models.get(&active) {
-        Some(Some(m)) => m.clone(),
-        _ => return ApiResponse::error("Model not found or not loaded."),
+    drop(jobs);
+    // The receiver lives as long as the server
+    let _ = state.load_queue.send(job.id);
+    job
+}
+
+// Update a queued load; no-op for loads that are not jobs
+fn set_job_status(state: &AppState, job: Option<u64>, status: JobStatus, message: Option<String>) {
+    let Some(id) = job else { return };
+    if let Some(job) = lock(&state.jobs).get_mut(&id) {
+        job.status = status;
+        job.message = message;
+    }
+}
+
+// Background loader: runs queued loads one after another, so concurrent requests do not
+// race each other for VRAM
+async fn load_worker(state: AppState, mut queue: mpsc::UnboundedReceiver<u64>) {
+    while let Some(id) = queue.recv().await {
+        let Some(model) = lock(&state.jobs).get(&id).map(|job| job.model.clone()) else {
+            continue;
+        };
+        set_job_status(&state, Some(id), JobStatus::Downloading, None);
+        match load_model(&state, &model, Some(id)).await {
+            Ok(msg) => set_job_status(&state, Some(id), JobStatus::Ready, Some(msg)),
+            Err(msg) => set_job_status(&state, Some(id), JobStatus::Failed, Some(msg)),
+        }
+    }
+}
+
+// GET /jobs/:id
+#[utoipa::path(
+    get, path = "/jobs/{id}", tag = "models",
+    params(("id" = u64, Path, description = "Job id from an async /load_model")),
+    responses(
+        (status = 200, description = "Progress of the load", body = ApiResponse<LoadJob>),
+        (status = 404, description = "Unknown or forgotten job", body = ApiResponse<String>),
+    )
+)]
+async fn job_handler(State(state): State<AppState>, Path(id): Path<u64>) -> Response {
+    match lock(&state.jobs).get(&id) {
+        Some(job) => ApiResponse::ok(job.clone()).into_response(),
+        None => ApiResponse::<()>::error_with_status(StatusCode::NOT_FOUND, format!("Job {} not found.", id)),
+    }
+}
+
+// Tokenizer of a configured model, read from its tokenizer file (downloaded if needed) on first
+// use and cached. This never touches the model itself, so it works while a generation runs.
+async fn model_tokenizer(state: &AppState, name: Option<String>) -> Result<Arc<Tokenizer>, Response> {
+    let name = match name {
+        Some(name) => resolve_model_name(state, &name),
+        None => state.active_model.lock().await.clone(),
     };
-    drop(models); // Release lock
-    // Apply template to input so that it match model's standard input
-    let prompt = apply_chat_template(&active, &req.prompt, req.system_prompt.clone());
-    let params = InferenceParams {
-        temperature: req.temperature,
-        top_p: req.top_p,
-        max_tokens: req.max_tokens,
-        seed: req.seed,
+    let Some(model_conf) = state.settings.models.get(&name).cloned() else {
+        return Err(ApiResponse::<()>::error_with_status(
+            StatusCode::NOT_FOUND,
+            format!("Model '{}' not found in config.", name),
+        ));
     };
-    // Run inference
+    if let Some(tokenizer) = lock(&state.tokenizers).get(&name) {
+        return Ok(tokenizer.clone());
+    }
     let result = task::spawn_blocking(move || {
-        let mut model = model_arc.lock().unwrap();
-        let mut output = String::new();
-        // The callback appends token to string buffer
-        let _ = run_inference(
-            &mut *model, 
-            &prompt, 
-            params, 
-            |t| output.push_str(&t)
-        );
-        output
+        let path = model::resolve_tokenizer_path(&model_conf)?;
+        Tokenizer::from_file(path).map_err(anyhow::Error::msg)
     })
     .await
     .unwrap();
-    ApiResponse::ok(format!("[Model: {}] {}", active, result))
+    match result {
+        Ok(tokenizer) => {
+            let tokenizer = Arc::new(tokenizer);
+            lock(&state.tokenizers).insert(name, tokenizer.clone());
+            Ok(tokenizer)
+        }
+        Err(e) => Err(ApiResponse::<()>::error_with_status(
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to load the tokenizer of '{}': {}", name, e),
+        )),
+    }
 }
 
-// POST /infer_stream
-// Return response using SSE which means token by token
-async fn infer_stream_handler(State(state): State<AppState>, Json(req): Json<InferRequest>) -> Sse<impl tokio_stream::Stream<Item = Result<Event, std::convert::Infallible>>> {
-    // Channel for tokens
-    let (tx, rx) = mpsc::channel(100);
-    task::spawn(async move {
-        // Concurrency Control
-        let permit = state.semaphore.clone().acquire_owned().await.unwrap();
-        let active_guard = state.active_model.lock().await;
-        let active = active_guard.clone();
-        drop(active_guard);
-        
-        // Check if there is active model
-        if active.is_empty() {
-            let error_msg = "Active model not selected.";
-            let _ = tx.send(error_msg.into()).await;
-            return;
+// POST /tokenize
+// Token ids of a text, for budgeting prompts
+#[utoipa::path(
+    post, path = "/tokenize", tag = "inference", request_body = TokenizeRequest,
+    responses(
+        (status = 200, description = "Token ids and count", body = ApiResponse<TokenizeResponse>),
+        (status = 404, description = "Model not in config.toml", body = ApiResponse<String>),
+        (status = 422, description = "The text could not be tokenized", body = ApiResponse<String>),
+        (status = 502, description = "The tokenizer could not be fetched", body = ApiResponse<String>),
+    )
+)]
+async fn tokenize_handler(State(state): State<AppState>, Json(req): Json<TokenizeRequest>) -> Response {
+    let tokenizer = match model_tokenizer(&state, req.model).await {
+        Ok(tokenizer) => tokenizer,
+        Err(response) => return response,
+    };
+    match tokenizer.encode(req.text, req.add_special_tokens) {
+        Ok(encoding) => {
+            let tokens = encoding.get_ids().to_vec();
+            ApiResponse::ok(TokenizeResponse { count: tokens.len(), tokens }).into_response()
         }
-        let models_guard = state.models.lock().await;
-        let model_arc_option = models_guard.get(&active);
-        let model_arc = match model_arc_option {
-            Some(Some(m)) => m.clone(),
-            _ => {
-                let error_msg = "Model not found or not loaded.";
-                let _ = tx.send(error_msg.into()).await;
-                return;
-            }
+        Err(e) => ApiResponse::<()>::error_with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Failed to tokenize text: {}", e),
+        ),
+    }
+}
+
+// POST /detokenize
+// Text of a list of token ids
+#[utoipa::path(
+    post, path = "/detokenize", tag = "inference", request_body = DetokenizeRequest,
+    responses(
+        (status = 200, description = "Decoded text", body = ApiResponse<DetokenizeResponse>),
+        (status = 404, description = "Model not in config.toml", body = ApiResponse<String>),
+        (status = 422, description = "Unknown token ids", body = ApiResponse<String>),
+        (status = 502, description = "The tokenizer could not be fetched", body = ApiResponse<String>),
+    )
+)]
+async fn detokenize_handler(State(state): State<AppState>, Json(req): Json<DetokenizeRequest>) -> Response {
+    let tokenizer = match model_tokenizer(&state, req.model).await {
+        Ok(tokenizer) => tokenizer,
+        Err(response) => return response,
+    };
+    match tokenizer.decode(&req.tokens, req.skip_special_tokens) {
+        Ok(text) => ApiResponse::ok(DetokenizeResponse { text }).into_response(),
+        Err(e) => ApiResponse::<()>::error_with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Failed to detokenize: {}", e),
+        ),
+    }
+}
+
+// GET /models/:name
+// Config entry of a model plus, if its GGUF is on disk, facts read from the file header
+#[utoipa::path(
+    get, path = "/models/{name}", tag = "models",
+    params(("name" = String, Path, description = "Configured model name")),
+    responses(
+        (status = 200, description = "Config entry and, if on disk, GGUF header facts", body = ApiResponse<ModelDetails>),
+        (status = 404, description = "Model not in config.toml", body = ApiResponse<String>),
+        (status = 500, description = "The file could not be read", body = ApiResponse<String>),
+    )
+)]
+async fn model_details_handler(State(state): State<AppState>, Path(name): Path<String>) -> Response {
+    let name = resolve_model_name(&state, &name);
+    let Some(model_conf) = state.settings.models.get(&name).cloned() else {
+        return ApiResponse::<()>::error_with_status(
+            StatusCode::NOT_FOUND,
+            format!("Model '{}' not found in config.", name),
+        );
+    };
+    let result = task::spawn_blocking(move || {
+        let Some(path) = model::cached_weights_path(&model_conf) else {
+            return anyhow::Ok(ModelDetails { name, config: model_conf, cached: false, file_size_mb: None, gguf: None });
         };
-        drop(models_guard);// Release lock
-        
-        let _permit = permit;
-        let prompt = apply_chat_template(&active, &req.prompt, req.system_prompt.clone());
-        let params = InferenceParams { 
-            temperature: req.temperature, 
-            top_p: req.top_p, 
-            max_tokens: req.max_tokens, 
-            seed: req.seed 
+        let file_size_mb = (std::fs::metadata(&path)?.len() / 1024 / 1024) as usize;
+        // Embedding models are safetensors, which have no GGUF header
+        let gguf = if model::is_embedding_arch(&model_conf.arch) {
+            None
+        } else {
+            Some(model::read_gguf_info(&path)?)
         };
-        let tx_clone = tx.clone();
-        
-        // Run inference
-        let handle = task::spawn_blocking(move || {
-            let _ = tx_clone.blocking_send(format!("[MODEL: {}]", active));   
-            // when there is a stop signal from frontend,
-            // the mutex becomes poisoned. Ignore the poison state and forcibly acquire lock
-            let mut model = model_arc.lock().unwrap_or_else(|e| e.into_inner());
-
-            let res = run_inference(
-                &mut *model, 
-                &prompt, 
+        anyhow::Ok(ModelDetails { name, config: model_conf, cached: true, file_size_mb: Some(file_size_mb), gguf })
+    })
+    .await
+    .unwrap();
+    match result {
+        Ok(details) => ApiResponse::ok(details).into_response(),
+        Err(e) => ApiResponse::<()>::error_with_status(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read the model file: {}", e),
+        ),
+    }
+}
+
+// Cached files of every configured model that has any; reads file metadata only
+fn cache_listing(settings: &Settings, hub: &hf_hub::Cache) -> CacheListing {
+    let mut seen = HashSet::new();
+    let mut total_bytes = 0;
+    let mut models = Vec::new();
+    for name in settings.model_names() {
+        let files = model::cached_files(&settings.models[&name], hub);
+        if files.is_empty() {
+            continue;
+        }
+        for file in &files {
+            if seen.insert(file.path.clone()) {
+                total_bytes += file.size_bytes;
+            }
+        }
+        let size_bytes: u64 = files.iter().map(|file| file.size_bytes).sum();
+        models.push(CachedModel { name, size_mb: size_bytes / 1024 / 1024, files });
+    }
+    CacheListing { models, total_size_mb: total_bytes / 1024 / 1024 }
+}
+
+// Recompute the disk space of the configured models' cached files. Run at startup and after
+// every download or deletion, so /health and /stats report it without walking the cache.
+async fn refresh_cache_size(state: &AppState, hub: hf_hub::Cache) {
+    let settings = state.settings.clone();
+    let size_mb = task::spawn_blocking(move || cache_listing(&settings, &hub).total_size_mb)
+        .await
+        .unwrap_or_default();
+    state.cache_size_mb.store(size_mb, Ordering::Relaxed);
+}
+
+// Cached files of `name` split into the ones to delete and the ones another configured model
+// also uses (a shared tokenizer, or the same weights under two names), which are kept
+fn cache_deletion_plan(settings: &Settings, name: &str, hub: &hf_hub::Cache) -> (Vec<model::CachedFile>, Vec<model::CachedFile>) {
+    let in_use: HashSet<String> = settings
+        .models
+        .iter()
+        .filter(|(other, _)| other.as_str() != name)
+        .flat_map(|(_, conf)| model::cached_files(conf, hub))
+        .map(|file| file.path)
+        .collect();
+    model::cached_files(&settings.models[name], hub)
+        .into_iter()
+        .partition(|file| !in_use.contains(&file.path))
+}
+
+// GET /cache
+// Downloaded weight, tokenizer and config files of the configured models
+#[utoipa::path(
+    get, path = "/cache", tag = "models",
+    responses((status = 200, description = "Downloaded files of the configured models", body = ApiResponse<CacheListing>))
+)]
+async fn cache_handler(State(state): State<AppState>) -> Json<ApiResponse<CacheListing>> {
+    let settings = state.settings.clone();
+    let listing = task::spawn_blocking(move || cache_listing(&settings, &hf_hub::Cache::default())).await.unwrap();
+    ApiResponse::ok(listing)
+}
+
+// DELETE /cache/responses
+// Forget every cached response, e.g. after changing a model's files; hit and miss counts are kept
+#[utoipa::path(
+    delete, path = "/cache/responses", tag = "inference",
+    responses(
+        (status = 200, description = "Number of responses dropped", body = ApiResponse<String>),
+        (status = 404, description = "response_cache_size is not set", body = ApiResponse<String>),
+    )
+)]
+async fn clear_response_cache_handler(State(state): State<AppState>) -> Response {
+    let Some(cache) = &state.response_cache else {
+        return ApiResponse::<()>::error_with_status(
+            StatusCode::NOT_FOUND,
+            "Response cache is disabled (set response_cache_size in config.toml).",
+        );
+    };
+    let cleared = lock(cache).clear();
+    ApiResponse::ok(format!("Cleared {} cached responses.", cleared)).into_response()
+}
+
+// DELETE /cache/:name
+// Remove a model's downloaded files; the next load downloads them again
+#[utoipa::path(
+    delete, path = "/cache/{name}", tag = "models",
+    params(("name" = String, Path, description = "Configured model name")),
+    responses(
+        (status = 200, description = "Deleted files", body = ApiResponse<CacheDeletion>),
+        (status = 404, description = "Model not in config.toml", body = ApiResponse<String>),
+        (status = 409, description = "The model is loaded or loading", body = ApiResponse<String>),
+        (status = 500, description = "A file could not be deleted", body = ApiResponse<String>),
+    )
+)]
+async fn delete_cache_handler(State(state): State<AppState>, Path(name): Path<String>) -> Response {
+    let name = resolve_model_name(&state, &name);
+    if !state.settings.models.contains_key(&name) {
+        return ApiResponse::<()>::error_with_status(
+            StatusCode::NOT_FOUND,
+            format!("Model '{}' not found in config.", name),
+        );
+    }
+    // The locks are held only while checking and collecting the paths. A load that starts
+    // during the deletion downloads whatever it finds missing.
+    let (files, kept) = {
+        let loading = state.loading.lock().await;
+        let models = state.models.lock().await;
+        if loading.contains(&name) || models.get(&name).is_some_and(Option::is_some) {
+            return ApiResponse::<()>::error_with_status(
+                StatusCode::CONFLICT,
+                format!("Model '{}' is loaded; unload it before deleting its files.", name),
+            );
+        }
+        let settings = state.settings.clone();
+        let plan_name = name.clone();
+        task::spawn_blocking(move || cache_deletion_plan(&settings, &plan_name, &hf_hub::Cache::default()))
+            .await
+            .unwrap()
+    };
+    let result = task::spawn_blocking(move || {
+        for file in &files {
+            model::remove_download(std::path::Path::new(&file.path))?;
+        }
+        anyhow::Ok(files)
+    })
+    .await
+    .unwrap();
+    refresh_cache_size(&state, hf_hub::Cache::default()).await;
+    match result {
+        Ok(files) => {
+            println!("Deleted {} cached file(s) of '{}', kept {} shared", files.len(), name, kept.len());
+            let freed: u64 = files.iter().map(|file| file.size_bytes).sum();
+            ApiResponse::ok(CacheDeletion {
+                deleted: files.into_iter().map(|file| file.path).collect(),
+                kept: kept.into_iter().map(|file| file.path).collect(),
+                freed_mb: freed / 1024 / 1024,
+            })
+            .into_response()
+        }
+        Err(e) => ApiResponse::<()>::error_with_status(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to delete the files of '{}': {}", name, e),
+        ),
+    }
+}
+
+// Embedding models are loaded like any model but only serve /embeddings
+fn embedding_model_message(name: &str) -> String {
+    format!("Model '{}' is an embedding model; use /embeddings.", name)
+}
+
+// POST /embeddings
+// Normalized sentence embeddings from a loaded embedding model
+#[utoipa::path(
+    post, path = "/embeddings", tag = "inference", request_body = EmbeddingRequest,
+    responses(
+        (status = 200, description = "L2-normalized embeddings in OpenAI's format", body = EmbeddingList),
+        (status = 404, description = "Model not loaded", body = ApiResponse<String>),
+        (status = 422, description = "Not an embedding model, or empty input", body = ApiResponse<String>),
+        (status = 503, description = "Shutting down", body = ApiResponse<String>),
+    )
+)]
+async fn embeddings_handler(State(state): State<AppState>, Json(mut req): Json<EmbeddingRequest>) -> Response {
+    req.model = resolve_model_name(&state, &req.model);
+    let Some(_in_flight) = InFlightGuard::enter(&state) else {
+        return shutting_down_response();
+    };
+    let inputs = match req.input {
+        EmbeddingInput::One(text) => vec![text],
+        EmbeddingInput::Many(texts) => texts,
+    };
+    if inputs.is_empty() {
+        return ApiResponse::<()>::error_with_status(StatusCode::UNPROCESSABLE_ENTITY, "input must not be empty.");
+    }
+    if !state.settings.models.contains_key(&req.model) {
+        return ApiResponse::<()>::error_with_status(
+            StatusCode::NOT_FOUND,
+            format!("Model '{}' not found in config.", req.model),
+        );
+    }
+    if !model::is_embedding_arch(model_arch(&state, &req.model)) {
+        return ApiResponse::<()>::error_with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Model '{}' is not an embedding model.", req.model),
+        );
+    }
+    let model_arc = match state.models.lock().await.get(&req.model) {
+        Some(Some(m)) => m.clone(),
+        _ => {
+            return ApiResponse::<()>::error_with_status(
+                StatusCode::NOT_FOUND,
+                format!("Model '{}' is not loaded.", req.model),
+            );
+        }
+    };
+    touch_model(&state, &req.model).await;
+    // Shares the generation slot: the GPU does one thing at a time
+    let (_permit, _) = acquire_permit(&state).await;
+    let batch_size = state.settings.embedding_batch_size.unwrap_or(embedding::DEFAULT_EMBEDDING_BATCH_SIZE);
+    let result = task::spawn_blocking(move || {
+        let model = lock_model(&model_arc);
+        embedding::embed(&model, &inputs, batch_size)
+    })
+    .await
+    .unwrap();
+    match result {
+        Ok((embeddings, tokens)) => Json(EmbeddingList {
+            object: "list",
+            data: embeddings
+                .into_iter()
+                .enumerate()
+                .map(|(index, embedding)| EmbeddingData { object: "embedding", index, embedding })
+                .collect(),
+            model: req.model,
+            usage: EmbeddingUsage { prompt_tokens: tokens, total_tokens: tokens },
+        })
+        .into_response(),
+        Err(e) => ApiResponse::<()>::error_with_status(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Embedding failed: {}", e),
+        ),
+    }
+}
+
+// POST /v1/embeddings
+// /embeddings at the path OpenAI client libraries use
+#[utoipa::path(
+    post, path = "/v1/embeddings", tag = "openai", request_body = EmbeddingRequest,
+    responses(
+        (status = 200, description = "L2-normalized embeddings", body = EmbeddingList),
+        (status = 404, description = "Model not loaded", body = ApiResponse<String>),
+        (status = 422, description = "Not an embedding model, or empty input", body = ApiResponse<String>),
+        (status = 503, description = "Shutting down", body = ApiResponse<String>),
+    )
+)]
+async fn openai_embeddings_handler(state: State<AppState>, req: Json<EmbeddingRequest>) -> Response {
+    embeddings_handler(state, req).await
+}
+
+// POST /benchmark (only when enable_benchmark is set)
+// Time synthetic generations on a loaded model. With admin_api_key set, only admins may run it.
+#[utoipa::path(
+    post, path = "/benchmark", tag = "server", request_body = BenchmarkRequest,
+    security((), ("admin_api_key" = [])),
+    responses(
+        (status = 200, description = "Per-run timings and their summary (only routed when enable_benchmark = true)", body = ApiResponse<BenchmarkReport>),
+        (status = 401, description = "admin_api_key is set and the request does not carry it", body = ApiResponse<String>),
+        (status = 404, description = "Model not loaded", body = ApiResponse<String>),
+        (status = 422, description = "Bad parameters", body = ApiResponse<String>),
+        (status = 500, description = "A run failed", body = ApiResponse<String>),
+        (status = 503, description = "Shutting down", body = ApiResponse<String>),
+    )
+)]
+async fn benchmark_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<BenchmarkRequest>,
+) -> Response {
+    if let Some(admin_key) = &state.settings.admin_api_key
+        && !is_admin(&headers, admin_key)
+    {
+        return ApiResponse::<()>::error_with_status(StatusCode::UNAUTHORIZED, "Admin API key required.");
+    }
+    let Some(_in_flight) = InFlightGuard::enter(&state) else {
+        return shutting_down_response();
+    };
+    let name = match req.model {
+        Some(name) => resolve_model_name(&state, &name),
+        None => state.active_model.lock().await.clone(),
+    };
+    let model_arc = match state.models.lock().await.get(&name) {
+        Some(Some(m)) => m.clone(),
+        _ => {
+            return ApiResponse::<()>::error_with_status(
+                StatusCode::NOT_FOUND,
+                format!("Model '{}' is not loaded.", name),
+            );
+        }
+    };
+    let max_context = state.settings.models.get(&name)
+        .and_then(|c| c.max_context)
+        .unwrap_or_else(|| model::default_max_context(model_arch(&state, &name)));
+    let runs = req.runs.unwrap_or(3);
+    let prompt_tokens = req.prompt_tokens.unwrap_or(benchmark::DEFAULT_PROMPT_TOKENS);
+    let gen_tokens = req.gen_tokens.unwrap_or(benchmark::DEFAULT_GEN_TOKENS);
+    let invalid = if prompt_tokens == 0 || gen_tokens < 2 {
+        Some("prompt_tokens must be at least 1 and gen_tokens at least 2.".to_string())
+    } else if prompt_tokens + gen_tokens > max_context {
+        Some(format!("prompt_tokens + gen_tokens exceeds the model's context of {} tokens.", max_context))
+    } else if runs == 0 || runs > MAX_BENCHMARK_RUNS {
+        Some(format!("runs must be between 1 and {}.", MAX_BENCHMARK_RUNS))
+    } else {
+        None
+    };
+    if let Some(message) = invalid {
+        return ApiResponse::<()>::error_with_status(StatusCode::UNPROCESSABLE_ENTITY, message);
+    }
+
+    let vram_index = model_vram_index(&state, &name);
+    let mut results = Vec::with_capacity(runs);
+    for _ in 0..runs {
+        // Queue like any request, one run at a time, so real requests can get in between runs
+        let (_permit, _) = acquire_permit(&state).await;
+        let model_arc = model_arc.clone();
+        let result = task::spawn_blocking(move || {
+            let mut model = lock_model(&model_arc);
+            benchmark::run(&mut model, prompt_tokens, gen_tokens, vram_index)
+        })
+        .await
+        .unwrap();
+        match result {
+            Ok(run) => results.push(run),
+            Err(e) => {
+                return ApiResponse::<()>::error_with_status(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Benchmark failed: {}", e),
+                );
+            }
+        }
+    }
+    let metric = |f: fn(&BenchmarkRun) -> f64| benchmark::summarize(&results.iter().map(f).collect::<Vec<_>>());
+    ApiResponse::ok(BenchmarkReport {
+        model: name,
+        prompt_tokens,
+        gen_tokens,
+        ttft_ms: metric(|run| run.ttft_ms),
+        prefill_tokens_per_sec: metric(|run| run.prefill_tokens_per_sec),
+        decode_tokens_per_sec: metric(|run| run.decode_tokens_per_sec),
+        peak_gpu_used_mb: results.iter().filter_map(|run| run.gpu_used_mb).max(),
+        runs: results,
+    })
+    .into_response()
+}
+
+// POST /models/:name/download
+// Fetch weights and tokenizer into the HF cache without loading anything into VRAM
+#[utoipa::path(
+    post, path = "/models/{name}/download", tag = "models",
+    params(("name" = String, Path, description = "Configured model name")),
+    responses(
+        (status = 200, description = "Paths of the downloaded (and verified) files", body = ApiResponse<DownloadInfo>),
+        (status = 404, description = "Model not in config.toml", body = ApiResponse<String>),
+        (status = 502, description = "Download or verification failed", body = ApiResponse<String>),
+    )
+)]
+async fn download_model_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Response {
+    let name = resolve_model_name(&state, &name);
+    let Some(model_conf) = state.settings.models.get(&name).cloned() else {
+        return ApiResponse::<()>::error_with_status(
+            StatusCode::NOT_FOUND,
+            format!("Model '{}' not found in config.", name),
+        );
+    };
+    let name_clone = name.clone();
+    let result = task::spawn_blocking(move || {
+        let (path, estimated_mb) = get_model_file_info(&name_clone, &model_conf)?;
+        let tokenizer_path = model::resolve_tokenizer_path(&model_conf)?;
+        anyhow::Ok((path, tokenizer_path, estimated_mb))
+    })
+    .await
+    .unwrap();
+    refresh_cache_size(&state, hf_hub::Cache::default()).await;
+    match result {
+        Ok((path, tokenizer_path, estimated_mb)) => {
+            // The estimate is known now, so /models can report it before the first load
+            let mut sizes = state.model_sizes.lock().await;
+            sizes.entry(name.clone()).or_default().estimated_mb = estimated_mb;
+            println!("Downloaded '{}' to {}", name, path.display());
+            ApiResponse::ok(DownloadInfo {
+                path: path.display().to_string(),
+                tokenizer_path: tokenizer_path.display().to_string(),
+                estimated_mb,
+            })
+            .into_response()
+        }
+        Err(e) => ApiResponse::<()>::error_with_status(
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to download model '{}': {}", name, e),
+        ),
+    }
+}
+
+// Load the configured default/preload models in the background at startup.
+// The server keeps serving requests meanwhile; inference reports "still loading".
+async fn preload_models(state: AppState) {
+    let default_model = state.settings.default_model.clone();
+    let mut names: Vec<String> = state.settings.model_names()
+        .into_iter()
+        .filter(|name| state.settings.models[name].preload)
+        .filter(|name| Some(name) != default_model.as_ref())
+        .collect();
+    // Load the default last so it ends up as the active model
+    if let Some(name) = default_model {
+        names.push(name);
+    }
+    for name in names {
+        println!("Preloading model '{}'...", name);
+        match load_model(&state, &name, None).await {
+            Ok(msg) => println!("Preload: {}", msg),
+            Err(e) => println!("Preload of '{}' failed: {}", name, e),
+        }
+    }
+}
+
+// GET /v1/models
+// Configured models in the OpenAI format, so OpenAI client libraries can enumerate them
+#[utoipa::path(
+    get, path = "/v1/models", tag = "openai",
+    responses((status = 200, description = "Configured models in the OpenAI format", body = OpenAiModelList))
+)]
+async fn openai_models_handler(State(state): State<AppState>) -> Json<OpenAiModelList> {
+    // Aliases are listed too: OpenAI clients pick a model by one of these ids
+    let data = state.settings.model_names()
+        .into_iter()
+        .flat_map(|name| {
+            let aliases = state.settings.models[&name].aliases.clone();
+            std::iter::once(name).chain(aliases)
+        })
+        .map(|id| OpenAiModel {
+            id,
+            object: "model",
+            created: state.started_at,
+            owned_by: "local",
+        })
+        .collect();
+    Json(OpenAiModelList { object: "list", data })
+}
+
+// GET /models
+// Return a list with all models, including status and VRAM usage
+#[utoipa::path(
+    get, path = "/models", tag = "models",
+    responses((status = 200, description = "Configured models with load state and VRAM usage", body = ModelList))
+)]
+async fn list_models(State(state): State<AppState>) -> Json<ModelList> {
+    let models = state.models.lock().await;
+    let sizes = state.model_sizes.lock().await;
+    let active = state.active_model.lock().await;
+    let last_used = state.last_used.lock().await;
+    let unhealthy = lock(&state.unhealthy).clone();
+    let now = now_secs();
+    let mut result = HashMap::new();
+    let mut used: BTreeMap<usize, usize> = state.vram_limits.keys().map(|&i| (i, 0)).collect();
+    for (name, instance) in models.iter() {
+        let is_loaded = instance.is_some();
+        let size = sizes.get(name).copied().unwrap_or_default();
+        if is_loaded && let Some(index) = model_vram_index(&state, name) {
+            *used.entry(index).or_default() += size.effective_mb();
+        }
+        let size_source = if size.measured_mb.is_some() { "measured" } else { "estimated" };
+        let idle_secs = last_used.get(name).filter(|_| is_loaded).map(|&used| now.saturating_sub(used));
+        let idle_unload_in_secs = idle_secs.and_then(|idle| {
+            idle_threshold(&state, name, &active).map(|threshold| threshold.saturating_sub(idle))
+        });
+        result.insert(
+            name.clone(),
+            ModelStatus {
+                loaded: is_loaded,
+                size_mb: size.effective_mb(),
+                estimated_mb: size.estimated_mb,
+                measured_mb: size.measured_mb,
+                size_source: size_source.to_string(),
+                device: model_device(&state, name).to_string(),
+                last_used: last_used.get(name).copied(),
+                idle_secs,
+                idle_unload_in_secs,
+                unhealthy: unhealthy.get(name).cloned(),
+                aliases: state.settings.models.get(name).map(|c| c.aliases.clone()).unwrap_or_default(),
+            },
+        );
+    }
+    Json(ModelList {
+        models: result,
+        active: active.clone(),
+        vram_usage: used
+            .into_iter()
+            .map(|(index, used)| (index, format!("{}/{} MB", used, state.vram_limit(index))))
+            .collect(),
+    })
+}
+
+// Error text for inference without an active model; mentions models still loading
+async fn no_active_model_message(state: &AppState) -> String {
+    let loading = state.loading.lock().await;
+    if loading.is_empty() {
+        return "Active model not selected.".to_string();
+    }
+    let mut names: Vec<&String> = loading.iter().collect();
+    names.sort();
+    format!("Model loading: {:?}. Try again shortly.", names)
+}
+
+// GET /stats
+// Queue depth and average queue/generation time, to judge latency under load, plus the GPUs
+// and the disk space of the downloaded models
+#[utoipa::path(
+    get, path = "/stats", tag = "server",
+    responses((status = 200, description = "Queue depth, request timings, GPUs and cache size", body = StatsResponse))
+)]
+async fn stats_handler(State(state): State<AppState>) -> Json<StatsResponse> {
+    // Re-probe so free memory is current. This runs NVML (or nvidia-smi), which is why it lives
+    // here and not in the frequently polled /health.
+    let fraction = unified_memory_fraction(&state.settings);
+    let devices = task::spawn_blocking(move || gpu::detect_gpus(fraction))
+        .await
+        .unwrap_or_default();
+    let stats = lock(&state.stats);
+    let average = |total: u64| total.checked_div(stats.completed).unwrap_or(0);
+    Json(StatsResponse {
+        queue_depth: state.queue_depth.load(Ordering::SeqCst),
+        in_flight: state.in_flight.load(Ordering::SeqCst),
+        max_concurrency: state.max_concurrency,
+        completed: stats.completed,
+        avg_queue_ms: average(stats.total_queue_ms),
+        avg_generate_ms: average(stats.total_generate_ms),
+        response_cache: state.response_cache.as_ref().map(|cache| lock(cache).stats()),
+        devices,
+        cache_size_mb: state.cache_size_mb.load(Ordering::Relaxed),
+    })
+}
+
+// GET /health
+// Report that the server is up, plus any models still loading and the GPUs found at startup.
+// Polled by the frontend, so it only reads in-memory state.
+#[utoipa::path(
+    get, path = "/health", tag = "server",
+    responses((status = 200, description = "Server status, active model, models loading and detected GPUs", body = HealthStatus))
+)]
+async fn health_handler(State(state): State<AppState>) -> Json<HealthStatus> {
+    let active = state.active_model.lock().await.clone();
+    let mut loading: Vec<String> = state.loading.lock().await.iter().cloned().collect();
+    loading.sort();
+    Json(HealthStatus {
+        status: "ok".to_string(),
+        active,
+        loading,
+        max_prompt_chars: state.settings.max_prompt_chars,
+        devices: state.devices.to_vec(),
+        cache_size_mb: state.cache_size_mb.load(Ordering::Relaxed),
+    })
+}
+
+// Build generation parameters for a request. Missing fields fall back to the
+// model's configured defaults; run_inference applies global defaults after that.
+fn build_params(state: &AppState, model_name: &str, req: &InferRequest) -> InferenceParams {
+    let model_conf = state.settings.models.get(model_name);
+    let mut max_tokens = req.max_tokens.or(model_conf.and_then(|c| c.default_max_tokens));
+    // Hard cap so a client cannot ask for an enormous generation; it also bounds "until the
+    // context is full"
+    if let Some(limit) = state.settings.max_tokens_limit {
+        match max_tokens {
+            Some(requested) if requested > limit => {
+                println!("Clamping max_tokens from {} to max_tokens_limit {}", requested, limit);
+                max_tokens = Some(limit);
+            }
+            Some(_) => {}
+            None => max_tokens = Some(limit),
+        }
+    }
+    // A request may shorten the server's timeout but not extend it
+    let server_timeout = state.settings.generation_timeout_secs;
+    let timeout_secs = match (req.timeout_secs, server_timeout) {
+        (Some(requested), Some(limit)) => Some(requested.min(limit)),
+        (requested, limit) => requested.or(limit),
+    };
+    InferenceParams {
+        temperature: req.temperature.or(model_conf.and_then(|c| c.default_temperature)),
+        top_p: req.top_p.or(model_conf.and_then(|c| c.default_top_p)),
+        max_tokens,
+        max_context: model_conf.and_then(|c| c.max_context),
+        seed: req.seed,
+        timeout_secs,
+        shutdown: Some(state.shutdown_cancel.clone()),
+        cancel: None,
+        mirostat_tau: req.mirostat_tau,
+        mirostat_eta: req.mirostat_eta,
+        reuse_prefix: model_conf.and_then(|c| c.prefix_cache)
+            .or(state.settings.prefix_cache)
+            .unwrap_or(false),
+        prefill_chunk_size: state.settings.prefill_chunk_size,
+        logit_bias: req.logit_bias.clone(),
+        banned_strings: req.banned_strings.clone(),
+        no_repeat_ngram_size: req.no_repeat_ngram_size,
+        repeat_last_n: req.repeat_last_n,
+        logprobs: req.logprobs,
+    }
+}
+
+// Configured name of the model a request named, which may be one of its aliases
+fn resolve_model_name(state: &AppState, name: &str) -> String {
+    state.settings.canonical_name(name).to_string()
+}
+
+// Architecture of a configured model, which picks its chat template
+fn model_arch<'a>(state: &'a AppState, model_name: &str) -> &'a str {
+    state.settings.models.get(model_name).map_or("", |c| c.arch.as_str())
+}
+
+// How to template a request's prompt and fit it into the model's context window
+struct PromptFit {
+    arch: String,
+    system_prompt: Option<String>,
+    history: Vec<ChatTurn>, // Earlier turns of a multi-turn request
+    max_context: Option<usize>, // From config.toml; otherwise the loaded model decides
+    max_tokens: Option<usize>, // Reserved for generation; None needs only room for one token
+    strategy: ContextStrategy,
+}
+impl PromptFit {
+    fn new(state: &AppState, model_name: &str, req: &InferRequest, params: &InferenceParams) -> Self {
+        Self {
+            arch: model_arch(state, model_name).to_string(),
+            // An empty system_prompt in the request means none, not the model's default
+            system_prompt: req.system_prompt.clone().or_else(|| {
+                state.settings.models.get(model_name).and_then(|c| c.default_system_prompt.clone())
+            }),
+            history: req.messages.clone(),
+            max_context: state.settings.models.get(model_name).and_then(|c| c.max_context),
+            max_tokens: params.max_tokens,
+            strategy: state.settings.context_strategy.unwrap_or_default(),
+        }
+    }
+    // Templated prompt, shortened per the strategy if needed, and the number of tokens dropped.
+    // The model's GGUF template is used when it has one and it accepts the messages
+    // (some reject a system turn); otherwise the built-in template for the arch.
+    // Control tokens in the user's prompt and history are removed first so they cannot forge turns.
+    // A conversation that does not fit loses its oldest turns before the latest prompt is cut.
+    fn apply(&self, model: &LoadedModel, raw_prompt: &str) -> anyhow::Result<(String, usize)> {
+        let raw_prompt = &strip_control_tokens(&self.arch, &model.tokenizer, raw_prompt);
+        let history: Vec<ChatTurn> = self.history.iter()
+            .map(|turn| ChatTurn {
+                role: turn.role,
+                content: strip_control_tokens(&self.arch, &model.tokenizer, &turn.content),
+            })
+            .collect();
+        let render = |history: &[ChatTurn], p: &str| {
+            model.chat_template.as_ref()
+                .and_then(|template| template.render(history, p, self.system_prompt.as_deref()).ok())
+                .unwrap_or_else(|| apply_chat_template(&self.arch, history, p, self.system_prompt.clone()))
+        };
+        let token_budget = model.max_context(self.max_context).saturating_sub(self.max_tokens.unwrap_or(1));
+        let too_long = |history: &[ChatTurn]| -> anyhow::Result<bool> {
+            Ok(encode_prompt(&model.tokenizer, &render(history, raw_prompt))?.len() > token_budget)
+        };
+        // After a cut the history starts with a user turn, as templates that check the roles
+        // alternate expect
+        let mut first = 0;
+        let mut dropped = 0;
+        if self.strategy != ContextStrategy::Error {
+            while first < history.len()
+                && ((first > 0 && history[first].role == ChatRole::Assistant) || too_long(&history[first..])?)
+            {
+                dropped += model.tokenizer.encode(history[first].content.as_str(), false)
+                    .map_err(anyhow::Error::msg)?
+                    .len();
+                first += 1;
+            }
+        }
+        let kept = &history[first..];
+        let (prompt, cut) = fit_prompt(&model.tokenizer, raw_prompt, |p| render(kept, p), token_budget, self.strategy)?;
+        Ok((prompt, dropped + cut))
+    }
+}
+
+// Number of completions a request gets: its `n`, between 1 and max_choices
+fn choice_count(state: &AppState, req: &InferRequest) -> usize {
+    let limit = state.settings.max_choices.unwrap_or(DEFAULT_MAX_CHOICES).max(1);
+    let requested = req.n.unwrap_or(1).max(1);
+    if requested > limit {
+        println!("Clamping n from {} to max_choices {}", requested, limit);
+    }
+    requested.min(limit)
+}
+
+// Stats of several completions of one prompt: the prompt counted once, generated tokens summed
+fn total_stats(choices: &[InferChoice]) -> GenerationStats {
+    let mut total = choices[0].stats;
+    total.generated_tokens = choices.iter().map(|c| c.stats.generated_tokens).sum();
+    total
+}
+
+// Fit the prompt into the context, then generate
+fn generate(
+    model: &mut LoadedModel,
+    fit: &PromptFit,
+    raw_prompt: &str,
+    params: InferenceParams,
+    callback: impl FnMut(TokenEvent),
+) -> anyhow::Result<GenerationOutcome> {
+    let (prompt, dropped) = fit.apply(model, raw_prompt)?;
+    let mut outcome = run_inference(model, &prompt, params, callback)?;
+    outcome.stats.context_dropped_tokens = dropped;
+    Ok(outcome)
+}
+
+// Receives each token of a streamed /compare run; `cancel` stops the run once the client is gone
+struct TokenSink {
+    send: Box<dyn FnMut(&TokenEvent) + Send>,
+    cancel: Arc<AtomicBool>,
+}
+
+// Generate the request's completions on a loaded model, one after another, then record the
+// timing and log the request. Shared by /infer and /compare; the caller holds the permit.
+async fn run_completions(
+    state: &AppState,
+    name: &str,
+    model_arc: Arc<StdMutex<LoadedModel>>,
+    req: &InferRequest,
+    queue_ms: u64,
+    mut sink: Option<TokenSink>,
+) -> (anyhow::Result<Vec<InferChoice>>, Timing) {
+    let mut params = build_params(state, name, req);
+    if let Some(sink) = &sink {
+        params.cancel = Some(sink.cancel.clone());
+    }
+    let choice_count = choice_count(state, req);
+    let keep_events = response_cacheable(state, name, req);
+    // Apply template to input so that it match model's standard input
+    let fit = PromptFit::new(state, name, req, &params);
+    let raw_prompt = req.prompt.clone();
+    // Run inference
+    let generate_start = Instant::now();
+    let result = task::spawn_blocking(move || {
+        let mut model = lock_model(&model_arc);
+        // Completions run one after another. Each gets its own seed: seed + index when the
+        // request fixed one (so the set is reproducible), otherwise a generated base + index.
+        let base_seed = params.seed.unwrap_or_else(derive_seed_from_time);
+        let mut choices = Vec::with_capacity(choice_count);
+        for index in 0..choice_count {
+            let mut params = params.clone();
+            if choice_count > 1 {
+                params.seed = Some(base_seed.wrapping_add(index as u64));
+            }
+            let mut output = String::new();
+            let mut logprobs = params.logprobs.map(|_| Vec::new());
+            let mut events = Vec::new();
+            // The callback appends token to string buffer
+            let outcome = generate(
+                &mut model, 
+                &fit, 
+                &raw_prompt, 
+                params, 
+                |event| {
+                    if let Some(sink) = sink.as_mut() {
+                        (sink.send)(&event);
+                    }
+                    output.push_str(&event.text);
+                    if let (Some(all), Some(logprob)) = (logprobs.as_mut(), event.logprob.clone()) {
+                        all.push(logprob);
+                    }
+                    if keep_events {
+                        events.push(event);
+                    }
+                }
+            )?;
+            choices.push(InferChoice {
+                index,
+                text: output,
+                finish_reason: outcome.finish_reason,
+                stats: outcome.stats,
+                logprobs,
+                events,
+            });
+            // Do not start another completion once the server is going down
+            if outcome.finish_reason == FinishReason::ServerShutdown {
+                break;
+            }
+        }
+        anyhow::Ok(choices)
+    })
+    .await
+    .unwrap_or_else(|e| Err(GenerationPanicked(e.to_string()).into()));
+    let timing = Timing { queue_ms, generate_ms: generate_start.elapsed().as_millis() as u64 };
+    lock(&state.stats).record(&timing);
+    let outcome = match &result {
+        Ok(choices) => RequestOutcome::Completed(choices[0].finish_reason),
+        Err(e) => RequestOutcome::Failed(e.to_string()),
+    };
+    log_request(
+        &RequestLog {
+            model: name,
+            prompt: &req.prompt,
+            stats: result.as_ref().ok().map(|choices| total_stats(choices)),
+            duration_ms: timing.generate_ms,
+            outcome,
+        },
+        state.settings.log_prompts,
+    );
+    // A broken model would fail every later request too
+    let result = match result {
+        Err(e) if is_fatal_inference_error(&e) => {
+            let note = unload_failed_model(state, name, &e).await;
+            Err(anyhow::anyhow!("{}. {}", e, note))
+        }
+        other => other,
+    };
+    (result, timing)
+}
+
+// Whether the Accept header asks for text/plain rather than JSON. JSON wins ties and is the
+// default, also for a missing header or `*/*`.
+fn prefers_plain_text(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(ACCEPT).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+    // Highest quality the header gives any of these media ranges, 0 when it lists none
+    let quality = |wanted: &[&str]| {
+        accept.split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let media = parts.next()?.trim().to_ascii_lowercase();
+                if !wanted.contains(&media.as_str()) {
+                    return None;
+                }
+                let q = parts.find_map(|p| p.trim().strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()));
+                Some(q.unwrap_or(1.0))
+            })
+            .fold(0.0, f32::max)
+    };
+    let text = quality(&["text/plain", "text/*"]);
+    text > 0.0 && text > quality(&["application/json"])
+}
+
+// POST /infer
+// Return full response at once: JSON, or only the text for `Accept: text/plain`
+#[utoipa::path(
+    post, path = "/infer", tag = "inference", request_body = InferRequest,
+    responses(
+        (status = 200, description = "Generated text; with `Accept: text/plain` only the text of the first completion", content(
+            (ApiResponse<InferResponse> = "application/json"),
+            (String = "text/plain"),
+        )),
+        (status = 413, description = "Prompt longer than max_prompt_chars", body = ApiResponse<String>),
+        (status = 422, description = "Bad parameters, prompt too long, or an embedding model", body = ApiResponse<String>),
+        (status = 503, description = "No model ready, queue full or shutting down", body = ApiResponse<String>),
+    )
+)]
+async fn infer_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<InferRequest>,
+) -> Response {
+    if let Some(response) = prompt_length_error(&state, &req) {
+        return response;
+    }
+    // Refuse new work while shutting down
+    let Some(_in_flight) = InFlightGuard::enter(&state) else {
+        return shutting_down_response();
+    };
+    let choice_count = choice_count(&state, &req);
+    // Seeded repeats are answered from the response cache without queueing
+    let requested = state.active_model.lock().await.clone();
+    let (active, choices, timing) = match cached_completions(&state, &requested, &req).await {
+        Some(cached) => {
+            let choices = cached.into_iter().enumerate().map(|(index, choice)| InferChoice {
+                index,
+                text: choice.events.iter().map(|event| event.text.as_str()).collect(),
+                finish_reason: choice.finish_reason,
+                stats: choice.stats,
+                logprobs: req.logprobs.map(|_| choice.events.iter().filter_map(|event| event.logprob.clone()).collect()),
+                events: choice.events,
+            }).collect();
+            (requested, choices, Timing { queue_ms: 0, generate_ms: 0 })
+        }
+        None => {
+            // Concurrency Control
+            let (_permit, queue_ms) = acquire_permit(&state).await;
+            // Check if there is active model
+            let active = state.active_model.lock().await.clone();
+            if active.is_empty() {
+                return ApiResponse::<InferResponse>::error(no_active_model_message(&state).await).into_response();
+            }
+            if model::is_embedding_arch(model_arch(&state, &active)) {
+                return ApiResponse::<()>::error_with_status(StatusCode::UNPROCESSABLE_ENTITY, embedding_model_message(&active));
+            }
+            let models = state.models.lock().await;
+            // Clone the Arc to the model
+            let model_arc = match models.get(&active) {
+                Some(Some(m)) => m.clone(),
+                _ => return ApiResponse::<InferResponse>::error("Model not found or not loaded.").into_response(),
+            };
+            drop(models); // Release lock
+            touch_model(&state, &active).await;
+            let (result, timing) = run_completions(&state, &active, model_arc, &req, queue_ms, None).await;
+            match result {
+                Ok(choices) => {
+                    let cacheable = choices.iter()
+                        .map(|c| CachedChoice { events: c.events.clone(), finish_reason: c.finish_reason, stats: c.stats })
+                        .collect();
+                    cache_completions(&state, &active, &req, cacheable);
+                    (active, choices, timing)
+                }
+                Err(e) => {
+                    return ApiResponse::<InferResponse>::error(format!("Inference failed: {}", e)).into_response();
+                }
+            }
+        }
+    };
+    let first = choices[0].clone();
+    // Errors stay JSON, so a plain text body is always generated text
+    if prefers_plain_text(&headers) {
+        return ([(CONTENT_TYPE, "text/plain; charset=utf-8")], first.text).into_response();
+    }
+    ApiResponse::ok(InferResponse {
+        model: active,
+        text: first.text,
+        finish_reason: first.finish_reason,
+        stats: first.stats,
+        timing,
+        logprobs: first.logprobs,
+        choices: (choice_count > 1).then_some(choices),
+    }).into_response()
+}
+
+// Completions the response cache holds for a seeded request on `model`, which must still be loaded
+async fn cached_completions(state: &AppState, model: &str, req: &InferRequest) -> Option<Vec<CachedChoice>> {
+    let cache = state.response_cache.as_ref()?;
+    let key = response_cache::cache_key(model, req)?;
+    if !matches!(state.models.lock().await.get(model), Some(Some(_))) {
+        return None;
+    }
+    lock(cache).get(key)
+}
+
+// Whether completions of `req` on `model` may go into the response cache; their token events
+// are only kept for it when they do
+fn response_cacheable(state: &AppState, model: &str, req: &InferRequest) -> bool {
+    state.response_cache.is_some() && response_cache::cache_key(model, req).is_some()
+}
+
+// Keep the completions of a seeded request for identical repeats, if they all ran to their end
+fn cache_completions(state: &AppState, model: &str, req: &InferRequest, choices: Vec<CachedChoice>) {
+    let Some(cache) = &state.response_cache else { return };
+    let Some(key) = response_cache::cache_key(model, req) else { return };
+    if choices.iter().all(|choice| response_cache::is_cacheable(choice.finish_reason)) {
+        lock(cache).insert(key, choices);
+    }
+}
+
+// POST /compare
+// Run one prompt through several loaded models with identical parameters and seed, each with
+// its own chat template. The runs queue for the generation slot one after another; a model
+// that cannot run gets an error entry instead of failing the request.
+#[utoipa::path(
+    post, path = "/compare", tag = "inference", request_body = CompareRequest,
+    responses(
+        (status = 200, description = "Every model's completion or error, in order; with `stream` an SSE stream of events tagged with `model`, ending in [DONE]", content(
+            (ApiResponse<CompareResponse> = "application/json"),
+            (String = "text/event-stream"),
+        )),
+        (status = 413, description = "Prompt longer than max_prompt_chars", body = ApiResponse<String>),
+        (status = 422, description = "No models given, or `n` above 1", body = ApiResponse<String>),
+        (status = 503, description = "Shutting down", body = ApiResponse<String>),
+    )
+)]
+async fn compare_handler(State(state): State<AppState>, Json(body): Json<CompareRequest>) -> Response {
+    let CompareRequest { models: names, stream, mut request } = body;
+    if let Some(response) = prompt_length_error(&state, &request) {
+        return response;
+    }
+    if names.is_empty() {
+        return ApiResponse::<()>::error_with_status(StatusCode::UNPROCESSABLE_ENTITY, "models must name at least one model.");
+    }
+    if request.n.is_some_and(|n| n > 1) {
+        return ApiResponse::<()>::error_with_status(StatusCode::UNPROCESSABLE_ENTITY, "n > 1 is not supported by /compare.");
+    }
+    let Some(in_flight) = InFlightGuard::enter(&state) else {
+        return shutting_down_response();
+    };
+    let names: Vec<String> = names.iter().map(|name| resolve_model_name(&state, name)).collect();
+    // Every run samples with the same seed
+    let seed = request.seed.unwrap_or_else(derive_seed_from_time);
+    request.seed = Some(seed);
+    request.n = None;
+
+    if !stream {
+        let _in_flight = in_flight;
+        let mut results = Vec::with_capacity(names.len());
+        for name in names {
+            results.push(compare_run(&state, name, &request, None).await);
+        }
+        return ApiResponse::ok(CompareResponse { seed, results }).into_response();
+    }
+
+    // Runs stay sequential, so each model's events arrive together, tagged with its name
+    let (tx, rx) = mpsc::channel(100);
+    task::spawn(async move {
+        let _in_flight = in_flight;
+        let send_timeout = stream_send_timeout(&state);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let _ = tx.send(json!({ "seed": seed }).to_string()).await;
+        for name in names {
+            if cancel.load(Ordering::SeqCst) {
+                return;
+            }
+            let sink = TokenSink {
+                send: {
+                    let (tx, cancel, model) = (tx.clone(), cancel.clone(), name.clone());
+                    let runtime = tokio::runtime::Handle::current();
+                    Box::new(move |event: &TokenEvent| {
+                        let mut msg = json!({ "model": model, "text": event.text });
+                        if let Some(logprob) = &event.logprob {
+                            msg["logprobs"] = json!(logprob);
+                        }
+                        // A client that left or stopped reading stops the run at the next token
+                        if runtime.block_on(tx.send_timeout(msg.to_string(), send_timeout)).is_err() {
+                            cancel.store(true, Ordering::SeqCst);
+                        }
+                    })
+                },
+                cancel: cancel.clone(),
+            };
+            let mut result = json!(compare_run(&state, name, &request, Some(sink)).await);
+            // The text went out token by token
+            if let Some(result) = result.as_object_mut() {
+                result.remove("text");
+            }
+            let _ = tx.send(result.to_string()).await;
+        }
+        let _ = tx.send("[DONE]".to_string()).await;
+    });
+    Sse::new(ReceiverStream::new(rx).map(|m| Ok::<_, std::convert::Infallible>(Event::default().data(m))))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+// One model's part of /compare: queue for the slot, then generate if the model can chat and is loaded
+async fn compare_run(state: &AppState, name: String, request: &InferRequest, sink: Option<TokenSink>) -> CompareResult {
+    let error = if !state.settings.models.contains_key(&name) {
+        Some(format!("Model '{}' not found in config.", name))
+    } else if model::is_embedding_arch(model_arch(state, &name)) {
+        Some(embedding_model_message(&name))
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        return CompareResult { model: name, error: Some(error), ..Default::default() };
+    }
+    // Queue like any request, one run at a time
+    let (_permit, queue_ms) = acquire_permit(state).await;
+    let model_arc = match state.models.lock().await.get(&name) {
+        Some(Some(m)) => m.clone(),
+        _ => {
+            let error = format!("Model '{}' is not loaded.", name);
+            return CompareResult { model: name, error: Some(error), ..Default::default() };
+        }
+    };
+    touch_model(state, &name).await;
+    let (result, timing) = run_completions(state, &name, model_arc, request, queue_ms, sink).await;
+    match result {
+        Ok(mut choices) => {
+            let choice = choices.remove(0);
+            CompareResult {
+                model: name,
+                text: Some(choice.text),
+                finish_reason: Some(choice.finish_reason),
+                stats: Some(choice.stats),
+                timing: Some(timing),
+                error: None,
+            }
+        }
+        Err(e) => CompareResult { model: name, error: Some(format!("Inference failed: {}", e)), ..Default::default() },
+    }
+}
+
+// POST /infer_stream
+// Return response using SSE which means token by token
+#[utoipa::path(
+    post, path = "/infer_stream", tag = "inference", request_body = InferRequest,
+    responses(
+        (status = 200, description = openapi::STREAM_EVENTS, content_type = "text/event-stream", body = String),
+        (status = 413, description = "Prompt longer than max_prompt_chars", body = ApiResponse<String>),
+        (status = 422, description = "`n` above 1, or bad parameters", body = ApiResponse<String>),
+        (status = 503, description = "Queue full or shutting down", body = ApiResponse<String>),
+    )
+)]
+async fn infer_stream_handler(State(state): State<AppState>, Json(req): Json<InferRequest>) -> Response {
+    if let Some(response) = prompt_length_error(&state, &req) {
+        return response;
+    }
+    // Several completions cannot share one token stream
+    if req.n.is_some_and(|n| n > 1) {
+        return ApiResponse::<()>::error_with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "n > 1 is only supported by /infer.",
+        );
+    }
+    // Refuse new work while shutting down
+    let Some(in_flight) = InFlightGuard::enter(&state) else {
+        return shutting_down_response();
+    };
+    stream_completion(state, req, None, in_flight, None)
+}
+
+// How long a stream event may wait for the client to make room
+fn stream_send_timeout(state: &AppState) -> Duration {
+    Duration::from_secs(state.settings.stream_send_timeout_secs.unwrap_or(DEFAULT_STREAM_SEND_TIMEOUT_SECS))
+}
+
+// Follow the shared stream under `key`: the events so far, then the rest as they come.
+// None when there is none, in which case the caller now leads a new one.
+fn join_shared_stream(state: &AppState, key: u64) -> Option<mpsc::UnboundedReceiver<String>> {
+    let mut streams = lock(&state.shared_streams);
+    let Some(shared) = streams.get_mut(&key) else {
+        streams.insert(key, SharedStream { sent: Vec::new(), followers: Vec::new() });
+        return None;
+    };
+    let (follower, events) = mpsc::unbounded_channel();
+    for msg in &shared.sent {
+        let _ = follower.send(msg.clone());
+    }
+    shared.followers.push(follower);
+    Some(events)
+}
+
+// Pass a leading stream's events to its own client and to every follower. Followers never
+// wait on each other or on the leader's client; the generation is only stopped (by closing
+// `events`) once the client and all followers are gone.
+async fn relay_shared_stream(
+    state: AppState,
+    key: u64,
+    mut events: mpsc::Receiver<String>,
+    client: mpsc::Sender<String>,
+) {
+    let send_timeout = stream_send_timeout(&state);
+    let mut client = Some(client);
+    while let Some(msg) = events.recv().await {
+        let followers = match lock(&state.shared_streams).get_mut(&key) {
+            Some(shared) => {
+                shared.followers.retain(|follower| follower.send(msg.clone()).is_ok());
+                shared.sent.push(msg.clone());
+                shared.followers.len()
+            }
+            None => 0,
+        };
+        if let Some(sender) = &client
+            && sender.send_timeout(msg, send_timeout).await.is_err()
+        {
+            client = None;
+        }
+        if client.is_none() && followers == 0 {
+            break;
+        }
+    }
+    // Dropping the followers' senders ends their streams
+    lock(&state.shared_streams).remove(&key);
+}
+
+// A follower's copy of the finish event reports its stats as coalesced
+fn mark_coalesced(msg: String) -> String {
+    if !msg.starts_with("{\"finish_reason\"") {
+        return msg;
+    }
+    match serde_json::from_str::<serde_json::Value>(&msg) {
+        Ok(mut event) if event["stats"].is_object() => {
+            event["stats"]["coalesced"] = json!(true);
+            event.to_string()
+        }
+        _ => msg,
+    }
+}
+
+// Stream event of one generated token
+fn token_message(event: &TokenEvent) -> String {
+    let mut msg = json!({ "text": event.text });
+    if let Some(logprob) = &event.logprob {
+        msg["logprobs"] = json!(logprob);
+    }
+    msg.to_string()
+}
+
+// Stream events after the last token: why generation ended, then OpenAI-style usage
+fn finish_messages(finish_reason: FinishReason, stats: &GenerationStats, timing: &Timing) -> [String; 2] {
+    let done_msg = json!({
+        "finish_reason": finish_reason,
+        "stats": stats,
+        "timing": timing,
+    });
+    let usage_msg = json!({
+        "usage": {
+            "prompt_tokens": stats.prompt_tokens,
+            "completion_tokens": stats.generated_tokens,
+            "total_tokens": stats.prompt_tokens + stats.generated_tokens,
+        }
+    });
+    [done_msg.to_string(), usage_msg.to_string()]
+}
+
+// Called with the full reply text once a streamed generation finishes without being cancelled
+type ReplyHook = Box<dyn FnOnce(String) + Send>;
+
+// Stream one completion of `req` as SSE events, from `model` or else the active model.
+// Shared by /infer_stream and session messages; the request has already been validated.
+fn stream_completion(
+    state: AppState,
+    req: InferRequest,
+    model: Option<String>,
+    in_flight: InFlightGuard,
+    on_reply: Option<ReplyHook>,
+) -> Response {
+    // Channel for tokens
+    let (tx, rx) = mpsc::channel(100);
+    task::spawn(async move {
+        // Counted as in flight until the stream task ends
+        let _in_flight = in_flight;
+        // Seeded repeats are replayed from the response cache without queueing; echo needs the model
+        let requested = match &model {
+            Some(model) => model.clone(),
+            None => state.active_model.lock().await.clone(),
+        };
+        if !req.echo
+            && let Some(mut cached) = cached_completions(&state, &requested, &req).await
+        {
+            let choice = cached.swap_remove(0);
+            let _ = tx.send(format!("[MODEL: {}]", requested)).await;
+            let mut reported_tokens = 0;
+            for event in &choice.events {
+                let _ = tx.send(token_message(event)).await;
+                if event.generated_tokens >= reported_tokens + STREAM_PROGRESS_INTERVAL {
+                    reported_tokens = event.generated_tokens;
+                    let _ = tx.send(json!({ "tokens_generated": reported_tokens }).to_string()).await;
+                }
+            }
+            let timing = Timing { queue_ms: 0, generate_ms: 0 };
+            for msg in finish_messages(choice.finish_reason, &choice.stats, &timing) {
+                let _ = tx.send(msg).await;
+            }
+            if let Some(on_reply) = on_reply {
+                on_reply(choice.events.into_iter().map(|event| event.text).collect());
+            }
+            let _ = tx.send("[DONE]".to_string()).await;
+            return;
+        }
+        // An identical seeded stream that is already running is followed instead of generated
+        // twice; echo and session replies need a generation of their own
+        let shared_key = response_cache::cache_key(&requested, &req).filter(|_| !req.echo && on_reply.is_none());
+        let tx = match shared_key {
+            Some(key) => {
+                if let Some(mut events) = join_shared_stream(&state, key) {
+                    while let Some(msg) = events.recv().await {
+                        if tx.send(mark_coalesced(msg)).await.is_err() {
+                            break;
+                        }
+                    }
+                    return;
+                }
+                // Leading: events pass through a relay that also feeds later followers
+                let (events_tx, events_rx) = mpsc::channel(100);
+                task::spawn(relay_shared_stream(state.clone(), key, events_rx, tx));
+                events_tx
+            }
+            None => tx,
+        };
+        // Followers joined under the key of `requested`, so a leader generates with it even
+        // if another model is made active while it waits for the permit
+        let model = model.or_else(|| shared_key.map(|_| requested.clone()));
+        // Concurrency Control
+        let (permit, queue_ms) = acquire_permit(&state).await;
+        let active = match model {
+            Some(model) => model,
+            None => state.active_model.lock().await.clone(),
+        };
+        
+        // Check if there is active model
+        let error = if active.is_empty() {
+            Some(no_active_model_message(&state).await)
+        } else if model::is_embedding_arch(model_arch(&state, &active)) {
+            Some(embedding_model_message(&active))
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            let _ = tx.send(stream_error(error)).await;
+            let _ = tx.send("[DONE]".to_string()).await;
+            return;
+        }
+        let models_guard = state.models.lock().await;
+        let model_arc_option = models_guard.get(&active);
+        let model_arc = match model_arc_option {
+            Some(Some(m)) => m.clone(),
+            _ => {
+                let _ = tx.send(stream_error("Model not found or not loaded.")).await;
+                let _ = tx.send("[DONE]".to_string()).await;
+                return;
+            }
+        };
+        drop(models_guard);// Release lock
+        touch_model(&state, &active).await;
+        
+        let _permit = permit;
+        let mut params = build_params(&state, &active, &req);
+        // Set by the token callback once the client has gone away or stopped reading
+        let disconnected = Arc::new(AtomicBool::new(false));
+        let too_slow = Arc::new(AtomicBool::new(false));
+        params.cancel = Some(disconnected.clone());
+        let fit = PromptFit::new(&state, &active, &req, &params);
+        let keep_events = response_cacheable(&state, &active, &req);
+        let tx_clone = tx.clone();
+        let stats = state.stats.clone();
+        let log_prompts = state.settings.log_prompts;
+        let send_timeout = stream_send_timeout(&state);
+        let runtime = tokio::runtime::Handle::current();
+        let model_name = active.clone();
+        let task_state = state.clone();
+        
+        // Run inference
+        let generate_start = Instant::now();
+        let handle = task::spawn_blocking(move || {
+            // Wait at most send_timeout for room in the channel. A full buffer means the client
+            // is not reading, and waiting forever would hold the model and the generation slot.
+            let send = |msg: String| {
+                let result = runtime.block_on(tx_clone.send_timeout(msg, send_timeout));
+                match result {
+                    Ok(()) => {}
+                    Err(SendTimeoutError::Timeout(_)) => {
+                        too_slow.store(true, Ordering::SeqCst);
+                        disconnected.store(true, Ordering::SeqCst);
+                    }
+                    Err(SendTimeoutError::Closed(_)) => disconnected.store(true, Ordering::SeqCst),
+                }
+            };
+            let mut model = lock_model(&model_arc);
+            // Show what the model receives; a prompt that cannot be fitted is reported by generate()
+            if req.echo
+                && let Ok((prompt, _)) = fit.apply(&model, &req.prompt)
+            {
+                let prompt_tokens = encode_prompt(&model.tokenizer, &prompt).map_or(0, |ids| ids.len());
+                let echo_msg = json!({ "prompt": prompt, "prompt_tokens": prompt_tokens }).to_string();
+                send(echo_msg);
+            }
+            send(format!("[MODEL: {}]", active));
+
+            let mut reported_tokens = 0;
+            let mut reply = String::new();
+            let mut events = Vec::new();
+            let res = generate(
+                &mut model, 
+                &fit, 
+                &req.prompt, 
                 params, 
-                |t| { 
-                    let json_msg = json!({ "text": t }).to_string();
-                    
-                    // if client disconnect, stop inference
-                    let send_result = tx_clone.blocking_send(json_msg);
-                    if send_result.is_err() {
-                        panic!("Client disconnected, stopping inference.");
+                |event| { 
+                    reply.push_str(&event.text);
+                    // if client disconnect or stall, stop inference at the next token
+                    send(token_message(&event));
+                    // Progress for live speed displays; carries no text
+                    if event.generated_tokens >= reported_tokens + STREAM_PROGRESS_INTERVAL {
+                        reported_tokens = event.generated_tokens;
+                        send(json!({ "tokens_generated": reported_tokens }).to_string());
+                    }
+                    if keep_events {
+                        events.push(event);
                     }
                 }
             );
-            if let Err(e) = res {
-                let error_msg = format!("[ERROR] {}", e);
-                let _ = tx_clone.blocking_send(error_msg);
-            }
-            let _ = tx_clone.blocking_send("[DONE]".to_string());
-        });
-        match handle.await {
-            Ok(_) => {}, // task complete
-            Err(e) => {
-                if e.is_panic() {
-                    println!("Inference stopped by user.");
-                } else {
-                    println!("Inference task failed: {:?}", e);
+            // Free the model for the next request before the trailing events
+            drop(model);
+            let slow_client = too_slow.load(Ordering::SeqCst);
+            let timing = Timing { queue_ms, generate_ms: generate_start.elapsed().as_millis() as u64 };
+            lock(&stats).record(&timing);
+            log_request(
+                &RequestLog {
+                    model: &active,
+                    prompt: &req.prompt,
+                    stats: res.as_ref().ok().map(|outcome| outcome.stats),
+                    duration_ms: timing.generate_ms,
+                    outcome: match &res {
+                        Ok(outcome) if outcome.finish_reason == FinishReason::Cancelled && slow_client => {
+                            RequestOutcome::SlowClient
+                        }
+                        Ok(outcome) if outcome.finish_reason == FinishReason::Cancelled => {
+                            RequestOutcome::Cancelled
+                        }
+                        Ok(outcome) => RequestOutcome::Completed(outcome.finish_reason),
+                        Err(e) => RequestOutcome::Failed(e.to_string()),
+                    },
+                },
+                log_prompts,
+            );
+            match res {
+                Ok(outcome) if outcome.finish_reason == FinishReason::Cancelled => {
+                    if slow_client {
+                        println!("Client stopped reading for {:?}, inference cancelled.", send_timeout);
+                    } else {
+                        println!("Inference stopped by user.");
+                    }
+                    return;
+                }
+                // Tell the client why generation ended before the done marker
+                Ok(outcome) => {
+                    if let Some(on_reply) = on_reply {
+                        on_reply(reply);
+                    }
+                    for msg in finish_messages(outcome.finish_reason, &outcome.stats, &timing) {
+                        send(msg);
+                    }
+                    let choice = CachedChoice { events, finish_reason: outcome.finish_reason, stats: outcome.stats };
+                    cache_completions(&task_state, &active, &req, vec![choice]);
                 }
+                // Unloaded before [DONE], so the client's next request cannot reach the broken model
+                Err(e) if is_fatal_inference_error(&e) => {
+                    let note = runtime.block_on(unload_failed_model(&task_state, &active, &e));
+                    send(stream_error(format!("{}. {}", e, note)));
+                }
+                Err(e) => send(stream_error(e)),
             }
+            send("[DONE]".to_string());
+        });
+        // A panic in the generation task (e.g. a poisoned model lock) still ends the stream properly
+        if let Err(e) = handle.await {
+            println!("Inference task failed: {:?}", e);
+            let note = unload_failed_model(&state, &model_name, &GenerationPanicked(e.to_string()).into()).await;
+            let _ = tx.send(stream_error(format!("Inference task failed. {}", note))).await;
+            let _ = tx.send("[DONE]".to_string()).await;
         }
     });
     
     // Convert the channel receiver into a Stream compatible with Axum SSE
-    Sse::new(ReceiverStream::new(rx).map(|m| Ok(Event::default().data(m))))
+    Sse::new(ReceiverStream::new(rx).map(|m| Ok::<_, std::convert::Infallible>(Event::default().data(m))))
         .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+// POST /sessions
+// Start a conversation kept on the server; only new messages are sent after this
+#[utoipa::path(
+    post, path = "/sessions", tag = "sessions", request_body = CreateSessionRequest,
+    responses(
+        (status = 200, description = "The new, empty session", body = ApiResponse<Session>),
+        (status = 404, description = "Model not in config.toml", body = ApiResponse<String>),
+        (status = 422, description = "No model given and none active, or an embedding model", body = ApiResponse<String>),
+    )
+)]
+async fn create_session_handler(State(state): State<AppState>, Json(req): Json<CreateSessionRequest>) -> Response {
+    let model = match req.model {
+        Some(name) => resolve_model_name(&state, &name),
+        None => state.active_model.lock().await.clone(),
+    };
+    if model.is_empty() {
+        let message = no_active_model_message(&state).await;
+        return ApiResponse::<()>::error_with_status(StatusCode::UNPROCESSABLE_ENTITY, message);
+    }
+    if !state.settings.models.contains_key(&model) {
+        return ApiResponse::<()>::error_with_status(
+            StatusCode::NOT_FOUND,
+            format!("Model '{}' not found in config.", model),
+        );
+    }
+    if model::is_embedding_arch(model_arch(&state, &model)) {
+        return ApiResponse::<()>::error_with_status(StatusCode::UNPROCESSABLE_ENTITY, embedding_model_message(&model));
+    }
+    ApiResponse::ok(state.sessions.create(model, req.system_prompt)).into_response()
+}
+
+// GET /sessions/:id
+#[utoipa::path(
+    get, path = "/sessions/{id}", tag = "sessions",
+    params(("id" = String, Path, description = "Session id from POST /sessions")),
+    responses(
+        (status = 200, description = "The session and its transcript", body = ApiResponse<Session>),
+        (status = 404, description = "Unknown, deleted or expired session", body = ApiResponse<String>),
+    )
+)]
+async fn get_session_handler(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.sessions.get(&id) {
+        Some(session) => ApiResponse::ok(session).into_response(),
+        None => session_not_found(&id),
+    }
+}
+
+// DELETE /sessions/:id
+#[utoipa::path(
+    delete, path = "/sessions/{id}", tag = "sessions",
+    params(("id" = String, Path, description = "Session id from POST /sessions")),
+    responses(
+        (status = 200, description = "Confirmation", body = ApiResponse<String>),
+        (status = 404, description = "Unknown, deleted or expired session", body = ApiResponse<String>),
+    )
+)]
+async fn delete_session_handler(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    if state.sessions.remove(&id) {
+        ApiResponse::ok(format!("Session {} deleted.", id)).into_response()
+    } else {
+        session_not_found(&id)
+    }
+}
+
+// POST /sessions/:id/message
+// Answer the next user message with the session's model and history, streamed like /infer_stream.
+// The exchange is added to the transcript once the reply finishes; a cancelled or failed reply leaves it unchanged.
+#[utoipa::path(
+    post, path = "/sessions/{id}/message", tag = "sessions", request_body = InferRequest,
+    params(("id" = String, Path, description = "Session id from POST /sessions")),
+    responses(
+        (status = 200, description = openapi::STREAM_EVENTS, content_type = "text/event-stream", body = String),
+        (status = 404, description = "Unknown, deleted or expired session", body = ApiResponse<String>),
+        (status = 409, description = "The previous message is still being answered", body = ApiResponse<String>),
+        (status = 413, description = "Message longer than max_prompt_chars", body = ApiResponse<String>),
+        (status = 422, description = "`messages`, `system_prompt` or `n` above 1 given, or bad parameters", body = ApiResponse<String>),
+        (status = 503, description = "Queue full or shutting down", body = ApiResponse<String>),
+    )
+)]
+async fn session_message_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(mut req): Json<InferRequest>,
+) -> Response {
+    // The session supplies both; `prompt` is the new message
+    if !req.messages.is_empty() || req.system_prompt.is_some() {
+        return ApiResponse::<()>::error_with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "A session keeps its own history and system prompt; send only the new message as `prompt`.",
+        );
+    }
+    if req.n.is_some_and(|n| n > 1) {
+        return ApiResponse::<()>::error_with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "n > 1 is not supported in sessions.",
+        );
+    }
+    // Earlier turns are not counted: the prompt fitting drops the oldest ones when the context is full
+    if let Some(response) = prompt_length_error(&state, &req) {
+        return response;
+    }
+    let Some(in_flight) = InFlightGuard::enter(&state) else {
+        return shutting_down_response();
+    };
+    let turn = match state.sessions.begin_turn(&id, req.prompt.clone()) {
+        Ok(turn) => turn,
+        Err(TurnError::NotFound) => return session_not_found(&id),
+        Err(TurnError::Busy) => {
+            return ApiResponse::<()>::error_with_status(
+                StatusCode::CONFLICT,
+                format!("Session {} is still answering the previous message.", id),
+            );
+        }
+    };
+    req.messages = turn.history.clone();
+    req.system_prompt = turn.system_prompt.clone();
+    let model = turn.model.clone();
+    stream_completion(state, req, Some(model), in_flight, Some(Box::new(move |reply| turn.complete(reply))))
+}
+
+fn session_not_found(id: &str) -> Response {
+    ApiResponse::<()>::error_with_status(StatusCode::NOT_FOUND, format!("Session {} not found.", id))
+}
+
+// POST /preview_prompt
+// Show exactly what would be fed to the active model, without running inference
+#[utoipa::path(
+    post, path = "/preview_prompt", tag = "inference", request_body = InferRequest,
+    responses((status = 200, description = "Templated prompt and its token count; `status` is \"error\" without an active model", body = ApiResponse<PromptPreview>))
+)]
+async fn preview_prompt_handler(
+    State(state): State<AppState>,
+    Json(req): Json<InferRequest>,
+) -> Json<ApiResponse<PromptPreview>> {
+    let active = state.active_model.lock().await.clone();
+    if active.is_empty() {
+        return ApiResponse::error(no_active_model_message(&state).await);
+    }
+    if model::is_embedding_arch(model_arch(&state, &active)) {
+        return ApiResponse::error(embedding_model_message(&active));
+    }
+    // The tokenizer lives with the loaded model
+    let models = state.models.lock().await;
+    let model_arc = match models.get(&active) {
+        Some(Some(m)) => m.clone(),
+        _ => return ApiResponse::error(format!("Tokenizer for model '{}' is not loaded.", active)),
+    };
+    drop(models);
+    let fit = PromptFit::new(&state, &active, &req, &build_params(&state, &active, &req));
+    let result = task::spawn_blocking(move || {
+        let model = lock_model(&model_arc);
+        // Same templating and truncation as /infer
+        let (templated_prompt, dropped_tokens) = fit.apply(&model, &req.prompt)?;
+        let prompt_tokens = encode_prompt(&model.tokenizer, &templated_prompt)?.len();
+        anyhow::Ok(PromptPreview { templated_prompt, prompt_tokens, dropped_tokens })
+    })
+    .await
+    .unwrap();
+    match result {
+        Ok(preview) => ApiResponse::ok(preview),
+        Err(e) => ApiResponse::error(format!("Failed to tokenize prompt: {}", e)),
+    }
 }
 
 //POST /set_model
 // Set active model for one of loaded models
+#[utoipa::path(
+    post, path = "/set_model", tag = "models", request_body = SetModelRequest,
+    responses((status = 200, description = "Confirmation; `status` is \"error\" if the model is not loaded or cannot chat", body = ApiResponse<String>))
+)]
 async fn set_model(
     State(state): State<AppState>,
-    Json(req): Json<SetModelRequest>,
+    Json(mut req): Json<SetModelRequest>,
 ) -> Json<ApiResponse<String>> {
+    req.name = resolve_model_name(&state, &req.name);
     let models = state.models.lock().await;
     if !models.contains_key(&req.name) {
         return ApiResponse::error("Model not found.");
     }
+    if model::is_embedding_arch(model_arch(&state, &req.name)) {
+        return ApiResponse::error(embedding_model_message(&req.name));
+    }
     if models.get(&req.name).unwrap().is_some() {
         let mut active = state.active_model.lock().await;
         *active = req.name.clone();
@@ -452,12 +2798,65 @@ async fn set_model(
     ApiResponse::error(format!("Model {} not loaded.", req.name))
 }
 
+// POST /switch_model
+// Make a model active, loading it first (evicting others from VRAM as needed) if it is not loaded
+#[utoipa::path(
+    post, path = "/switch_model", tag = "models", request_body = SetModelRequest,
+    responses(
+        (status = 200, description = "The model is active; `outcome` says whether it already was, was loaded, or had to be loaded", body = ApiResponse<SwitchModelResponse>),
+        (status = 404, description = "Model not in config.toml", body = ApiResponse<String>),
+        (status = 422, description = "An embedding model, which cannot be active", body = ApiResponse<String>),
+        (status = 500, description = "The load failed", body = ApiResponse<String>),
+    )
+)]
+async fn switch_model_handler(State(state): State<AppState>, Json(req): Json<SetModelRequest>) -> Response {
+    let name = resolve_model_name(&state, &req.name);
+    if !state.settings.models.contains_key(&name) {
+        return ApiResponse::<()>::error_with_status(
+            StatusCode::NOT_FOUND,
+            format!("Model '{}' not found in config.", name),
+        );
+    }
+    if model::is_embedding_arch(model_arch(&state, &name)) {
+        return ApiResponse::<()>::error_with_status(StatusCode::UNPROCESSABLE_ENTITY, embedding_model_message(&name));
+    }
+    let models = state.models.lock().await;
+    let (outcome, message) = if matches!(models.get(&name), Some(Some(_))) {
+        // Switched under the models lock, so the model cannot be unloaded in between
+        let mut active = state.active_model.lock().await;
+        if *active == name {
+            (SwitchOutcome::AlreadyActive, format!("Model {} is already active.", name))
+        } else {
+            *active = name.clone();
+            (SwitchOutcome::ActivatedExisting, format!("Active model switched to {}", name))
+        }
+    } else {
+        drop(models);
+        match load_model(&state, &name, None).await {
+            Ok(message) => (SwitchOutcome::LoadedAndActivated, message),
+            Err(message) => return ApiResponse::<()>::error_with_status(StatusCode::INTERNAL_SERVER_ERROR, message),
+        }
+    };
+    ApiResponse::ok(SwitchModelResponse {
+        model: name,
+        outcome,
+        loaded: outcome == SwitchOutcome::LoadedAndActivated,
+        message,
+    })
+    .into_response()
+}
+
 //POST /unload_model
 // Drop model to free VRAM
+#[utoipa::path(
+    post, path = "/unload_model", tag = "models", request_body = UnloadModelRequest,
+    responses((status = 200, description = "Confirmation; `status` is \"error\" if the model was not loaded", body = ApiResponse<String>))
+)]
 async fn unload_model_handler(
     State(state): State<AppState>,
-    Json(req): Json<UnloadModelRequest>,
+    Json(mut req): Json<UnloadModelRequest>,
 ) -> Json<ApiResponse<String>> {
+    req.name = resolve_model_name(&state, &req.name);
     let mut models = state.models.lock().await;
     if let Some(slot) = models.get_mut(&req.name) {
         if slot.is_some() {
@@ -472,57 +2871,976 @@ async fn unload_model_handler(
     ApiResponse::error(format!("Model {} not loaded.", req.name))
 }
 
-#[tokio::main]
-async fn main() {
+// POST /reset
+// Clear a loaded model's KV cache so the next request is prefilled from scratch, e.g. to
+// recover from a bad cache without reloading the model. Waits for a running generation.
+#[utoipa::path(
+    post, path = "/reset", tag = "models", request_body = ResetRequest,
+    responses(
+        (status = 200, description = "Confirmation with the number of cached tokens dropped", body = ApiResponse<String>),
+        (status = 404, description = "The model is not in config.toml", body = ApiResponse<String>),
+        (status = 409, description = "The model is not loaded", body = ApiResponse<String>),
+    )
+)]
+async fn reset_handler(State(state): State<AppState>, Json(mut req): Json<ResetRequest>) -> Response {
+    req.name = resolve_model_name(&state, &req.name);
+    if !state.settings.models.contains_key(&req.name) {
+        return ApiResponse::<()>::error_with_status(
+            StatusCode::NOT_FOUND,
+            format!("Model '{}' not found in config.", req.name),
+        );
+    }
+    let Some(Some(model_arc)) = state.models.lock().await.get(&req.name).cloned() else {
+        return ApiResponse::<()>::error_with_status(StatusCode::CONFLICT, format!("Model {} not loaded.", req.name));
+    };
+    let dropped = task::spawn_blocking(move || {
+        let mut model = lock_model(&model_arc);
+        let dropped = model.cached_tokens.len();
+        model.reset_cache();
+        dropped
+    })
+    .await
+    .unwrap();
+    println!("KV cache of model '{}' reset ({} tokens dropped)", req.name, dropped);
+    ApiResponse::ok(format!("KV cache of {} cleared ({} tokens dropped).", req.name, dropped)).into_response()
+}
+
+// Whether the request carries `Authorization: Bearer <admin_api_key>`.
+// Compared without an early exit so the response time does not leak the key.
+fn is_admin(headers: &HeaderMap, admin_key: &str) -> bool {
+    let Some(token) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    token.len() == admin_key.len()
+        && token.bytes().zip(admin_key.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// POST /admin/shutdown
+// Same graceful shutdown as SIGTERM: drain in-flight generations, unload the models and exit.
+// Only routed when admin_api_key is configured.
+#[utoipa::path(
+    post, path = "/admin/shutdown", tag = "server",
+    security(("admin_api_key" = [])),
+    responses(
+        (status = 202, description = "Graceful shutdown started (only routed when admin_api_key is set)", body = ApiResponse<String>),
+        (status = 401, description = "Missing or wrong admin key", body = ApiResponse<String>),
+    )
+)]
+async fn admin_shutdown_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let admin_key = state.settings.admin_api_key.as_deref().unwrap_or_default();
+    if !is_admin(&headers, admin_key) {
+        return ApiResponse::<()>::error_with_status(StatusCode::UNAUTHORIZED, "Admin API key required.");
+    }
+    println!("Shutdown requested through /admin/shutdown");
+    // Stored as a permit, so it is not lost if the server is not yet waiting on it
+    state.shutdown_requested.notify_one();
+    (StatusCode::ACCEPTED, ApiResponse::ok("Shutting down.")).into_response()
+}
+
+// GET /openapi.json
+async fn openapi_handler() -> Json<utoipa::openapi::OpenApi> {
+    Json(openapi::ApiDoc::openapi())
+}
+
+// GET /docs: Swagger UI over /openapi.json
+async fn docs_handler() -> Html<&'static str> {
+    Html(openapi::DOCS_HTML)
+}
+
+// Resolve on Ctrl-C or SIGTERM
+async fn wait_for_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// Graceful shutdown: stop taking inference requests, give in-flight generations
+// the grace period to finish, then cancel the rest (they still send their done event)
+async fn shutdown_signal(state: AppState) {
+    tokio::select! {
+        _ = wait_for_signal() => {},
+        _ = state.shutdown_requested.notified() => {},
+    }
+    state.shutting_down.store(true, Ordering::SeqCst);
+    let pending = state.in_flight.load(Ordering::SeqCst);
+    let grace = Duration::from_secs(state.settings.shutdown_grace_secs.unwrap_or(10));
+    println!("Shutdown requested: {} request(s) in flight, waiting up to {:?}", pending, grace);
+
+    let deadline = Instant::now() + grace;
+    while state.in_flight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    let cut_off = state.in_flight.load(Ordering::SeqCst);
+    if cut_off > 0 {
+        // Generations stop at their next token and report finish_reason "server_shutdown"
+        state.shutdown_cancel.store(true, Ordering::SeqCst);
+        while state.in_flight.load(Ordering::SeqCst) > 0 {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+    println!("Shutdown: {} request(s) drained, {} cut off", pending.saturating_sub(cut_off), cut_off);
+}
+
+fn main() {
     // Load settings from config.toml
     let settings = Settings::new().expect("Failed to load config.toml");
-    let settings_arc = Arc::new(settings.clone());
+    // candle's CPU kernels and rayon's pool both size themselves from RAYON_NUM_THREADS when first
+    // used. Set it before the runtime starts, while this is the only thread reading the environment.
+    if let Some(threads) = settings.cpu_threads {
+        unsafe { std::env::set_var("RAYON_NUM_THREADS", threads.to_string()) };
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start the async runtime")
+        .block_on(serve(settings));
+}
+
+// Fresh application state: every configured model known but unloaded. The receiver takes the
+// load jobs queued by async /load_model; load_worker must run on it.
+fn app_state(
+    settings: Settings,
+    devices: Vec<gpu::GpuInfo>,
+    vram_limits: BTreeMap<usize, usize>,
+) -> (AppState, mpsc::UnboundedReceiver<u64>) {
+    let settings_arc = Arc::new(settings);
     // Initialize state maps
     let mut model_map = HashMap::new();
     let mut size_map = HashMap::new();
 
-    for (name, _) in settings.models {
+    for name in settings_arc.models.keys() {
         model_map.insert(name.clone(), None);
         // Initial size is 0 until we download/measure it
-        size_map.insert(name, 0);
+        size_map.insert(name.clone(), ModelSize::default());
     }
     //println!("Loaded config: {:?} models found.", model_map.len());
 
-    // Auto-detect VRAM
-    let auto_vram_limit = detect_vram_mb();
+    let (load_queue, load_jobs) = mpsc::unbounded_channel();
+    let response_cache = settings_arc.response_cache_size
+        .filter(|&size| size > 0)
+        .map(|size| Arc::new(StdMutex::new(ResponseCache::new(size))));
     // Create shared application state
     let state = AppState {
         models: Arc::new(TokioMutex::new(model_map)),
         active_model: Arc::new(TokioMutex::new("".to_string())),
-        semaphore: Arc::new(Semaphore::new(1)), // Only one allowed for enough VRAM space
+        semaphore: Arc::new(Semaphore::new(MAX_CONCURRENCY)),
         model_sizes: Arc::new(TokioMutex::new(size_map)),
-        vram_limit: auto_vram_limit,
+        last_used: Arc::new(TokioMutex::new(HashMap::new())),
+        loading: Arc::new(TokioMutex::new(HashSet::new())),
+        shutting_down: Arc::new(AtomicBool::new(false)),
+        shutdown_cancel: Arc::new(AtomicBool::new(false)),
+        shutdown_requested: Arc::new(Notify::new()),
+        jobs: Arc::new(StdMutex::new(BTreeMap::new())),
+        next_job_id: Arc::new(AtomicU64::new(1)),
+        load_queue,
+        in_flight: Arc::new(AtomicUsize::new(0)),
+        started_at: now_secs(),
+        queue_depth: Arc::new(AtomicUsize::new(0)),
+        max_concurrency: MAX_CONCURRENCY,
+        stats: Arc::new(StdMutex::new(RequestStats::default())),
+        tokenizers: Arc::new(StdMutex::new(HashMap::new())),
+        vram_limits,
+        devices: Arc::new(devices),
+        cache_size_mb: Arc::new(AtomicU64::new(0)),
+        placements: Arc::new(StdMutex::new(HashMap::new())),
+        unhealthy: Arc::new(StdMutex::new(HashMap::new())),
+        next_gpu: Arc::new(AtomicUsize::new(0)),
+        sessions: Sessions::default(),
+        response_cache,
+        shared_streams: Arc::new(StdMutex::new(HashMap::new())),
         settings: settings_arc,
     };
+    (state, load_jobs)
+}
+
+// Routes of the API, all described in openapi::ApiDoc
+fn api_routes(settings: &Settings) -> Vec<(&'static str, MethodRouter<AppState>)> {
+    let mut routes = vec![
+        ("/health", get(health_handler)),
+        ("/models", get(list_models)),
+        ("/v1/models", get(openai_models_handler)),
+        ("/stats", get(stats_handler)),
+        ("/set_model", post(set_model)),
+        ("/switch_model", post(switch_model_handler)),
+        ("/load_model", post(load_model_handler)),
+        ("/jobs/:id", get(job_handler)),
+        ("/models/:name", get(model_details_handler)),
+        ("/models/:name/download", post(download_model_handler)),
+        ("/cache", get(cache_handler)),
+        ("/cache/responses", delete(clear_response_cache_handler)),
+        ("/cache/:name", delete(delete_cache_handler)),
+        ("/unload_model", post(unload_model_handler)),
+        ("/reset", post(reset_handler)),
+        ("/infer", post(infer_handler)),
+        ("/compare", post(compare_handler)),
+        ("/preview_prompt", post(preview_prompt_handler)),
+        ("/embeddings", post(embeddings_handler)),
+        ("/v1/embeddings", post(openai_embeddings_handler)),
+        ("/tokenize", post(tokenize_handler)),
+        ("/detokenize", post(detokenize_handler)),
+        ("/infer_stream", post(infer_stream_handler)),
+        ("/sessions", post(create_session_handler)),
+        ("/sessions/:id", get(get_session_handler).delete(delete_session_handler)),
+        ("/sessions/:id/message", post(session_message_handler)),
+    ];
+    // Opt-in: benchmark runs hold the model between real requests
+    if settings.enable_benchmark {
+        routes.push(("/benchmark", post(benchmark_handler)));
+    }
+    // Without a key there is no way to authorize it, so the route does not exist
+    if settings.admin_api_key.is_some() {
+        routes.push(("/admin/shutdown", post(admin_shutdown_handler)));
+    }
+    routes
+}
 
+fn router(state: AppState) -> Router {
     // Configure CORS
-    let cors_layer = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
+    let cors_layer = cors_layer(&state.settings.allowed_origins);
 
     // Routers
-    let app = Router::new()
-        .route("/health", get(|| async { "OK" }))
-        .route("/models", get(list_models))
-        .route("/set_model", post(set_model))
-        .route("/load_model", post(load_model_handler))
-        .route("/unload_model", post(unload_model_handler))
-        .route("/infer", post(infer_handler))
-        .route("/infer_stream", post(infer_stream_handler))
-        .with_state(state)
-        .layer(cors_layer); // Enable CORS
+    let app = api_routes(&state.settings)
+        .into_iter()
+        .fold(Router::new(), |app, (path, route)| app.route(path, route))
+        .route("/openapi.json", get(openapi_handler))
+        .route("/docs", get(docs_handler));
+    app.with_state(state).layer(cors_layer) // Enable CORS
+}
+
+async fn serve(settings: Settings) {
+    // Keep the guard alive so buffered log lines are flushed on exit
+    let _log_guard = logging::init(&settings);
+    if let Some(attempts) = settings.download_attempts {
+        model::set_download_attempts(attempts);
+    }
+    if let Some(token) = &settings.hf_token {
+        model::set_hf_token(token.trim().to_string());
+    }
+    let source = if settings.cpu_threads.is_some() { "cpu_threads" } else { "default" };
+    println!("CPU inference threads: {} ({})", candle_core::utils::get_num_threads(), source);
+
+    // Auto-detect VRAM
+    let (devices, vram_limits) = detect_devices(&settings);
+    let (state, load_jobs) = app_state(settings, devices, vram_limits);
+    // Size the model cache without holding up startup
+    let startup = state.clone();
+    task::spawn(async move { refresh_cache_size(&startup, hf_hub::Cache::default()).await });
+    // Unload idle models in the background (no-op unless idle_unload_secs is configured)
+    task::spawn(idle_unload_loop(state.clone()));
+    // Drop sessions nobody has used for session_idle_secs
+    task::spawn(session_expiry_loop(state.clone()));
+    // Load default/preload models without blocking startup
+    task::spawn(preload_models(state.clone()));
+    // Run queued (async) loads one at a time
+    task::spawn(load_worker(state.clone(), load_jobs));
+    let app = router(state.clone());
 
     // Start server
     let addr = SocketAddr::from(([127, 0, 0, 1], 8081));
     println!("Server running at http://{}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal(state.clone()))
         .await
         .unwrap();
+
+    // Free the loaded models before exiting
+    let mut models = state.models.lock().await;
+    for slot in models.values_mut() {
+        *slot = None;
+    }
+    println!("Models unloaded, server stopped.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{Body, to_bytes};
+    use axum::http::Request;
+    use serde_json::Value;
+    use shared_types::sse::SseParser;
+    use tower::ServiceExt;
+
+    const MOCK_TOKENIZER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/mock/tokenizer.json");
+
+    // Settings with `global` lines and mock models a, b and c of 100 MB each
+    fn mock_settings(global: &str) -> Settings {
+        let mut toml = format!("warmup = false\n{}\n", global);
+        for name in ["a", "b", "c"] {
+            toml += &format!(
+                "[models.{}]\narch = \"mock\"\ntokenizer_local_path = \"{}\"\nmock_size_mb = 100\n",
+                name, MOCK_TOKENIZER
+            );
+        }
+        Settings::from_toml(&toml).unwrap()
+    }
+
+    // State over mock_settings(global) where GPU 0 has room for two of the mock models
+    fn mock_state(global: &str) -> AppState {
+        app_state(mock_settings(global), Vec::new(), BTreeMap::from([(0, 250)])).0
+    }
+
+    // Send a request through the router; returns the status and the body
+    async fn call(state: &AppState, method: &str, uri: &str, body: Option<Value>) -> (StatusCode, String) {
+        let request = Request::builder().method(method).uri(uri).header(CONTENT_TYPE, "application/json");
+        let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+        let response = router(state.clone()).oneshot(request.body(body).unwrap()).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    async fn call_json(state: &AppState, method: &str, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+        let (status, body) = call(state, method, uri, body).await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    // `data` of every event of an /infer_stream response
+    async fn stream(state: &AppState, body: Value) -> Vec<String> {
+        let (status, body) = call(state, "POST", "/infer_stream", Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+        SseParser::default().push(body.as_bytes()).into_iter().map(|event| event.data).collect()
+    }
+
+    async fn load(state: &AppState, name: &str) {
+        let (status, body) = call_json(state, "POST", "/load_model", Some(json!({ "name": name }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok", "loading {}: {}", name, body);
+    }
+
+    async fn loaded_models(state: &AppState) -> Vec<String> {
+        let mut names: Vec<String> = state.models.lock().await
+            .iter()
+            .filter(|(_, slot)| slot.is_some())
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn plan_evictions_table() {
+        // name, GPU, size in MB, last used
+        let resident: Vec<Resident> = [
+            ("a", Some(0), 3000, 30),
+            ("b", Some(0), 2000, 10),
+            ("c", Some(0), 1000, 20),
+            ("d", Some(1), 4000, 0),
+            ("cpu", None, 5000, 0),
+        ]
+        .into_iter()
+        .map(|(name, vram_index, mb, last_used)| Resident { name: name.to_string(), vram_index, mb, last_used })
+        .collect();
+        // GPU 0 holds 6000 MB. (GPU, required, limit, max_loaded, keep, expected)
+        let cases = [
+            // Room already
+            (Some(0), 2000, 8000, None, None, Ok(vec![])),
+            // Least recently used first: b, then c
+            (Some(0), 3000, 8000, None, None, Ok(vec!["b"])),
+            (Some(0), 4001, 8000, None, None, Ok(vec!["b", "c"])),
+            (Some(0), 8000, 8000, None, None, Ok(vec!["b", "c", "a"])),
+            (Some(0), 8001, 8000, None, None, Err(NoRoom::TooLarge)),
+            // Each GPU has its own budget: nothing on GPU 0 or the CPU goes for GPU 1
+            (Some(1), 4000, 8000, None, None, Ok(vec![])),
+            (Some(1), 5000, 8000, None, None, Ok(vec!["d"])),
+            // CPU loads have no budget to make room in
+            (None, 100_000, usize::MAX, None, None, Ok(vec![])),
+            // The kept model is skipped, even when it is the least recently used
+            (Some(0), 3000, 8000, None, Some("b"), Ok(vec!["c"])),
+            (Some(0), 4000, 8000, None, Some("b"), Ok(vec!["c", "a"])),
+            (Some(0), 7000, 8000, None, Some("a"), Err(NoRoom::TooLarge)),
+            // max_loaded_models refuses rather than evicts
+            (Some(0), 100, 8000, Some(5), None, Err(NoRoom::TooManyModels(5))),
+            (Some(0), 100, 8000, Some(6), None, Ok(vec![])),
+        ];
+        for (vram_index, required_mb, limit_mb, max_loaded, keep, expected) in cases {
+            let plan = plan_evictions(&resident, vram_index, required_mb, limit_mb, max_loaded, keep);
+            let expected = expected.map(|names| names.into_iter().map(String::from).collect::<Vec<_>>());
+            assert_eq!(plan, expected, "GPU {:?} required {} keep {:?}", vram_index, required_mb, keep);
+        }
+    }
+
+    #[test]
+    fn only_device_errors_and_panics_are_fatal() {
+        let out_of_memory = || candle_core::Error::Cuda(Box::new(std::io::Error::other("CUDA_ERROR_OUT_OF_MEMORY")));
+        let fatal = [
+            anyhow::Error::from(out_of_memory()),
+            anyhow::Error::from(out_of_memory().context("forward")).context("generation failed"),
+            anyhow::Error::from(GenerationPanicked("index out of bounds".to_string())),
+        ];
+        for e in fatal {
+            assert!(is_fatal_inference_error(&e), "{:#}", e);
+        }
+        // Errors that merely mention a device are not device errors
+        let not_fatal = [
+            anyhow::anyhow!("unknown model 'cuda-metal' (check the driver section of config.toml)"),
+            anyhow::Error::from(candle_core::Error::Msg("tensor on cuda:0 is out of memory range".to_string())),
+            anyhow::Error::from(candle_core::Error::NotCompiledWithCudaSupport),
+        ];
+        for e in not_fatal {
+            assert!(!is_fatal_inference_error(&e), "{:#}", e);
+        }
+    }
+
+    #[test]
+    fn plan_evictions_skips_models_of_unknown_size() {
+        let resident = [
+            Resident { name: "unknown".to_string(), vram_index: Some(0), mb: 0, last_used: 0 },
+            Resident { name: "known".to_string(), vram_index: Some(0), mb: 3000, last_used: 1 },
+        ];
+        assert_eq!(plan_evictions(&resident, Some(0), 4000, 5000, None, None), Ok(vec!["known".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn load_model_makes_it_active() {
+        let state = mock_state("");
+        let (_, body) = call_json(&state, "POST", "/load_model", Some(json!({ "name": "a" }))).await;
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["data"], "Model 'a' loaded.");
+        assert_eq!(*state.active_model.lock().await, "a");
+
+        let (_, body) = call_json(&state, "POST", "/load_model", Some(json!({ "name": "missing" }))).await;
+        assert_eq!(body["status"], "error");
+        assert_eq!(body["message"], "Model 'missing' not found in config.");
+    }
+
+    #[tokio::test]
+    async fn switch_model_loads_what_it_needs_to() {
+        let state = mock_state("");
+        load(&state, "a").await;
+        let switch = |name: &str| call_json(&state, "POST", "/switch_model", Some(json!({ "name": name })));
+        // (model, outcome, whether it had to be loaded)
+        let steps = [
+            ("b", "loaded_and_activated", true),
+            ("a", "activated_existing", false),
+            ("a", "already_active", false),
+        ];
+        for (name, outcome, loaded) in steps {
+            let (status, body) = switch(name).await;
+            assert_eq!(status, StatusCode::OK, "{}", body);
+            assert_eq!((body["data"]["outcome"].as_str(), body["data"]["loaded"].as_bool()), (Some(outcome), Some(loaded)));
+            assert_eq!(*state.active_model.lock().await, name);
+        }
+        assert_eq!(loaded_models(&state).await, ["a", "b"]);
+
+        let (status, body) = switch("missing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["message"], "Model 'missing' not found in config.");
+        assert_eq!(*state.active_model.lock().await, "a");
+        assert_eq!(loaded_models(&state).await, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn loading_an_embedding_model_keeps_the_active_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = mock_settings("");
+        settings.models.insert("embed".to_string(), embedding::write_tiny_bert(dir.path()));
+        let state = app_state(settings, Vec::new(), BTreeMap::from([(0, 1000)])).0;
+        load(&state, "a").await;
+        // The second load finds the model already loaded
+        for _ in 0..2 {
+            load(&state, "embed").await;
+            assert_eq!(*state.active_model.lock().await, "a");
+        }
+        assert_eq!(loaded_models(&state).await, ["a", "embed"]);
+    }
+
+    #[tokio::test]
+    async fn infer_needs_a_loaded_model() {
+        let state = mock_state("");
+        let (_, body) = call_json(&state, "POST", "/infer", Some(json!({ "prompt": "hello" }))).await;
+        assert_eq!(body["status"], "error");
+    }
+
+    #[tokio::test]
+    async fn health_reports_only_in_memory_state() {
+        let gpu = gpu::GpuInfo { index: 0, name: "Mock GPU".to_string(), total_mb: 8192, free_mb: 8000 };
+        let state = app_state(mock_settings("max_prompt_chars = 100"), vec![gpu], BTreeMap::from([(0, 250)])).0;
+        load(&state, "a").await;
+        let (status, body) = call_json(&state, "GET", "/health", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({
+                "status": "ok",
+                "active": "a",
+                "loading": [],
+                "max_prompt_chars": 100,
+                "devices": [{ "index": 0, "name": "Mock GPU", "total_mb": 8192, "free_mb": 8000 }],
+                "cache_size_mb": 0,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn seeded_repeats_are_replayed_from_the_response_cache() {
+        let state = mock_state("response_cache_size = 4");
+        load(&state, "a").await;
+        let request = json!({ "prompt": "hello world", "max_tokens": 5, "seed": 3 });
+        let (_, first) = call_json(&state, "POST", "/infer", Some(request.clone())).await;
+        let (_, second) = call_json(&state, "POST", "/infer", Some(request)).await;
+        assert_eq!(first["data"]["stats"]["cached"], false);
+        assert_eq!(second["data"]["stats"]["cached"], true);
+        assert_eq!(second["data"]["text"], first["data"]["text"]);
+        assert!(!first["data"]["text"].as_str().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn temperature_zero_ignores_the_seed() {
+        // No response cache, so every answer below is generated
+        let state = mock_state("");
+        load(&state, "a").await;
+        let text = |temperature: f64, seed: u64| {
+            let request = json!({ "prompt": "hello world", "max_tokens": 12, "temperature": temperature, "seed": seed });
+            let state = state.clone();
+            async move {
+                let (_, body) = call_json(&state, "POST", "/infer", Some(request)).await;
+                assert_eq!(body["data"]["stats"]["cached"], false);
+                body["data"]["text"].as_str().unwrap().to_string()
+            }
+        };
+        assert_eq!(text(0.0, 1).await, text(0.0, 2).await);
+        // Sampling hot enough that the seed matters on this model
+        assert_ne!(text(100.0, 1).await, text(100.0, 2).await);
+    }
+
+    #[tokio::test]
+    async fn admin_shutdown_needs_the_admin_key() {
+        let (status, _) = call(&mock_state(""), "POST", "/admin/shutdown", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let state = mock_state("admin_api_key = \"s3cret\"");
+        let shutdown = |authorization: Option<&'static str>| {
+            let mut request = Request::builder().method("POST").uri("/admin/shutdown");
+            if let Some(authorization) = authorization {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+            router(state.clone()).oneshot(request.body(Body::empty()).unwrap())
+        };
+        let notified = || time::timeout(Duration::from_millis(50), state.shutdown_requested.notified());
+        for authorization in [None, Some("Bearer wrong!"), Some("Bearer s3cret2"), Some("Bearer "), Some("s3cret"), Some("Basic s3cret")] {
+            let response = shutdown(authorization).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{:?}", authorization);
+        }
+        assert!(notified().await.is_err(), "a refused request started the shutdown");
+
+        let response = shutdown(Some("Bearer s3cret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(notified().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_request_after_a_dropped_stream_succeeds() {
+        let state = mock_state("");
+        load(&state, "a").await;
+        let request = Request::builder()
+            .method("POST")
+            .uri("/infer_stream")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "prompt": "hello world", "seed": 1 }).to_string()))
+            .unwrap();
+        let response = router(state.clone()).oneshot(request).await.unwrap();
+        let mut events = response.into_body().into_data_stream();
+        // Hang up as soon as generation has started
+        events.next().await.unwrap().unwrap();
+        drop(events);
+
+        let (status, body) = call_json(&state, "POST", "/infer", Some(json!({ "prompt": "hello", "max_tokens": 4 }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok", "{}", body);
+        assert_eq!(body["data"]["stats"]["generated_tokens"], 4);
+    }
+
+    #[tokio::test]
+    async fn a_panic_while_holding_a_model_does_not_break_it() {
+        let state = mock_state("");
+        load(&state, "a").await;
+        let model = state.models.lock().await["a"].clone().unwrap();
+        lock_model(&model).cached_tokens = vec![2, 3];
+        let held = model.clone();
+        std::thread::spawn(move || {
+            let _model = held.lock().unwrap();
+            panic!("generation panicked");
+        })
+        .join()
+        .unwrap_err();
+        assert!(model.is_poisoned());
+
+        // The cache it may have left half-written is dropped, and the model serves again
+        assert!(lock_model(&model).cached_tokens.is_empty());
+        assert!(!model.is_poisoned());
+        let (status, body) = call_json(&state, "POST", "/infer", Some(json!({ "prompt": "hello", "max_tokens": 4 }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok", "{}", body);
+    }
+
+    #[tokio::test]
+    async fn infer_stops_at_max_tokens() {
+        let state = mock_state("");
+        load(&state, "a").await;
+        let request = json!({ "prompt": "hello world", "max_tokens": 6, "seed": 1 });
+        let (status, body) = call_json(&state, "POST", "/infer", Some(request)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+        let data = &body["data"];
+        assert_eq!(data["model"], "a");
+        assert_eq!(data["finish_reason"], "length");
+        assert_eq!(data["stats"]["generated_tokens"], 6);
+        assert_eq!(data["stats"]["seed"], 1);
+        assert!(!data["text"].as_str().unwrap().is_empty());
+    }
+
+    #[test]
+    fn prefers_plain_text_reads_the_accept_header() {
+        let cases = [
+            (None, false),
+            (Some("*/*"), false),
+            (Some("application/json"), false),
+            (Some("text/plain"), true),
+            (Some("TEXT/PLAIN; charset=utf-8"), true),
+            (Some("text/*"), true),
+            (Some("text/html"), false),
+            // JSON wins ties; otherwise the higher quality wins
+            (Some("text/plain, application/json"), false),
+            (Some("text/plain;q=0.9, application/json;q=0.5"), true),
+            (Some("application/json;q=0.5, text/plain"), true),
+            (Some("text/plain;q=0.5, application/json"), false),
+            (Some("text/plain;q=0"), false),
+        ];
+        for (accept, want) in cases {
+            let mut headers = HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(ACCEPT, HeaderValue::from_static(accept));
+            }
+            assert_eq!(prefers_plain_text(&headers), want, "Accept: {:?}", accept);
+        }
+    }
+
+    #[tokio::test]
+    async fn infer_answers_text_plain_with_the_text_alone() {
+        let state = mock_state("");
+        load(&state, "a").await;
+        let body = json!({ "prompt": "hello world", "max_tokens": 6, "seed": 1 });
+        let (_, json) = call_json(&state, "POST", "/infer", Some(body.clone())).await;
+        let request = Request::builder()
+            .method("POST")
+            .uri("/infer")
+            .header(CONTENT_TYPE, "application/json")
+            .header(ACCEPT, "text/plain")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/plain; charset=utf-8");
+        let text = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(std::str::from_utf8(&text).unwrap(), json["data"]["text"]);
+    }
+
+    #[tokio::test]
+    async fn infer_stops_at_a_configured_stop_token() {
+        let state = mock_state("");
+        load(&state, "a").await;
+        let request = json!({ "prompt": "hello world", "max_tokens": 6, "seed": 1 });
+        let (_, body) = call_json(&state, "POST", "/infer", Some(request.clone())).await;
+        let text = body["data"]["text"].as_str().unwrap().to_string();
+
+        // Stop on the third token the model produced for this prompt
+        let third = text.split_whitespace().nth(2).unwrap();
+        let id = {
+            let models = state.models.lock().await;
+            let model = lock_model(models["a"].as_ref().unwrap());
+            model.tokenizer.token_to_id(third).unwrap()
+        };
+        lock_model(state.models.lock().await["a"].as_ref().unwrap()).stop_token_ids = vec![id];
+        let (_, body) = call_json(&state, "POST", "/infer", Some(request)).await;
+        assert_eq!(body["data"]["finish_reason"], "stop");
+        assert!(body["data"]["stats"]["generated_tokens"].as_u64().unwrap() <= 3);
+    }
+
+    #[tokio::test]
+    async fn infer_stream_sends_tokens_then_finish_and_done() {
+        let state = mock_state("");
+        load(&state, "a").await;
+        let request = json!({ "prompt": "hello world", "max_tokens": 6, "seed": 1 });
+        let (_, body) = call_json(&state, "POST", "/infer", Some(request.clone())).await;
+        let expected = body["data"]["text"].as_str().unwrap().to_string();
+
+        let events = stream(&state, request).await;
+        assert_eq!(events.first().map(String::as_str), Some("[MODEL: a]"));
+        assert_eq!(events.last().map(String::as_str), Some("[DONE]"));
+        let messages: Vec<Value> = events[1..events.len() - 1]
+            .iter()
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let text: String = messages.iter().filter_map(|msg| msg["text"].as_str()).collect();
+        assert_eq!(text, expected);
+        let done = messages.iter().find(|msg| msg.get("finish_reason").is_some()).unwrap();
+        assert_eq!(done["finish_reason"], "length");
+        assert_eq!(done["stats"]["generated_tokens"], 6);
+        let usage = messages.iter().find(|msg| msg.get("usage").is_some()).unwrap();
+        assert_eq!(usage["usage"]["completion_tokens"], 6);
+    }
+
+    // Wait until the shared stream of an identical request has `count` followers
+    async fn wait_for_followers(state: &AppState, count: usize) {
+        for _ in 0..500 {
+            let followers = lock(&state.shared_streams).values().map(|s| s.followers.len()).sum::<usize>();
+            if followers == count {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no stream joined the shared generation");
+    }
+
+    // Token and finish events of a stream, without the coalesced flag
+    fn generated(events: &[String]) -> Vec<Value> {
+        events
+            .iter()
+            .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+            .filter(|msg| msg.get("text").is_some() || msg.get("finish_reason").is_some())
+            .map(|mut msg| {
+                if let Some(stats) = msg.get_mut("stats") {
+                    stats["coalesced"] = json!(false);
+                }
+                msg
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn identical_seeded_streams_share_one_generation() {
+        let state = mock_state("");
+        load(&state, "a").await;
+        let request = json!({ "prompt": "hello world", "max_tokens": 20, "seed": 7 });
+        // Hold the generation slot so the second request arrives while the first is queued
+        let permit = state.semaphore.clone().acquire_owned().await.unwrap();
+        let leader = task::spawn({
+            let (state, request) = (state.clone(), request.clone());
+            async move { stream(&state, request).await }
+        });
+        while lock(&state.shared_streams).is_empty() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let follower = task::spawn({
+            let (state, request) = (state.clone(), request.clone());
+            async move { stream(&state, request).await }
+        });
+        wait_for_followers(&state, 1).await;
+        drop(permit);
+        let (leader, follower) = (leader.await.unwrap(), follower.await.unwrap());
+
+        assert_eq!(lock(&state.stats).completed, 1);
+        assert_eq!(generated(&leader).len(), 21);
+        assert_eq!(generated(&leader), generated(&follower));
+        let coalesced = |events: &[String]| {
+            events.iter()
+                .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+                .find_map(|msg| msg["stats"]["coalesced"].as_bool())
+        };
+        assert_eq!(coalesced(&leader), Some(false));
+        assert_eq!(coalesced(&follower), Some(true));
+    }
+
+    #[tokio::test]
+    async fn shared_stream_keeps_the_model_it_was_keyed_on() {
+        let state = mock_state("");
+        load(&state, "b").await;
+        load(&state, "a").await;
+        let request = json!({ "prompt": "hello world", "max_tokens": 5, "seed": 7 });
+        let permit = state.semaphore.clone().acquire_owned().await.unwrap();
+        let leader = task::spawn({
+            let (state, request) = (state.clone(), request.clone());
+            async move { stream(&state, request).await }
+        });
+        while lock(&state.shared_streams).is_empty() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let follower = task::spawn({
+            let (state, request) = (state.clone(), request.clone());
+            async move { stream(&state, request).await }
+        });
+        wait_for_followers(&state, 1).await;
+        // Switched while both wait; they were keyed on a, so a must answer them
+        let (_, body) = call_json(&state, "POST", "/set_model", Some(json!({ "name": "b" }))).await;
+        assert_eq!(body["status"], "ok");
+        drop(permit);
+        for events in [leader.await.unwrap(), follower.await.unwrap()] {
+            assert_eq!(events[0], "[MODEL: a]");
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_unload_honours_per_model_thresholds_and_the_active_model() {
+        for unload_active in [false, true] {
+            let mut settings = mock_settings(&format!("idle_unload_secs = 60\nunload_active_on_idle = {}", unload_active));
+            settings.models.get_mut("a").unwrap().idle_unload_secs = Some(1000);
+            let state = app_state(settings, Vec::new(), BTreeMap::from([(0, 1000)])).0;
+            for name in ["a", "b", "c"] {
+                load(&state, name).await;
+            }
+            // All three unused for 100s; c, loaded last, is the active model
+            let then = now_secs() - 100;
+            state.last_used.lock().await.extend(["a", "b", "c"].map(|name| (name.to_string(), then)));
+            unload_idle_models(&state).await;
+
+            let (kept, active) = if unload_active { (vec!["a"], "") } else { (vec!["a", "c"], "c") };
+            assert_eq!(loaded_models(&state).await, kept, "unload_active_on_idle = {}", unload_active);
+            assert_eq!(*state.active_model.lock().await, active);
+        }
+    }
+
+    #[tokio::test]
+    async fn loading_past_the_vram_limit_evicts_the_least_recently_used_model() {
+        let state = mock_state("");
+        load(&state, "a").await;
+        load(&state, "b").await;
+        // a was used more recently than b, though it was loaded first
+        state.last_used.lock().await.extend([("a".to_string(), 200), ("b".to_string(), 100)]);
+        load(&state, "c").await;
+        assert_eq!(loaded_models(&state).await, ["a", "c"]);
+        assert_eq!(*state.active_model.lock().await, "c");
+    }
+
+    // Put `file` of `repo` in the hf-hub cache at `hub`, laid out the way hf-hub downloads it
+    fn cache_file(hub: &hf_hub::Cache, repo: &str, file: &str, size_bytes: usize) -> String {
+        let hub_repo = hf_hub::Repo::model(repo.to_string());
+        hub.repo(hub_repo.clone()).create_ref("0123abcd").unwrap();
+        let path = hub.path().join(hub_repo.folder_name()).join("snapshots/0123abcd").join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, vec![0u8; size_bytes]).unwrap();
+        path.display().to_string()
+    }
+
+    #[test]
+    fn cache_listing_counts_shared_files_once() {
+        const MB: usize = 1024 * 1024;
+        let dir = tempfile::tempdir().unwrap();
+        let hub = hf_hub::Cache::new(dir.path().to_path_buf());
+        let local = dir.path().join("local.gguf");
+        std::fs::write(&local, vec![0u8; MB]).unwrap();
+        let settings = Settings::from_toml(&format!(
+            r#"
+            [models.a]
+            arch = "llama"
+            repo = "org/a-GGUF"
+            file = "a.gguf"
+            tokenizer_repo = "org/base"
+            tokenizer_file = "tokenizer.json"
+            [models.b]
+            arch = "llama"
+            repo = "org/b-GGUF"
+            file = "b.gguf"
+            tokenizer_repo = "org/base"
+            tokenizer_file = "tokenizer.json"
+            [models.c]
+            arch = "llama"
+            repo = "org/c-GGUF"
+            file = "c.gguf"
+            tokenizer_repo = "org/c"
+            tokenizer_file = "tokenizer.json"
+            [models.local]
+            arch = "llama"
+            local_path = "{}"
+            tokenizer_local_path = "{}"
+            "#,
+            local.display(),
+            local.display()
+        ))
+        .unwrap();
+        cache_file(&hub, "org/a-GGUF", "a.gguf", 3 * MB);
+        let tokenizer = cache_file(&hub, "org/base", "tokenizer.json", 2 * MB);
+
+        let listing = cache_listing(&settings, &hub);
+        // b has only the shared tokenizer, c nothing, and local files are not the cache's
+        let models: Vec<(&str, u64)> = listing.models.iter().map(|model| (model.name.as_str(), model.size_mb)).collect();
+        assert_eq!(models, [("a", 5), ("b", 2)]);
+        assert_eq!(listing.total_size_mb, 5);
+        let kinds: Vec<&str> = listing.models[0].files.iter().map(|file| file.kind).collect();
+        assert_eq!(kinds, ["weights", "tokenizer"]);
+        assert_eq!(listing.models[1].files[0].path, tokenizer);
+        assert_eq!(listing.models[1].files[0].size_bytes, 2 * MB as u64);
+    }
+
+    #[test]
+    fn deleting_a_model_keeps_a_tokenizer_another_model_uses() {
+        let dir = tempfile::tempdir().unwrap();
+        let hub = hf_hub::Cache::new(dir.path().to_path_buf());
+        let settings = Settings::from_toml(
+            r#"
+            [models.a]
+            arch = "llama"
+            repo = "org/a-GGUF"
+            file = "a.gguf"
+            tokenizer_repo = "org/base"
+            tokenizer_file = "tokenizer.json"
+            [models.b]
+            arch = "llama"
+            repo = "org/b-GGUF"
+            file = "b.gguf"
+            tokenizer_repo = "org/base"
+            tokenizer_file = "tokenizer.json"
+            "#,
+        )
+        .unwrap();
+        let a_weights = cache_file(&hub, "org/a-GGUF", "a.gguf", 1);
+        let b_weights = cache_file(&hub, "org/b-GGUF", "b.gguf", 1);
+        let tokenizer = cache_file(&hub, "org/base", "tokenizer.json", 1);
+
+        let paths = |files: Vec<model::CachedFile>| files.into_iter().map(|file| file.path).collect::<Vec<_>>();
+        let (delete, kept) = cache_deletion_plan(&settings, "a", &hub);
+        assert_eq!(paths(delete), [a_weights]);
+        assert_eq!(paths(kept), [tokenizer.as_str()]);
+
+        // With a gone from the config, b is the only user and owns the tokenizer
+        let only_b = Settings::from_toml(
+            "[models.b]\narch = \"llama\"\nrepo = \"org/b-GGUF\"\nfile = \"b.gguf\"\ntokenizer_repo = \"org/base\"\ntokenizer_file = \"tokenizer.json\"\n",
+        )
+        .unwrap();
+        let (delete, kept) = cache_deletion_plan(&only_b, "b", &hub);
+        assert_eq!(paths(delete), [b_weights, tokenizer]);
+        assert!(kept.is_empty());
+    }
+
+    #[tokio::test]
+    async fn health_reports_the_cache_size_last_measured() {
+        const MB: usize = 1024 * 1024;
+        let dir = tempfile::tempdir().unwrap();
+        let hub = hf_hub::Cache::new(dir.path().to_path_buf());
+        let settings = Settings::from_toml(
+            "[models.a]\narch = \"llama\"\nrepo = \"org/a-GGUF\"\nfile = \"a.gguf\"\ntokenizer_repo = \"org/base\"\ntokenizer_file = \"tokenizer.json\"\n",
+        )
+        .unwrap();
+        let state = app_state(settings, Vec::new(), BTreeMap::new()).0;
+        let health_size = || async { call_json(&state, "GET", "/health", None).await.1["cache_size_mb"].clone() };
+
+        let weights = cache_file(&hub, "org/a-GGUF", "a.gguf", 3 * MB);
+        cache_file(&hub, "org/base", "tokenizer.json", 2 * MB);
+        // Files appearing on disk change nothing until the next refresh
+        assert_eq!(health_size().await, 0);
+        refresh_cache_size(&state, hub.clone()).await;
+        assert_eq!(health_size().await, 5);
+        assert_eq!(call_json(&state, "GET", "/stats", None).await.1["cache_size_mb"], 5);
+
+        std::fs::remove_file(weights).unwrap();
+        refresh_cache_size(&state, hub).await;
+        assert_eq!(health_size().await, 2);
+    }
 }
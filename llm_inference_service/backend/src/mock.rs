@@ -0,0 +1,63 @@
+// src/mock.rs
+// Stand-in model for running the service without weights or a GPU (arch = "mock").
+// Each token is picked from a hash of everything in the context before it, so the same
+// prompt always gives the same text, however the prompt was fed in. Pair it with the
+// word-level tokenizer in mock/.
+use anyhow::{Context, Result, bail};
+use candle_core::Tensor;
+
+// Ids below this are special in the bundled tokenizer (<unk>, </s>) and never emitted
+const FIRST_WORD_ID: u32 = 2;
+// Logit of the chosen token; every other token gets 0, so sampling always picks it
+const CHOSEN_LOGIT: f32 = 100.0;
+// FNV-1a parameters
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+pub struct MockModel {
+    vocab_size: usize,
+    cached_len: usize, // Positions in the "KV cache", i.e. tokens seen since the last position 0
+    context_hash: u64, // FNV-1a hash of those tokens
+    forward_calls: usize,
+}
+
+impl MockModel {
+    pub fn new(vocab_size: usize) -> Self {
+        Self { vocab_size, cached_len: 0, context_hash: FNV_OFFSET, forward_calls: 0 }
+    }
+
+    // Same contract as candle's quantized models: `input` is [1, seq] placed at `start_pos`,
+    // the result is [1, vocab] logits for the position after it. Position 0 starts a new
+    // context; any other position must continue the cached one exactly.
+    pub fn forward(&mut self, input: &Tensor, start_pos: usize) -> Result<Tensor> {
+        let ids: Vec<u32> = input.squeeze(0)?.to_vec1().context("mock: input is not [1, seq] u32")?;
+        self.forward_calls += 1;
+        if start_pos == 0 {
+            self.cached_len = 0;
+            self.context_hash = FNV_OFFSET;
+        } else if start_pos != self.cached_len {
+            bail!("mock: input placed at position {} but the KV cache holds {} tokens", start_pos, self.cached_len);
+        }
+        for &id in &ids {
+            self.context_hash = (self.context_hash ^ id as u64).wrapping_mul(FNV_PRIME);
+        }
+        self.cached_len += ids.len();
+        let words = (self.vocab_size as u64).saturating_sub(FIRST_WORD_ID as u64).max(1);
+        let next = FIRST_WORD_ID + (self.context_hash.rotate_left(32) % words) as u32;
+        let mut logits = vec![0f32; self.vocab_size];
+        logits[next as usize] = CHOSEN_LOGIT;
+        Ok(Tensor::new(logits, input.device())?.unsqueeze(0)?)
+    }
+
+    // Tokens in the KV cache
+    #[cfg(test)]
+    pub fn cached_len(&self) -> usize {
+        self.cached_len
+    }
+
+    // Number of forward passes run so far
+    #[cfg(test)]
+    pub fn forward_calls(&self) -> usize {
+        self.forward_calls
+    }
+}
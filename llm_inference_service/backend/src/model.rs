@@ -1,29 +1,78 @@
 // src/model.rs
 use anyhow::{Error as E, Result};
 use candle_core::Device;
-use candle_core::quantized::gguf_file::Content; 
+use candle_core::quantized::gguf_file::Content;
 
 // Import model architectures
 use candle_transformers::models::quantized_phi::ModelWeights as QPhiModel;
-use candle_transformers::models::quantized_llama::ModelWeights as QMistralModel;
+use candle_transformers::models::quantized_llama::ModelWeights as QLlamaModel;
+use candle_transformers::models::bert::{self, BertModel};
+use candle_nn::VarBuilder;
 
-use hf_hub::{api::sync::Api, Repo, RepoType};
-use tokenizers::Tokenizer;
+use hf_hub::{api::sync::{ApiBuilder, ApiError}, Cache, Repo, RepoType};
+use serde::Serialize;
+use utoipa::ToSchema;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokenizers::{PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
 
-#[path = "config.rs"]
-mod config;
-use config::Settings;
+use crate::config::{DeviceSpec, ModelConfig};
+use crate::infer::default_stop_token_ids;
+use crate::mock::MockModel;
+use crate::template::ChatTemplate;
 
+// One variant per candle implementation. "mistral" and "llama3" GGUFs both run on
+// quantized_llama; they differ only in chat template and stop tokens, which follow `arch`.
 pub enum ModelEnum {
     Phi(QPhiModel),
-    Mistral(QMistralModel),
-    Llama3(QMistralModel),
+    Llama(QLlamaModel),
+    Embedding(BertModel), // Sentence embeddings only; cannot generate text
+    Mock(MockModel), // Deterministic fake for running without weights or a GPU
+}
+
+// `arch` of BERT-style sentence-embedding models (safetensors, served by /embeddings)
+pub const EMBEDDING_ARCH: &str = "bert";
+
+pub fn is_embedding_arch(arch: &str) -> bool {
+    arch == EMBEDDING_ARCH
+}
+
+// `arch` of the mock model, which needs only a tokenizer
+pub const MOCK_ARCH: &str = "mock";
+
+pub fn is_mock_arch(arch: &str) -> bool {
+    arch == MOCK_ARCH
 }
 
 pub struct LoadedModel {
     pub model: ModelEnum,
     pub tokenizer: Tokenizer,
     pub device: Device,
+    pub arch: String, // "phi", "mistral" or "llama3" from config.toml
+    // Token ids whose keys/values are currently in the model's KV cache
+    pub cached_tokens: Vec<u32>,
+    // Template from the GGUF metadata; None means the built-in template for `arch`
+    pub chat_template: Option<ChatTemplate>,
+    // Context length the GGUF was trained with, if its metadata says
+    pub context_length: Option<usize>,
+    // Generation ends on any of these; from config.toml, else the defaults for `arch`
+    pub stop_token_ids: Vec<u32>,
+}
+
+// Header facts of a GGUF file, read without loading any tensor
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct GgufInfo {
+    pub architecture: Option<String>,
+    pub context_length: Option<usize>,
+    pub embedding_length: Option<usize>,
+    pub block_count: Option<usize>,
+    pub vocab_size: Option<usize>,
+    pub parameter_count: u64,
+    pub quantization: String,
 }
 
 fn pick_device() -> Device {
@@ -57,33 +106,430 @@ fn pick_device() -> Device {
     }
 }
 
+// Context window when config.toml does not set max_context. candle's quantized llama
+// precomputes rotary embeddings for 4096 positions; phi-2 was trained with 2048.
+pub fn default_max_context(arch: &str) -> usize {
+    match arch {
+        "phi" => 2048,
+        _ => 4096,
+    }
+}
+
+// Name of a GGUF `general.file_type` (llama.cpp's LLAMA_FTYPE values)
+fn file_type_name(file_type: u64) -> Option<&'static str> {
+    let name = match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        _ => return None,
+    };
+    Some(name)
+}
+
+// Summarize the metadata and tensor table of a parsed GGUF header
+pub fn gguf_info(content: &Content) -> GgufInfo {
+    let meta = |key: &str| content.metadata.get(key);
+    let number = |key: &str| meta(key).and_then(|v| v.to_u64().ok()).map(|v| v as usize);
+    let architecture = meta("general.architecture").and_then(|v| v.to_string().ok()).cloned();
+    // Per-architecture keys are prefixed with its name, e.g. llama.context_length
+    let arch_number = |key: &str| architecture.as_ref().and_then(|arch| number(&format!("{}.{}", arch, key)));
+    // Without general.file_type, name the dtype most tensors use
+    let quantization = match meta("general.file_type").and_then(|v| v.to_u64().ok()).and_then(file_type_name) {
+        Some(name) => name.to_string(),
+        None => {
+            let mut counts: HashMap<String, usize> = HashMap::new();
+            for info in content.tensor_infos.values() {
+                *counts.entry(format!("{:?}", info.ggml_dtype)).or_default() += 1;
+            }
+            counts.into_iter().max_by_key(|(_, count)| *count).map_or_else(|| "unknown".to_string(), |(dtype, _)| dtype)
+        }
+    };
+    GgufInfo {
+        context_length: arch_number("context_length"),
+        embedding_length: arch_number("embedding_length"),
+        block_count: arch_number("block_count"),
+        vocab_size: meta("tokenizer.ggml.tokens").and_then(|v| v.to_vec().ok()).map(Vec::len),
+        parameter_count: content.tensor_infos.values().map(|info| info.shape.elem_count() as u64).sum(),
+        quantization,
+        architecture,
+    }
+}
+
+// Read only the header (metadata and tensor table) of a GGUF file
+pub fn read_gguf_info(path: &Path) -> Result<GgufInfo> {
+    let mut file = std::fs::File::open(path)?;
+    Ok(gguf_info(&Content::read(&mut file)?))
+}
+
+// Path of `file` from `repo` in the hf-hub cache, if it has been downloaded; never downloads
+fn hub_cached_path(cache: &Cache, repo: &str, file: &str) -> Option<PathBuf> {
+    if repo.is_empty() || file.is_empty() {
+        return None;
+    }
+    cache.repo(Repo::new(repo.to_string(), RepoType::Model)).get(file)
+}
+
+// Weights file of a model if it is already on disk; never downloads
+pub fn cached_weights_path(conf: &ModelConfig) -> Option<PathBuf> {
+    match &conf.local_path {
+        Some(local) => Some(PathBuf::from(local)).filter(|path| path.is_file()),
+        None => hub_cached_path(&Cache::default(), &conf.repo, &conf.file),
+    }
+}
+
+// A file of a model in the hf-hub cache
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct CachedFile {
+    pub kind: &'static str, // "weights", "tokenizer" or "config"
+    pub path: String,
+    pub size_bytes: u64,
+    pub last_access_secs: Option<u64>, // Unix time, when the filesystem records it
+}
+
+// Files of a model that hf-hub has downloaded. Files configured by local path belong to the
+// user and are not part of the cache.
+pub fn cached_files(conf: &ModelConfig, cache: &Cache) -> Vec<CachedFile> {
+    let config_file = conf.config_file.as_deref().unwrap_or("config.json");
+    let sources = [
+        ("weights", conf.local_path.is_none(), &conf.repo, conf.file.as_str()),
+        ("tokenizer", conf.tokenizer_local_path.is_none(), &conf.tokenizer_repo, conf.tokenizer_file.as_str()),
+        ("config", is_embedding_arch(&conf.arch) && conf.config_local_path.is_none(), &conf.repo, config_file),
+    ];
+    sources
+        .into_iter()
+        .filter(|&(_, from_hub, _, _)| from_hub)
+        .filter_map(|(kind, _, repo, file)| {
+            let path = hub_cached_path(cache, repo, file)?;
+            // The cache entry is a symlink; its blob holds the size and access time
+            let metadata = std::fs::metadata(&path).ok()?;
+            let last_access_secs = metadata
+                .accessed()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|since| since.as_secs());
+            Some(CachedFile {
+                kind,
+                path: path.display().to_string(),
+                size_bytes: metadata.len(),
+                last_access_secs,
+            })
+        })
+        .collect()
+}
+
+// Create the device a model asked for; an explicit device that is unavailable is an error
+fn select_device(spec: DeviceSpec) -> Result<Device> {
+    let device = match spec {
+        DeviceSpec::Auto => pick_device(),
+        DeviceSpec::Cpu => Device::Cpu,
+        DeviceSpec::Cuda(index) => Device::new_cuda(index).map_err(|e| {
+            E::msg(format!("Requested device cuda:{} is not available: {}", index, e))
+        })?,
+        DeviceSpec::Metal => Device::new_metal(0)
+            .map_err(|e| E::msg(format!("Requested device metal is not available: {}", e)))?,
+    };
+    Ok(device)
+}
+
+// Chat template embedded in the GGUF, if any. Special tokens are looked up by the ids the
+// metadata gives. A template that does not compile or render is ignored.
+fn read_chat_template(content: &Content, tokenizer: &Tokenizer) -> Option<ChatTemplate> {
+    let source = content.metadata.get("tokenizer.chat_template")?.to_string().ok()?.clone();
+    let token = |key: &str| {
+        content.metadata.get(key)
+            .and_then(|id| id.to_u32().ok())
+            .and_then(|id| tokenizer.id_to_token(id))
+            .unwrap_or_default()
+    };
+    let bos_token = token("tokenizer.ggml.bos_token_id");
+    let eos_token = token("tokenizer.ggml.eos_token_id");
+    match ChatTemplate::new(source, bos_token, eos_token) {
+        Ok(template) => {
+            println!("Using the chat template from the GGUF metadata");
+            Some(template)
+        }
+        Err(e) => {
+            println!("Ignoring the GGUF chat template: {:#}", e);
+            None
+        }
+    }
+}
+
+// Tries per HF download; download_attempts in config.toml replaces it at startup
+const DEFAULT_DOWNLOAD_ATTEMPTS: u32 = 3;
+static DOWNLOAD_ATTEMPTS: AtomicU32 = AtomicU32::new(DEFAULT_DOWNLOAD_ATTEMPTS);
+// Wait before the first retry, doubled for each one after it
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+pub fn set_download_attempts(attempts: u32) {
+    DOWNLOAD_ATTEMPTS.store(attempts, Ordering::Relaxed);
+}
+
+// hf_token from config.toml, set once at startup
+static HF_TOKEN: OnceLock<String> = OnceLock::new();
+
+pub fn set_hf_token(token: String) {
+    let _ = HF_TOKEN.set(token);
+}
+
+// Token sent with HF downloads: hf_token, else the HF_TOKEN environment variable.
+// None leaves hf-hub to read the token saved by `huggingface-cli login`, if any.
+fn hf_token() -> Option<String> {
+    HF_TOKEN
+        .get()
+        .cloned()
+        .or_else(|| std::env::var("HF_TOKEN").ok().filter(|token| !token.trim().is_empty()))
+}
+
+// Whether a failed download may succeed if tried again: connection trouble, timeouts, rate
+// limits and server errors may; a missing file (404) or refused access (401/403) will not
+fn is_retryable(e: &ApiError) -> bool {
+    match e {
+        ApiError::RequestError(e) => match e.as_ref() {
+            ureq::Error::Status(code, _) => matches!(code, 408 | 429) || *code >= 500,
+            ureq::Error::Transport(_) => true,
+        },
+        // Includes a connection dropped mid-file, which resumes from the partial download
+        ApiError::IoError(_) | ApiError::TooManyRetries(_) => true,
+        _ => false,
+    }
+}
+
+// Run a download, retrying transient failures with exponential backoff
+fn with_retries<T>(what: &str, download: impl FnMut() -> Result<T, ApiError>) -> Result<T> {
+    retry(what, DOWNLOAD_ATTEMPTS.load(Ordering::Relaxed), DOWNLOAD_RETRY_DELAY, download)
+}
+
+// with_retries with the attempt count and first delay given
+fn retry<T>(what: &str, attempts: u32, mut delay: Duration, mut download: impl FnMut() -> Result<T, ApiError>) -> Result<T> {
+    let attempts = attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        match download() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < attempts && is_retryable(&e) => {
+                println!("Downloading {} failed (attempt {}/{}): {}; retrying in {:?}", what, attempt, attempts, e, delay);
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(download_error(what, e)),
+        }
+    }
+}
+
+// A refused download names the likely cause instead of the bare HTTP status
+fn download_error(what: &str, e: ApiError) -> E {
+    if let ApiError::RequestError(inner) = &e
+        && let ureq::Error::Status(code @ (401 | 403), _) = inner.as_ref()
+    {
+        let cause = if hf_token().is_some() { "token invalid or without access to this repo" } else { "authentication required" };
+        return E::msg(format!(
+            "failed to download {}: {} (HTTP {}); set hf_token in config.toml or HF_TOKEN, and accept the model's license on huggingface.co",
+            what, cause, code
+        ));
+    }
+    E::msg(format!("failed to download {}: {}", what, e))
+}
+
+// Use a local file if configured (checking it exists), otherwise fetch `file` from the HF `repo`.
+// The hf-hub .get() call downloads the file if not present, or returns the cached path.
+fn resolve_file(local_path: Option<&str>, repo: &str, file: &str, what: &str) -> Result<PathBuf> {
+    if let Some(local) = local_path {
+        let path = Path::new(local);
+        if !path.is_file() {
+            return Err(E::msg(format!("Local {} file '{}' does not exist", what, local)));
+        }
+        return Ok(path.to_path_buf());
+    }
+    if repo.is_empty() || file.is_empty() {
+        return Err(E::msg(format!("No {} source configured: set a local path or a repo and file", what)));
+    }
+    let mut builder = ApiBuilder::new();
+    if let Some(token) = hf_token() {
+        builder = builder.with_token(Some(token));
+    }
+    let api = builder.build()?;
+    let hf_repo = api.repo(Repo::new(repo.to_string(), RepoType::Model));
+    with_retries(&format!("{} from {}", file, repo), || hf_repo.get(file))
+}
+
+// Path of the GGUF weights for a model
+pub fn resolve_weights_path(conf: &ModelConfig) -> Result<PathBuf> {
+    resolve_file(conf.local_path.as_deref(), &conf.repo, &conf.file, "model")
+}
+
+// Hex SHA-256 of a file, read in chunks so multi-GB weights are never held in memory
+fn sha256_hex(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// Delete a downloaded file: the hf-hub cache entry is a symlink to a blob, so remove both
+pub fn remove_download(path: &Path) -> Result<()> {
+    if let Ok(blob) = std::fs::canonicalize(path)
+        && blob != path
+    {
+        std::fs::remove_file(blob)?;
+    }
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+// Path of the weights for a model, downloaded if needed and checked before any load.
+// With `sha256` configured the file must match it; a mismatching download is deleted and
+// fetched once more. Without one, a GGUF must at least have a header that parses.
+pub fn fetch_verified_weights(conf: &ModelConfig) -> Result<PathBuf> {
+    let path = resolve_weights_path(conf)?;
+    let Some(expected) = conf.sha256.as_deref().map(str::to_ascii_lowercase) else {
+        if !is_embedding_arch(&conf.arch) {
+            let mut file = std::fs::File::open(&path)?;
+            if let Err(e) = Content::read(&mut file) {
+                return Err(E::msg(format!(
+                    "{} appears corrupted, try /models/<name>/download again ({})",
+                    path.display(),
+                    e
+                )));
+            }
+        }
+        return Ok(path);
+    };
+    let mut actual = sha256_hex(&path)?;
+    // A local file is the user's own copy: never delete it
+    if actual != expected && conf.local_path.is_none() {
+        println!("Checksum mismatch for {}, downloading it again", path.display());
+        remove_download(&path)?;
+        let path = resolve_weights_path(conf)?;
+        actual = sha256_hex(&path)?;
+        if actual == expected {
+            return Ok(path);
+        }
+    }
+    if actual != expected {
+        return Err(E::msg(format!(
+            "checksum mismatch for {}: expected sha256 {}, got {}",
+            path.display(),
+            expected,
+            actual
+        )));
+    }
+    Ok(path)
+}
+
+// Path of the config.json of an embedding model, next to its weights
+fn resolve_config_path(conf: &ModelConfig) -> Result<PathBuf> {
+    let file = conf.config_file.as_deref().unwrap_or("config.json");
+    resolve_file(conf.config_local_path.as_deref(), &conf.repo, file, "config")
+}
+
+// Load a BERT-style model from safetensors. The tokenizer is set up to pad each batch to its
+// longest input and to truncate inputs to the model's maximum length.
+fn load_embedding_model(conf: &ModelConfig, weights: &Path, device: &Device, tokenizer: &mut Tokenizer) -> Result<BertModel> {
+    let config: bert::Config = serde_json::from_str(&std::fs::read_to_string(resolve_config_path(conf)?)?)?;
+    // Keep the tokenizer's own pad token if it defines one
+    let padding = tokenizer.get_padding().cloned().unwrap_or_default();
+    tokenizer.with_padding(Some(PaddingParams { strategy: PaddingStrategy::BatchLongest, ..padding }));
+    tokenizer
+        .with_truncation(Some(TruncationParams {
+            max_length: config.max_position_embeddings,
+            ..Default::default()
+        }))
+        .map_err(E::msg)?;
+    // Safety: the weights file is not modified while it is mapped
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights], bert::DTYPE, device)? };
+    Ok(BertModel::load(vb, &config)?)
+}
+
+// Path of the tokenizer.json for a model
+pub fn resolve_tokenizer_path(conf: &ModelConfig) -> Result<PathBuf> {
+    resolve_file(conf.tokenizer_local_path.as_deref(), &conf.tokenizer_repo, &conf.tokenizer_file, "tokenizer")
+}
+
 impl LoadedModel {
-    pub fn load(name: &str) -> Result<Self> {
-        // Select available computing device
-    let device = pick_device();
-    println!("Loading model '{}' on {:?}...", name, device);
-
-        // Load Configuration
-        let settings = Settings::new()?;
-        
-        // Find specific model config by name
-        let model_conf = settings.models.get(name)
-            .ok_or_else(|| E::msg(format!("Model '{}' not found in config.toml", name)))?;
+    // Forget what the KV cache holds. This only clears our record of it: candle 0.8's quantized
+    // phi and llama have no way to clear their cache, and instead replace it on a forward at
+    // position 0 (LayerWeights::forward_attn concatenates to it only when index_pos > 0). So
+    // after a reset the caller must prefill the whole prompt from position 0, as run_inference
+    // does; if a candle upgrade changes that behaviour, earlier requests would leak into later
+    // ones. The mock model follows the same rule, and the tests in infer.rs check it.
+    pub fn reset_cache(&mut self) {
+        self.cached_tokens.clear();
+    }
+
+    // Context window: the configured one, else the GGUF's own, capped at what candle supports for the arch
+    pub fn max_context(&self, configured: Option<usize>) -> usize {
+        let supported = default_max_context(&self.arch);
+        configured.unwrap_or_else(|| self.context_length.map_or(supported, |len| len.min(supported)))
+    }
+
+    // Load model `name` as configured by `model_conf` onto `device`
+    pub fn load(name: &str, model_conf: &ModelConfig, device: DeviceSpec) -> Result<Self> {
         println!("Config found: Arch={}, Repo={}", model_conf.arch, model_conf.repo);
 
-        // Download Files using Config
-        let api = Api::new()?;
-        
+        // Create the device the server placed the model on
+        let device = select_device(device)?;
+        println!("Loading model '{}' on {:?}...", name, device);
+
         // Fetch Tokenizer
-        let tokenizer_repo = api.repo(Repo::new(model_conf.tokenizer_repo.clone(), RepoType::Model));
-        let tokenizer_filename = tokenizer_repo.get(&model_conf.tokenizer_file)?;
-        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+        let tokenizer_filename = resolve_tokenizer_path(model_conf)?;
+        let mut tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+
+        if is_mock_arch(&model_conf.arch) {
+            let stop_token_ids = model_conf
+                .stop_token_ids
+                .clone()
+                .unwrap_or_else(|| default_stop_token_ids(&model_conf.arch, &tokenizer));
+            return Ok(Self {
+                model: ModelEnum::Mock(MockModel::new(tokenizer.get_vocab_size(true))),
+                tokenizer,
+                device,
+                arch: model_conf.arch.clone(),
+                cached_tokens: Vec::new(),
+                chat_template: None,
+                context_length: None,
+                stop_token_ids,
+            });
+        }
 
         // Fetch Weights
-        let model_repo = api.repo(Repo::new(model_conf.repo.clone(), RepoType::Model));
-        let model_filename = model_repo.get(&model_conf.file)?;
+        let model_filename = resolve_weights_path(model_conf)?;
+
+        // Embedding models are safetensors; everything else is GGUF
+        if is_embedding_arch(&model_conf.arch) {
+            let model = load_embedding_model(model_conf, &model_filename, &device, &mut tokenizer)?;
+            return Ok(Self {
+                model: ModelEnum::Embedding(model),
+                tokenizer,
+                device,
+                arch: model_conf.arch.clone(),
+                cached_tokens: Vec::new(),
+                chat_template: None,
+                context_length: None,
+                stop_token_ids: Vec::new(),
+            });
+        }
         let mut file = std::fs::File::open(&model_filename)?;
         let content = Content::read(&mut file)?;
+        let chat_template = read_chat_template(&content, &tokenizer);
+        let context_length = gguf_info(&content).context_length;
+        let stop_token_ids = model_conf
+            .stop_token_ids
+            .clone()
+            .unwrap_or_else(|| default_stop_token_ids(&model_conf.arch, &tokenizer));
 
         // Load Model based on Architecture defined in Config
         let model_enum = match model_conf.arch.as_str() {
@@ -91,13 +537,9 @@ impl LoadedModel {
                 let model = QPhiModel::from_gguf(content, &mut file, &device)?;
                 ModelEnum::Phi(model)
             },
-            "mistral" => {
-                let model = QMistralModel::from_gguf(content, &mut file, &device)?;
-                ModelEnum::Mistral(model)
-            },
-            "llama3" => {
-                let model = QMistralModel::from_gguf(content, &mut file, &device)?;
-                ModelEnum::Llama3(model)
+            "mistral" | "llama3" => {
+                let model = QLlamaModel::from_gguf(content, &mut file, &device)?;
+                ModelEnum::Llama(model)
             },
             _ => return Err(E::msg(format!("Architecture '{}' not supported", model_conf.arch))),
         };
@@ -106,6 +548,69 @@ impl LoadedModel {
             model: model_enum,
             tokenizer,
             device,
+            arch: model_conf.arch.clone(),
+            cached_tokens: Vec::new(),
+            chat_template,
+            context_length,
+            stop_token_ids,
         })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(code: u16) -> ApiError {
+        let response = ureq::Response::new(code, "status", "").unwrap();
+        ApiError::RequestError(Box::new(ureq::Error::Status(code, response)))
+    }
+
+    fn dropped() -> ApiError {
+        ApiError::IoError(std::io::Error::from(std::io::ErrorKind::ConnectionReset))
+    }
+
+    // Run `retry` against a server answering with `failures` in turn, then success;
+    // returns the result and the number of tries made
+    fn run(attempts: u32, failures: Vec<ApiError>) -> (Result<&'static str>, usize) {
+        let mut failures = failures.into_iter();
+        let mut tries = 0;
+        let result = retry("file", attempts, Duration::ZERO, || {
+            tries += 1;
+            failures.next().map_or(Ok("done"), Err)
+        });
+        (result, tries)
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        for code in [408, 429, 500, 503] {
+            assert!(is_retryable(&status(code)), "HTTP {}", code);
+        }
+        assert!(is_retryable(&dropped()));
+        let (result, tries) = run(3, vec![status(503), dropped()]);
+        assert_eq!(result.unwrap(), "done");
+        assert_eq!(tries, 3);
+    }
+
+    #[test]
+    fn retries_stop_after_the_last_attempt() {
+        let (result, tries) = run(3, vec![status(503), status(503), status(503), status(503)]);
+        assert!(result.is_err());
+        assert_eq!(tries, 3);
+        // 0 attempts still makes one
+        let (result, tries) = run(0, vec![status(503)]);
+        assert!(result.is_err());
+        assert_eq!(tries, 1);
+    }
+
+    #[test]
+    fn missing_or_refused_files_are_not_retried() {
+        for code in [401, 403, 404] {
+            assert!(!is_retryable(&status(code)), "HTTP {}", code);
+            let (result, tries) = run(3, vec![status(code)]);
+            assert_eq!(tries, 1, "HTTP {}", code);
+            let err = result.unwrap_err().to_string();
+            assert_eq!(err.contains("set hf_token in config.toml"), code != 404, "{}", err);
+        }
+    }
+}
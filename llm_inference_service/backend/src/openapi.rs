@@ -0,0 +1,157 @@
+// src/openapi.rs
+// OpenAPI description of the HTTP API, served at /openapi.json and browsable at /docs
+use utoipa::{
+    Modify, OpenApi,
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+};
+
+// Event formats of /infer_stream, one `data:` line each, in order
+pub const STREAM_EVENTS: &str = "Server-sent events, each a `data:` line:\n\
+1. With `echo: true`, `{\"prompt\": ..., \"prompt_tokens\": ...}`\n\
+2. `[MODEL: <name>]`\n\
+3. Per token, `{\"text\": ...}` plus `\"logprobs\"` (a TokenLogprob) when requested; every 16 tokens \
+also `{\"tokens_generated\": n}`, which carries no text\n\
+4. `{\"finish_reason\": ..., \"stats\": GenerationStats, \"timing\": ...}` followed by \
+`{\"usage\": {\"prompt_tokens\": ..., \"completion_tokens\": ..., \"total_tokens\": ...}}`, \
+or `{\"error\": <message>}` if generation failed\n\
+5. `[DONE]`\n\n\
+If no model is active, the model cannot chat or it is not loaded, the stream is just `{\"error\": <message>}` and `[DONE]`.";
+
+// Swagger UI page; the assets come from a CDN, so the server ships no JS
+pub const DOCS_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>LLM Inference Service API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "LLM Inference Service", description = "Load GGUF models and run inference over HTTP."),
+    paths(
+        crate::health_handler,
+        crate::list_models,
+        crate::openai_models_handler,
+        crate::stats_handler,
+        crate::set_model,
+        crate::switch_model_handler,
+        crate::load_model_handler,
+        crate::job_handler,
+        crate::model_details_handler,
+        crate::download_model_handler,
+        crate::cache_handler,
+        crate::delete_cache_handler,
+        crate::clear_response_cache_handler,
+        crate::unload_model_handler,
+        crate::reset_handler,
+        crate::infer_handler,
+        crate::compare_handler,
+        crate::preview_prompt_handler,
+        crate::embeddings_handler,
+        crate::openai_embeddings_handler,
+        crate::tokenize_handler,
+        crate::detokenize_handler,
+        crate::infer_stream_handler,
+        crate::create_session_handler,
+        crate::get_session_handler,
+        crate::delete_session_handler,
+        crate::session_message_handler,
+        crate::benchmark_handler,
+        crate::admin_shutdown_handler,
+    ),
+    modifiers(&AdminKey),
+    tags(
+        (name = "server", description = "Health, statistics and administration"),
+        (name = "models", description = "Loading, downloading and selecting models"),
+        (name = "inference", description = "Generation, embeddings and tokenization"),
+        (name = "sessions", description = "Conversations kept on the server"),
+        (name = "openai", description = "OpenAI-compatible listings"),
+    )
+)]
+pub struct ApiDoc;
+
+// Bearer token checked by /admin/shutdown (admin_api_key in config.toml)
+struct AdminKey;
+
+impl Modify for AdminKey {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "admin_api_key",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use axum::body::{Body, to_bytes};
+    use axum::http::{Request, StatusCode};
+    use std::collections::BTreeMap;
+    use tower::ServiceExt;
+
+    // Every optional route turned on
+    fn all_routes_settings() -> Settings {
+        Settings::from_toml(
+            "enable_benchmark = true\nadmin_api_key = \"key\"\nwarmup = false\n[models.mock]\narch = \"mock\"\n",
+        )
+        .unwrap()
+    }
+
+    // "/jobs/:id" as OpenAPI writes it, "/jobs/{id}"
+    fn openapi_path(route: &str) -> String {
+        route
+            .split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(param) => format!("{{{}}}", param),
+                None => segment.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[test]
+    fn every_route_is_documented() {
+        let spec = ApiDoc::openapi();
+        let routes: Vec<String> = crate::api_routes(&all_routes_settings())
+            .into_iter()
+            .map(|(route, _)| openapi_path(route))
+            .collect();
+        for path in &routes {
+            assert!(spec.paths.paths.contains_key(path), "{} is served but not in the OpenAPI spec", path);
+        }
+        for path in spec.paths.paths.keys() {
+            assert!(routes.contains(path), "{} is in the OpenAPI spec but not served", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn every_documented_method_is_routed() {
+        let (state, _load_jobs) = crate::app_state(all_routes_settings(), Vec::new(), BTreeMap::new());
+        for (path, item) in ApiDoc::openapi().paths.paths {
+            let methods = [("GET", &item.get), ("POST", &item.post), ("DELETE", &item.delete)];
+            for (method, _) in methods.into_iter().filter(|(_, operation)| operation.is_some()) {
+                let uri = path.replace("{id}", "x").replace("{name}", "x");
+                let request = Request::builder().method(method).uri(&uri).body(Body::empty()).unwrap();
+                let response = crate::router(state.clone()).oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                // Unrouted requests get axum's bare 404 or 405; a handler's own 404 has a body
+                assert_ne!(status, StatusCode::METHOD_NOT_ALLOWED, "{} {} is not routed", method, path);
+                assert!(status != StatusCode::NOT_FOUND || !body.is_empty(), "{} {} is not routed", method, path);
+            }
+        }
+    }
+}
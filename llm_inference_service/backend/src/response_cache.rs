@@ -0,0 +1,230 @@
+// src/response_cache.rs
+// Finished generations of seeded requests, replayed when the identical request comes again.
+// With a fixed seed, sampling is deterministic: the same model, prompt and options produce the
+// same tokens, so a repeat can be answered without touching the model. Off unless
+// response_cache_size is set in config.toml.
+use crate::infer::{FinishReason, GenerationStats, TokenEvent};
+use serde::Serialize;
+use shared_types::InferRequest;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use utoipa::ToSchema;
+
+// One completion as it was generated
+#[derive(Clone)]
+pub struct CachedChoice {
+    pub events: Vec<TokenEvent>, // In order, so streams replay token by token
+    pub finish_reason: FinishReason,
+    pub stats: GenerationStats,
+}
+
+// Least recently used entries are evicted once `capacity` is reached
+pub struct ResponseCache {
+    capacity: usize,
+    entries: HashMap<u64, Entry>,
+    clock: u64, // Bumped on every access; orders entries by recency
+    hits: u64,
+    misses: u64,
+}
+
+struct Entry {
+    choices: Vec<CachedChoice>,
+    last_used: u64,
+}
+
+// Counters reported by GET /stats
+#[derive(Serialize, ToSchema)]
+pub struct ResponseCacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, entries: HashMap::new(), clock: 0, hits: 0, misses: 0 }
+    }
+
+    // Completions stored under `key`, marked as cached; counts a hit or a miss
+    pub fn get(&mut self, key: u64) -> Option<Vec<CachedChoice>> {
+        self.clock += 1;
+        let Some(entry) = self.entries.get_mut(&key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        entry.last_used = self.clock;
+        let mut choices = entry.choices.clone();
+        for choice in &mut choices {
+            choice.stats.cached = true;
+            choice.stats.prefill_skipped = 0;
+        }
+        Some(choices)
+    }
+
+    pub fn insert(&mut self, key: u64, choices: Vec<CachedChoice>) {
+        self.clock += 1;
+        if !self.entries.contains_key(&key)
+            && self.entries.len() >= self.capacity
+            && let Some(oldest) = self.entries.iter().min_by_key(|(_, e)| e.last_used).map(|(k, _)| *k)
+        {
+            self.entries.remove(&oldest);
+        }
+        self.entries.insert(key, Entry { choices, last_used: self.clock });
+    }
+
+    // Drop every entry; returns how many there were. The counters keep running.
+    pub fn clear(&mut self) -> usize {
+        let cleared = self.entries.len();
+        self.entries.clear();
+        cleared
+    }
+
+    pub fn stats(&self) -> ResponseCacheStats {
+        ResponseCacheStats {
+            entries: self.entries.len(),
+            capacity: self.capacity,
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
+
+// Key of a request on a model, or None when the request set no seed and its output cannot be
+// reproduced. The raw prompt, turns and system prompt stand for the rendered prompt, which is
+// a function of them and the model's config. Fields that do not change the output are left out.
+pub fn cache_key(model: &str, req: &InferRequest) -> Option<u64> {
+    req.seed?;
+    let mut keyed = req.clone();
+    keyed.echo = false;
+    keyed.timeout_secs = None;
+    if keyed.n.is_some_and(|n| n <= 1) {
+        keyed.n = None;
+    }
+    // Through a Value, whose maps are sorted, so logit_bias hashes the same in any order
+    let json = serde_json::to_value(&keyed).ok()?.to_string();
+    let mut hasher = DefaultHasher::new();
+    (model, json).hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+// Only generations that ran to their natural end are worth replaying
+pub fn is_cacheable(finish_reason: FinishReason) -> bool {
+    matches!(finish_reason, FinishReason::Stop | FinishReason::Length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One completion whose single event carries `text`
+    fn choice(text: &str) -> Vec<CachedChoice> {
+        vec![CachedChoice {
+            events: vec![TokenEvent { text: text.to_string(), logprob: None, generated_tokens: 1 }],
+            finish_reason: FinishReason::Stop,
+            stats: GenerationStats {
+                seed: 1,
+                prompt_tokens: 3,
+                generated_tokens: 1,
+                prefill_skipped: 2,
+                context_dropped_tokens: 0,
+                cached: false,
+                coalesced: false,
+            },
+        }]
+    }
+
+    fn text(cache: &mut ResponseCache, key: u64) -> Option<String> {
+        cache.get(key).map(|choices| choices[0].events[0].text.clone())
+    }
+
+    fn seeded(prompt: &str) -> InferRequest {
+        InferRequest { prompt: prompt.to_string(), seed: Some(7), ..Default::default() }
+    }
+
+    #[test]
+    fn hits_are_marked_cached() {
+        let mut cache = ResponseCache::new(2);
+        cache.insert(1, choice("a"));
+        let hit = cache.get(1).unwrap();
+        assert!(hit[0].stats.cached);
+        assert_eq!(hit[0].stats.prefill_skipped, 0);
+    }
+
+    #[test]
+    fn the_least_recently_used_entry_is_evicted() {
+        let mut cache = ResponseCache::new(2);
+        cache.insert(1, choice("a"));
+        cache.insert(2, choice("b"));
+        // Reading 1 makes 2 the oldest
+        assert_eq!(text(&mut cache, 1).as_deref(), Some("a"));
+        cache.insert(3, choice("c"));
+        assert_eq!(text(&mut cache, 2), None);
+        assert_eq!(text(&mut cache, 1).as_deref(), Some("a"));
+        assert_eq!(text(&mut cache, 3).as_deref(), Some("c"));
+        // Replacing an entry evicts nothing
+        cache.insert(3, choice("c2"));
+        assert_eq!(cache.stats().entries, 2);
+        assert_eq!(text(&mut cache, 1).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn hits_and_misses_are_counted() {
+        let mut cache = ResponseCache::new(4);
+        assert!(cache.get(1).is_none());
+        cache.insert(1, choice("a"));
+        cache.get(1);
+        cache.get(1);
+        cache.get(2);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.capacity, stats.hits, stats.misses), (1, 4, 2, 2));
+        // Clearing drops the entries but not the counters
+        assert_eq!(cache.clear(), 1);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (0, 2, 2));
+    }
+
+    #[test]
+    fn key_needs_a_seed() {
+        assert!(cache_key("m", &InferRequest { seed: None, ..seeded("hi") }).is_none());
+        assert!(cache_key("m", &seeded("hi")).is_some());
+    }
+
+    #[test]
+    fn key_ignores_what_does_not_change_the_output() {
+        let base = cache_key("m", &seeded("hi"));
+        let same = [
+            InferRequest { echo: true, ..seeded("hi") },
+            InferRequest { timeout_secs: Some(5), ..seeded("hi") },
+            InferRequest { n: Some(1), ..seeded("hi") },
+        ];
+        for req in same {
+            assert_eq!(cache_key("m", &req), base, "{:?}", req);
+        }
+        // logit_bias is a map: the order its entries were inserted in does not matter
+        let bias = |pairs: &[(u32, f32)]| {
+            let mut map = HashMap::with_capacity(pairs.len());
+            map.extend(pairs.iter().copied());
+            InferRequest { logit_bias: Some(map), ..seeded("hi") }
+        };
+        let pairs: Vec<(u32, f32)> = (0..32).map(|id| (id, id as f32)).collect();
+        let reversed: Vec<(u32, f32)> = pairs.iter().rev().copied().collect();
+        assert_eq!(cache_key("m", &bias(&pairs)), cache_key("m", &bias(&reversed)));
+    }
+
+    #[test]
+    fn key_changes_with_the_output() {
+        let base = cache_key("m", &seeded("hi"));
+        let different = [
+            cache_key("other", &seeded("hi")),
+            cache_key("m", &seeded("hello")),
+            cache_key("m", &InferRequest { seed: Some(8), ..seeded("hi") }),
+            cache_key("m", &InferRequest { temperature: Some(0.2), ..seeded("hi") }),
+            cache_key("m", &InferRequest { n: Some(2), ..seeded("hi") }),
+        ];
+        for key in different {
+            assert_ne!(key, base);
+        }
+    }
+}
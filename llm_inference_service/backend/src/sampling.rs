@@ -0,0 +1,377 @@
+// src/sampling.rs
+// Turn the logits of the last position into the next token.
+// Sampling runs as a pipeline: logit transforms (in the order they are added), then a final
+// sampler. Temperature and top-p stay inside candle's LogitsProcessor as the final stage, so
+// with no transforms configured the output for a fixed seed is bit-for-bit what it was before
+// (candle does the softmax on the model's device).
+use crate::infer::InferenceParams;
+use anyhow::{Context, Result};
+use candle_core::Tensor;
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use rand::distr::{Distribution, weighted::WeightedIndex};
+use rand::{SeedableRng, rngs::StdRng};
+use std::collections::HashMap;
+
+// One step that rewrites the logits before sampling (penalties, bans, biases...)
+pub trait LogitsTransform: Send {
+    // `generated` holds the tokens generated so far in this request
+    fn apply(&mut self, logits: &mut Vec<f32>, generated: &[u32]);
+}
+
+// Last stage of the pipeline, which picks the token
+enum FinalSampler {
+    // Temperature then top-p, or argmax when temperature is ~0 or negative
+    Candle(LogitsProcessor),
+    Mirostat(Mirostat),
+}
+
+pub struct SamplerPipeline {
+    transforms: Vec<Box<dyn LogitsTransform>>,
+    sampler: FinalSampler,
+}
+
+impl SamplerPipeline {
+    pub fn from_params(params: &InferenceParams, seed: u64, tokenizer: &tokenizers::Tokenizer) -> Result<Self> {
+        let sampler = match params.mirostat_tau {
+            // Mirostat replaces temperature/top-p
+            Some(tau) => FinalSampler::Mirostat(Mirostat::new(seed, tau, params.mirostat_eta.unwrap_or(0.1))),
+            None => {
+                // temperature for randomness
+                // top-p for diversity
+                let temp = params.temperature.unwrap_or(0.7);
+                let top_p = params.top_p.unwrap_or(0.9);
+                let sampling = if temp < 1e-7 {
+                    // Greedy (0 or negative): the same prompt gives the same text whatever the seed
+                    Sampling::ArgMax
+                } else {
+                    Sampling::TopP { p: top_p, temperature: temp }
+                };
+                FinalSampler::Candle(LogitsProcessor::from_sampling(seed, sampling))
+            }
+        };
+        let mut pipeline = Self {
+            transforms: Vec::new(),
+            sampler,
+        };
+        if let Some(bias) = &params.logit_bias {
+            pipeline.push(LogitBias(bias.clone()));
+        }
+        if let Some(banned) = &params.banned_strings {
+            pipeline.push(BannedStrings::new(tokenizer, banned)?);
+        }
+        if let Some(size) = params.no_repeat_ngram_size.filter(|&size| size > 0) {
+            pipeline.push(NoRepeatNgram { size, window: params.repeat_last_n });
+        }
+        Ok(pipeline)
+    }
+
+    // Append a transform; transforms run in insertion order
+    pub fn push(&mut self, transform: impl LogitsTransform + 'static) {
+        self.transforms.push(Box::new(transform));
+    }
+
+    // Sample the next token from the F32 logits of the last position
+    pub fn sample(&mut self, logits: &Tensor, generated: &[u32]) -> Result<u32> {
+        match &mut self.sampler {
+            // Nothing to rewrite: hand the tensor to candle untouched
+            FinalSampler::Candle(processor) if self.transforms.is_empty() => processor
+                .sample(logits)
+                .context("logits_processor.sample failed"),
+            FinalSampler::Candle(processor) => {
+                let mut values: Vec<f32> = logits.to_vec1()?;
+                apply_transforms(&mut self.transforms, &mut values, generated);
+                let logits = Tensor::new(values, logits.device())?;
+                processor
+                    .sample(&logits)
+                    .context("logits_processor.sample failed")
+            }
+            FinalSampler::Mirostat(mirostat) => {
+                let mut values: Vec<f32> = logits.to_vec1().context("mirostat: logits to_vec1 failed")?;
+                apply_transforms(&mut self.transforms, &mut values, generated);
+                mirostat.sample(&values)
+            }
+        }
+    }
+}
+
+// Add a fixed value to the logits of chosen token ids
+pub struct LogitBias(pub HashMap<u32, f32>);
+
+impl LogitsTransform for LogitBias {
+    fn apply(&mut self, logits: &mut Vec<f32>, _generated: &[u32]) {
+        for (&id, &bias) in &self.0 {
+            if let Some(logit) = logits.get_mut(id as usize) {
+                *logit += bias;
+            }
+        }
+    }
+}
+
+// Never complete one of the banned strings. Each string is tokenized (with and without a
+// leading space, as BPE vocabularies differ there); a single-token string is always banned,
+// a longer one has its last token banned whenever the output ends with the rest of it.
+pub struct BannedStrings {
+    sequences: Vec<Vec<u32>>,
+}
+
+impl BannedStrings {
+    pub fn new(tokenizer: &tokenizers::Tokenizer, banned: &[String]) -> Result<Self> {
+        let mut sequences = Vec::new();
+        for text in banned.iter().filter(|t| !t.is_empty()) {
+            for variant in [text.clone(), format!(" {}", text)] {
+                let ids = tokenizer
+                    .encode(variant, false)
+                    .map_err(anyhow::Error::msg)
+                    .with_context(|| format!("failed to tokenize banned string {:?}", text))?
+                    .get_ids()
+                    .to_vec();
+                if !ids.is_empty() && !sequences.contains(&ids) {
+                    sequences.push(ids);
+                }
+            }
+        }
+        Ok(Self { sequences })
+    }
+}
+
+impl LogitsTransform for BannedStrings {
+    fn apply(&mut self, logits: &mut Vec<f32>, generated: &[u32]) {
+        for sequence in &self.sequences {
+            let (last, prefix) = sequence.split_last().expect("banned sequences are non-empty");
+            if generated.ends_with(prefix)
+                && let Some(logit) = logits.get_mut(*last as usize)
+            {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+// Ban every token that would repeat an n-gram of `size` tokens already in the output
+// (within the last `window` tokens, if set). A size of 1 bans every token seen so far.
+pub struct NoRepeatNgram {
+    pub size: usize,
+    pub window: Option<usize>,
+}
+
+impl LogitsTransform for NoRepeatNgram {
+    fn apply(&mut self, logits: &mut Vec<f32>, generated: &[u32]) {
+        let start = self.window.map_or(0, |window| generated.len().saturating_sub(window));
+        let recent = &generated[start..];
+        if recent.len() < self.size {
+            return;
+        }
+        let prefix = &generated[generated.len() - (self.size - 1)..];
+        for ngram in recent.windows(self.size) {
+            let (last, head) = ngram.split_last().expect("n-gram size is at least 1");
+            if head == prefix
+                && let Some(logit) = logits.get_mut(*last as usize)
+            {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+fn apply_transforms(transforms: &mut [Box<dyn LogitsTransform>], logits: &mut Vec<f32>, generated: &[u32]) {
+    for transform in transforms.iter_mut() {
+        transform.apply(logits, generated);
+    }
+}
+
+// Mirostat v2 sampler: each step keeps only tokens whose surprise (-log2 p) is at most `mu`,
+// samples among them, then moves `mu` toward keeping the observed surprise near `tau`
+pub struct Mirostat {
+    tau: f64,
+    eta: f64,
+    mu: f64,
+    rng: StdRng,
+}
+
+impl Mirostat {
+    pub fn new(seed: u64, tau: f64, eta: f64) -> Self {
+        Self {
+            tau,
+            eta,
+            mu: 2.0 * tau,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    // Sample from raw logits of the last position
+    pub fn sample(&mut self, logits: &[f32]) -> Result<u32> {
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let exp: Vec<f64> = logits.iter().map(|&l| ((l - max) as f64).exp()).collect();
+        let sum: f64 = exp.iter().sum();
+        let probs: Vec<f64> = exp.iter().map(|e| e / sum).collect();
+        Ok(self.sample_probs(&probs)?.0)
+    }
+
+    // Sample from a probability distribution; returns the token and its observed surprise
+    pub fn sample_probs(&mut self, probs: &[f64]) -> Result<(u32, f64)> {
+        let mut candidates: Vec<(usize, f64)> = probs
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, p)| p > 0.0 && -p.log2() <= self.mu)
+            .collect();
+        // Always keep at least the most likely token
+        if candidates.is_empty() {
+            let best = probs
+                .iter()
+                .copied()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .context("mirostat: empty distribution")?;
+            candidates.push(best);
+        }
+        let total: f64 = candidates.iter().map(|&(_, p)| p).sum();
+        let dist = WeightedIndex::new(candidates.iter().map(|&(_, p)| p))
+            .context("mirostat: invalid distribution")?;
+        let (token, p) = candidates[dist.sample(&mut self.rng)];
+        // Surprise under the truncated distribution drives the update
+        let surprise = -(p / total).log2();
+        self.mu -= self.eta * (surprise - self.tau);
+        Ok((token as u32, surprise))
+    }
+}
+
+// Natural-log probabilities of every token, computed stably from raw logits
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f64 = logits.iter().map(|&l| ((l - max) as f64).exp()).sum();
+    let log_sum = max + sum.ln() as f32;
+    logits.iter().map(|&l| l - log_sum).collect()
+}
+
+// The `k` highest values with their token ids, most likely first
+pub fn top_k(logprobs: &[f32], k: usize) -> Vec<(u32, f32)> {
+    let mut ranked: Vec<(u32, f32)> = logprobs.iter().copied().enumerate().map(|(id, lp)| (id as u32, lp)).collect();
+    let k = k.min(ranked.len());
+    if k == 0 {
+        return Vec::new();
+    }
+    ranked.select_nth_unstable_by(k - 1, |a, b| b.1.total_cmp(&a.1));
+    ranked.truncate(k);
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    fn mock_tokenizer() -> tokenizers::Tokenizer {
+        tokenizers::Tokenizer::from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/mock/tokenizer.json")).unwrap()
+    }
+
+    fn id(tokenizer: &tokenizers::Tokenizer, word: &str) -> u32 {
+        tokenizer.token_to_id(word).unwrap()
+    }
+
+    // Indices of the logits a transform set to -inf
+    fn banned(transform: &mut impl LogitsTransform, vocab: usize, generated: &[u32]) -> Vec<u32> {
+        let mut logits = vec![0.0; vocab];
+        transform.apply(&mut logits, generated);
+        (0..vocab as u32).filter(|&id| logits[id as usize] == f32::NEG_INFINITY).collect()
+    }
+
+    #[test]
+    fn logit_bias_shifts_and_bans() {
+        let mut logits = vec![1.0, 2.0, 3.0];
+        LogitBias(HashMap::from([(0, 0.5), (2, f32::NEG_INFINITY), (7, 1.0)])).apply(&mut logits, &[]);
+        assert_eq!(logits, [1.5, 2.0, f32::NEG_INFINITY]);
+    }
+
+    #[test]
+    fn pipeline_never_samples_a_token_biased_to_minus_infinity() {
+        let params = InferenceParams {
+            temperature: Some(0.0),
+            logit_bias: Some(HashMap::from([(2, f32::NEG_INFINITY)])),
+            ..Default::default()
+        };
+        let mut pipeline = SamplerPipeline::from_params(&params, 0, &mock_tokenizer()).unwrap();
+        let logits = Tensor::new(&[0.0f32, 1.0, 5.0, 2.0], &Device::Cpu).unwrap();
+        assert_eq!(pipeline.sample(&logits, &[]).unwrap(), 3);
+    }
+
+    #[test]
+    fn banned_strings_ban_the_last_token_after_the_rest() {
+        let tokenizer = mock_tokenizer();
+        let banned_list = ["yes".to_string(), "hello world".to_string()];
+        let mut bans = BannedStrings::new(&tokenizer, &banned_list).unwrap();
+        let vocab = tokenizer.get_vocab_size(true);
+        let (yes, hello, world) = (id(&tokenizer, "yes"), id(&tokenizer, "hello"), id(&tokenizer, "world"));
+        // A single-token string is banned everywhere, a longer one only right after its prefix
+        assert_eq!(banned(&mut bans, vocab, &[]), [yes]);
+        assert_eq!(banned(&mut bans, vocab, &[hello]), [world, yes]);
+        assert_eq!(banned(&mut bans, vocab, &[hello, world]), [yes]);
+    }
+
+    #[test]
+    fn no_repeat_ngram_masks_the_token_that_would_repeat() {
+        let mut bigrams = NoRepeatNgram { size: 2, window: None };
+        // "5 6" was generated and the output ends in 5, so 6 would repeat it
+        assert_eq!(banned(&mut bigrams, 10, &[5, 6, 7, 5]), [6]);
+        assert_eq!(banned(&mut bigrams, 10, &[5, 6, 7, 8]), Vec::<u32>::new());
+        // Size 1 bans everything seen so far
+        let mut unigrams = NoRepeatNgram { size: 1, window: None };
+        assert_eq!(banned(&mut unigrams, 10, &[3, 1, 3]), [1, 3]);
+        // The window only looks back that far
+        let mut windowed = NoRepeatNgram { size: 2, window: Some(3) };
+        assert_eq!(banned(&mut windowed, 10, &[5, 6, 7, 8, 5]), Vec::<u32>::new());
+        assert_eq!(banned(&mut windowed, 10, &[1, 5, 6, 5]), [6]);
+    }
+
+    #[test]
+    fn mirostat_moves_mu_toward_the_target() {
+        // Uniform over 16 tokens: every pick has a surprise of 4 bits
+        let uniform = vec![1.0 / 16.0; 16];
+        let mut above = Mirostat::new(0, 2.0, 0.5); // mu starts at 2 * tau = 4
+        let (_, surprise) = above.sample_probs(&uniform).unwrap();
+        assert_eq!(surprise, 4.0);
+        assert_eq!(above.mu, 4.0 - 0.5 * (4.0 - 2.0));
+        let mut below = Mirostat::new(0, 6.0, 0.5);
+        below.sample_probs(&uniform).unwrap();
+        assert_eq!(below.mu, 12.0 - 0.5 * (4.0 - 6.0));
+    }
+
+    #[test]
+    fn mirostat_holds_the_average_surprise_near_tau() {
+        // Zipf-like over 1000 tokens, a long tail like real next-token distributions
+        let weights: Vec<f64> = (1..=1000).map(|rank| 1.0 / rank as f64).collect();
+        let total: f64 = weights.iter().sum();
+        let probs: Vec<f64> = weights.iter().map(|w| w / total).collect();
+        for tau in [3.0, 5.0, 7.0] {
+            let mut mirostat = Mirostat::new(42, tau, 0.1);
+            let steps = 400;
+            let mut surprise_sum = 0.0;
+            for _ in 0..steps {
+                surprise_sum += mirostat.sample_probs(&probs).unwrap().1;
+                assert!(mirostat.mu > 0.0 && mirostat.mu < 4.0 * tau, "tau {}: mu ran off to {}", tau, mirostat.mu);
+            }
+            let mean = surprise_sum / steps as f64;
+            assert!((mean - tau).abs() < 0.25, "tau {}: mean surprise {}", tau, mean);
+        }
+    }
+
+    #[test]
+    fn mirostat_keeps_the_most_likely_token_when_mu_excludes_all() {
+        let mut mirostat = Mirostat::new(0, 0.1, 0.1);
+        mirostat.mu = 0.0;
+        let (token, surprise) = mirostat.sample_probs(&[0.2, 0.5, 0.3]).unwrap();
+        assert_eq!((token, surprise), (1, 0.0));
+    }
+
+    #[test]
+    fn top_k_ranks_the_highest_first() {
+        let logprobs = log_softmax(&[0.0, 3.0, 1.0, 2.0]);
+        let sum: f32 = logprobs.iter().map(|lp| lp.exp()).sum();
+        assert!((sum - 1.0).abs() < 1e-6);
+        let ids: Vec<u32> = top_k(&logprobs, 2).into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, [1, 3]);
+        assert!(top_k(&logprobs, 0).is_empty());
+        assert_eq!(top_k(&logprobs, 9).len(), 4);
+    }
+}
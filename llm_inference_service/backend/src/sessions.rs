@@ -0,0 +1,202 @@
+// src/sessions.rs
+// Conversations kept on the server for /sessions, so thin clients send only the new message
+// each turn. A session holds text only: with prefix_cache enabled, the model's KV cache from the
+// previous turn is reused whenever the session was the last thing that model generated for.
+use serde::Serialize;
+use shared_types::{ChatRole, ChatTurn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use utoipa::ToSchema;
+
+// Idle time after which a session is dropped, unless session_idle_secs says otherwise
+pub const DEFAULT_SESSION_IDLE_SECS: u64 = 1800;
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct Session {
+    pub id: String,
+    pub model: String,
+    pub system_prompt: Option<String>,
+    pub turns: Vec<ChatTurn>, // Completed exchanges, oldest first
+    pub created_at: u64, // Unix seconds
+    pub last_used: u64, // Unix seconds of the last message; idle expiry counts from here
+    #[serde(skip)]
+    busy: bool, // A reply is being generated; a second message must wait for it
+}
+
+// Why a message could not start a turn
+pub enum TurnError {
+    NotFound,
+    Busy,
+}
+
+// All live sessions by id
+#[derive(Clone, Default)]
+pub struct Sessions(Arc<Mutex<HashMap<String, Session>>>);
+
+impl Sessions {
+    pub fn create(&self, model: String, system_prompt: Option<String>) -> Session {
+        let now = crate::now_secs();
+        let session = Session {
+            id: format!("{:032x}", rand::random::<u128>()),
+            model,
+            system_prompt,
+            turns: Vec::new(),
+            created_at: now,
+            last_used: now,
+            busy: false,
+        };
+        crate::lock(&self.0).insert(session.id.clone(), session.clone());
+        session
+    }
+
+    pub fn get(&self, id: &str) -> Option<Session> {
+        crate::lock(&self.0).get(id).cloned()
+    }
+
+    // A generation still running for a removed session finishes, but its reply is discarded
+    pub fn remove(&self, id: &str) -> bool {
+        crate::lock(&self.0).remove(id).is_some()
+    }
+
+    // Claim the session for one user message; returns a snapshot to generate from
+    pub fn begin_turn(&self, id: &str, prompt: String) -> Result<PendingTurn, TurnError> {
+        let mut sessions = crate::lock(&self.0);
+        let session = sessions.get_mut(id).ok_or(TurnError::NotFound)?;
+        if session.busy {
+            return Err(TurnError::Busy);
+        }
+        session.busy = true;
+        session.last_used = crate::now_secs();
+        Ok(PendingTurn {
+            sessions: self.clone(),
+            id: id.to_string(),
+            prompt,
+            model: session.model.clone(),
+            system_prompt: session.system_prompt.clone(),
+            history: session.turns.clone(),
+        })
+    }
+
+    // Drop sessions idle for longer than `idle_secs`; ones generating a reply are kept
+    pub fn expire(&self, idle_secs: u64) -> usize {
+        let now = crate::now_secs();
+        let mut sessions = crate::lock(&self.0);
+        let before = sessions.len();
+        sessions.retain(|_, s| s.busy || now.saturating_sub(s.last_used) < idle_secs);
+        before - sessions.len()
+    }
+}
+
+// A user message being answered. The turn is recorded only by complete(); dropping it
+// (cancelled or failed generation) leaves the transcript as it was and frees the session.
+pub struct PendingTurn {
+    sessions: Sessions,
+    id: String,
+    prompt: String,
+    pub model: String,
+    pub system_prompt: Option<String>,
+    pub history: Vec<ChatTurn>,
+}
+
+impl PendingTurn {
+    pub fn complete(self, reply: String) {
+        if let Some(session) = crate::lock(&self.sessions.0).get_mut(&self.id) {
+            session.turns.push(ChatTurn { role: ChatRole::User, content: self.prompt.clone() });
+            session.turns.push(ChatTurn { role: ChatRole::Assistant, content: reply });
+        }
+    }
+}
+
+impl Drop for PendingTurn {
+    fn drop(&mut self) {
+        if let Some(session) = crate::lock(&self.sessions.0).get_mut(&self.id) {
+            session.busy = false;
+            session.last_used = crate::now_secs();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pretend the session was last used `secs` ago
+    fn age(sessions: &Sessions, id: &str, secs: u64) {
+        crate::lock(&sessions.0).get_mut(id).unwrap().last_used -= secs;
+    }
+
+    fn turns(sessions: &Sessions, id: &str) -> Vec<(ChatRole, String)> {
+        sessions.get(id).unwrap().turns.into_iter().map(|turn| (turn.role, turn.content)).collect()
+    }
+
+    #[test]
+    fn completed_turns_are_appended_in_order() {
+        let sessions = Sessions::default();
+        let id = sessions.create("phi".to_string(), Some("Be brief.".to_string())).id;
+        for (prompt, reply) in [("hello", "hi"), ("and now?", "now this")] {
+            let turn = sessions.begin_turn(&id, prompt.to_string()).ok().unwrap();
+            assert_eq!(turn.model, "phi");
+            assert_eq!(turn.system_prompt.as_deref(), Some("Be brief."));
+            turn.complete(reply.to_string());
+        }
+        assert_eq!(
+            turns(&sessions, &id),
+            [
+                (ChatRole::User, "hello".to_string()),
+                (ChatRole::Assistant, "hi".to_string()),
+                (ChatRole::User, "and now?".to_string()),
+                (ChatRole::Assistant, "now this".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_turn_sees_the_history_before_it() {
+        let sessions = Sessions::default();
+        let id = sessions.create("phi".to_string(), None).id;
+        sessions.begin_turn(&id, "hello".to_string()).ok().unwrap().complete("hi".to_string());
+        let turn = sessions.begin_turn(&id, "again".to_string()).ok().unwrap();
+        assert_eq!(turn.history.len(), 2);
+    }
+
+    #[test]
+    fn one_turn_at_a_time() {
+        let sessions = Sessions::default();
+        let id = sessions.create("phi".to_string(), None).id;
+        let turn = sessions.begin_turn(&id, "hello".to_string()).ok().unwrap();
+        assert!(matches!(sessions.begin_turn(&id, "too soon".to_string()), Err(TurnError::Busy)));
+        // A dropped turn (cancelled or failed) frees the session and records nothing
+        drop(turn);
+        assert!(turns(&sessions, &id).is_empty());
+        assert!(sessions.begin_turn(&id, "hello".to_string()).is_ok());
+        assert!(matches!(sessions.begin_turn("missing", "x".to_string()), Err(TurnError::NotFound)));
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let sessions = Sessions::default();
+        let fresh = sessions.create("phi".to_string(), None).id;
+        let idle = sessions.create("phi".to_string(), None).id;
+        let busy = sessions.create("phi".to_string(), None).id;
+        age(&sessions, &idle, 2);
+        let _turn = sessions.begin_turn(&busy, "hello".to_string()).ok().unwrap();
+        age(&sessions, &busy, 2);
+
+        assert_eq!(sessions.expire(2), 1);
+        assert!(sessions.get(&fresh).is_some());
+        assert!(sessions.get(&idle).is_none());
+        // Generating a reply keeps a session however long it takes
+        assert!(sessions.get(&busy).is_some());
+    }
+
+    #[test]
+    fn a_reply_to_a_removed_session_is_discarded() {
+        let sessions = Sessions::default();
+        let id = sessions.create("phi".to_string(), None).id;
+        let turn = sessions.begin_turn(&id, "hello".to_string()).ok().unwrap();
+        assert!(sessions.remove(&id));
+        turn.complete("hi".to_string());
+        assert!(sessions.get(&id).is_none());
+        assert!(!sessions.remove(&id));
+    }
+}
@@ -1,46 +1,382 @@
 use leptos::*;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use gloo_net::http::Request;
 use futures::StreamExt;
 use wasm_streams::ReadableStream;
 use wasm_bindgen::JsCast;
 use wasm_bindgen::closure::Closure;
 use web_sys::{HtmlInputElement, FileReader, AbortController};
+// API bodies shared with the backend
+use shared_types::{
+    ApiResponse, ChatRole, ChatTurn, InferRequest, JobStatus, LoadJob, LoadModelRequest, ModelList, TokenizeRequest, TokenizeResponse,
+    UnloadModelRequest, sse::SseParser,
+};
 
-const API_BASE: &str = "http://127.0.0.1:8081";
+mod markdown;
+mod server;
+mod storage;
+
+use server::api_url;
+
+const DEFAULT_CONTEXT_LIMIT: usize = 4096; // Context window assumed for the token warning
+const DEFAULT_HISTORY_TURNS: usize = 10; // Earlier exchanges sent with each message
+// Sidebar presets as (name, temperature, top_p); "Balanced" is the default
+const SAMPLING_PRESETS: [(&str, f64, f64); 3] = [("Precise", 0.2, 0.8), ("Balanced", 0.7, 0.9), ("Creative", 1.0, 0.95)];
+// The chat counts as scrolled to the bottom within this many pixels of it
+const SCROLL_BOTTOM_THRESHOLD_PX: i32 = 40;
+// Messages this far above or below the viewport are still rendered
+const WINDOW_OVERSCAN_PX: f64 = 1500.0;
+// /health polling: this often while the server is up, backing off to the maximum while it is down
+const HEALTH_POLL_MS: u64 = 5_000;
+const HEALTH_POLL_MAX_MS: u64 = 30_000;
+// A stream silent for this long is taken as dead; the server sends a keep-alive every 15 s
+const STREAM_STALL_MS: u64 = 45_000;
 
 // --- Data Structures ---
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 struct ChatMessage {
-    id: u64, // id for each chat message
-    role: String, // User or AI
+    id: u64, // id for each chat message, from new_message_id
+    role: String, // User, AI or Error (a failed request, shown as its own bubble)
     content: String,
+    // Templated prompt echoed by the server, shown collapsed under AI replies
+    #[serde(default)]
+    prompt_sent: Option<String>,
+    // User messages: what was typed and attached, for Edit and Regenerate
+    #[serde(default)]
+    sent: Option<SentMessage>,
+    // AI messages: which model wrote the reply and how fast, shown under it
+    #[serde(default)]
+    stats: Option<ReplyStats>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ReplyStats {
+    model: String,
+    generated_tokens: u64,
+    tokens_per_sec: Option<f64>, // None for replies too short to measure
+}
+
+impl ReplyStats {
+    fn summary(&self) -> String {
+        let mut line = format!("{} tokens", self.generated_tokens);
+        if let Some(speed) = self.tokens_per_sec {
+            line.push_str(&format!(" · {:.1} tokens/s", speed));
+        }
+        if !self.model.is_empty() {
+            line.push_str(&format!(" · {}", self.model));
+        }
+        line
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct SentMessage {
+    text: String,
+    file_name: String,
+    file_content: String,
 }
 
-#[derive(Deserialize)]
-struct ModelListResponse {
-    // model list, key as model name, value as model settings
-    models: std::collections::HashMap<String, serde_json::Value>,
-    active: String,
+impl SentMessage {
+    fn prompt(&self) -> String {
+        build_prompt(&self.text, &self.file_name, &self.file_content)
+    }
 }
 
-#[derive(Serialize)]
-// load model request
-struct LoadModelRequest { name: String }
+// What the Retry button of the connection banner tries again
+#[derive(Clone, Debug, PartialEq)]
+enum Retry {
+    ServerCheck,       // Health check and model list
+    LoadModel(String), // Loading this model
+    UnloadModel(String), // Unloading this model
+    Reply(u64),        // The last reply of this conversation
+}
 
-#[derive(Deserialize)]
-// api response
-struct ApiResponse { status: String, message: Option<String> }
+// Id for a new message: the current time, bumped past the last id handed out. A reply that
+// replaces another (Regenerate) or two messages added in one millisecond still get distinct
+// ids, which the chat list is keyed by.
+fn new_message_id() -> u64 {
+    thread_local! {
+        static LAST_ID: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+    }
+    LAST_ID.with(|last| {
+        let id = (js_sys::Date::now() as u64).max(last.get() + 1);
+        last.set(id);
+        id
+    })
+}
 
-#[derive(Serialize)]
-struct InferRequest {
-    // inference request parameters
-    prompt: String,
+// Generation settings a conversation keeps, restored when switching to it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ChatParams {
     temperature: f64,
     top_p: f64,
     max_tokens: usize,
     seed: Option<u64>,
+    system_prompt: String,
+    // Earlier exchanges (a message and its reply) sent along, so the model remembers the conversation
+    #[serde(default = "default_history_turns")]
+    history_turns: usize,
+}
+
+impl Default for ChatParams {
+    fn default() -> Self {
+        ChatParams {
+            temperature: 0.7,
+            top_p: 0.9,
+            max_tokens: 200,
+            seed: None,
+            system_prompt: String::new(),
+            history_turns: DEFAULT_HISTORY_TURNS,
+        }
+    }
+}
+
+fn default_history_turns() -> usize {
+    DEFAULT_HISTORY_TURNS
+}
+
+// Preset the sliders are on, if any; moving either one off it makes the settings custom
+fn matching_preset(temperature: f64, top_p: f64) -> Option<&'static str> {
+    SAMPLING_PRESETS
+        .iter()
+        .find(|(_, t, p)| (t - temperature).abs() < 1e-9 && (p - top_p).abs() < 1e-9)
+        .map(|(name, _, _)| *name)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Conversation {
+    id: u64, // Creation time, so ids sort oldest first
+    title: String, // From the first user message; empty until one is sent
+    messages: Vec<ChatMessage>,
+    params: ChatParams,
+}
+
+impl Conversation {
+    fn new(params: ChatParams) -> Self {
+        Conversation { id: js_sys::Date::now() as u64, title: String::new(), messages: vec![], params }
+    }
+}
+
+// A conversation in an exported JSON file. `system_prompt` repeats the one in `params` for
+// readers of the file; files exported before `params` and `title` were added still import.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ExportedChat {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
     system_prompt: Option<String>,
+    #[serde(default)]
+    params: Option<ChatParams>,
+    messages: Vec<ChatMessage>,
+}
+
+// Earlier turns to send with the last message of a conversation, at most `max_turns` exchanges.
+// The greeting before the first user message is not a turn, failed requests are left out, and a
+// user message that got no reply is dropped so user and assistant turns alternate.
+fn conversation_history(messages: &[ChatMessage], max_turns: usize) -> Vec<ChatTurn> {
+    let earlier = &messages[..messages.len().saturating_sub(1)];
+    let mut turns: Vec<ChatTurn> = Vec::new();
+    for msg in earlier.iter().skip_while(|m| m.role != "User") {
+        let turn = match msg.role.as_str() {
+            "User" => ChatTurn {
+                role: ChatRole::User,
+                content: msg.sent.as_ref().map_or_else(|| msg.content.clone(), SentMessage::prompt),
+            },
+            "AI" => ChatTurn {
+                role: ChatRole::Assistant,
+                content: msg.content.strip_suffix("\n\n(stopped)").unwrap_or(&msg.content).to_string(),
+            },
+            _ => continue,
+        };
+        if turns.last().is_some_and(|last| last.role == turn.role) {
+            turns.pop();
+        }
+        turns.push(turn);
+    }
+    if turns.last().is_some_and(|last| last.role == ChatRole::User) {
+        turns.pop();
+    }
+    let keep_from = turns.len().saturating_sub(max_turns * 2);
+    turns.split_off(keep_from)
+}
+
+// Messages of the conversation on screen that are in the DOM, and the space standing in for the
+// ones before and after them
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct MessageWindow {
+    start: usize,
+    end: usize,
+    space_above: f64,
+    space_below: f64,
+}
+
+// Rough height in pixels of a message not shown yet: its padding plus a line per ~90 characters
+fn estimate_message_height(msg: &ChatMessage) -> f64 {
+    let lines: usize = msg.content.lines().map(|line| line.chars().count() / 90 + 1).sum();
+    60.0 + 26.0 * lines.max(1) as f64
+}
+
+// Messages to render for a viewport starting `top` pixels into the list, given each one's height
+fn visible_window(heights: &[f64], top: f64, viewport: f64) -> MessageWindow {
+    let from = top - WINDOW_OVERSCAN_PX;
+    let to = top + viewport + WINDOW_OVERSCAN_PX;
+    let (mut start, mut end) = (None, heights.len());
+    let mut y = 0.0;
+    for (index, height) in heights.iter().enumerate() {
+        if start.is_none() && y + height > from {
+            start = Some(index);
+        }
+        y += height;
+        if y >= to {
+            end = index + 1;
+            break;
+        }
+    }
+    // Scrolled below the last message, e.g. into the streaming reply: keep the last one
+    let start = start.unwrap_or(heights.len().saturating_sub(1)).min(end);
+    MessageWindow {
+        start,
+        end,
+        space_above: heights[..start].iter().sum(),
+        space_below: heights[end..].iter().sum(),
+    }
+}
+
+// When a message was created, for display: ids are creation times in milliseconds, except in
+// chats older than that
+fn message_time(id: u64) -> Option<js_sys::Date> {
+    // 2001-09-09, earlier than any message created with a time id
+    const FIRST_TIME_ID: u64 = 1_000_000_000_000;
+    (id >= FIRST_TIME_ID).then(|| js_sys::Date::new(&wasm_bindgen::JsValue::from_f64(id as f64)))
+}
+
+// "just now", "5 min ago", "3 h ago", then the date
+fn relative_time(then: &js_sys::Date, now: f64) -> String {
+    let minutes = ((now - then.get_time()) / 60_000.0).max(0.0) as u64;
+    match minutes {
+        0 => "just now".to_string(),
+        1..=59 => format!("{} min ago", minutes),
+        60..=1439 => format!("{} h ago", minutes / 60),
+        _ => String::from(then.to_locale_date_string("default", &wasm_bindgen::JsValue::UNDEFINED)),
+    }
+}
+
+// Sidebar title for a conversation: the first line of its first message, shortened
+fn conversation_title(text: &str) -> String {
+    const MAX_CHARS: usize = 40;
+    let line = text.lines().next().unwrap_or_default().trim();
+    if line.chars().count() > MAX_CHARS {
+        format!("{}…", line.chars().take(MAX_CHARS).collect::<String>())
+    } else {
+        line.to_string()
+    }
+}
+
+// Resolve after `ms` milliseconds
+async fn sleep_ms(ms: u64) {
+    let (tx, rx) = futures::channel::oneshot::channel();
+    set_timeout(move || { let _ = tx.send(()); }, std::time::Duration::from_millis(ms));
+    let _ = rx.await;
+}
+
+// Poll a queued model load until it finishes, showing its stage in the loading overlay
+async fn wait_for_load_job(job_id: u64, model_name: &str, set_overlay: WriteSignal<Option<String>>) -> Result<(), String> {
+    loop {
+        sleep_ms(500).await;
+        let res = Request::get(&api_url(&format!("/jobs/{}", job_id))).send().await.map_err(|e| e.to_string())?;
+        let body = res.json::<ApiResponse<LoadJob>>().await.map_err(|e| e.to_string())?;
+        let Some(job) = body.data else {
+            return Err(body.message.unwrap_or_default());
+        };
+        let stage = match job.status {
+            JobStatus::Pending => "Waiting to load",
+            JobStatus::Downloading => "Downloading",
+            JobStatus::Loading => "Loading",
+            JobStatus::Ready => return Ok(()),
+            JobStatus::Failed => return Err(job.message.unwrap_or_default()),
+        };
+        set_overlay.set(Some(format!("{} {}...", stage, model_name)));
+    }
+}
+
+// Used and budgeted MB of a GPU, from a "used/limit MB" entry of /models' vram_usage
+fn parse_vram_usage(usage: &str) -> Option<(usize, usize)> {
+    let (used, limit) = usage.trim_end_matches(" MB").split_once('/')?;
+    Some((used.trim().parse().ok()?, limit.trim().parse().ok()?))
+}
+
+// Write text to the system clipboard
+fn copy_to_clipboard(text: &str) {
+    if let Some(window) = web_sys::window() {
+        // Fire and forget, the browser resolves the promise asynchronously
+        let _ = window.navigator().clipboard().write_text(text);
+    }
+}
+
+// Collect the bodies of all fenced (```) code blocks in a message
+fn extract_code_blocks(content: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut current: Option<Vec<&str>> = None;
+    for line in content.lines() {
+        if line.trim_start().starts_with("```") {
+            // An opening fence starts a block, the next fence closes it
+            match current.take() {
+                Some(lines) => blocks.push(lines.join("\n")),
+                None => current = Some(Vec::new()),
+            }
+        } else if let Some(lines) = current.as_mut() {
+            lines.push(line);
+        }
+    }
+    // Keep an unterminated block (e.g. generation stopped mid-block)
+    if let Some(lines) = current {
+        if !lines.is_empty() {
+            blocks.push(lines.join("\n"));
+        }
+    }
+    blocks
+}
+
+// Prompt sent to the server: the typed text, with the attached file's content (if any) injected
+fn build_prompt(text: &str, file_name: &str, file_content: &str) -> String {
+    if !file_content.is_empty() {
+        format!("The user uploaded a file named '{}'.\n\nFile Content:\n```\n{}\n```\n\nUser Instruction:\n{}",
+            file_name, file_content, text)
+    } else {
+        text.to_string()
+    }
+}
+
+// Rough token count for when the tokenizer is unavailable (~4 characters per token)
+fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+// Render the chat as Markdown: the system prompt if any, then a heading per message, content
+// kept verbatim so code fences survive
+fn chat_to_markdown(history: &[ChatMessage], system_prompt: &str) -> String {
+    let mut markdown_text = String::new();
+    markdown_text.push_str("# Chat History Export\n\n");
+    if !system_prompt.is_empty() {
+        markdown_text.push_str(&format!("## System Prompt\n{}\n\n", system_prompt));
+    }
+    for msg in history {
+        let role_title = match msg.role.as_str() {
+            "User" => "## User",
+            "Error" => "## Error",
+            _ => "## AI",
+        };
+        markdown_text.push_str(&format!("{}\n{}\n\n", role_title, msg.content));
+        // An unterminated block (generation stopped mid-block) would swallow the next headings
+        if msg.content.lines().filter(|line| line.trim_start().starts_with("```")).count() % 2 == 1 {
+            markdown_text.push_str("```\n\n");
+        }
+        if let Some(stats) = &msg.stats {
+            markdown_text.push_str(&format!("*{}*\n\n", stats.summary()));
+        }
+    }
+    markdown_text
 }
 
 #[component]
@@ -66,6 +402,13 @@ fn App() -> impl IntoView {
     let (abort_controller, set_abort_controller) = create_signal::<Option<AbortController>>(None);
     // Handle the streaming text separately
     let (streaming_content, set_streaming_content) = create_signal("".to_string());
+    // Conversation the running reply belongs to; it is only shown there
+    let (streaming_conversation, set_streaming_conversation) = create_signal::<Option<u64>>(None);
+    // Generation speed of the running reply, from the stream's progress events
+    let (tokens_per_sec, set_tokens_per_sec) = create_signal::<Option<f64>>(None);
+    let (streamed_tokens, set_streamed_tokens) = create_signal(0u64); // Token events of the reply being streamed
+    // Server could not be reached: shown in a banner until dismissed or retried
+    let (connection_error, set_connection_error) = create_signal::<Option<(String, Retry)>>(None);
 
     let stop_generation = move || {
         if let Some(controller) = abort_controller.get_untracked() {
@@ -77,51 +420,169 @@ fn App() -> impl IntoView {
     };
 
     
-    // chat history box
-    let (chat_history, set_chat_history) = create_signal::<Vec<ChatMessage>>(
-        vec![
-            ChatMessage { 
-                id: js_sys::Date::now() as u64,
-                role: "AI".into(), 
-                content: "Hello! I am your local AI.".into(), 
+    // Conversations by id and the one on screen, restored from the last visit; a fresh session
+    // starts with a single conversation holding the greeting
+    let stored = storage::load_conversations().unwrap_or_else(|| {
+        let mut first = Conversation::new(ChatParams::default());
+        first.messages.push(ChatMessage { 
+            id: new_message_id(),
+            role: "AI".into(), 
+            content: "Hello! I am your local AI.".into(), 
+            prompt_sent: None,
+            sent: None,
+            stats: None,
+        });
+        storage::StoredConversations { active: first.id, conversations: [(first.id, first)].into() }
+    });
+    let initial_params = stored.conversations[&stored.active].params.clone();
+    let (conversations, set_conversations) = create_signal(stored.conversations);
+    let (active_conversation, set_active_conversation) = create_signal(stored.active);
+    create_effect(move |_| storage::save_conversations(&storage::StoredConversations {
+        active: active_conversation.get(),
+        conversations: conversations.get(),
+    }));
+    // chat history box: messages of the conversation on screen
+    let chat_history = create_memo(move |_| conversations.with(|all| {
+        all.get(&active_conversation.get()).map(|c| c.messages.clone()).unwrap_or_default()
+    }));
+    // Add a message to a conversation, which may no longer be the one on screen (or exist)
+    let push_message = move |conversation: u64, message: ChatMessage| {
+        set_conversations.update(|all| {
+            if let Some(c) = all.get_mut(&conversation) {
+                c.messages.push(message);
             }
-        ]
-    ); 
+        });
+    };
     
+    // Which copy button was just clicked, e.g. "<id>-text" or "<id>-code"
+    let (copied_key, set_copied_key) = create_signal::<Option<String>>(None);
+    let copy_message = move |key: String, text: String| {
+        copy_to_clipboard(&text);
+        set_copied_key.set(Some(key.clone()));
+        // Hide the confirmation after a moment unless another copy replaced it
+        set_timeout(
+            move || {
+                if copied_key.get_untracked().as_deref() == Some(key.as_str()) {
+                    set_copied_key.set(None);
+                }
+            },
+            std::time::Duration::from_millis(1500),
+        );
+    };
+
     let (user_input_text, set_user_input_text) = create_signal("".to_string()); // user input
     let (loading_overlay, set_loading_overlay) = create_signal::<Option<String>>(None); // add overlay when model is loading
 
-    // Model inference parameters
-    let (temperature, set_temperature) = create_signal(0.7);
-    let (top_p, set_top_p) = create_signal(0.9);
-    let (max_tokens, set_max_tokens) = create_signal(200);
-    let (seed, set_seed) = create_signal::<Option<u64>>(None);
-    let (system_prompt, set_system_prompt) = create_signal("".to_string());
+    // Model inference parameters of the conversation on screen
+    let (temperature, set_temperature) = create_signal(initial_params.temperature);
+    let (top_p, set_top_p) = create_signal(initial_params.top_p);
+    let (max_tokens, set_max_tokens) = create_signal(initial_params.max_tokens);
+    let (context_limit, set_context_limit) = create_signal(DEFAULT_CONTEXT_LIMIT);
+    let (max_prompt_chars, set_max_prompt_chars) = create_signal::<Option<usize>>(None); // server's cap, from /health
+    let (seed, set_seed) = create_signal::<Option<u64>>(initial_params.seed);
+    let (show_prompt, set_show_prompt) = create_signal(false); // show the prompt the model received
+    let (system_prompt, set_system_prompt) = create_signal(initial_params.system_prompt);
+    let (history_turns, set_history_turns) = create_signal(initial_params.history_turns);
+    let current_params = move || ChatParams {
+        temperature: temperature.get(),
+        top_p: top_p.get(),
+        max_tokens: max_tokens.get(),
+        seed: seed.get(),
+        system_prompt: system_prompt.get(),
+        history_turns: history_turns.get(),
+    };
+    // Parameter changes are kept with the conversation on screen
+    create_effect(move |_| {
+        let params = current_params();
+        let id = active_conversation.get_untracked();
+        let changed = conversations.with_untracked(|all| all.get(&id).is_some_and(|c| c.params != params));
+        if changed {
+            set_conversations.update(|all| {
+                if let Some(c) = all.get_mut(&id) {
+                    c.params = params;
+                }
+            });
+        }
+    });
+    // Show another conversation with its parameters; a reply still streaming stays with its own
+    let switch_conversation = move |id: u64| {
+        let Some(params) = conversations.with_untracked(|all| all.get(&id).map(|c| c.params.clone())) else {
+            return;
+        };
+        set_active_conversation.set(id);
+        set_temperature.set(params.temperature);
+        set_top_p.set(params.top_p);
+        set_max_tokens.set(params.max_tokens);
+        set_seed.set(params.seed);
+        set_system_prompt.set(params.system_prompt);
+        set_history_turns.set(params.history_turns);
+    };
+    // Generation settings back to their defaults; the system prompt is content, so it stays
+    let reset_params = move || {
+        let defaults = ChatParams::default();
+        set_temperature.set(defaults.temperature);
+        set_top_p.set(defaults.top_p);
+        set_max_tokens.set(defaults.max_tokens);
+        set_seed.set(defaults.seed);
+        set_history_turns.set(defaults.history_turns);
+    };
+    let params_are_default = move || {
+        let defaults = ChatParams::default();
+        ChatParams { system_prompt: String::new(), ..current_params() } == defaults
+    };
+    // A new conversation starts with the parameters currently set
+    let new_conversation = move || {
+        let conversation = Conversation::new(untrack(current_params));
+        let id = conversation.id;
+        set_conversations.update(|all| { all.insert(id, conversation); });
+        set_active_conversation.set(id);
+    };
+    let delete_conversation = move |id: u64| {
+        if streaming_conversation.get_untracked() == Some(id) {
+            stop_generation();
+        }
+        set_conversations.update(|all| { all.remove(&id); });
+        if active_conversation.get_untracked() == id {
+            match conversations.with_untracked(|all| all.keys().next_back().copied()) {
+                Some(newest) => switch_conversation(newest),
+                None => new_conversation(),
+            }
+        }
+    };
     // control chat history window
     let chat_history_ref = create_node_ref::<html::Div>();
     // control file import
     let (file_content, set_file_content) = create_signal("".to_string());
     let (file_name, set_file_name) = create_signal("".to_string());
     let file_input_ref = create_node_ref::<html::Input>();
-    // Export chat history into a markdown file
+    // Export chat history into a markdown or JSON file
+    let (export_format, set_export_format) = create_signal("md".to_string());
     let export_chat = move || {
         // Get current history
         let history = chat_history.get_untracked();
         if history.is_empty() { return; }
 
-        let mut markdown_text = String::new();
-        markdown_text.push_str("# Chat History Export\n\n");
-    
-        for msg in history {
-            let role_title = if msg.role == "User" { "## User" } else { "## AI" };
-            markdown_text.push_str(&format!("{}\n{}\n\n", role_title, msg.content));
-        }
+        let system_prompt = system_prompt.get_untracked().trim().to_string();
+        let format = export_format.get_untracked();
+        let (text, mime) = if format == "json" {
+            let export = ExportedChat {
+                title: conversations.with_untracked(|all| {
+                    all.get(&active_conversation.get_untracked()).map(|c| c.title.clone()).unwrap_or_default()
+                }),
+                system_prompt: (!system_prompt.is_empty()).then_some(system_prompt),
+                params: Some(untrack(current_params)),
+                messages: history,
+            };
+            (serde_json::to_string_pretty(&export).unwrap_or_default(), "application/json")
+        } else {
+            (chat_to_markdown(&history, &system_prompt), "text/markdown")
+        };
         // create a blob
         use web_sys::{Blob, BlobPropertyBag, Url, HtmlAnchorElement};
         let props = BlobPropertyBag::new();
-        props.set_type("text/markdown");
+        props.set_type(mime);
         // Convert rust string to js array
-        let parts = js_sys::Array::of1(&wasm_bindgen::JsValue::from_str(&markdown_text));
+        let parts = js_sys::Array::of1(&wasm_bindgen::JsValue::from_str(&text));
         
         if let Ok(blob) = Blob::new_with_str_sequence_and_options(&parts, &props) {
             // create a temporary URL
@@ -132,7 +593,7 @@ fn App() -> impl IntoView {
                             let anchor: HtmlAnchorElement = anchor.unchecked_into();
                             anchor.set_href(&url);
                             // Generate filename with timestamp
-                            let filename = format!("chat_history_{}.md", js_sys::Date::now() as u64);
+                            let filename = format!("chat_history_{}.{}", js_sys::Date::now() as u64, format);
                             anchor.set_download(&filename);
                             anchor.click();
                             let _ = Url::revoke_object_url(&url); // Cleanup
@@ -143,36 +604,304 @@ fn App() -> impl IntoView {
         }
     };
 
-    // Init
-    create_effect(move |_| {
-        spawn_local(async move {
-            // Health Check to set if server online
-            if Request::get(&format!("{}/health", API_BASE)).send().await.is_ok() {
-                set_is_online.set(true);
-                set_status_text.set("Server Online".to_string());
-            } else {
-                set_status_text.set("Server Offline".to_string());
+    // Open a JSON export as a new conversation. Messages keep their order, roles and ids; the
+    // greeting comes with them, since a new conversation starts empty.
+    let import_input_ref = create_node_ref::<html::Input>();
+    let import_chat = move |text: String| {
+        let imported = match serde_json::from_str::<ExportedChat>(&text) {
+            Ok(imported) => imported,
+            Err(e) => {
+                let message = format!("Not a chat exported as JSON: {}", e);
+                if let Some(window) = web_sys::window() {
+                    let _ = window.alert_with_message(&message);
+                }
+                return;
             }
+        };
+        // Older files have only the system prompt; the other parameters are the current ones
+        let params = imported.params.unwrap_or_else(|| ChatParams {
+            system_prompt: imported.system_prompt.clone().unwrap_or_default(),
+            ..untrack(current_params)
+        });
+        let mut conversation = Conversation::new(params);
+        // Ids are creation times, so a conversation made in the same millisecond would clash
+        while conversations.with_untracked(|all| all.contains_key(&conversation.id)) {
+            conversation.id += 1;
+        }
+        conversation.title = if !imported.title.is_empty() {
+            imported.title
+        } else {
+            imported.messages.iter()
+                .find(|m| m.role == "User")
+                .map(|m| conversation_title(&m.content))
+                .unwrap_or_default()
+        };
+        conversation.messages = imported.messages;
+        let id = conversation.id;
+        set_conversations.update(|all| { all.insert(id, conversation); });
+        switch_conversation(id);
+    };
+    let on_import_file = move |ev: web_sys::Event| {
+        let input: HtmlInputElement = event_target(&ev);
+        let Some(file) = input.files().and_then(|files| files.get(0)) else { return };
+        let reader = FileReader::new().unwrap();
+        let reader_c = reader.clone();
+        let onload = Closure::wrap(Box::new(move |_e: web_sys::Event| {
+            if let Some(text) = reader_c.result().ok().and_then(|res| res.as_string()) {
+                import_chat(text);
+            }
+        }) as Box<dyn FnMut(_)>);
+        reader.set_onload(Some(onload.as_ref().unchecked_ref()));
+        onload.forget(); // Keep closure alive
+        reader.read_as_text(&file).unwrap();
+        // The same file can be imported again
+        input.set_value("");
+    };
 
-            // Fetch Model list
-            if let Ok(res) = Request::get(&format!("{}/models", API_BASE)).send().await {
-                if let Ok(data) = res.json::<ModelListResponse>().await {
-                    let mut model_names: Vec<String> = data.models.into_keys().collect();
+    // Configured models with their load state and the VRAM in use, for the models panel
+    let (model_list, set_model_list) = create_signal::<Option<ModelList>>(None);
+    let refresh_models = move || {
+        spawn_local(async move {
+            if let Ok(res) = Request::get(&api_url("/models")).send().await {
+                if let Ok(data) = res.json::<ModelList>().await {
+                    let mut model_names: Vec<String> = data.models.keys().cloned().collect();
                     model_names.sort();
-                    set_models.set(model_names);
-                    set_active_model.set(data.active); // set current active model
+                    // Only on change, so a poll does not disturb the model select
+                    if data.active != active_model.get_untracked() {
+                        set_active_model.set(data.active.clone()); // set current active model
+                    }
+                    if model_names != models.get_untracked() {
+                        set_models.set(model_names); // after the active model, for the restore below
+                    }
+                    set_model_list.set(Some(data));
                 }
             }
         });
+    };
+
+    // Init
+    // The banner for an unreachable server is shown once per outage, not at every poll
+    let offline_reported = store_value(false);
+    // Health Check to set if server online. Coming (back) online fetches the models again.
+    let check_health = move || async move {
+        let was_online = is_online.get_untracked();
+        let Ok(res) = Request::get(&api_url("/health")).send().await else {
+            set_is_online.set(false);
+            set_status_text.set("Server Offline".to_string());
+            if !offline_reported.get_value() {
+                offline_reported.set_value(true);
+                set_connection_error.set(Some((format!("Cannot reach the server at {}.", server::server_url()), Retry::ServerCheck)));
+            }
+            return false;
+        };
+        set_is_online.set(true);
+        set_status_text.set("Server Online".to_string());
+        offline_reported.set_value(false);
+        // Prompt length limit, if the server sets one
+        if let Ok(health) = res.json::<serde_json::Value>().await {
+            set_max_prompt_chars.set(health["max_prompt_chars"].as_u64().map(|n| n as usize));
+        }
+        if !was_online {
+            if matches!(connection_error.get_untracked(), Some((_, Retry::ServerCheck))) {
+                set_connection_error.set(None);
+            }
+            refresh_models();
+        }
+        true
+    };
+    // Check now, from scratch (startup, Retry, another server)
+    let check_server = move || {
+        set_is_online.set(false);
+        set_status_text.set("Checking server...".to_string());
+        offline_reported.set_value(false);
+        spawn_local(async move {
+            check_health().await;
+        });
+    };
+    create_effect(move |_| check_server());
+    // Keep following the server, e.g. one started after the page or restarted
+    spawn_local(async move {
+        let mut delay = HEALTH_POLL_MS;
+        loop {
+            sleep_ms(delay).await;
+            delay = if check_health().await { HEALTH_POLL_MS } else { (delay * 2).min(HEALTH_POLL_MAX_MS) };
+        }
+    });
+    // Keep the panel current with loads by other clients and idle unloads
+    set_interval(
+        move || {
+            if is_online.get_untracked() {
+                refresh_models();
+            }
+        },
+        std::time::Duration::from_secs(10),
+    );
+
+    // Current time for the messages' relative timestamps, updated every minute
+    let (now, set_now) = create_signal(js_sys::Date::now());
+    set_interval(move || set_now.set(js_sys::Date::now()), std::time::Duration::from_secs(60));
+
+    // Server the frontend talks to, shown in the status footer; the address box edits it
+    let (server_url, set_server_url) = create_signal(server::server_url());
+    let (editing_server, set_editing_server) = create_signal(false);
+    let (server_input, set_server_input) = create_signal(String::new());
+    let (server_error, set_server_error) = create_signal::<Option<String>>(None);
+    // Switch to the server typed in (empty for the default) and start over with its models
+    let change_server = move |url: String| {
+        if let Err(e) = server::set_server_url(&url) {
+            set_server_error.set(Some(e));
+            return;
+        }
+        set_server_url.set(server::server_url());
+        set_server_error.set(None);
+        set_editing_server.set(false);
+        set_connection_error.set(None);
+        set_active_model.set("".to_string());
+        set_models.set(vec![]);
+        set_model_list.set(None);
+        check_server();
+    };
+
+    // Characters the server would count (message, attached file and system prompt), against its limit
+    let prompt_chars = create_memo(move |_| {
+        build_prompt(user_input_text.get().trim(), &file_name.get(), &file_content.get()).chars().count()
+            + system_prompt.get().trim().chars().count()
+    });
+    let over_prompt_limit = move || max_prompt_chars.get().is_some_and(|limit| prompt_chars.get() > limit);
+
+    // Live token count of the prompt (typed message plus attached file), from the active
+    // model's tokenizer, or estimated when the server cannot tokenize it
+    let (input_tokens, set_input_tokens) = create_signal::<Option<usize>>(None);
+    let token_count_request = store_value(0u64); // latest request, older answers are dropped
+    create_effect(move |_| {
+        let typed = user_input_text.get();
+        let model = active_model.get();
+        let request_id = token_count_request.get_value() + 1;
+        token_count_request.set_value(request_id);
+        if typed.trim().is_empty() {
+            set_input_tokens.set(None);
+            return;
+        }
+        let text = build_prompt(typed.trim(), &file_name.get(), &file_content.get());
+        let estimate = estimate_tokens(&text);
+        // An oversized paste is not sent to the tokenizer either
+        if model.is_empty() || over_prompt_limit() {
+            set_input_tokens.set(Some(estimate));
+            return;
+        }
+        // Wait for a pause in typing before asking the server
+        set_timeout(
+            move || {
+                if token_count_request.get_value() != request_id { return; }
+                spawn_local(async move {
+                    let payload = TokenizeRequest { model: Some(model), text, add_special_tokens: false };
+                    let res = Request::post(&api_url("/tokenize"))
+                        .json(&payload)
+                        .unwrap()
+                        .send()
+                        .await;
+                    let count = match res {
+                        Ok(r) => r.json::<ApiResponse<TokenizeResponse>>().await.ok().and_then(|body| body.data).map(|d| d.count),
+                        Err(_) => None,
+                    };
+                    if token_count_request.get_value() == request_id {
+                        set_input_tokens.set(Some(count.unwrap_or(estimate)));
+                    }
+                });
+            },
+            std::time::Duration::from_millis(300),
+        );
+    });
+
+    // Prompt plus the reply budget, against the context limit
+    let context_needed = move || input_tokens.get().map(|tokens| tokens + max_tokens.get());
+    let near_context = move || context_needed().is_some_and(|needed| needed * 10 >= context_limit.get() * 9);
+    let over_context = move || context_needed().is_some_and(|needed| needed > context_limit.get());
+
+    // Whether the chat follows new content: true while the user is at the bottom, false once
+    // they scroll up to read. New content meanwhile shows a button to jump back down.
+    let (at_bottom, set_at_bottom) = create_signal(true);
+    let (new_content_below, set_new_content_below) = create_signal(false);
+
+    // Long chats only keep the messages near the viewport in the DOM. Heights are measured once
+    // a message has been shown and estimated before; while at the bottom, the window ends with
+    // the last message.
+    let message_heights = store_value(HashMap::<u64, f64>::new());
+    let (message_window, set_message_window) = create_signal(MessageWindow::default());
+    let update_window = move || {
+        let history = chat_history.get_untracked();
+        let div = chat_history_ref.get_untracked();
+        if let Some(nodes) = div.as_ref().and_then(|div| div.query_selector_all(".message[data-id]").ok()) {
+            message_heights.update_value(|heights| {
+                for index in 0..nodes.length() {
+                    let Some(element) = nodes.item(index).and_then(|n| n.dyn_into::<web_sys::HtmlElement>().ok()) else {
+                        continue;
+                    };
+                    if let Some(id) = element.get_attribute("data-id").and_then(|id| id.parse().ok()) {
+                        heights.insert(id, element.offset_height() as f64);
+                    }
+                }
+            });
+        }
+        let heights: Vec<f64> = message_heights.with_value(|known| {
+            history.iter().map(|m| known.get(&m.id).copied().unwrap_or_else(|| estimate_message_height(m))).collect()
+        });
+        // Before the chat is mounted, a typical window height
+        let viewport = div.as_ref().map_or(1000.0, |div| div.client_height() as f64);
+        let top = match &div {
+            Some(div) if !at_bottom.get_untracked() => div.scroll_top() as f64,
+            _ => (heights.iter().sum::<f64>() - viewport).max(0.0),
+        };
+        let window = visible_window(&heights, top, viewport);
+        if window != message_window.get_untracked() {
+            set_message_window.set(window);
+        }
+    };
+    create_effect(move |_| {
+        chat_history.track();
+        update_window();
     });
 
-    // Auto-scroll the chat window to bottom
+    let last_scroll_top = store_value(0);
+    let on_chat_scroll = move |_| {
+        let Some(div) = chat_history_ref.get_untracked() else { return };
+        let top = div.scroll_top();
+        if top + div.client_height() >= div.scroll_height() - SCROLL_BOTTOM_THRESHOLD_PX {
+            set_at_bottom.set(true);
+            set_new_content_below.set(false);
+        } else if top < last_scroll_top.get_value() {
+            // Only scrolling up leaves the bottom: growing content or a smooth scroll down does not
+            set_at_bottom.set(false);
+        }
+        last_scroll_top.set_value(top);
+        update_window();
+    };
+
+    // Scroll the chat window to the bottom, e.g. after the user sent a message
     let scroll_to_bottom = move || {
+        set_at_bottom.set(true);
+        set_new_content_below.set(false);
+        update_window();
         // Check if chat_history_ref is currently attached to a real DOM element
         if let Some(div) = chat_history_ref.get() {
             let _ = div.set_scroll_top(div.scroll_height()); // Scroll
         }
     };
+    // A conversation opens at its latest message
+    create_effect(move |_| {
+        active_conversation.track();
+        if chat_history_ref.get().is_some() {
+            scroll_to_bottom();
+        }
+    });
+    // Keep new content in view, unless the user scrolled up to read
+    let follow_output = move || {
+        if at_bottom.get_untracked() {
+            scroll_to_bottom();
+        } else {
+            set_new_content_below.set(true);
+        }
+    };
 
     // Load Model
     let load_model = move |model_name: String| {
@@ -182,36 +911,135 @@ fn App() -> impl IntoView {
         spawn_local(async move {
             // show overlay if model is loading
             set_loading_overlay.set(Some(format!("Loading {}...", model_name)));
-            // load model
-            let res = Request::post(&format!("{}/load_model", API_BASE))
-                .json(&LoadModelRequest { name: model_name.clone() })
+            // queue the load, then follow it until the model is ready
+            let res = Request::post(&api_url("/load_model"))
+                .json(&LoadModelRequest { name: model_name.clone(), run_async: true })
                 .unwrap()
                 .send()
                 .await;
             match res {
                 Ok(r) => {
-                    // Request success
-                     if let Ok(data) = r.json::<ApiResponse>().await {
-                        if data.status == "ok" {
+                    let outcome = match r.json::<ApiResponse<LoadJob>>().await {
+                        Ok(ApiResponse { data: Some(job), .. }) => wait_for_load_job(job.id, &model_name, set_loading_overlay).await,
+                        Ok(data) => Err(data.message.unwrap_or_default()),
+                        Err(_) => Err(format!("Server returned {}", r.status())),
+                    };
+                    match outcome {
+                        Ok(()) => {
                             // Set active model
                             set_active_model.set(model_name.clone());
-                            set_chat_history.update(|h| h.push(ChatMessage {
-                                id: js_sys::Date::now() as u64,
+                            push_message(active_conversation.get_untracked(), ChatMessage {
+                                id: new_message_id(),
                                 role: "AI".into(),
                                 content: format!("System: Model loaded: {}", model_name),
-                            }));
-                            scroll_to_bottom();
-                        } else {
-                            logging::error!("Error loading model: {:?}", data.message);
+                                prompt_sent: None,
+                                sent: None,
+                                stats: None,
+                            });
+                            follow_output();
+                        }
+                        // Shown in the chat with the server's reason
+                        Err(message) => {
+                            logging::error!("Error loading model: {}", message);
+                            let reason = if message.is_empty() { "unknown error".to_string() } else { message };
+                            push_message(active_conversation.get_untracked(), ChatMessage {
+                                id: new_message_id(),
+                                role: "Error".into(),
+                                content: format!("Could not load {}: {}", model_name, reason),
+                                prompt_sent: None,
+                                sent: None,
+                                stats: None,
+                            });
+                            follow_output();
                         }
-                     }
+                    }
+                }
+                Err(e) => {
+                    logging::error!("Failed to connect: {}", e);
+                    set_connection_error.set(Some((
+                        format!("Could not reach the server to load {}.", model_name),
+                        Retry::LoadModel(model_name.clone()),
+                    )));
                 }
-                Err(e) => logging::error!("Failed to connect: {}", e),
             }
             // hide overlay when model loading done
             set_loading_overlay.set(None);
+            refresh_models();
         });
     };
+
+    // Unload a model to free its memory. The active model is only unloaded after confirming,
+    // since chatting needs a model loaded again afterwards.
+    let unload_model = move |model_name: String| {
+        let was_active = model_name == active_model.get_untracked();
+        if was_active {
+            let question = format!("{} is the active model. Unload it anyway?", model_name);
+            let confirmed = web_sys::window().and_then(|w| w.confirm_with_message(&question).ok()).unwrap_or(false);
+            if !confirmed {
+                return;
+            }
+        }
+        spawn_local(async move {
+            let res = Request::post(&api_url("/unload_model"))
+                .json(&UnloadModelRequest { name: model_name.clone() })
+                .unwrap()
+                .send()
+                .await;
+            match res {
+                Ok(r) => match r.json::<ApiResponse<String>>().await {
+                    Ok(body) if body.status == "ok" => {
+                        if was_active {
+                            set_active_model.set("".to_string());
+                        }
+                    }
+                    outcome => {
+                        let reason = outcome.ok().and_then(|body| body.message).unwrap_or_else(|| "unknown error".to_string());
+                        push_message(active_conversation.get_untracked(), ChatMessage {
+                            id: new_message_id(),
+                            role: "Error".into(),
+                            content: format!("Could not unload {}: {}", model_name, reason),
+                            prompt_sent: None,
+                            sent: None,
+                            stats: None,
+                        });
+                        follow_output();
+                    }
+                },
+                Err(e) => {
+                    logging::error!("Failed to connect: {}", e);
+                    set_connection_error.set(Some((
+                        format!("Could not reach the server to unload {}.", model_name),
+                        Retry::UnloadModel(model_name.clone()),
+                    )));
+                }
+            }
+            refresh_models();
+        });
+    };
+
+    // Reselect the model from the last visit once the list arrives, if the server has none
+    // active (e.g. it restarted); a model someone else made active is left alone
+    let saved_model = store_value(storage::load_settings().model);
+    create_effect(move |restored: Option<bool>| {
+        if restored == Some(true) || models.get().is_empty() {
+            return restored.unwrap_or(false);
+        }
+        let model = saved_model.get_value();
+        if active_model.get_untracked().is_empty() && models.get_untracked().contains(&model) {
+            load_model(model);
+        }
+        true
+    });
+    // Remember the model once one is active
+    create_effect(move |_| {
+        let active = active_model.get();
+        if !active.is_empty() {
+            saved_model.set_value(active.clone());
+            let mut settings = storage::load_settings();
+            settings.model = active;
+            storage::save_settings(&settings);
+        }
+    });
     // Handle file upload reading
     let on_file_upload = move |ev: web_sys::Event| {
         let input: HtmlInputElement = event_target(&ev);
@@ -250,67 +1078,35 @@ fn App() -> impl IntoView {
         }
     };
 
-    // Send Message
-    let send_message = move || {
-        // fetch user input and remove space
-        let text = user_input_text.get_untracked().trim().to_string();
-        let current_file_content = file_content.get_untracked();
-        let current_file_name = file_name.get_untracked();
-        if text.is_empty() || is_generating.get_untracked() { 
-            return; 
-        }
-        // Check if there is active model selected
-        let current_model = active_model.get_untracked();
-        if current_model.is_empty() {
-             logging::warn!("No active model selected");
-             return;
-        }
-        // Clean user input after user send the message
-        set_user_input_text.set("".into());
+    // Stream a reply to `prompt_payload` into `conversation`, whose last message is the user's turn
+    let stream_reply = move |conversation: u64, prompt_payload: String| {
         set_is_generating.set(true);
         set_streaming_content.set("".to_string()); // Clear stream buffer
-
-        if let Some(input) = file_input_ref.get() {
-            input.set_value("");
-        }
-        set_file_name.set("".to_string()); // Reset UI name
-        set_file_content.set("".to_string()); // Reset content signal logic
-
-        // Construct Prompt with file context
-        let display_content = if !current_file_name.is_empty() {
-             format!("[File: {}]\n{}", current_file_name, text)
-        } else {
-             text.clone()
-        };
-        // Inject the file content into the prompt
-        let prompt_payload = if !current_file_content.is_empty() {
-            format!("The user uploaded a file named '{}'.\n\nFile Content:\n```\n{}\n```\n\nUser Instruction:\n{}", 
-                current_file_name, current_file_content, text)
-        } else {
-            text
-        };
-
-        // Push user input to chat history
-        set_chat_history.update(|h| {
-            h.push(ChatMessage { 
-                    id: js_sys::Date::now() as u64,
-                    role: "User".into(), 
-                    content: display_content, 
-                }
-            )
-        });
+        set_tokens_per_sec.set(None);
+        set_streamed_tokens.set(0);
+        // The reply goes to this conversation even if the user switches away meanwhile
+        set_streaming_conversation.set(Some(conversation));
         scroll_to_bottom();
-        
+
         spawn_local(async move {
             // inference parameters
             let sys_prompt_input = system_prompt.get_untracked().trim().to_string();
+            // The last message is the one being answered, sent as the prompt
+            let messages = conversations.with_untracked(|all| {
+                all.get(&conversation)
+                    .map(|c| conversation_history(&c.messages, history_turns.get_untracked()))
+                    .unwrap_or_default()
+            });
             let payload = InferRequest {
                 prompt: prompt_payload,
-                temperature: temperature.get_untracked(),
-                top_p: top_p.get_untracked(),
-                max_tokens: max_tokens.get_untracked(),
+                messages,
+                temperature: Some(temperature.get_untracked()),
+                top_p: Some(top_p.get_untracked()),
+                max_tokens: Some(max_tokens.get_untracked()),
                 seed: seed.get_untracked(),
                 system_prompt: if sys_prompt_input.is_empty() { None } else { Some(sys_prompt_input) },
+                echo: show_prompt.get_untracked(),
+                ..Default::default()
             };
 
             let controller = AbortController::new().ok();
@@ -318,7 +1114,7 @@ fn App() -> impl IntoView {
             set_abort_controller.set(controller);
 
             // Use Request builder pattern to attach signal correctly
-            let mut request_builder = Request::post(&format!("{}/infer_stream", API_BASE));
+            let mut request_builder = Request::post(&api_url("/infer_stream"));
             
             if let Some(s) = signal.as_ref() {
                 // gloo_net expects Option<&AbortSignal>, so we wrap s in Some()
@@ -332,82 +1128,342 @@ fn App() -> impl IntoView {
                 .send()
                 .await;
 
-            if let Ok(resp) = response {
-                if let Some(body) = resp.body() {
+            let mut prompt_sent = None;
+            // For the stats line under the reply
+            let mut model: Option<String> = None;
+            let mut generated_tokens: Option<u64> = None;
+            // Reported by the server; shown after the reply
+            let mut error: Option<String> = None;
+            let mut finished = false; // [DONE] arrived
+            let mut stalled = false; // Nothing arrived for STREAM_STALL_MS
+            // Time of the first token; speed is measured from there to leave out queueing and prefill
+            let mut first_token_at: Option<f64> = None;
+            match response {
+                // Rejected before the stream started: the body is an ApiResponse
+                Ok(resp) if !resp.ok() => {
+                    let reply = resp.json::<ApiResponse<()>>().await.ok();
+                    error = Some(reply.and_then(|r| r.message).unwrap_or_else(|| format!("Server returned {}", resp.status())));
+                }
+                Ok(resp) => if let Some(body) = resp.body() {
                     // Convert the Web ReadableStream(JavaScript) into a Rust Stream
                     let mut stream = ReadableStream::from_raw(body.dyn_into().unwrap()).into_stream();
-                    let mut buffer = String::new();
-                    // Loop through each incoming data chunk
-                    while let Some(Ok(chunk_js_value)) = stream.next().await {
-                        // Convert raw js value into rust vec, convert raw bytes to string
+                    let mut parser = SseParser::default();
+                    // Loop through each incoming data chunk, stop entirely at the done marker
+                    'stream: loop {
+                        // A connection that died without closing would otherwise keep the reply waiting forever
+                        let next = futures::future::select(stream.next(), Box::pin(sleep_ms(STREAM_STALL_MS))).await;
+                        let chunk_js_value = match next {
+                            futures::future::Either::Left((Some(Ok(chunk)), _)) => chunk,
+                            futures::future::Either::Left(_) => break,
+                            futures::future::Either::Right(_) => {
+                                stalled = true;
+                                break;
+                            }
+                        };
+                        // Convert raw js value into rust vec; the parser decodes the text
                         let chunk = js_sys::Uint8Array::new(&chunk_js_value).to_vec();
-                        let chunk_str = String::from_utf8_lossy(&chunk);
-                        buffer.push_str(&chunk_str);
-
-                        // Parse SSE data
-                        // Split buffer by newline, keep last line
-                        let lines: Vec<&str> = buffer.split('\n').collect();
-                        let last_line = lines.last().cloned().unwrap_or("").to_string();
-                        
-                        for line in lines.iter().take(lines.len() - 1) {
-                            let line = line.trim();
-                            if line.is_empty() { continue; }
-
-                            if line.starts_with("data:") {
-                                let raw_content = &line[5..]; 
-                                let content_str = if raw_content.starts_with(' '){ 
-                                    &raw_content[1..] 
-                                } else { 
-                                    raw_content 
-                                };
-                                // Done marker, inference finished
-                                if content_str == "[DONE]" { 
-                                    break; 
-                                } 
-                                if content_str.starts_with("[MODEL:"){ 
-                                    continue; 
+
+                        for event in parser.push(&chunk) {
+                            // The server only sends unnamed events; any other kind is not meant for the chat
+                            if event.event != "message" {
+                                continue;
+                            }
+                            let content_str = event.data;
+                            // Done marker, inference finished
+                            if content_str == "[DONE]" { 
+                                finished = true;
+                                break 'stream; 
+                            } 
+                            if let Some(name) = content_str.strip_prefix("[MODEL:") {
+                                model = Some(name.trim_end_matches(']').trim().to_string());
+                                continue;
+                            }
+
+                            // Try parse JSON
+                            let text_to_append = match serde_json::from_str::<serde_json::Value>(&content_str) {
+                                // Generation failed, or could not start; [DONE] follows
+                                Ok(json) if json.get("error").is_some() => {
+                                    error = Some(json["error"].as_str().unwrap_or("Generation failed.").to_string());
+                                    continue;
                                 }
-                                if content_str.starts_with("[ERROR]"){ 
-                                    continue; 
+                                // Echoed prompt, kept apart from the reply text
+                                Ok(json) if json.get("prompt").is_some() => {
+                                    prompt_sent = json["prompt"].as_str().map(str::to_string);
+                                    continue;
                                 }
+                                // Progress and final usage only drive the speed display
+                                Ok(json) if json.get("tokens_generated").is_some() || json.get("usage").is_some() => {
+                                    let generated = json["tokens_generated"].as_u64()
+                                        .or_else(|| json["usage"]["completion_tokens"].as_u64());
+                                    if json.get("usage").is_some() {
+                                        generated_tokens = generated;
+                                    }
+                                    if let (Some(generated), Some(start)) = (generated, first_token_at) {
+                                        let secs = (js_sys::Date::now() - start) / 1000.0;
+                                        if generated > 1 && secs > 0.0 {
+                                            set_tokens_per_sec.set(Some((generated - 1) as f64 / secs));
+                                        }
+                                    }
+                                    continue;
+                                }
+                                Ok(json) if json.get("text").is_some() => {
+                                    first_token_at.get_or_insert_with(js_sys::Date::now);
+                                    set_streamed_tokens.update(|n| *n += 1);
+                                    json["text"].as_str().unwrap_or("").to_string()
+                                }
+                                Ok(json) => json["text"].as_str().unwrap_or("").to_string(),
+                                Err(_) => content_str.clone(),
+                            };
 
-                                // Try parse JSON
-                                let text_to_append = match serde_json::from_str::<serde_json::Value>(content_str) {
-                                    Ok(json) => json["text"].as_str().unwrap_or("").to_string(),
-                                    Err(_) => content_str.to_string(),
-                                };
-
-                                // Update separate signal instead of history
-                                set_streaming_content.update(|s| s.push_str(&text_to_append));
-                                scroll_to_bottom();
-                            }
+                            // Update separate signal instead of history
+                            set_streaming_content.update(|s| s.push_str(&text_to_append));
+                            follow_output();
                         }
-                        buffer = last_line;
                     }
-                }
-            } else {
-                logging::error!("Network error or aborted");
+                },
+                Err(e) => logging::error!("Network error or aborted: {}", e),
             }
 
-            // When done, push the full message to history
-            let final_content = streaming_content.get_untracked();
+            // When done, push the full message to history; a reply cut short by Stop is kept, marked
+            let stopped = signal.as_ref().is_some_and(|s| s.aborted());
+            // Give up on a stalled request; the partial reply is kept below
+            if stalled {
+                if let Some(controller) = abort_controller.get_untracked() {
+                    controller.abort();
+                }
+            }
+            // Lost connection, offered for retry in the banner; it is not the model's fault
+            if error.is_none() && !stopped && !finished {
+                set_connection_error.set(Some((
+                    "The server could not be reached or closed the connection before the reply finished.".to_string(),
+                    Retry::Reply(conversation),
+                )));
+            }
+            let mut final_content = streaming_content.get_untracked();
             if !final_content.is_empty() {
-                set_chat_history.update(|h| h.push(ChatMessage {
-                    id: js_sys::Date::now() as u64,
+                if stopped {
+                    final_content.push_str("\n\n(stopped)");
+                }
+                push_message(conversation, ChatMessage {
+                    id: new_message_id(),
                     role: "AI".into(),
                     content: final_content,
-                }));
+                    prompt_sent,
+                    sent: None,
+                    stats: Some(ReplyStats {
+                        model: model.unwrap_or_default(),
+                        // A stopped reply gets no usage event; its streamed tokens are counted instead
+                        generated_tokens: generated_tokens.unwrap_or_else(|| streamed_tokens.get_untracked()),
+                        tokens_per_sec: tokens_per_sec.get_untracked(),
+                    }),
+                });
                 set_streaming_content.set("".to_string());
             }
+            if let Some(message) = error {
+                push_message(conversation, ChatMessage {
+                    id: new_message_id(),
+                    role: "Error".into(),
+                    content: format!("Generation failed: {}", message),
+                    prompt_sent: None,
+                    sent: None,
+                    stats: None,
+                });
+                follow_output();
+            }
 
-            set_is_generating.set(false);
-            set_abort_controller.set(None);
+            // After Stop these were reset already, and may belong to the next message by now
+            if !stopped {
+                set_is_generating.set(false);
+                set_abort_controller.set(None);
+            }
+        });
+    };
+
+    // Message being edited, as (conversation, message id): its text is back in the input box
+    // until it is sent again or the edit is cancelled
+    let (editing, set_editing) = create_signal::<Option<(u64, u64)>>(None);
+    let edit_message = move |id: u64| {
+        let conversation = active_conversation.get_untracked();
+        let sent = conversations.with_untracked(|all| {
+            all.get(&conversation)?.messages.iter().find(|m| m.id == id)?.sent.clone()
+        });
+        if let Some(sent) = sent {
+            set_user_input_text.set(sent.text);
+            set_file_name.set(sent.file_name);
+            set_file_content.set(sent.file_content);
+            set_editing.set(Some((conversation, id)));
+        }
+    };
+    let cancel_edit = move || {
+        set_editing.set(None);
+        set_user_input_text.set("".into());
+        set_file_name.set("".to_string());
+        set_file_content.set("".to_string());
+    };
+    // Remove a message from the conversation on screen, and so from storage and exports.
+    // Replies take longer to get back, so deleting one asks first.
+    let delete_message = move |id: u64, role: String| {
+        if role == "AI" {
+            let confirmed = web_sys::window()
+                .and_then(|w| w.confirm_with_message("Delete this reply?").ok())
+                .unwrap_or(false);
+            if !confirmed {
+                return;
+            }
+        }
+        let conversation = active_conversation.get_untracked();
+        if editing.get_untracked() == Some((conversation, id)) {
+            cancel_edit();
+        }
+        set_conversations.update(|all| {
+            if let Some(c) = all.get_mut(&conversation) {
+                c.messages.retain(|m| m.id != id);
+            }
+        });
+    };
+
+    // Ask again for the last reply of the conversation on screen, replacing it. With no seed
+    // pinned the server picks a new one, so the answer differs.
+    let regenerate = move || {
+        if is_generating.get_untracked() || active_model.get_untracked().is_empty() {
+            return;
+        }
+        let conversation = active_conversation.get_untracked();
+        let mut prompt = None;
+        set_conversations.update(|all| {
+            let Some(c) = all.get_mut(&conversation) else { return };
+            if let Some(position) = c.messages.iter().rposition(|m| m.role == "User") {
+                if let Some(sent) = &c.messages[position].sent {
+                    prompt = Some(sent.prompt());
+                    c.messages.truncate(position + 1);
+                }
+            }
+        });
+        if let Some(prompt) = prompt {
+            stream_reply(conversation, prompt);
+        }
+    };
+    // Whether message `id` is the reply Regenerate would replace: the last message, after a
+    // user message that can be resent
+    let can_regenerate = move |id: u64| chat_history.with(|history| {
+        history.last().is_some_and(|m| m.id == id && m.role != "User")
+            && history.iter().rev().find(|m| m.role == "User").is_some_and(|m| m.sent.is_some())
+    });
+
+    // Try again what the connection banner reports as failed
+    let retry = move |action: Retry| {
+        set_connection_error.set(None);
+        match action {
+            Retry::ServerCheck => check_server(),
+            Retry::LoadModel(name) => load_model(name),
+            Retry::UnloadModel(name) => unload_model(name),
+            Retry::Reply(conversation) => {
+                // Resent in its own conversation, if that still exists
+                if !conversations.with_untracked(|all| all.contains_key(&conversation)) {
+                    return;
+                }
+                switch_conversation(conversation);
+                regenerate();
+            }
+        }
+    };
+
+    // Send Message
+    let send_message = move || {
+        // fetch user input and remove space
+        let text = user_input_text.get_untracked().trim().to_string();
+        let current_file_content = file_content.get_untracked();
+        let current_file_name = file_name.get_untracked();
+        if text.is_empty() || is_generating.get_untracked() { 
+            return; 
+        }
+        // The server would reject it; the toolbar explains why
+        if !is_online.get_untracked() || over_context() || over_prompt_limit() {
+            return;
+        }
+        // Check if there is active model selected
+        let current_model = active_model.get_untracked();
+        if current_model.is_empty() {
+             logging::warn!("No active model selected");
+             return;
+        }
+        // Clean user input after user send the message
+        set_user_input_text.set("".into());
+        let conversation = active_conversation.get_untracked();
+        // Sending an edited message replaces it and everything after it
+        let edited = editing.get_untracked().filter(|(c, _)| *c == conversation).map(|(_, id)| id);
+        set_editing.set(None);
+
+        if let Some(input) = file_input_ref.get() {
+            input.set_value("");
+        }
+        set_file_name.set("".to_string()); // Reset UI name
+        set_file_content.set("".to_string()); // Reset content signal logic
+
+        // Construct Prompt with file context
+        let display_content = if !current_file_name.is_empty() {
+             format!("[File: {}]\n{}", current_file_name, text)
+        } else {
+             text.clone()
+        };
+        let sent = SentMessage { text: text.clone(), file_name: current_file_name, file_content: current_file_content };
+        // Inject the file content into the prompt
+        let prompt_payload = sent.prompt();
+
+        // Push user input to chat history; the first message names the conversation
+        set_conversations.update(|all| {
+            if let Some(c) = all.get_mut(&conversation) {
+                if let Some(position) = edited.and_then(|id| c.messages.iter().position(|m| m.id == id)) {
+                    c.messages.truncate(position);
+                }
+                if !c.messages.iter().any(|m| m.role == "User") {
+                    c.title = conversation_title(&text);
+                }
+                c.messages.push(ChatMessage { 
+                    id: new_message_id(),
+                    role: "User".into(), 
+                    content: display_content, 
+                    prompt_sent: None,
+                    sent: Some(sent),
+                    stats: None,
+                });
+            }
         });
+        stream_reply(conversation, prompt_payload);
     };
 
     view! {
         <div id="sidebar">
             <h2>"LLM Chat"</h2>
+
+            // Conversations, newest first
+            <div class="control-group">
+                <button class="export-btn" on:click=move |_| new_conversation()>"+ New Chat"</button>
+                <div class="conversation-list">
+                    <For
+                        each=move || conversations.with(|all| {
+                            all.values().rev().map(|c| (c.id, c.title.clone())).collect::<Vec<_>>()
+                        })
+                        key=|entry| entry.clone()
+                        children=move |(id, title)| view! {
+                            <div class="conversation"
+                                class:active=move || active_conversation.get() == id
+                                class:generating=move || is_generating.get() && streaming_conversation.get() == Some(id)
+                                on:click=move |_| switch_conversation(id)
+                            >
+                                <span class="conversation-title">
+                                    {if title.is_empty() { "New chat".to_string() } else { title }}
+                                </span>
+                                <button class="conversation-delete" title="Delete chat"
+                                    on:click=move |ev| { ev.stop_propagation(); delete_conversation(id); }
+                                >"✕"</button>
+                            </div>
+                        }
+                    />
+                </div>
+            </div>
+
+            <hr style="border-color: #4d4d4f; width: 100%; margin: 10px 0;" />
             
             // Model selection
             <div class="control-group">
@@ -435,20 +1491,99 @@ fn App() -> impl IntoView {
                 </select>
             </div>
 
+            // Every configured model with Load/Unload, and the VRAM in use on each GPU
+            <details class="control-group models-panel">
+                <summary>"Models"</summary>
+                {move || model_list.get().map(|list| {
+                    let mut entries: Vec<_> = list.models.into_iter().collect();
+                    entries.sort_by(|a, b| a.0.cmp(&b.0));
+                    let active = list.active;
+                    let rows = entries.into_iter().map(|(name, status)| {
+                        let is_active = name == active;
+                        let size = if status.size_source == "measured" {
+                            format!("{} MB", status.size_mb)
+                        } else {
+                            format!("~{} MB", status.size_mb)
+                        };
+                        let action_name = name.clone();
+                        let action = if status.loaded {
+                            view! {
+                                <button class="model-action" on:click=move |_| unload_model(action_name.clone())>"Unload"</button>
+                            }
+                        } else {
+                            view! {
+                                <button
+                                    class="model-action"
+                                    disabled=move || loading_overlay.get().is_some()
+                                    on:click=move |_| load_model(action_name.clone())
+                                >
+                                    "Load"
+                                </button>
+                            }
+                        };
+                        view! {
+                            <div class="model-row" class:active=is_active title=format!("Device: {}", status.device)>
+                                <span class="model-name">{name}</span>
+                                {status.loaded.then(|| view! { <span class="loaded-badge">"loaded"</span> })}
+                                <span class="model-size">{size}</span>
+                                {action}
+                            </div>
+                        }
+                    }).collect_view();
+                    let bars = list.vram_usage.iter().filter_map(|(index, usage)| {
+                        let (used, limit) = parse_vram_usage(usage)?;
+                        let percent = if limit == 0 { 100.0 } else { (used as f64 / limit as f64 * 100.0).min(100.0) };
+                        Some(view! {
+                            <div class="vram-usage">
+                                <div class="vram-label">{format!("GPU {}: {} / {} MB", index, used, limit)}</div>
+                                <div class="vram-bar">
+                                    <div class="vram-fill" class:high={percent >= 90.0} style=format!("width: {:.0}%", percent)></div>
+                                </div>
+                            </div>
+                        })
+                    }).collect_view();
+                    view! { <div class="model-list">{rows}</div> {bars} }
+                })}
+            </details>
+
             <hr style="border-color: #4d4d4f; width: 100%; margin: 10px 0;" />
 
-            // System Prompt
-            <div class="control-group">
-                <label class="flex-row">
-                    "System Prompt"
-                    <HelpTooltip text="Give the AI a role or instruction. E.g., 'Speak in a happy way with no more than 30 words'."/>
-                </label>
+            // System Prompt, sent with every message of this conversation; collapsed by default
+            <details class="control-group system-prompt">
+                <summary>
+                    <label class="flex-row">
+                        "System Prompt"
+                        {move || (!system_prompt.get().trim().is_empty()).then_some(view! { <span class="set-dot">" ●"</span> })}
+                        <HelpTooltip text="Give the AI a role or instruction. E.g., 'Speak in a happy way with no more than 30 words'. Changes apply from the next message."/>
+                    </label>
+                </summary>
                 <textarea
                     rows="3"
                     placeholder="Optional: Give the AI a role or instruction..."
                     prop:value=move || system_prompt.get()
                     on:input=move |ev| set_system_prompt.set(event_target_value(&ev))
                 ></textarea>
+            </details>
+
+            // Presets set Temperature and Top P together
+            <div class="control-group">
+                <label class="flex-row">
+                    "Preset"
+                    <HelpTooltip text="Precise: focused, consistent answers. Balanced: the default mix. Creative: more varied wording and ideas. Sets Temperature and Top P."/>
+                </label>
+                <select
+                    prop:value=move || matching_preset(temperature.get(), top_p.get()).unwrap_or("custom").to_string()
+                    on:change=move |ev| {
+                        let name = event_target_value(&ev);
+                        if let Some((_, t, p)) = SAMPLING_PRESETS.iter().find(|(n, _, _)| *n == name) {
+                            set_temperature.set(*t);
+                            set_top_p.set(*p);
+                        }
+                    }
+                >
+                    {SAMPLING_PRESETS.iter().map(|(name, _, _)| view! { <option value=*name>{*name}</option> }).collect_view()}
+                    <option value="custom" disabled>"Custom"</option>
+                </select>
             </div>
 
             // Temperature slide
@@ -493,6 +1628,30 @@ fn App() -> impl IntoView {
                 />
             </div>
 
+            // History Turns
+            <div class="control-group">
+                <label class="flex-row">
+                    "History Turns"
+                    <HelpTooltip text="Earlier exchanges sent with each message so the model remembers the conversation. 0 sends only the new message."/>
+                </label>
+                <input type="number" min="0"
+                    prop:value=move || history_turns.get()
+                    on:input=move |ev| set_history_turns.set(event_target_value(&ev).parse().unwrap_or(DEFAULT_HISTORY_TURNS))
+                />
+            </div>
+
+            // Context Limit
+            <div class="control-group">
+                <label class="flex-row">
+                    "Context Limit"
+                    <HelpTooltip text="Model context window in tokens. Messages that would not fit with Max Tokens are blocked."/>
+                </label>
+                <input type="number"
+                    prop:value=move || context_limit.get()
+                    on:input=move |ev| set_context_limit.set(event_target_value(&ev).parse().unwrap_or(DEFAULT_CONTEXT_LIMIT))
+                />
+            </div>
+
             // Seed
             <div class="control-group">
                 <label class="flex-row">
@@ -500,6 +1659,7 @@ fn App() -> impl IntoView {
                     <HelpTooltip text="Fixed number for reproducible results."/>
                 </label>
                 <input type="number" placeholder="Random"
+                    prop:value=move || seed.get().map(|s| s.to_string()).unwrap_or_default()
                     // If no input
                     on:input=move |ev| {
                         let val = event_target_value(&ev);
@@ -509,15 +1669,70 @@ fn App() -> impl IntoView {
                 />
             </div>
 
+            // Prompt echo
+            <div class="control-group">
+                <label class="flex-row">
+                    <input type="checkbox"
+                        prop:checked=move || show_prompt.get()
+                        on:change=move |ev| set_show_prompt.set(event_target_checked(&ev))
+                    />
+                    "Show Prompt Sent"
+                    <HelpTooltip text="Attach the exact prompt the model received (chat template included) to each reply."/>
+                </label>
+            </div>
+
+            // Reset the settings above (except the system prompt)
+            <div class="control-group">
+                <button
+                    class="export-btn"
+                    on:click=move |_| reset_params()
+                    disabled=params_are_default
+                >
+                    "Reset to Defaults"
+                </button>
+            </div>
+
             <hr style="border-color: #4d4d4f; width: 100%; margin: 10px 0;" />
             // Export button
             <div class="control-group">
+                // Empties the conversation on screen, which is named again by its next message
+                <button
+                    class="export-btn"
+                    on:click=move |_| set_conversations.update(|all| {
+                        if let Some(c) = all.get_mut(&active_conversation.get_untracked()) {
+                            c.messages.clear();
+                            c.title.clear();
+                        }
+                    })
+                    disabled=move || chat_history.get().is_empty() || is_generating.get()
+                >
+                    "Clear Chat"
+                </button>
                 <button 
                     class="export-btn" 
                     on:click=move |_| export_chat()
                     disabled=move || chat_history.get().is_empty()
                 >
-                    "Export Chat (.md)"
+                    "Export Chat"
+                </button>
+                <select
+                    prop:value=move || export_format.get()
+                    on:change=move |ev| set_export_format.set(event_target_value(&ev))
+                >
+                    <option value="md">"Markdown (.md)"</option>
+                    <option value="json">"JSON (.json)"</option>
+                </select>
+                // Hidden file input, opened by the Import button
+                <input type="file" accept=".json,application/json" class="hidden-input"
+                    node_ref=import_input_ref
+                    on:change=on_import_file
+                />
+                <button
+                    class="export-btn"
+                    title="Open a chat exported as JSON as a new conversation"
+                    on:click=move |_| if let Some(input) = import_input_ref.get() { input.click(); }
+                >
+                    "Import Chat"
                 </button>
             </div>
 
@@ -525,37 +1740,171 @@ fn App() -> impl IntoView {
             <div id="server-status">
                 <div class={move || format!("status-dot {}", if is_online.get() { "online" } else { "" })}></div>
                 <span>{move || status_text.get()}</span>
+                <button
+                    class="server-host"
+                    title=move || format!("{} (click to change)", server_url.get())
+                    on:click=move |_| {
+                        set_server_input.set(server_url.get_untracked());
+                        set_server_error.set(None);
+                        set_editing_server.update(|open| *open = !*open);
+                    }
+                >
+                    {move || server::host_of(&server_url.get())}
+                </button>
             </div>
+            // Server address box: empty goes back to the default
+            <Show when=move || editing_server.get()>
+                <form class="server-settings" on:submit=move |ev| {
+                    ev.prevent_default();
+                    change_server(server_input.get_untracked());
+                }>
+                    <input
+                        type="text"
+                        placeholder=server::default_url()
+                        prop:value=move || server_input.get()
+                        on:input=move |ev| set_server_input.set(event_target_value(&ev))
+                    />
+                    <div class="server-settings-actions">
+                        <button type="submit">"Connect"</button>
+                        <button type="button" on:click=move |_| change_server(String::new())>"Default"</button>
+                        <button type="button" on:click=move |_| set_editing_server.set(false)>"Cancel"</button>
+                    </div>
+                    {move || server_error.get().map(|e| view! { <div class="server-error">{e}</div> })}
+                </form>
+            </Show>
         </div>
 
         <div id="main-chat">
+            // Server unreachable: what failed, with Retry and dismiss
+            {move || connection_error.get().map(|(message, action)| view! {
+                <div class="connection-banner">
+                    <span>{message}</span>
+                    <button class="retry-btn" on:click=move |_| retry(action.clone())>"Retry"</button>
+                    <button class="dismiss-btn" title="Dismiss" on:click=move |_| set_connection_error.set(None)>"✕"</button>
+                </div>
+            })}
+            // Reminds that replies follow a system prompt; hover shows it
+            <Show when=move || !system_prompt.get().trim().is_empty()>
+                <div class="system-prompt-indicator" title=move || system_prompt.get()>"System prompt active"</div>
+            </Show>
             // Chat history box
-            <div id="chat-history" node_ref=chat_history_ref>
+            <div id="chat-history" node_ref=chat_history_ref on:scroll=on_chat_scroll>
+                <div style:height=move || format!("{}px", message_window.get().space_above)></div>
                 <For
-                    each=move || chat_history.get()
+                    each=move || chat_history.with(|history| {
+                        let window = message_window.get();
+                        let end = window.end.min(history.len());
+                        history[window.start.min(end)..end].to_vec()
+                    })
                     // use unique ID
                     key=|msg| msg.id
                     children=move |msg| {
-                        let msg_type = if msg.role == "User" { "user" } else { "ai" };
-                        let avatar_text = if msg.role == "User" { "U" } else { "AI" };
+                        let (msg_type, avatar_text) = match msg.role.as_str() {
+                            "User" => ("user", "U"),
+                            "Error" => ("error", "!"),
+                            _ => ("ai", "AI"),
+                        };
+                        let text_key = format!("{}-text", msg.id);
+                        let code_key = format!("{}-code", msg.id);
+                        // Only AI messages offer copying their code blocks
+                        let code_blocks = if msg.role != "AI" { vec![] } else { extract_code_blocks(&msg.content) };
+                        let has_code = !code_blocks.is_empty();
+                        let code_text = code_blocks.join("\n\n");
+                        let content = msg.content.clone();
+                        let copy_text = {
+                            let key = text_key.clone();
+                            move |_| copy_message(key.clone(), content.clone())
+                        };
+                        let copy_code = {
+                            let key = code_key.clone();
+                            move |_| copy_message(key.clone(), code_text.clone())
+                        };
+                        let is_copied = move |key: &str| copied_key.get().as_deref() == Some(key);
+                        let id = msg.id;
+                        let role = msg.role.clone();
+                        let created = message_time(id);
+                        let can_edit = msg.sent.is_some();
                         view! {
-                            <div class={format!("message {}", msg_type)}>
+                            <div class={format!("message {}", msg_type)} data-id=id.to_string()>
                                 <div class="avatar">{avatar_text}</div>
-                                <div class="content">{msg.content}</div>
+                                <div class="content">
+                                    {msg.prompt_sent.map(|prompt| view! {
+                                        <details class="prompt-sent">
+                                            <summary>"Prompt sent"</summary>
+                                            <pre>{prompt}</pre>
+                                        </details>
+                                    })}
+                                    // Replies are rendered as Markdown, the user's own text and errors as they are
+                                    {if msg.role != "AI" {
+                                        msg.content.into_view()
+                                    } else {
+                                        view! {
+                                            <div class="markdown" inner_html=markdown::render(&msg.content)
+                                                on:click=|ev| { markdown::copy_code_block(ev); }></div>
+                                        }.into_view()
+                                    }}
+                                    {msg.stats.map(|stats| view! {
+                                        <div class="message-stats">{stats.summary()}</div>
+                                    })}
+                                </div>
+                                <div class="message-actions">
+                                    <button class="message-action" on:click=copy_text>
+                                        {move || if is_copied(&text_key) { "Copied!" } else { "Copy" }}
+                                    </button>
+                                    <Show when=move || has_code>
+                                        <button class="message-action" on:click=copy_code.clone()>
+                                            {let key = code_key.clone(); move || if is_copied(&key) { "Copied!" } else { "Copy code" }}
+                                        </button>
+                                    </Show>
+                                    <Show when=move || can_edit>
+                                        <button class="message-action" disabled=move || is_generating.get()
+                                            on:click=move |_| edit_message(id)>"Edit"</button>
+                                    </Show>
+                                    <Show when=move || can_regenerate(id)>
+                                        <button class="message-action" disabled=move || is_generating.get()
+                                            on:click=move |_| regenerate()>"Regenerate"</button>
+                                    </Show>
+                                    <button class="message-action" disabled=move || is_generating.get()
+                                        on:click={let role = role.clone(); move |_| delete_message(id, role.clone())}>"Delete"</button>
+                                    {created.map(|time| {
+                                        let exact = String::from(time.to_locale_string("default", &wasm_bindgen::JsValue::UNDEFINED));
+                                        view! {
+                                            <span class="message-time" title=exact>{move || relative_time(&time, now.get())}</span>
+                                        }
+                                    })}
+                                </div>
                             </div>
                         }
                     }
                 />
-                
+                <div style:height=move || format!("{}px", message_window.get().space_below)></div>
+
                 // streaming content 
-                <Show when=move || !streaming_content.get().is_empty() || is_generating.get()>
+                <Show when=move || (!streaming_content.get().is_empty() || is_generating.get())
+                    && streaming_conversation.get() == Some(active_conversation.get())>
                      <div class="message ai">
                         <div class="avatar">"AI"</div>
-                        <div class="content">{move || streaming_content.get()}</div>
+                        <div class="content">
+                            // Re-rendered as each token arrives; an open code fence runs to the end
+                            <div class="markdown" inner_html=move || markdown::render(&streaming_content.get())
+                                on:click=|ev| { markdown::copy_code_block(ev); }></div>
+                            <Show when={move || streamed_tokens.get() > 0}>
+                                <div class="tokens-per-sec">
+                                    {move || match tokens_per_sec.get() {
+                                        Some(speed) => format!("{} tokens · {:.1} tokens/s", streamed_tokens.get(), speed),
+                                        None => format!("{} tokens", streamed_tokens.get()),
+                                    }}
+                                </div>
+                            </Show>
+                        </div>
                     </div>
                 </Show>
 
             </div>
+            // New content arrived while the user was reading further up
+            <Show when=move || new_content_below.get()>
+                <button class="scroll-to-bottom" on:click=move |_| scroll_to_bottom()>"↓ New messages"</button>
+            </Show>
 
             // User input box
             <div id="input-area">
@@ -586,6 +1935,32 @@ fn App() -> impl IntoView {
                                 <button class="remove-file" on:click=move |_| clear_file()>"✕"</button>
                             </span>
                         </Show>
+                        // Sending replaces the edited message and the replies after it
+                        <Show when=move || editing.get().is_some_and(|(c, _)| c == active_conversation.get())>
+                            <span class="editing-badge">
+                                "Editing a message"
+                                <button class="remove-file" title="Cancel the edit" on:click=move |_| cancel_edit()>"✕"</button>
+                            </span>
+                        </Show>
+                        // Token count of the message, in red as it nears the context limit
+                        <Show when=move || input_tokens.get().is_some() || over_prompt_limit()>
+                            <span class="token-count" class:warning=move || near_context() || over_prompt_limit()>
+                                {move || {
+                                    let tokens = input_tokens.get().unwrap_or(0);
+                                    if over_prompt_limit() {
+                                        format!("{} characters exceed the server's {}-character limit: shorten the message or the attached file",
+                                            prompt_chars.get(), max_prompt_chars.get().unwrap_or(0))
+                                    } else if over_context() {
+                                        format!("~{} + {} reply tokens exceed the {}-token context: shorten the message or lower Max Tokens",
+                                            tokens, max_tokens.get(), context_limit.get())
+                                    } else if near_context() {
+                                        format!("~{} + {} reply tokens of {}", tokens, max_tokens.get(), context_limit.get())
+                                    } else {
+                                        format!("~{} tokens", tokens)
+                                    }
+                                }}
+                            </span>
+                        </Show>
                     </div>
 
                     <textarea 
@@ -605,7 +1980,13 @@ fn App() -> impl IntoView {
                         when=move || is_generating.get()
                         fallback=move || view! {
                             // Send button
-                            <button id="send-btn" class="action-btn" on:click=move |_| send_message()>
+                            <button
+                                id="send-btn"
+                                class="action-btn"
+                                title=move || if is_online.get() { "" } else { "The server is offline; Send is enabled again once it is back" }
+                                prop:disabled=move || !is_online.get() || over_context() || over_prompt_limit()
+                                on:click=move |_| send_message()
+                            >
                                 "Send"
                             </button>
                         }
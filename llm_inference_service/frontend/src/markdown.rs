@@ -0,0 +1,66 @@
+// Markdown rendering of AI replies
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, Options, Parser, Tag, TagEnd};
+
+// Link and image targets a reply may use; anything else (javascript:, data:, ...) is dropped
+const SAFE_SCHEMES: [&str; 3] = ["http://", "https://", "mailto:"];
+
+fn safe_url(url: CowStr) -> CowStr {
+    let lower = url.to_ascii_lowercase();
+    if SAFE_SCHEMES.iter().any(|scheme| lower.starts_with(scheme)) {
+        url
+    } else {
+        CowStr::Borrowed("#")
+    }
+}
+
+// Render a reply to HTML. Raw HTML from the model is escaped, so it shows as text instead of
+// becoming markup. Fenced code blocks get a Copy button (handled by `copy_code_block`).
+// An unterminated fence, as while streaming, runs to the end of the text.
+pub fn render(text: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let events = Parser::new_ext(text, options).map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        Event::Start(Tag::CodeBlock(kind)) => {
+            let mut open = String::from("<div class=\"code-block\"><button class=\"copy-code\">Copy</button><pre><code");
+            if let CodeBlockKind::Fenced(info) = kind {
+                // Only characters that need no escaping inside the attribute
+                let lang: String = info
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .chars()
+                    .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '+' | '#'))
+                    .collect();
+                if !lang.is_empty() {
+                    open.push_str(&format!(" class=\"language-{}\"", lang));
+                }
+            }
+            open.push('>');
+            Event::Html(open.into())
+        }
+        Event::End(TagEnd::CodeBlock) => Event::Html("</code></pre></div>".into()),
+        Event::Start(Tag::Link { link_type, dest_url, title, id }) => {
+            Event::Start(Tag::Link { link_type, dest_url: safe_url(dest_url), title, id })
+        }
+        Event::Start(Tag::Image { link_type, dest_url, title, id }) => {
+            Event::Start(Tag::Image { link_type, dest_url: safe_url(dest_url), title, id })
+        }
+        other => other,
+    });
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events);
+    html
+}
+
+// Click handler for a rendered reply: a click on a code block's Copy button copies that block.
+// None when the click landed anywhere else.
+pub fn copy_code_block(ev: web_sys::MouseEvent) -> Option<()> {
+    use wasm_bindgen::JsCast;
+    let button = ev.target()?.dyn_into::<web_sys::Element>().ok()?.closest(".copy-code").ok()??;
+    let code = button.closest(".code-block").ok()??.query_selector("code").ok()??;
+    super::copy_to_clipboard(&code.text_content().unwrap_or_default());
+    // Confirm on the button itself; the reply may re-render before this fires while streaming
+    button.set_text_content(Some("Copied!"));
+    leptos::set_timeout(move || button.set_text_content(Some("Copy")), std::time::Duration::from_millis(1500));
+    Some(())
+}
@@ -0,0 +1,91 @@
+// Address of the backend, chosen at runtime: a `?server=` query parameter, else the address
+// saved in localStorage, else a default derived from the page's own address
+use std::cell::RefCell;
+
+use leptos::logging;
+
+use crate::storage;
+
+// Port of `trunk serve`; a page served from it talks to the backend on BACKEND_PORT of the same host
+const TRUNK_PORT: &str = "8080";
+const BACKEND_PORT: &str = "8081";
+
+thread_local! {
+    static SERVER_URL: RefCell<String> = RefCell::new(initial_url());
+}
+
+// Full URL of an API path (e.g. "/health") on the current server
+pub fn api_url(path: &str) -> String {
+    SERVER_URL.with(|url| format!("{}{}", url.borrow(), path))
+}
+
+pub fn server_url() -> String {
+    SERVER_URL.with(|url| url.borrow().clone())
+}
+
+// Host and port of a server URL, for display
+pub fn host_of(url: &str) -> String {
+    web_sys::Url::new(url).map(|u| u.host()).unwrap_or_else(|_| url.to_string())
+}
+
+// Switch to another server and remember it; an empty address goes back to the default
+pub fn set_server_url(url: &str) -> Result<(), String> {
+    let url = normalize(url)?;
+    remember(&url);
+    let url = if url.is_empty() { default_url() } else { url };
+    SERVER_URL.with(|current| *current.borrow_mut() = url);
+    Ok(())
+}
+
+// The page's own origin, except under `trunk serve`, where the backend runs on its own port
+pub fn default_url() -> String {
+    let Some(location) = web_sys::window().map(|w| w.location()) else {
+        return format!("http://127.0.0.1:{}", BACKEND_PORT);
+    };
+    if location.port().unwrap_or_default() == TRUNK_PORT {
+        let protocol = location.protocol().unwrap_or_default();
+        let hostname = location.hostname().unwrap_or_default();
+        format!("{}//{}:{}", protocol, hostname, BACKEND_PORT)
+    } else {
+        location.origin().unwrap_or_default()
+    }
+}
+
+// Trimmed and without a trailing slash; empty stays empty (the default)
+fn normalize(url: &str) -> Result<String, String> {
+    let url = url.trim().trim_end_matches('/');
+    if url.is_empty() {
+        return Ok(String::new());
+    }
+    let parsed = web_sys::Url::new(url).map_err(|_| format!("Not a valid URL: {}", url))?;
+    if !matches!(parsed.protocol().as_str(), "http:" | "https:") {
+        return Err("The server address must start with http:// or https://".to_string());
+    }
+    Ok(url.to_string())
+}
+
+fn remember(url: &str) {
+    let mut settings = storage::load_settings();
+    settings.server_url = url.to_string();
+    storage::save_settings(&settings);
+}
+
+fn initial_url() -> String {
+    // A ?server= link also sets the server for later visits
+    let from_query = web_sys::window()
+        .and_then(|w| w.location().search().ok())
+        .and_then(|search| web_sys::UrlSearchParams::new_with_str(&search).ok())
+        .and_then(|params| params.get("server"));
+    if let Some(url) = from_query {
+        match normalize(&url) {
+            Ok(url) if !url.is_empty() => {
+                remember(&url);
+                return url;
+            }
+            Ok(_) => {}
+            Err(e) => logging::warn!("Ignoring the ?server= parameter: {}", e),
+        }
+    }
+    let saved = storage::load_settings().server_url;
+    if saved.is_empty() { default_url() } else { saved }
+}
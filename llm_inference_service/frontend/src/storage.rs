@@ -0,0 +1,64 @@
+// Conversations and settings kept in localStorage, so reloading the page does not lose them
+use std::collections::BTreeMap;
+
+use gloo_storage::{LocalStorage, Storage};
+use leptos::logging;
+use serde::{Deserialize, Serialize};
+
+use crate::Conversation;
+
+const CONVERSATIONS_KEY: &str = "llm-chat.conversations";
+const SETTINGS_KEY: &str = "llm-chat.settings";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredConversations {
+    pub active: u64, // Conversation on screen
+    pub conversations: BTreeMap<u64, Conversation>,
+}
+
+// Settings that are not per conversation; fields missing from an older stored copy take their defaults
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoredSettings {
+    pub model: String, // Last model the user chose, empty if none
+    pub server_url: String, // Backend address the user set, empty for the default
+}
+
+// None for a fresh session; a stored copy that cannot be read counts as none
+pub fn load_conversations() -> Option<StoredConversations> {
+    LocalStorage::get::<StoredConversations>(CONVERSATIONS_KEY)
+        .ok()
+        .filter(|stored| stored.conversations.contains_key(&stored.active))
+}
+
+pub fn load_settings() -> StoredSettings {
+    LocalStorage::get(SETTINGS_KEY).unwrap_or_default()
+}
+
+// Save the conversations. When the quota is exceeded, the oldest ones other than the
+// conversation on screen are dropped until the rest fits.
+pub fn save_conversations(stored: &StoredConversations) {
+    let mut stored = stored.clone();
+    loop {
+        if LocalStorage::set(CONVERSATIONS_KEY, &stored).is_ok() {
+            return;
+        }
+        let oldest = stored.conversations.keys().copied().find(|id| *id != stored.active);
+        match oldest {
+            Some(id) => {
+                stored.conversations.remove(&id);
+                logging::warn!("Conversations too large for localStorage, not saving the oldest one");
+            }
+            None => {
+                logging::warn!("Could not save the conversation to localStorage");
+                return;
+            }
+        }
+    }
+}
+
+pub fn save_settings(settings: &StoredSettings) {
+    if let Err(e) = LocalStorage::set(SETTINGS_KEY, settings) {
+        logging::warn!("Could not save settings to localStorage: {}", e);
+    }
+}
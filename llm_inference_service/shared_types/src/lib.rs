@@ -0,0 +1,339 @@
+// shared_types/src/lib.rs
+// Request and response bodies of the backend's REST API, used by both the backend and the
+// frontend so the two cannot drift apart, plus the clients' parser for the /infer_stream
+// events. Plain serde types and std only: this crate must build for native and wasm32 targets
+// alike.
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+pub mod sse;
+
+// Standardized API response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiResponse<T> {
+    pub status: String, // "ok" or "error"
+    pub data: Option<T>,
+    pub message: Option<String>,
+}
+
+// GET /models
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ModelList {
+    pub models: HashMap<String, ModelStatus>,
+    pub active: String,
+    pub vram_usage: BTreeMap<usize, String>, // "used/limit MB" per GPU index
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ModelStatus {
+    pub loaded: bool,
+    pub size_mb: usize, // measured_mb if known, otherwise estimated_mb
+    pub estimated_mb: usize,
+    pub measured_mb: Option<usize>,
+    pub size_source: String, // "measured" or "estimated"
+    pub device: String, // Device it was last placed on, else the configured one ("auto" by default)
+    pub last_used: Option<u64>,
+    pub idle_secs: Option<u64>, // Loaded models: seconds since last use
+    pub idle_unload_in_secs: Option<u64>, // Loaded models: time left before the idle unload, if it applies
+    pub unhealthy: Option<String>, // Error that unloaded the model as unusable, until it is loaded again
+    #[serde(default)]
+    pub aliases: Vec<String>, // Other names requests may use for it
+}
+
+// POST /load_model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LoadModelRequest {
+    pub name: String,
+    // Queue the load and answer at once with a LoadJob to poll, instead of waiting for it
+    #[serde(default, rename = "async")]
+    pub run_async: bool,
+}
+
+// Progress of a queued load, from GET /jobs/:id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending, // Waiting behind other loads
+    Downloading, // Fetching or checking the files
+    Loading, // Making room in VRAM and loading the weights
+    Ready,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(self, JobStatus::Ready | JobStatus::Failed)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LoadJob {
+    pub id: u64,
+    pub model: String,
+    pub status: JobStatus,
+    pub message: Option<String>, // Outcome once finished
+}
+
+// POST /unload_model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct UnloadModelRequest {
+    pub name: String,
+}
+
+// Speaker of an earlier turn of a conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ChatRole {
+    User,
+    Assistant,
+}
+
+impl ChatRole {
+    // Role name used by chat templates
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChatRole::User => "user",
+            ChatRole::Assistant => "assistant",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ChatTurn {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+// POST /infer, /infer_stream and /preview_prompt. Omitted options use the model's defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct InferRequest {
+    pub prompt: String, // The latest user message
+    // Earlier turns of the conversation, oldest first, templated before `prompt`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<ChatTurn>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    // Omitted, null or -1: the model's default_max_tokens, else until a stop token or a full context
+    #[serde(default, deserialize_with = "max_tokens_or_unlimited")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<i64>, minimum = -1))]
+    pub max_tokens: Option<usize>,
+    pub seed: Option<u64>,
+    pub system_prompt: Option<String>,
+    pub timeout_secs: Option<u64>,
+    pub mirostat_tau: Option<f64>,
+    pub mirostat_eta: Option<f64>,
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    pub banned_strings: Option<Vec<String>>,
+    pub no_repeat_ngram_size: Option<usize>,
+    pub repeat_last_n: Option<usize>,
+    // Return each token's log-probability with this many top alternatives (max 20)
+    pub logprobs: Option<usize>,
+    // /infer_stream only: send the templated prompt as the first event
+    #[serde(default)]
+    pub echo: bool,
+    // /infer only: number of completions to generate for the prompt
+    pub n: Option<usize>,
+}
+
+// max_tokens as clients send it: a token count, or -1 like null for no explicit limit
+fn max_tokens_or_unlimited<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+    match Option::<i64>::deserialize(deserializer)? {
+        None | Some(-1) => Ok(None),
+        Some(n) => usize::try_from(n)
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("max_tokens must be -1 or a token count, got {}", n))),
+    }
+}
+
+// POST /tokenize
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct TokenizeRequest {
+    pub model: Option<String>, // Defaults to the active model
+    pub text: String,
+    #[serde(default)]
+    pub add_special_tokens: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct TokenizeResponse {
+    pub tokens: Vec<u32>,
+    pub count: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::DeserializeOwned;
+    use serde_json::{Value, json};
+
+    // Parse `wire`, serialize it again and check nothing changed on the way
+    fn round_trip<T: Serialize + DeserializeOwned>(wire: Value) -> T {
+        let parsed: T = serde_json::from_value(wire.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), wire);
+        parsed
+    }
+
+    #[test]
+    fn infer_request_defaults_omitted_options() {
+        let req: InferRequest = serde_json::from_value(json!({ "prompt": "hi" })).unwrap();
+        assert_eq!(req.prompt, "hi");
+        assert!(req.messages.is_empty());
+        assert_eq!(req.max_tokens, None);
+        assert!(!req.echo);
+        assert!(req.temperature.is_none() && req.seed.is_none() && req.n.is_none());
+    }
+
+    #[test]
+    fn infer_request_round_trips() {
+        let req: InferRequest = round_trip(json!({
+            "prompt": "and now?",
+            "messages": [
+                { "role": "user", "content": "hello" },
+                { "role": "assistant", "content": "hi" },
+            ],
+            "temperature": 0.5,
+            "top_p": null,
+            "max_tokens": 64,
+            "seed": 7,
+            "system_prompt": "Be brief.",
+            "timeout_secs": null,
+            "mirostat_tau": null,
+            "mirostat_eta": null,
+            "logit_bias": { "42": -1.5 },
+            "banned_strings": ["```"],
+            "no_repeat_ngram_size": null,
+            "repeat_last_n": null,
+            "logprobs": 2,
+            "echo": true,
+            "n": null,
+        }));
+        assert_eq!(req.messages[1], ChatTurn { role: ChatRole::Assistant, content: "hi".to_string() });
+        assert_eq!(req.logit_bias, Some(HashMap::from([(42, -1.5)])));
+    }
+
+    #[test]
+    fn infer_request_reads_the_repetition_options() {
+        let req: InferRequest =
+            serde_json::from_value(json!({ "prompt": "hi", "no_repeat_ngram_size": 3, "repeat_last_n": 64 })).unwrap();
+        assert_eq!((req.no_repeat_ngram_size, req.repeat_last_n), (Some(3), Some(64)));
+        // Clients that predate the options get none
+        let req: InferRequest = serde_json::from_value(json!({ "prompt": "hi" })).unwrap();
+        assert_eq!((req.no_repeat_ngram_size, req.repeat_last_n), (None, None));
+        assert!(serde_json::from_value::<InferRequest>(json!({ "prompt": "hi", "repeat_last_n": -1 })).is_err());
+    }
+
+    #[test]
+    fn infer_request_skips_empty_messages() {
+        let wire = serde_json::to_value(InferRequest { prompt: "hi".to_string(), ..Default::default() }).unwrap();
+        assert!(wire.get("messages").is_none());
+        assert_eq!(wire["max_tokens"], Value::Null);
+    }
+
+    #[test]
+    fn max_tokens_minus_one_and_null_mean_unlimited() {
+        for max_tokens in [json!(-1), Value::Null] {
+            let req: InferRequest = serde_json::from_value(json!({ "prompt": "", "max_tokens": max_tokens })).unwrap();
+            assert_eq!(req.max_tokens, None);
+        }
+        let err = serde_json::from_value::<InferRequest>(json!({ "prompt": "", "max_tokens": -2 })).unwrap_err();
+        assert!(err.to_string().contains("max_tokens must be -1 or a token count, got -2"));
+    }
+
+    #[test]
+    fn chat_turn_roles_are_lowercase() {
+        round_trip::<ChatTurn>(json!({ "role": "user", "content": "hello" }));
+        round_trip::<ChatTurn>(json!({ "role": "assistant", "content": "hi" }));
+        assert!(serde_json::from_value::<ChatTurn>(json!({ "role": "system", "content": "x" })).is_err());
+    }
+
+    #[test]
+    fn load_model_request_reads_async() {
+        let req: LoadModelRequest = round_trip(json!({ "name": "phi", "async": true }));
+        assert!(req.run_async);
+        let req: LoadModelRequest = serde_json::from_value(json!({ "name": "phi" })).unwrap();
+        assert!(!req.run_async);
+    }
+
+    #[test]
+    fn model_list_round_trips() {
+        let list: ApiResponse<ModelList> = round_trip(json!({
+            "status": "ok",
+            "data": {
+                "models": {
+                    "phi": {
+                        "loaded": true,
+                        "size_mb": 2900,
+                        "estimated_mb": 3000,
+                        "measured_mb": 2900,
+                        "size_source": "measured",
+                        "device": "cuda:0",
+                        "last_used": 1700000000,
+                        "idle_secs": 5,
+                        "idle_unload_in_secs": null,
+                        "unhealthy": null,
+                        "aliases": ["small"],
+                    },
+                },
+                "active": "phi",
+                "vram_usage": { "0": "2900/8000 MB" },
+            },
+            "message": null,
+        }));
+        let data = list.data.unwrap();
+        assert_eq!(data.vram_usage[&0], "2900/8000 MB");
+        assert_eq!(data.models["phi"].aliases, ["small"]);
+    }
+
+    #[test]
+    fn model_status_aliases_default_to_empty() {
+        let status: ModelStatus = serde_json::from_value(json!({
+            "loaded": false,
+            "size_mb": 3000,
+            "estimated_mb": 3000,
+            "measured_mb": null,
+            "size_source": "estimated",
+            "device": "auto",
+            "last_used": null,
+            "idle_secs": null,
+            "idle_unload_in_secs": null,
+            "unhealthy": null,
+        }))
+        .unwrap();
+        assert!(status.aliases.is_empty());
+    }
+
+    #[test]
+    fn error_response_has_no_data() {
+        let res: ApiResponse<ModelList> =
+            round_trip(json!({ "status": "error", "data": null, "message": "Model 'x' not found." }));
+        assert!(res.data.is_none());
+    }
+
+    #[test]
+    fn load_job_status_is_snake_case() {
+        let job: LoadJob = round_trip(json!({ "id": 3, "model": "phi", "status": "downloading", "message": null }));
+        assert_eq!(job.status, JobStatus::Downloading);
+        assert!(!job.status.is_finished());
+        assert!(serde_json::from_value::<JobStatus>(json!("ready")).unwrap().is_finished());
+    }
+
+    #[test]
+    fn tokenize_round_trips() {
+        let req: TokenizeRequest = serde_json::from_value(json!({ "text": "hello" })).unwrap();
+        assert!(req.model.is_none() && !req.add_special_tokens);
+        round_trip::<TokenizeRequest>(json!({ "model": "phi", "text": "hello", "add_special_tokens": true }));
+        round_trip::<TokenizeResponse>(json!({ "tokens": [1, 22172], "count": 2 }));
+    }
+}
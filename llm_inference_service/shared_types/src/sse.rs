@@ -0,0 +1,147 @@
+// shared_types/src/sse.rs
+// Incremental parser for the server-sent events stream, following the SSE spec.
+// Network chunks can end anywhere, even in the middle of a line or of a UTF-8
+// character, so bytes are buffered until a full line arrives. Lines may end in
+// LF, CRLF or a lone CR (proxies such as nginx may rewrite the framing). An
+// event may carry several `data:` lines (the server splits payloads that
+// contain newlines); they are joined with '\n' and the event is only emitted
+// at the blank line that terminates it.
+
+// One dispatched event
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SseEvent {
+    pub event: String, // From the `event:` field, "message" when the stream named none
+    pub data: String,
+}
+
+#[derive(Default)]
+pub struct SseParser {
+    line: Vec<u8>,  // Bytes of the unfinished line
+    after_cr: bool, // The last line ended in CR, so a LF right after it is part of that line ending
+    event: String,
+    data_lines: Vec<String>,
+}
+
+impl SseParser {
+    // Feed a chunk of the response body and return every event it completed
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        for &byte in chunk {
+            if std::mem::take(&mut self.after_cr) && byte == b'\n' {
+                continue;
+            }
+            match byte {
+                b'\r' | b'\n' => {
+                    self.after_cr = byte == b'\r';
+                    // CR and LF never occur inside a multi-byte character, so the line is whole
+                    let line = std::mem::take(&mut self.line);
+                    events.extend(self.line_done(&String::from_utf8_lossy(&line)));
+                }
+                _ => self.line.push(byte),
+            }
+        }
+        events
+    }
+
+    fn line_done(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            // Blank line: dispatch the collected event; one without data is dropped
+            let event = std::mem::take(&mut self.event);
+            if self.data_lines.is_empty() {
+                return None;
+            }
+            let data = self.data_lines.join("\n");
+            self.data_lines.clear();
+            let event = if event.is_empty() { "message".to_string() } else { event };
+            return Some(SseEvent { event, data });
+        }
+        // Comment, e.g. a keep-alive
+        if line.starts_with(':') {
+            return None;
+        }
+        // A line without a colon is a field name with an empty value
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "data" => self.data_lines.push(value.to_string()),
+            "event" => self.event = value.to_string(),
+            // id: and retry: only matter for reconnecting, which the clients do not do
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(data: &str) -> SseEvent {
+        SseEvent { event: "message".to_string(), data: data.to_string() }
+    }
+
+    // Events of `stream` fed as two chunks split at `at`
+    fn parse_split(stream: &[u8], at: usize) -> Vec<SseEvent> {
+        let mut parser = SseParser::default();
+        let mut events = parser.push(&stream[..at]);
+        events.extend(parser.push(&stream[at..]));
+        events
+    }
+
+    // The same events must come out wherever the network happens to cut the stream
+    fn assert_split_invariant(stream: &str, expected: &[SseEvent]) {
+        let bytes = stream.as_bytes();
+        for at in 0..=bytes.len() {
+            assert_eq!(parse_split(bytes, at), expected, "split at byte {} of {:?}", at, stream);
+        }
+        let mut parser = SseParser::default();
+        let byte_by_byte: Vec<SseEvent> = bytes.iter().flat_map(|byte| parser.push(&[*byte])).collect();
+        assert_eq!(byte_by_byte, expected, "byte by byte: {:?}", stream);
+    }
+
+    #[test]
+    fn line_endings() {
+        let expected = [message("one"), message("two")];
+        assert_split_invariant("data: one\n\ndata: two\n\n", &expected);
+        assert_split_invariant("data: one\r\n\r\ndata: two\r\n\r\n", &expected);
+        assert_split_invariant("data: one\r\rdata: two\r\r", &expected);
+        assert_split_invariant("data: one\r\n\ndata: two\r\r\n", &expected);
+    }
+
+    #[test]
+    fn crlf_split_across_chunks_is_one_line_ending() {
+        let mut parser = SseParser::default();
+        assert_eq!(parser.push(b"data: one\r"), []);
+        assert_eq!(parser.push(b"\n\r"), [message("one")]);
+        assert_eq!(parser.push(b"\ndata: two\r\n\r\n"), [message("two")]);
+    }
+
+    #[test]
+    fn multi_line_data_is_joined() {
+        assert_split_invariant("data: first\ndata: second\r\ndata:third\r\n", &[]);
+        assert_split_invariant("data: first\ndata: second\r\ndata:third\r\n\r\n", &[message("first\nsecond\nthird")]);
+    }
+
+    #[test]
+    fn comments_and_event_names() {
+        let stream = ": keep-alive\n\nevent: error\ndata: {\"error\":\"x\"}\n\n:\ndata: plain\n\n";
+        let expected = [
+            SseEvent { event: "error".to_string(), data: "{\"error\":\"x\"}".to_string() },
+            message("plain"),
+        ];
+        assert_split_invariant(stream, &expected);
+    }
+
+    #[test]
+    fn multi_byte_characters_survive_any_split() {
+        assert_split_invariant("data: héllo 世界 🦀\r\n\r\ndata: ✓\n\n", &[message("héllo 世界 🦀"), message("✓")]);
+    }
+
+    #[test]
+    fn event_without_data_is_dropped() {
+        assert_split_invariant("event: ping\n\ndata: x\n\n", &[message("x")]);
+        assert_split_invariant("data\n\n", &[message("")]);
+    }
+}